@@ -0,0 +1,509 @@
+use defmt::Format;
+use heapless::Vec;
+use serde::{Serialize, Serializer};
+
+use crate::drivers::environment::{MAX_SENSORS, SensorId};
+use crate::drivers::sht3x::Sht3xReading;
+
+/// Most rules the configuration holds.
+pub const MAX_ALERT_RULES: usize = 4;
+/// Most alerts active at once, every rule on every sensor.
+pub const MAX_ACTIVE_ALERTS: usize = MAX_ALERT_RULES * MAX_SENSORS;
+/// Length of a rule in the configuration record, see [`AlertRule::to_bytes`].
+pub const ENCODED_RULE_LEN: usize = 19;
+
+/// Stands in for the sensor of a rule that watches every sensor.
+const ANY_SENSOR: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Quantity {
+    /// °C.
+    Temperature,
+    /// %RH.
+    Humidity,
+}
+
+impl Quantity {
+    pub fn label(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::Humidity => "humidity",
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "C",
+            Quantity::Humidity => "%",
+        }
+    }
+
+    /// The value of this quantity in `reading`.
+    pub fn of(self, reading: &Sht3xReading) -> f32 {
+        match self {
+            Quantity::Temperature => reading.temperature as f32,
+            Quantity::Humidity => reading.humidity as f32,
+        }
+    }
+}
+
+impl Serialize for Quantity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.label())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+impl Comparison {
+    pub fn label(self) -> &'static str {
+        match self {
+            Comparison::Above => "above",
+            Comparison::Below => "below",
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Above => ">",
+            Comparison::Below => "<",
+        }
+    }
+}
+
+impl Serialize for Comparison {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.label())
+    }
+}
+
+/// Raises an alert once a quantity has been past a threshold for a while, e.g. humidity
+/// above 70 % for 10 minutes.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct AlertRule {
+    /// Sensor watched, `None` for each of them on its own.
+    pub sensor: Option<SensorId>,
+    pub quantity: Quantity,
+    pub comparison: Comparison,
+    pub threshold: f32,
+    /// How far back past the threshold the value has to go to clear the alert, so a value
+    /// hovering around it doesn't flap.
+    pub hysteresis: f32,
+    /// How long the value has to stay past the threshold before the alert is raised.
+    pub min_duration_s: u32,
+    /// How long after clearing the alert can't be raised again.
+    pub rearm_s: u32,
+}
+
+impl AlertRule {
+    /// Encodes the rule for the configuration record, multi-byte values little-endian.
+    pub fn to_bytes(&self) -> [u8; ENCODED_RULE_LEN] {
+        let mut out = [0u8; ENCODED_RULE_LEN];
+
+        out[0] = self.sensor.map_or(ANY_SENSOR, |id| id.index() as u8);
+        out[1] = self.quantity as u8;
+        out[2] = self.comparison as u8;
+        out[3..7].copy_from_slice(&self.threshold.to_le_bytes());
+        out[7..11].copy_from_slice(&self.hysteresis.to_le_bytes());
+        out[11..15].copy_from_slice(&self.min_duration_s.to_le_bytes());
+        out[15..19].copy_from_slice(&self.rearm_s.to_le_bytes());
+        out
+    }
+
+    /// `None` for rules on sensors or quantities this firmware doesn't know.
+    pub fn from_bytes(bytes: &[u8; ENCODED_RULE_LEN]) -> Option<Self> {
+        let word = |at: usize| [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+
+        let sensor = match bytes[0] {
+            ANY_SENSOR => None,
+            index => Some(SensorId::new(index as usize)?),
+        };
+        let quantity = match bytes[1] {
+            0 => Quantity::Temperature,
+            1 => Quantity::Humidity,
+            _ => return None,
+        };
+        let comparison = match bytes[2] {
+            0 => Comparison::Above,
+            1 => Comparison::Below,
+            _ => return None,
+        };
+
+        Some(Self {
+            sensor,
+            quantity,
+            comparison,
+            threshold: f32::from_le_bytes(word(3)),
+            hysteresis: f32::from_le_bytes(word(7)),
+            min_duration_s: u32::from_le_bytes(word(11)),
+            rearm_s: u32::from_le_bytes(word(15)),
+        })
+    }
+
+    fn applies_to(&self, sensor: SensorId) -> bool {
+        self.sensor.is_none_or(|id| id == sensor)
+    }
+
+    fn is_past(&self, value: f32) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+
+    fn is_back(&self, value: f32) -> bool {
+        match self.comparison {
+            Comparison::Above => value <= self.threshold - self.hysteresis,
+            Comparison::Below => value >= self.threshold + self.hysteresis,
+        }
+    }
+}
+
+/// A rule that went off on a sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Alert {
+    /// Position of the rule in the configuration.
+    pub rule: u8,
+    pub sensor: SensorId,
+    pub quantity: Quantity,
+    pub comparison: Comparison,
+    pub threshold: f32,
+    /// The value that raised or cleared the alert.
+    pub value: f32,
+}
+
+impl Alert {
+    /// Whether both are about the same rule on the same sensor.
+    pub fn is_same(&self, other: &Alert) -> bool {
+        self.rule == other.rule && self.sensor == other.sensor
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Transition {
+    Raised(Alert),
+    Cleared(Alert),
+}
+
+/// Where one rule stands on one sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// Past the threshold since then, in ms, but not for long enough yet.
+    Pending(u64),
+    Active,
+    /// Cleared, can't be raised before then, in ms.
+    Rearming(u64),
+}
+
+/// Evaluates the rules against every reading. A rule on a sensor that stops reporting stays
+/// where it was until the sensor comes back.
+pub struct AlertEngine {
+    states: [[State; MAX_SENSORS]; MAX_ALERT_RULES],
+}
+
+impl Default for AlertEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AlertEngine {
+    pub const fn new() -> Self {
+        Self {
+            states: [[State::Idle; MAX_SENSORS]; MAX_ALERT_RULES],
+        }
+    }
+
+    /// Runs `rules` against a reading of `sensor` taken at `now_ms` and returns the alerts
+    /// it raised or cleared. Rules must stay in the same order between calls.
+    pub fn evaluate(
+        &mut self,
+        rules: &[AlertRule],
+        now_ms: u64,
+        sensor: SensorId,
+        reading: &Sht3xReading,
+    ) -> Vec<Transition, MAX_ALERT_RULES> {
+        let mut transitions = Vec::new();
+
+        for (index, (rule, states)) in rules.iter().zip(&mut self.states).enumerate() {
+            if !rule.applies_to(sensor) {
+                continue;
+            }
+
+            let value = rule.quantity.of(reading);
+            let state = &mut states[sensor.index()];
+
+            if matches!(*state, State::Rearming(until_ms) if now_ms >= until_ms) {
+                *state = State::Idle;
+            }
+
+            let mut raised = None;
+            *state = match *state {
+                State::Idle if rule.is_past(value) => State::Pending(now_ms),
+                State::Pending(_) if !rule.is_past(value) => State::Idle,
+                State::Active if rule.is_back(value) => {
+                    raised = Some(false);
+                    State::Rearming(now_ms + rule.rearm_s as u64 * 1000)
+                }
+                state => state,
+            };
+
+            // Checked right away so a rule without a minimum duration goes off on the
+            // first reading past the threshold.
+            let pending_ms = match *state {
+                State::Pending(since_ms) => Some(now_ms.saturating_sub(since_ms)),
+                _ => None,
+            };
+            if pending_ms.is_some_and(|ms| ms >= rule.min_duration_s as u64 * 1000) {
+                raised = Some(true);
+                *state = State::Active;
+            }
+
+            let Some(raised) = raised else {
+                continue;
+            };
+            let alert = Alert {
+                rule: index as u8,
+                sensor,
+                quantity: rule.quantity,
+                comparison: rule.comparison,
+                threshold: rule.threshold,
+                value,
+            };
+            // Can't fail, there is at most one per rule.
+            let _ = transitions.push(if raised {
+                Transition::Raised(alert)
+            } else {
+                Transition::Cleared(alert)
+            });
+        }
+
+        transitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOULD: AlertRule = AlertRule {
+        sensor: None,
+        quantity: Quantity::Humidity,
+        comparison: Comparison::Above,
+        threshold: 70.0,
+        hysteresis: 5.0,
+        min_duration_s: 600,
+        rearm_s: 1800,
+    };
+
+    const FROST: AlertRule = AlertRule {
+        sensor: Some(SensorId::at(1)),
+        quantity: Quantity::Temperature,
+        comparison: Comparison::Below,
+        threshold: 5.0,
+        hysteresis: 1.0,
+        min_duration_s: 0,
+        rearm_s: 0,
+    };
+
+    fn alert(rule: &AlertRule, index: u8, sensor: SensorId, value: f32) -> Alert {
+        Alert {
+            rule: index,
+            sensor,
+            quantity: rule.quantity,
+            comparison: rule.comparison,
+            threshold: rule.threshold,
+            value,
+        }
+    }
+
+    #[test]
+    fn raised_after_the_hold_time() {
+        let mut engine = AlertEngine::new();
+        let rules = [MOULD];
+        let humid = |engine: &mut AlertEngine, now_ms, humidity| {
+            engine.evaluate(
+                &rules,
+                now_ms,
+                SensorId::at(0),
+                &Sht3xReading::new(20.0, humidity),
+            )
+        };
+
+        assert_eq!(humid(&mut engine, 0, 75.0), []);
+        assert_eq!(humid(&mut engine, 599_999, 75.0), []);
+        assert_eq!(
+            humid(&mut engine, 600_000, 76.0),
+            [Transition::Raised(alert(&MOULD, 0, SensorId::at(0), 76.0))]
+        );
+        // Raised once.
+        assert_eq!(humid(&mut engine, 700_000, 80.0), []);
+    }
+
+    #[test]
+    fn hold_time_starts_over_when_the_value_dips() {
+        let mut engine = AlertEngine::new();
+        let rules = [MOULD];
+        let humid = |engine: &mut AlertEngine, now_ms, humidity| {
+            engine.evaluate(
+                &rules,
+                now_ms,
+                SensorId::at(0),
+                &Sht3xReading::new(20.0, humidity),
+            )
+        };
+
+        assert_eq!(humid(&mut engine, 0, 75.0), []);
+        // At the threshold isn't past it.
+        assert_eq!(humid(&mut engine, 300_000, 70.0), []);
+        assert_eq!(humid(&mut engine, 310_000, 75.0), []);
+        assert_eq!(humid(&mut engine, 700_000, 75.0), []);
+        assert_eq!(humid(&mut engine, 910_000, 75.0).len(), 1);
+    }
+
+    #[test]
+    fn cleared_past_the_hysteresis_then_rearmed() {
+        let mut engine = AlertEngine::new();
+        let rules = [MOULD];
+        let humid = |engine: &mut AlertEngine, now_ms, humidity| {
+            engine.evaluate(
+                &rules,
+                now_ms,
+                SensorId::at(0),
+                &Sht3xReading::new(20.0, humidity),
+            )
+        };
+        humid(&mut engine, 0, 75.0);
+        assert_eq!(humid(&mut engine, 600_000, 75.0).len(), 1);
+
+        // Back under the threshold, but not by the hysteresis yet.
+        assert_eq!(humid(&mut engine, 610_000, 65.5), []);
+        assert_eq!(
+            humid(&mut engine, 620_000, 65.0),
+            [Transition::Cleared(alert(&MOULD, 0, SensorId::at(0), 65.0))]
+        );
+        assert_eq!(humid(&mut engine, 630_000, 60.0), []);
+
+        // The hold time only starts once rearmed, 30 minutes after clearing.
+        assert_eq!(humid(&mut engine, 700_000, 90.0), []);
+        assert_eq!(humid(&mut engine, 2_419_999, 90.0), []);
+        assert_eq!(humid(&mut engine, 2_420_000, 90.0), []);
+        assert_eq!(humid(&mut engine, 3_019_999, 90.0), []);
+        assert_eq!(humid(&mut engine, 3_020_000, 90.0).len(), 1);
+    }
+
+    #[test]
+    fn below_without_hold_time() {
+        let mut engine = AlertEngine::new();
+        let rules = [MOULD, FROST];
+        let cold = |engine: &mut AlertEngine, now_ms, temperature| {
+            engine.evaluate(
+                &rules,
+                now_ms,
+                SensorId::at(1),
+                &Sht3xReading::new(temperature, 50.0),
+            )
+        };
+
+        // Raised by the first reading past the threshold.
+        assert_eq!(
+            cold(&mut engine, 0, 2.0),
+            [Transition::Raised(alert(&FROST, 1, SensorId::at(1), 2.0))]
+        );
+        assert_eq!(cold(&mut engine, 1, 5.5), []);
+        assert_eq!(
+            cold(&mut engine, 2, 6.0),
+            [Transition::Cleared(alert(&FROST, 1, SensorId::at(1), 6.0))]
+        );
+        // Nothing to rearm.
+        assert_eq!(cold(&mut engine, 3, 4.0).len(), 1);
+    }
+
+    #[test]
+    fn sensors_are_watched_separately() {
+        let mut engine = AlertEngine::new();
+        let rules = [MOULD, FROST];
+
+        // The frost rule only watches sensor 1.
+        assert_eq!(
+            engine.evaluate(&rules, 0, SensorId::at(0), &Sht3xReading::new(2.0, 80.0)),
+            []
+        );
+        assert_eq!(
+            engine.evaluate(&rules, 0, SensorId::at(1), &Sht3xReading::new(20.0, 80.0)),
+            []
+        );
+
+        assert_eq!(
+            engine.evaluate(
+                &rules,
+                600_000,
+                SensorId::at(1),
+                &Sht3xReading::new(4.0, 80.0)
+            ),
+            [
+                Transition::Raised(alert(&MOULD, 0, SensorId::at(1), 80.0)),
+                Transition::Raised(alert(&FROST, 1, SensorId::at(1), 4.0)),
+            ]
+        );
+        assert_eq!(
+            engine.evaluate(
+                &rules,
+                600_000,
+                SensorId::at(0),
+                &Sht3xReading::new(20.0, 80.0)
+            ),
+            [Transition::Raised(alert(&MOULD, 0, SensorId::at(0), 80.0))]
+        );
+    }
+
+    #[test]
+    fn every_rule_on_every_sensor() {
+        let rule = AlertRule {
+            min_duration_s: 0,
+            ..MOULD
+        };
+        let rules = [rule; MAX_ALERT_RULES + 1];
+        let mut engine = AlertEngine::new();
+
+        // Rules past MAX_ALERT_RULES are never evaluated.
+        let mut active = std::vec::Vec::new();
+        for id in SensorId::all() {
+            let transitions = engine.evaluate(&rules, 0, id, &Sht3xReading::new(20.0, 90.0));
+            assert_eq!(transitions.len(), MAX_ALERT_RULES);
+            active.extend(transitions);
+        }
+        assert_eq!(active.len(), MAX_ACTIVE_ALERTS);
+
+        let cleared: usize = SensorId::all()
+            .map(|id| {
+                engine
+                    .evaluate(&rules, 1, id, &Sht3xReading::new(20.0, 50.0))
+                    .len()
+            })
+            .sum();
+        assert_eq!(cleared, MAX_ACTIVE_ALERTS);
+    }
+
+    #[test]
+    fn encoding() {
+        for rule in [MOULD, FROST] {
+            assert_eq!(AlertRule::from_bytes(&rule.to_bytes()), Some(rule));
+        }
+
+        let mut bytes = MOULD.to_bytes();
+        bytes[0] = MAX_SENSORS as u8;
+        assert_eq!(AlertRule::from_bytes(&bytes), None);
+        let mut bytes = MOULD.to_bytes();
+        bytes[1] = 2;
+        assert_eq!(AlertRule::from_bytes(&bytes), None);
+        let mut bytes = MOULD.to_bytes();
+        bytes[2] = 2;
+        assert_eq!(AlertRule::from_bytes(&bytes), None);
+    }
+}
@@ -0,0 +1,688 @@
+use serde::Serialize;
+
+use crate::alert::{Alert, Comparison, Quantity};
+use crate::control::{Command, RelayStatus};
+use crate::drivers::environment::{Measurement, SensorError, SensorId, sensor_names};
+use crate::http::{BodyWriter, HttpError, StatusCode};
+use crate::metrics::{Registry, Runtime};
+use crate::sntp::Timestamp;
+use crate::stats::WindowSummary;
+
+pub const JSON: &str = "application/json";
+pub const HTML: &str = "text/html; charset=utf-8";
+pub const PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Size of the buffer [`handle`] gets from the server. Fits the metrics exposition, the
+/// largest response, with every metric set to its longest value.
+pub const RESPONSE_CAPACITY: usize = 5120;
+
+/// Polls the API so the page stays current without reloading.
+pub const INDEX_PAGE: &str = "<!DOCTYPE html><html><head>\
+<meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+<title>home-monitor</title></head><body>\
+<h1>home-monitor</h1>\
+<p id=\"a\" style=\"color:#c00\"></p>\
+<p id=\"r\">-</p>\
+<p id=\"y\" hidden>Relay: <b id=\"ys\"></b> \
+<button onclick=\"relay('on')\">on</button>\
+<button onclick=\"relay('off')\">off</button>\
+<button onclick=\"relay('auto')\">auto</button></p>\
+<p id=\"t\"></p>\
+<pre id=\"s\"></pre>\
+<script>\
+function showRelay(j){\
+document.getElementById('y').hidden=false;\
+document.getElementById('ys').textContent=(j.on?'on':'off')+' ('+j.mode+')';}\
+async function relay(c){\
+const y=await fetch('/api/relay',{method:'POST',body:c});\
+if(y.ok)showRelay(await y.json());}\
+async function poll(){\
+try{\
+const a=await fetch('/api/alerts');\
+if(a.ok){const j=await a.json();\
+document.getElementById('a').innerHTML=j.alerts.map(x=>\
+'<b>'+x.sensor+': '+x.quantity+' '+x.comparison+' '+x.threshold+'</b> ('+x.value.toFixed(1)+')'\
+).join('<br>');}\
+const r=await fetch('/api/reading');\
+if(r.ok){const j=await r.json();\
+document.getElementById('r').innerHTML=j.readings.map(x=>\
+x.sensor+': <b>'+x.temperature.toFixed(2)+'</b> &deg;C, <b>'+x.humidity.toFixed(2)+'</b> %'\
++(x.pressure!=null?', <b>'+x.pressure.toFixed(1)+'</b> hPa':'')\
++(x.co2!=null?', <b>'+x.co2+'</b> ppm':'')\
++(x.fault?' <i>fault: '+x.fault+'</i>':'')\
+).join('<br>');}\
+const y=await fetch('/api/relay');\
+if(y.ok)showRelay(await y.json());\
+const t=await fetch('/api/stats');\
+if(t.ok){const j=await t.json();\
+document.getElementById('t').innerHTML=j.statistics.map(x=>x.windows.map(w=>\
+x.sensor+' '+w.window+': '+w.temperature.min.toFixed(1)+'&ndash;'+w.temperature.max.toFixed(1)\
++' &deg;C, '+w.humidity.min.toFixed(0)+'&ndash;'+w.humidity.max.toFixed(0)+' %'\
+).join('<br>')).join('<br>');}\
+const s=await fetch('/api/status');\
+document.getElementById('s').textContent=JSON.stringify(await s.json(),null,2);\
+}catch(e){}\
+setTimeout(poll,5000);}\
+poll();\
+</script></body></html>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Index,
+    Reading,
+    Statistics,
+    Alerts,
+    Relay,
+    /// Overrides the relay with a `Command` in the body.
+    SetRelay,
+    Status,
+    Metrics,
+}
+
+impl Route {
+    /// Maps a request onto a route, or the error status to answer with.
+    pub fn resolve(method: &str, path: &str) -> Result<Self, StatusCode> {
+        let route = match path {
+            "/" | "/index.html" => Route::Index,
+            "/api/reading" => Route::Reading,
+            "/api/stats" => Route::Statistics,
+            "/api/alerts" => Route::Alerts,
+            "/api/relay" => Route::Relay,
+            "/api/status" => Route::Status,
+            "/metrics" => Route::Metrics,
+            _ => return Err(StatusCode(404)),
+        };
+
+        match (method, route) {
+            ("GET", route) => Ok(route),
+            ("POST", Route::Relay) => Ok(Route::SetRelay),
+            _ => Err(StatusCode(405)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ReadingBody {
+    /// Name of the sensor, see `SensorId::name`.
+    pub sensor: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity: Option<f64>,
+    /// Only from chips that measure it, like the rest of [`Measurement`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pressure: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub co2: Option<u16>,
+    /// How long ago the reading was taken.
+    pub age_ms: u64,
+    pub timestamp: Timestamp,
+    /// Set while the sensor is failing, the values are then its last good ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fault: Option<&'static str>,
+}
+
+impl ReadingBody {
+    pub fn new(sensor: SensorId, reading: &Measurement, age_ms: u64, timestamp: Timestamp) -> Self {
+        Self {
+            sensor: sensor.name(),
+            temperature: reading.temperature,
+            humidity: reading.humidity,
+            pressure: reading.pressure,
+            co2: reading.co2,
+            age_ms,
+            timestamp,
+            fault: None,
+        }
+    }
+
+    pub fn with_fault(self, fault: Option<SensorError>) -> Self {
+        Self {
+            fault: fault.map(|e| e.label()),
+            ..self
+        }
+    }
+}
+
+/// Latest reading of every sensor that has reported one.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ReadingsBody<'a> {
+    pub readings: &'a [ReadingBody],
+}
+
+/// Rolling statistics of one sensor.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SensorStatistics<'a> {
+    pub sensor: &'static str,
+    /// Windows that have readings, shortest first.
+    pub windows: &'a [WindowSummary],
+}
+
+/// Statistics of every sensor that has reported a reading.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StatisticsBody<'a> {
+    pub statistics: &'a [SensorStatistics<'a>],
+}
+
+/// An alert that is still active.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct AlertBody {
+    pub sensor: &'static str,
+    /// Position of the rule in the configuration.
+    pub rule: u8,
+    pub quantity: Quantity,
+    pub comparison: Comparison,
+    pub threshold: f32,
+    /// The value that raised the alert.
+    pub value: f32,
+    /// How long ago the alert was raised.
+    pub age_s: u64,
+    pub timestamp: Timestamp,
+}
+
+impl AlertBody {
+    pub fn new(alert: &Alert, age_s: u64, timestamp: Timestamp) -> Self {
+        Self {
+            sensor: alert.sensor.name(),
+            rule: alert.rule,
+            quantity: alert.quantity,
+            comparison: alert.comparison,
+            threshold: alert.threshold,
+            value: alert.value,
+            age_s,
+            timestamp,
+        }
+    }
+}
+
+/// Active alerts, oldest first. Empty while all is well.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct AlertsBody<'a> {
+    pub alerts: &'a [AlertBody],
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HeapStats {
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Outcome of the most recent upload to the collector.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UploadStatus<'a> {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+    /// HTTP status the collector answered with, if it answered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub age_s: u64,
+}
+
+/// Delivery state of one uplink.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UplinkStatus<'a> {
+    pub name: &'a str,
+    /// `starting`, `up` or `down`.
+    pub state: &'a str,
+    /// Why the last attempt failed, while down.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+    pub queued: usize,
+    pub capacity: usize,
+    pub dropped: u32,
+    pub delivered: u32,
+    pub rejected: u32,
+    pub failures: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StatusBody<'a> {
+    pub node_id: &'a str,
+    pub firmware_version: &'a str,
+    pub wifi: &'a str,
+    pub ip: Option<&'a str>,
+    pub uptime_s: u64,
+    pub heap: HeapStats,
+    pub last_upload: Option<UploadStatus<'a>>,
+    pub uplinks: &'a [UplinkStatus<'a>],
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ErrorBody<'a> {
+    pub error: &'a str,
+}
+
+/// Serializes `body` as JSON into `buf` and returns its length.
+pub fn to_json<T: Serialize>(body: &T, buf: &mut [u8]) -> Result<usize, HttpError> {
+    serde_json_core::to_slice(body, buf).map_err(|_| HttpError::BufferTooSmall)
+}
+
+/// Everything the API can report, gathered once per request.
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot<'a> {
+    /// Empty until the first reading.
+    pub readings: &'a [ReadingBody],
+    /// Empty until the first reading.
+    pub statistics: &'a [SensorStatistics<'a>],
+    pub alerts: &'a [AlertBody],
+    /// `None` without the `relay` feature. Taken after applying a [`Route::SetRelay`].
+    pub relay: Option<RelayStatus>,
+    pub status: StatusBody<'a>,
+    pub metrics: &'a Registry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply<'b> {
+    pub status: StatusCode,
+    pub content_type: &'static str,
+    pub body: &'b [u8],
+}
+
+/// Builds the reply to a request with `body`. JSON and metrics bodies are serialized into
+/// `buf`.
+pub fn handle<'b>(
+    method: &str,
+    path: &str,
+    body: &[u8],
+    snapshot: &Snapshot<'_>,
+    buf: &'b mut [u8],
+) -> Reply<'b> {
+    let route = match Route::resolve(method, path) {
+        Ok(route) => route,
+        Err(status) => return error(status, status.reason(), buf),
+    };
+
+    let (content_type, encoded) = match route {
+        Route::Index => {
+            return Reply {
+                status: StatusCode(200),
+                content_type: HTML,
+                body: INDEX_PAGE.as_bytes(),
+            };
+        }
+        Route::Reading => match snapshot.readings {
+            [] => return error(StatusCode(503), "no reading yet", buf),
+            readings => (JSON, to_json(&ReadingsBody { readings }, buf)),
+        },
+        Route::Statistics => match snapshot.statistics {
+            [] => return error(StatusCode(503), "no reading yet", buf),
+            statistics => (JSON, to_json(&StatisticsBody { statistics }, buf)),
+        },
+        Route::Alerts => (
+            JSON,
+            to_json(
+                &AlertsBody {
+                    alerts: snapshot.alerts,
+                },
+                buf,
+            ),
+        ),
+        Route::SetRelay if Command::parse(body).is_none() => {
+            return error(StatusCode(400), "bad command", buf);
+        }
+        Route::Relay | Route::SetRelay => match &snapshot.relay {
+            None => return error(StatusCode(503), "no relay", buf),
+            Some(relay) => (JSON, to_json(relay, buf)),
+        },
+        Route::Status => (JSON, to_json(&snapshot.status, buf)),
+        Route::Metrics => (PROMETHEUS, to_metrics(snapshot, buf)),
+    };
+
+    match encoded {
+        Ok(len) => Reply {
+            status: StatusCode(200),
+            content_type,
+            body: &buf[..len],
+        },
+        Err(_) => Reply {
+            status: StatusCode(500),
+            content_type,
+            body: &[],
+        },
+    }
+}
+
+fn to_metrics(snapshot: &Snapshot<'_>, buf: &mut [u8]) -> Result<usize, HttpError> {
+    let runtime = Runtime {
+        uptime_s: snapshot.status.uptime_s,
+        heap_used: snapshot.status.heap.used,
+        heap_free: snapshot.status.heap.free,
+        sensor_names: sensor_names(),
+    };
+
+    snapshot
+        .metrics
+        .encode(&runtime, BodyWriter::new(buf))
+        .map(|out| out.len())
+        .map_err(|_| HttpError::BufferTooSmall)
+}
+
+fn error<'b>(status: StatusCode, message: &str, buf: &'b mut [u8]) -> Reply<'b> {
+    let len = to_json(&ErrorBody { error: message }, buf).unwrap_or(0);
+
+    Reply {
+        status,
+        content_type: JSON,
+        body: &buf[..len],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::Mode;
+    use crate::stats::Summary;
+
+    const READINGS: [ReadingBody; 2] = [
+        ReadingBody {
+            sensor: "indoor",
+            temperature: Some(21.5),
+            humidity: Some(40.25),
+            pressure: Some(1013.5),
+            co2: None,
+            age_ms: 120,
+            timestamp: Timestamp {
+                unix_ms: 5,
+                synced: false,
+            },
+            fault: None,
+        },
+        ReadingBody {
+            sensor: "duct",
+            temperature: Some(30.0),
+            humidity: Some(20.0),
+            pressure: None,
+            co2: Some(640),
+            age_ms: 80,
+            timestamp: Timestamp {
+                unix_ms: 9,
+                synced: true,
+            },
+            fault: Some("crc"),
+        },
+    ];
+
+    const WINDOWS: [WindowSummary; 1] = [WindowSummary {
+        window: "1h",
+        samples: 60,
+        temperature: Summary {
+            min: 20.0,
+            max: 22.5,
+            mean: 21.25,
+            std_dev: 0.5,
+        },
+        humidity: Summary {
+            min: 40.0,
+            max: 50.0,
+            mean: 45.0,
+            std_dev: 2.5,
+        },
+    }];
+
+    const STATISTICS: [SensorStatistics<'static>; 1] = [SensorStatistics {
+        sensor: "indoor",
+        windows: &WINDOWS,
+    }];
+
+    const ALERTS: [AlertBody; 1] = [AlertBody {
+        sensor: "indoor",
+        rule: 0,
+        quantity: Quantity::Humidity,
+        comparison: Comparison::Above,
+        threshold: 70.0,
+        value: 71.5,
+        age_s: 12,
+        timestamp: Timestamp {
+            unix_ms: 5,
+            synced: false,
+        },
+    }];
+
+    const UPLINKS: [UplinkStatus<'static>; 2] = [
+        UplinkStatus {
+            name: "http",
+            state: "down",
+            error: Some("timeout"),
+            queued: 4,
+            capacity: 512,
+            dropped: 0,
+            delivered: 10,
+            rejected: 0,
+            failures: 2,
+        },
+        UplinkStatus {
+            name: "mqtt",
+            state: "up",
+            error: None,
+            queued: 0,
+            capacity: 8,
+            dropped: 1,
+            delivered: 3,
+            rejected: 0,
+            failures: 0,
+        },
+    ];
+
+    static METRICS: Registry = Registry::new();
+
+    /// What the node reports once it has readings, or before the first one.
+    fn snapshot(has_reading: bool) -> Snapshot<'static> {
+        Snapshot {
+            readings: if has_reading { &READINGS } else { &[] },
+            statistics: if has_reading { &STATISTICS } else { &[] },
+            alerts: if has_reading { &ALERTS } else { &[] },
+            relay: Some(RelayStatus {
+                on: true,
+                mode: Mode::Auto,
+                wanted: true,
+            }),
+            status: StatusBody {
+                node_id: "home-monitor-aabbccddeeff",
+                firmware_version: "0.1.0",
+                wifi: "connected",
+                ip: Some("192.168.1.20"),
+                uptime_s: 99,
+                heap: HeapStats { used: 1, free: 2 },
+                last_upload: Some(UploadStatus {
+                    ok: false,
+                    error: Some("server_error"),
+                    status: Some(503),
+                    age_s: 3,
+                }),
+                uplinks: &UPLINKS,
+            },
+            metrics: &METRICS,
+        }
+    }
+
+    fn get(path: &str, snapshot: &Snapshot<'_>) -> (StatusCode, std::string::String) {
+        request("GET", path, b"", snapshot)
+    }
+
+    fn request(
+        method: &str,
+        path: &str,
+        body: &[u8],
+        snapshot: &Snapshot<'_>,
+    ) -> (StatusCode, std::string::String) {
+        let mut buf = [0; RESPONSE_CAPACITY];
+        let reply = handle(method, path, body, snapshot, &mut buf);
+        let body = core::str::from_utf8(reply.body).unwrap().into();
+        (reply.status, body)
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(Route::resolve("GET", "/"), Ok(Route::Index));
+        assert_eq!(Route::resolve("GET", "/index.html"), Ok(Route::Index));
+        assert_eq!(Route::resolve("GET", "/api/reading"), Ok(Route::Reading));
+        assert_eq!(Route::resolve("GET", "/api/relay"), Ok(Route::Relay));
+        assert_eq!(Route::resolve("POST", "/api/relay"), Ok(Route::SetRelay));
+        assert_eq!(Route::resolve("GET", "/metrics"), Ok(Route::Metrics));
+
+        assert_eq!(Route::resolve("GET", "/nope"), Err(StatusCode(404)));
+        // Paths are matched exactly.
+        assert_eq!(Route::resolve("GET", "/api/reading/"), Err(StatusCode(404)));
+        assert_eq!(Route::resolve("POST", "/nope"), Err(StatusCode(404)));
+        assert_eq!(Route::resolve("POST", "/api/status"), Err(StatusCode(405)));
+        assert_eq!(Route::resolve("PUT", "/api/relay"), Err(StatusCode(405)));
+        assert_eq!(Route::resolve("get", "/"), Err(StatusCode(405)));
+    }
+
+    #[test]
+    fn errors() {
+        let snapshot = snapshot(true);
+        assert_eq!(
+            get("/nope", &snapshot),
+            (StatusCode(404), r#"{"error":"Not Found"}"#.into())
+        );
+        assert_eq!(
+            request("DELETE", "/api/reading", b"", &snapshot),
+            (StatusCode(405), r#"{"error":"Method Not Allowed"}"#.into())
+        );
+
+        let mut buf = [0; RESPONSE_CAPACITY];
+        let reply = handle("GET", "/nope", b"", &snapshot, &mut buf);
+        assert_eq!(reply.content_type, JSON);
+    }
+
+    #[test]
+    fn no_reading_yet() {
+        let snapshot = snapshot(false);
+        let unavailable = (StatusCode(503), r#"{"error":"no reading yet"}"#.into());
+        assert_eq!(get("/api/reading", &snapshot), unavailable);
+        assert_eq!(get("/api/stats", &snapshot), unavailable);
+
+        // Nothing to wait for on the rest.
+        assert_eq!(
+            get("/api/alerts", &snapshot),
+            (StatusCode(200), r#"{"alerts":[]}"#.into())
+        );
+        assert_eq!(get("/api/status", &snapshot).0, StatusCode(200));
+    }
+
+    #[test]
+    fn reading() {
+        assert_eq!(
+            get("/api/reading", &snapshot(true)),
+            (
+                StatusCode(200),
+                r#"{"readings":[{"sensor":"indoor","temperature":21.5,"humidity":40.25,"pressure":1013.5,"age_ms":120,"timestamp":{"unix_ms":5,"synced":false}},{"sensor":"duct","temperature":30.0,"humidity":20.0,"co2":640,"age_ms":80,"timestamp":{"unix_ms":9,"synced":true},"fault":"crc"}]}"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn statistics() {
+        assert_eq!(
+            get("/api/stats", &snapshot(true)),
+            (
+                StatusCode(200),
+                r#"{"statistics":[{"sensor":"indoor","windows":[{"window":"1h","samples":60,"temperature":{"min":20.0,"max":22.5,"mean":21.25,"std_dev":0.5},"humidity":{"min":40.0,"max":50.0,"mean":45.0,"std_dev":2.5}}]}]}"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn alerts() {
+        assert_eq!(
+            get("/api/alerts", &snapshot(true)),
+            (
+                StatusCode(200),
+                r#"{"alerts":[{"sensor":"indoor","rule":0,"quantity":"humidity","comparison":"above","threshold":70.0,"value":71.5,"age_s":12,"timestamp":{"unix_ms":5,"synced":false}}]}"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn every_alert_fits() {
+        let longest = AlertBody {
+            rule: u8::MAX,
+            quantity: Quantity::Temperature,
+            threshold: -1.175_494_4e-38,
+            value: -1.175_494_4e-38,
+            age_s: u64::MAX,
+            timestamp: Timestamp {
+                unix_ms: u64::MAX,
+                synced: false,
+            },
+            ..ALERTS[0]
+        };
+        let alerts = [longest; crate::alert::MAX_ACTIVE_ALERTS];
+
+        let mut buf = [0; RESPONSE_CAPACITY];
+        assert!(to_json(&AlertsBody { alerts: &alerts }, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            get("/api/status", &snapshot(true)),
+            (
+                StatusCode(200),
+                r#"{"node_id":"home-monitor-aabbccddeeff","firmware_version":"0.1.0","wifi":"connected","ip":"192.168.1.20","uptime_s":99,"heap":{"used":1,"free":2},"last_upload":{"ok":false,"error":"server_error","status":503,"age_s":3},"uplinks":[{"name":"http","state":"down","error":"timeout","queued":4,"capacity":512,"dropped":0,"delivered":10,"rejected":0,"failures":2},{"name":"mqtt","state":"up","queued":0,"capacity":8,"dropped":1,"delivered":3,"rejected":0,"failures":0}]}"#.into()
+            )
+        );
+    }
+
+    #[test]
+    fn relay() {
+        let snapshot = snapshot(true);
+        let status = (
+            StatusCode(200),
+            r#"{"on":true,"mode":"auto","wanted":true}"#.into(),
+        );
+        assert_eq!(get("/api/relay", &snapshot), status);
+        assert_eq!(request("POST", "/api/relay", b"on 60", &snapshot), status);
+        assert_eq!(request("POST", "/api/relay", b"auto", &snapshot), status);
+
+        let bad_command = (StatusCode(400), r#"{"error":"bad command"}"#.into());
+        assert_eq!(
+            request("POST", "/api/relay", b"toggle", &snapshot),
+            bad_command
+        );
+        assert_eq!(request("POST", "/api/relay", b"", &snapshot), bad_command);
+        assert_eq!(
+            request("POST", "/api/relay", b"auto 60", &snapshot),
+            bad_command
+        );
+
+        let without_relay = Snapshot {
+            relay: None,
+            ..snapshot
+        };
+        let unavailable = (StatusCode(503), r#"{"error":"no relay"}"#.into());
+        assert_eq!(get("/api/relay", &without_relay), unavailable);
+        assert_eq!(
+            request("POST", "/api/relay", b"off", &without_relay),
+            unavailable
+        );
+    }
+
+    #[test]
+    fn index_and_metrics() {
+        let snapshot = snapshot(true);
+        let mut buf = [0; RESPONSE_CAPACITY];
+        let reply = handle("GET", "/", b"", &snapshot, &mut buf);
+        assert_eq!(
+            (reply.status, reply.content_type, reply.body),
+            (StatusCode(200), HTML, INDEX_PAGE.as_bytes())
+        );
+
+        let reply = handle("GET", "/metrics", b"", &snapshot, &mut buf);
+        assert_eq!(
+            (reply.status, reply.content_type),
+            (StatusCode(200), PROMETHEUS)
+        );
+        let body = core::str::from_utf8(reply.body).unwrap();
+        assert!(body.contains("home_monitor_uptime_seconds 99\n"), "{body}");
+    }
+
+    #[test]
+    fn body_too_large() {
+        let mut buf = [0; 64];
+        let reply = handle("GET", "/api/status", b"", &snapshot(true), &mut buf);
+        assert_eq!((reply.status, reply.body), (StatusCode(500), &[][..]));
+    }
+}
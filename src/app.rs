@@ -1,37 +1,68 @@
-use defmt::info;
+use defmt::{info, warn};
 use embassy_executor::Spawner;
-use embassy_net::{Stack, StackResources};
+use embassy_net::{Ipv4Cidr, Stack, StackResources, StaticConfigV4};
+use embassy_time::Duration;
 use embedded_hal_bus::i2c::AtomicDevice;
 use embedded_hal_bus::util::AtomicCell;
+use esp_bootloader_esp_idf::partitions::{self, DataPartitionSubType, PartitionType};
 use esp_hal::clock::CpuClock;
 use esp_hal::delay::Delay;
-use esp_hal::i2c::master::{Config as I2cConfig, I2c};
+use esp_hal::efuse::Efuse;
+#[cfg(feature = "relay")]
+use esp_hal::gpio::{Output, OutputConfig};
+use esp_hal::i2c::master::Config as I2cConfig;
 use esp_hal::rng::Rng;
 use esp_hal::timer::timg::TimerGroup;
-use esp_hal::{Blocking, assign_resources, ram};
+use esp_hal::{assign_resources, ram};
 use esp_radio::Controller;
-use esp_radio::wifi::{AuthMethod, ClientConfig, ModeConfig};
+use esp_storage::FlashStorage;
 use static_cell::StaticCell;
 
-use crate::drivers::sht3x::Sht3x;
+use crate::config::{ConfigStore, NodeConfig};
+use crate::device::DeviceInfo;
+#[cfg(feature = "bme280")]
+use crate::drivers::bme280::Bme280;
+use crate::drivers::environment::{SensorId, set_sensor_names};
+use crate::drivers::i2c_bus::RecoverableI2c;
+use crate::drivers::scan::{self, Chip, KNOWN_CHIPS, Scan};
+#[cfg(feature = "sht3x")]
+use crate::drivers::sht3x::{Mode, Rate, Repeatability, Sht3x};
+#[cfg(feature = "sht4x")]
+use crate::drivers::sht4x::Sht4x;
 use crate::drivers::ssd1306::Ssd1306;
 use crate::error::{AppError, Result};
 
-use crate::tasks::I2cBus;
 use crate::tasks::display::display_task;
+#[cfg(feature = "http")]
 use crate::tasks::http_client::http_client_task;
+use crate::tasks::http_server::{SERVER_SOCKETS, http_server_task};
+#[cfg(feature = "influx")]
+use crate::tasks::influx::influx_task;
+#[cfg(feature = "mqtt")]
+use crate::tasks::mqtt::mqtt_task;
 use crate::tasks::net::{alive_task, net_task};
 use crate::tasks::orchestrate::orchestrate_task;
-use crate::tasks::sensor::sensor_task;
+use crate::tasks::portal::{AP_ADDRESS, AP_PREFIX_LEN, portal_task};
+#[cfg(feature = "relay")]
+use crate::tasks::relay::{self, relay_task};
+use crate::tasks::sensor::{Sensors, sensor_task};
+use crate::tasks::sntp::sntp_task;
 use crate::tasks::wifi::wifi_task;
+use crate::tasks::{ConfigFlash, I2cBus, SensorHandle};
 
-const HOTSPOT_SSID: &str = "Oblakoder-2.4G";
-const HOTSPOT_PASSWORD: &str = "skidambundujerjevrelo";
+/// `config` data partition of subtype `undefined` in `partitions.csv`.
+const CONFIG_PARTITION: PartitionType = PartitionType::Data(DataPartitionSubType::Undefined);
 
 static I2C_CELL: StaticCell<AtomicCell<I2cBus>> = StaticCell::new();
 static RADIO_CONTROLLER: StaticCell<Controller> = StaticCell::new();
-static NET_RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+static NET_RESOURCES: StaticCell<StackResources<9>> = StaticCell::new();
 static STACK: StaticCell<Stack> = StaticCell::new();
+static DEVICE: StaticCell<DeviceInfo> = StaticCell::new();
+static CONFIG: StaticCell<NodeConfig> = StaticCell::new();
+static FLASH: StaticCell<FlashStorage<'static>> = StaticCell::new();
+static PARTITION_TABLE: StaticCell<[u8; partitions::PARTITION_TABLE_MAX_LEN]> = StaticCell::new();
+static AP_RESOURCES: StaticCell<StackResources<4>> = StaticCell::new();
+static AP_STACK: StaticCell<Stack> = StaticCell::new();
 
 assign_resources! {
     Resources<'d> {
@@ -42,19 +73,146 @@ assign_resources! {
         },
         wifi: WifiResources<'d> {
             wifi: WIFI,
+        },
+        flash: FlashResources<'d> {
+            flash: FLASH,
+        },
+        relay: RelayResources<'d> {
+            pin: GPIO26,
+        }
+    }
+}
+
+fn init_i2c(r: I2cResources<'static>) -> Result<I2cBus> {
+    Ok(RecoverableI2c::new(
+        r.i2c0,
+        r.sda,
+        r.scl,
+        I2cConfig::default(),
+    )?)
+}
+
+/// Starts with the relay off, the task switches it from there.
+#[cfg(feature = "relay")]
+fn init_relay(r: RelayResources<'static>) -> Output<'static> {
+    Output::new(r.pin, relay::level(false), OutputConfig::default())
+}
+
+#[cfg(feature = "sht3x")]
+const SENSOR_CHIP: Chip = scan::SHT3X;
+#[cfg(feature = "sht4x")]
+const SENSOR_CHIP: Chip = scan::SHT4X;
+#[cfg(feature = "bme280")]
+const SENSOR_CHIP: Chip = scan::BME280;
+
+/// In the periodic modes the SHT3x converts on its own and reads only fetch the latest
+/// result, pick a rate at least as fast as the polling interval.
+#[cfg(feature = "sht3x")]
+const SHT3X_MODE: Mode = Mode::Periodic(Repeatability::High, Rate::One);
+
+#[cfg(feature = "sht3x")]
+fn init_sensor(i2c: &'static AtomicCell<I2cBus>, address: u8) -> Result<SensorHandle> {
+    let mut sensor = Sht3x::with_address(AtomicDevice::new(i2c), Delay::new(), address);
+    sensor
+        .set_mode(SHT3X_MODE)
+        .map_err(|e| AppError::Sensor(e.into()))?;
+
+    // Diagnostics only, a sensor that can't report them still measures.
+    match (sensor.serial_number(), sensor.status()) {
+        (Ok(serial), Ok(status)) => {
+            info!(
+                "app: SHT3x {=u32:#010x} at {=u8:#04x}, status {=u16:#06x}",
+                serial, address, status.0
+            );
+            if status.heater_on() {
+                warn!("app: SHT3x heater was left on, switching it off");
+                let _ = sensor.set_heater(false);
+            }
+            let _ = sensor.clear_status();
         }
+        (serial, status) => warn!(
+            "app: failed to read SHT3x diagnostics: {:?} {:?}",
+            serial.err(),
+            status.err()
+        ),
     }
+
+    Ok(sensor)
+}
+
+#[cfg(feature = "sht4x")]
+fn init_sensor(i2c: &'static AtomicCell<I2cBus>, address: u8) -> Result<SensorHandle> {
+    Ok(Sht4x::with_address(
+        AtomicDevice::new(i2c),
+        Delay::new(),
+        address,
+    ))
+}
+
+#[cfg(feature = "bme280")]
+fn init_sensor(i2c: &'static AtomicCell<I2cBus>, address: u8) -> Result<SensorHandle> {
+    Bme280::with_address(AtomicDevice::new(i2c), Delay::new(), address).map_err(AppError::Sensor)
+}
+
+/// Probes the addresses of every supported chip and logs what answered.
+fn scan_bus(i2c: &'static AtomicCell<I2cBus>) -> Scan {
+    let found = Scan::run(&mut AtomicDevice::new(i2c), &KNOWN_CHIPS);
+
+    if found.is_empty() {
+        warn!("app: no known I2C devices found");
+    }
+    for chip in &KNOWN_CHIPS {
+        if let Some(address) = found.find(chip) {
+            info!(
+                "app: I2C device at {=u8:#04x}, may be {}",
+                address, chip.name
+            );
+        }
+    }
+
+    found
 }
 
-fn init_i2c<'d>(r: I2cResources<'d>) -> Result<I2c<'d, Blocking>> {
-    let i2c = I2c::new(r.i2c0, I2cConfig::default())?
-        .with_scl(r.scl)
-        .with_sda(r.sda);
+/// Opens the config partition. Returns `None` if the partition table has none, so the node
+/// still comes up with the defaults after flashing with an old table.
+fn open_config_store(r: FlashResources<'static>) -> Option<ConfigStore<ConfigFlash>> {
+    let flash = FLASH.init(FlashStorage::new(r.flash));
+    let table_buf = PARTITION_TABLE.init([0u8; partitions::PARTITION_TABLE_MAX_LEN]);
 
-    Ok(i2c)
+    let table = match partitions::read_partition_table(&mut *flash, table_buf) {
+        Ok(table) => table,
+        Err(e) => {
+            warn!("config: failed to read partition table: {:?}", e);
+            return None;
+        }
+    };
+
+    let Ok(Some(partition)) = table.find_partition(CONFIG_PARTITION) else {
+        warn!("config: no config partition, using defaults");
+        return None;
+    };
+
+    Some(ConfigStore::new(partition.as_embedded_storage(flash)))
 }
 
-pub async fn run(spawner: Spawner) -> Result<()> {
+fn load_config(store: Option<&mut ConfigStore<ConfigFlash>>) -> NodeConfig {
+    let Some(store) = store else {
+        return NodeConfig::default();
+    };
+
+    match store.load() {
+        Ok((config, source)) => {
+            info!("config: loaded, source: {:?}", source);
+            config
+        }
+        Err(e) => {
+            warn!("config: load failed: {:?}, using defaults", e);
+            NodeConfig::default()
+        }
+    }
+}
+
+pub async fn run(spawner: Spawner, firmware_version: &'static str) -> Result<()> {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
 
@@ -68,20 +226,23 @@ pub async fn run(spawner: Spawner) -> Result<()> {
 
     info!("Embassy initialized!");
 
-    let radio_controller = RADIO_CONTROLLER.init(esp_radio::init()?);
+    let mut config_store = open_config_store(resources.flash);
+    let node_config = CONFIG.init(load_config(config_store.as_mut()));
+    set_sensor_names(
+        node_config
+            .sensor_names
+            .each_ref()
+            .map(|name| name.as_str()),
+    );
 
-    let config = ClientConfig::default()
-        .with_ssid(HOTSPOT_SSID.into())
-        .with_password(HOTSPOT_PASSWORD.into())
-        .with_auth_method(AuthMethod::Wpa2Personal);
+    let device_info = DEVICE.init(DeviceInfo::new(Efuse::mac_address(), firmware_version));
+    info!("node id: {}", device_info.node_id.as_str());
 
-    let sta_config = ModeConfig::Client(config);
+    let radio_controller = RADIO_CONTROLLER.init(esp_radio::init()?);
 
-    let (mut wifi_controller, interfaces) =
+    let (wifi_controller, interfaces) =
         esp_radio::wifi::new(radio_controller, resources.wifi.wifi, Default::default())?;
 
-    wifi_controller.set_config(&sta_config).unwrap();
-
     let device = interfaces.sta;
 
     let config = embassy_net::Config::dhcpv4(Default::default());
@@ -92,24 +253,94 @@ pub async fn run(spawner: Spawner) -> Result<()> {
     let (stack, runner) = embassy_net::new(
         device,
         config,
-        NET_RESOURCES.init(StackResources::<3>::new()),
+        NET_RESOURCES.init(StackResources::<9>::new()),
         seed,
     );
 
     let stack = STACK.init(stack);
 
+    let ap_config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(AP_ADDRESS, AP_PREFIX_LEN),
+        gateway: Some(AP_ADDRESS),
+        dns_servers: Default::default(),
+    });
+
+    let (ap_stack, ap_runner) = embassy_net::new(
+        interfaces.ap,
+        ap_config,
+        AP_RESOURCES.init(StackResources::<4>::new()),
+        seed.rotate_left(32),
+    );
+
+    let ap_stack = AP_STACK.init(ap_stack);
+
     let i2c = init_i2c(resources.i2c)?;
     let i2c_cell = I2C_CELL.init(AtomicCell::new(i2c));
 
-    let sht3x = Sht3x::new(AtomicDevice::new(i2c_cell), Delay::new());
-    let display = Ssd1306::new(AtomicDevice::new(i2c_cell)).map_err(|_| AppError::Display)?;
+    let found = scan_bus(i2c_cell);
 
     spawner.spawn(orchestrate_task())?;
-    spawner.spawn(display_task(display))?;
-    spawner.spawn(sensor_task(sht3x))?;
-    spawner.spawn(wifi_task(wifi_controller))?;
+    #[cfg(feature = "relay")]
+    spawner.spawn(relay_task(init_relay(resources.relay)))?;
+    match found.find(&scan::SSD1306) {
+        Some(address) => {
+            match Ssd1306::with_address(AtomicDevice::new(i2c_cell), address)
+                .map_err(|_| AppError::Display)
+            {
+                Ok(display) => spawner.spawn(display_task(display))?,
+                Err(e) => warn!("app: {}, running headless", e),
+            }
+        }
+        None => info!("app: no display, running headless"),
+    }
+
+    // The chip at the default address is sensor 0, the one at the alternate address
+    // sensor 1.
+    let mut sensors = Sensors::new();
+    for (index, &address) in SENSOR_CHIP.addresses.iter().enumerate() {
+        if let (Some(id), true) = (SensorId::new(index), found.contains(address)) {
+            match init_sensor(i2c_cell, address) {
+                // Can't fail, there are no more ids than room.
+                Ok(sensor) => {
+                    let _ = sensors.push((id, sensor));
+                }
+                Err(e) => warn!(
+                    "app: {} at {=u8:#04x}: {}, skipping it",
+                    SENSOR_CHIP.name, address, e
+                ),
+            }
+        }
+    }
+
+    if sensors.is_empty() {
+        warn!("app: no {} found, not reading sensors", SENSOR_CHIP.name);
+    } else {
+        spawner.spawn(sensor_task(
+            sensors,
+            AtomicDevice::new(i2c_cell),
+            Duration::from_millis(node_config.polling_interval_ms as u64),
+            &node_config.alert_rules,
+        ))?;
+    }
+    spawner.spawn(wifi_task(wifi_controller, node_config, device_info))?;
     spawner.spawn(net_task(runner))?;
-    spawner.spawn(http_client_task(stack))?;
+    spawner.spawn(net_task(ap_runner))?;
+    spawner.spawn(portal_task(
+        ap_stack,
+        device_info,
+        node_config,
+        config_store,
+    ))?;
+    #[cfg(feature = "http")]
+    spawner.spawn(http_client_task(stack, node_config))?;
+    #[cfg(feature = "mqtt")]
+    spawner.spawn(mqtt_task(stack, device_info, node_config))?;
+    #[cfg(feature = "influx")]
+    spawner.spawn(influx_task(stack, device_info, node_config))?;
+    for _ in 0..SERVER_SOCKETS {
+        spawner.spawn(http_server_task(stack, device_info))?;
+    }
+    spawner.spawn(sntp_task(stack))?;
     spawner.spawn(alive_task())?;
 
     Ok(())
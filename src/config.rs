@@ -0,0 +1,759 @@
+use defmt::Format;
+use embedded_storage::{ReadStorage, Storage};
+use heapless::{String, Vec};
+
+use crate::alert::{AlertRule, Comparison, ENCODED_RULE_LEN, MAX_ALERT_RULES, Quantity};
+use crate::drivers::environment::{DEFAULT_SENSOR_NAMES, MAX_SENSORS};
+
+pub const SSID_CAPACITY: usize = 32;
+pub const PASSWORD_CAPACITY: usize = 64;
+pub const URL_CAPACITY: usize = 64;
+pub const LOCATION_CAPACITY: usize = 32;
+pub const SENSOR_NAME_CAPACITY: usize = 16;
+pub const MQTT_HOST_CAPACITY: usize = 48;
+pub const MQTT_USERNAME_CAPACITY: usize = 32;
+pub const MQTT_PASSWORD_CAPACITY: usize = 64;
+pub const MQTT_CLIENT_ID_CAPACITY: usize = 32;
+pub const MQTT_TOPIC_PREFIX_CAPACITY: usize = 32;
+
+/// Bump when a field changes meaning and add a step to [`MIGRATIONS`]. Adding or removing a
+/// field doesn't need a new version, unknown fields are skipped and missing ones take their
+/// default.
+pub const SCHEMA_VERSION: u16 = 2;
+
+/// `MIGRATIONS[n]` upgrades a record from version `n + 1` to `n + 2`.
+const MIGRATIONS: [fn(&mut NodeConfig); SCHEMA_VERSION as usize - 1] = [v1_to_v2];
+
+/// Version 1 firmware always published to `broker.lan`, keep doing so.
+fn v1_to_v2(config: &mut NodeConfig) {
+    config.mqtt_host = String::try_from(V1_MQTT_HOST).unwrap_or_default();
+}
+
+/// Left empty so a fresh node starts the setup portal, see [`NodeConfig::has_wifi`].
+const DEFAULT_WIFI_SSID: &str = "";
+const DEFAULT_WIFI_PASSWORD: &str = "";
+const DEFAULT_COLLECTOR_URL: &str = "http://192.168.100.14:8080";
+const DEFAULT_POLLING_INTERVAL_MS: u32 = 1000;
+/// Stored intervals are raised to this, a zero interval would have the sensor task spin.
+const MIN_POLLING_INTERVAL_MS: u32 = 100;
+const DEFAULT_LOCATION: &str = "";
+/// No broker, so MQTT stays off until one is configured.
+const DEFAULT_MQTT_HOST: &str = "";
+const V1_MQTT_HOST: &str = "broker.lan";
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+const DEFAULT_MQTT_TOPIC_PREFIX: &str = "home-monitor";
+/// Mould risk once humidity stays above 70 % for 10 minutes, and pipes about to freeze.
+const DEFAULT_ALERT_RULES: [AlertRule; 2] = [
+    AlertRule {
+        sensor: None,
+        quantity: Quantity::Humidity,
+        comparison: Comparison::Above,
+        threshold: 70.0,
+        hysteresis: 5.0,
+        min_duration_s: 10 * 60,
+        rearm_s: 30 * 60,
+    },
+    AlertRule {
+        sensor: None,
+        quantity: Quantity::Temperature,
+        comparison: Comparison::Below,
+        threshold: 5.0,
+        hysteresis: 1.0,
+        min_duration_s: 60,
+        rearm_s: 30 * 60,
+    },
+];
+
+const MAGIC: [u8; 4] = *b"HMNC";
+/// Magic, schema version, payload length and CRC-32 of the payload.
+const HEADER_LEN: usize = 4 + 2 + 2 + 4;
+/// Largest record written, the record is read and written in one piece.
+pub const RECORD_CAPACITY: usize = 768;
+
+const TAG_WIFI_SSID: u8 = 1;
+const TAG_WIFI_PASSWORD: u8 = 2;
+const TAG_COLLECTOR_URL: u8 = 3;
+const TAG_POLLING_INTERVAL_MS: u8 = 4;
+const TAG_LOCATION: u8 = 5;
+/// Every rule in one field, so an empty field tells no rules from a record written before
+/// there were any.
+const TAG_ALERT_RULES: u8 = 6;
+/// One field per sensor, its index followed by its name.
+const TAG_SENSOR_NAME: u8 = 7;
+const TAG_MQTT_HOST: u8 = 8;
+const TAG_MQTT_PORT: u8 = 9;
+const TAG_MQTT_USERNAME: u8 = 10;
+const TAG_MQTT_PASSWORD: u8 = 11;
+const TAG_MQTT_CLIENT_ID: u8 = 12;
+const TAG_MQTT_TOPIC_PREFIX: u8 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ConfigError {
+    Storage,
+    BufferTooSmall,
+    /// The flash holds no record, as on first boot.
+    Erased,
+    BadMagic,
+    BadCrc,
+    Malformed,
+}
+
+/// Settings that can change without a rebuild.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeConfig {
+    pub wifi_ssid: String<SSID_CAPACITY>,
+    pub wifi_password: String<PASSWORD_CAPACITY>,
+    pub collector_url: String<URL_CAPACITY>,
+    pub polling_interval_ms: u32,
+    /// Where the node is placed, e.g. `living-room`. Empty if not set.
+    pub location: String<LOCATION_CAPACITY>,
+    /// Checked against every reading, see `AlertEngine`.
+    pub alert_rules: Vec<AlertRule, MAX_ALERT_RULES>,
+    /// What each sensor is reported under, by `SensorId`, e.g. where its probe is mounted.
+    /// See [`is_valid_sensor_name`].
+    pub sensor_names: [String<SENSOR_NAME_CAPACITY>; MAX_SENSORS],
+    /// Broker to publish to, MQTT is off while empty.
+    pub mqtt_host: String<MQTT_HOST_CAPACITY>,
+    pub mqtt_port: u16,
+    /// Connects without credentials while empty.
+    pub mqtt_username: String<MQTT_USERNAME_CAPACITY>,
+    pub mqtt_password: String<MQTT_PASSWORD_CAPACITY>,
+    /// The node id while empty.
+    pub mqtt_client_id: String<MQTT_CLIENT_ID_CAPACITY>,
+    /// State topics are `<prefix>/<node-id>/<object-id>`.
+    pub mqtt_topic_prefix: String<MQTT_TOPIC_PREFIX_CAPACITY>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            wifi_ssid: String::try_from(DEFAULT_WIFI_SSID).unwrap_or_default(),
+            wifi_password: String::try_from(DEFAULT_WIFI_PASSWORD).unwrap_or_default(),
+            collector_url: String::try_from(DEFAULT_COLLECTOR_URL).unwrap_or_default(),
+            polling_interval_ms: DEFAULT_POLLING_INTERVAL_MS,
+            location: String::try_from(DEFAULT_LOCATION).unwrap_or_default(),
+            alert_rules: Vec::from_slice(&DEFAULT_ALERT_RULES).unwrap_or_default(),
+            sensor_names: DEFAULT_SENSOR_NAMES
+                .map(|name| String::try_from(name).unwrap_or_default()),
+            mqtt_host: String::try_from(DEFAULT_MQTT_HOST).unwrap_or_default(),
+            mqtt_port: DEFAULT_MQTT_PORT,
+            mqtt_username: String::new(),
+            mqtt_password: String::new(),
+            mqtt_client_id: String::new(),
+            mqtt_topic_prefix: String::try_from(DEFAULT_MQTT_TOPIC_PREFIX).unwrap_or_default(),
+        }
+    }
+}
+
+/// Where the configuration returned by [`ConfigStore::load`] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Source {
+    Flash,
+    /// Written by older firmware and upgraded to [`SCHEMA_VERSION`].
+    Migrated {
+        from: u16,
+    },
+    /// Written by newer firmware. Fields this firmware doesn't know were ignored.
+    Newer {
+        version: u16,
+    },
+    /// Nothing usable was stored, the defaults were written instead.
+    Defaults(ConfigError),
+}
+
+impl NodeConfig {
+    /// Whether a network has been configured. The password may be empty for open networks.
+    pub fn has_wifi(&self) -> bool {
+        !self.wifi_ssid.is_empty()
+    }
+
+    /// Whether a broker has been configured.
+    pub fn has_mqtt(&self) -> bool {
+        !self.mqtt_host.is_empty()
+    }
+
+    /// Serializes the configuration as a complete record, header included.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, ConfigError> {
+        let payload = buf
+            .get_mut(HEADER_LEN..)
+            .ok_or(ConfigError::BufferTooSmall)?;
+
+        let mut w = Writer {
+            buf: payload,
+            pos: 0,
+        };
+        w.field(TAG_WIFI_SSID, self.wifi_ssid.as_bytes())?;
+        w.field(TAG_WIFI_PASSWORD, self.wifi_password.as_bytes())?;
+        w.field(TAG_COLLECTOR_URL, self.collector_url.as_bytes())?;
+        w.field(
+            TAG_POLLING_INTERVAL_MS,
+            &self.polling_interval_ms.to_le_bytes(),
+        )?;
+        w.field(TAG_LOCATION, self.location.as_bytes())?;
+
+        let mut rules = [0u8; MAX_ALERT_RULES * ENCODED_RULE_LEN];
+        for (rule, out) in self
+            .alert_rules
+            .iter()
+            .zip(rules.chunks_exact_mut(ENCODED_RULE_LEN))
+        {
+            out.copy_from_slice(&rule.to_bytes());
+        }
+        w.field(
+            TAG_ALERT_RULES,
+            &rules[..self.alert_rules.len() * ENCODED_RULE_LEN],
+        )?;
+
+        for (index, name) in self.sensor_names.iter().enumerate() {
+            let mut field = [0u8; 1 + SENSOR_NAME_CAPACITY];
+            field[0] = index as u8;
+            field[1..1 + name.len()].copy_from_slice(name.as_bytes());
+            w.field(TAG_SENSOR_NAME, &field[..1 + name.len()])?;
+        }
+        w.field(TAG_MQTT_HOST, self.mqtt_host.as_bytes())?;
+        w.field(TAG_MQTT_PORT, &self.mqtt_port.to_le_bytes())?;
+        w.field(TAG_MQTT_USERNAME, self.mqtt_username.as_bytes())?;
+        w.field(TAG_MQTT_PASSWORD, self.mqtt_password.as_bytes())?;
+        w.field(TAG_MQTT_CLIENT_ID, self.mqtt_client_id.as_bytes())?;
+        w.field(TAG_MQTT_TOPIC_PREFIX, self.mqtt_topic_prefix.as_bytes())?;
+        let payload_len = w.pos;
+
+        let crc = crc32(&payload[..payload_len]);
+
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&SCHEMA_VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&(payload_len as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        Ok(HEADER_LEN + payload_len)
+    }
+
+    /// Parses a record and upgrades it to the current schema.
+    pub fn decode(record: &[u8]) -> Result<(Self, Source), ConfigError> {
+        let header = record.get(..HEADER_LEN).ok_or(ConfigError::Malformed)?;
+
+        if header.iter().all(|&b| b == 0xff) {
+            return Err(ConfigError::Erased);
+        }
+
+        if header[0..4] != MAGIC {
+            return Err(ConfigError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        let payload_len = u16::from_le_bytes([header[6], header[7]]) as usize;
+        let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+
+        let payload = record
+            .get(HEADER_LEN..HEADER_LEN + payload_len)
+            .ok_or(ConfigError::Malformed)?;
+
+        if crc32(payload) != crc {
+            return Err(ConfigError::BadCrc);
+        }
+
+        if version == 0 {
+            return Err(ConfigError::Malformed);
+        }
+
+        let mut config = Self::default();
+        let mut rest = payload;
+
+        while let [tag, len, tail @ ..] = rest {
+            let len = *len as usize;
+            let value = tail.get(..len).ok_or(ConfigError::Malformed)?;
+            config.apply(*tag, value)?;
+            rest = &tail[len..];
+        }
+
+        if !rest.is_empty() {
+            return Err(ConfigError::Malformed);
+        }
+
+        let source = if version < SCHEMA_VERSION {
+            migrate(&mut config, version, &MIGRATIONS);
+            Source::Migrated { from: version }
+        } else if version > SCHEMA_VERSION {
+            Source::Newer { version }
+        } else {
+            Source::Flash
+        };
+
+        Ok((config, source))
+    }
+
+    fn apply(&mut self, tag: u8, value: &[u8]) -> Result<(), ConfigError> {
+        match tag {
+            TAG_WIFI_SSID => self.wifi_ssid = string(value)?,
+            TAG_WIFI_PASSWORD => self.wifi_password = string(value)?,
+            TAG_COLLECTOR_URL => self.collector_url = string(value)?,
+            TAG_POLLING_INTERVAL_MS => {
+                let bytes = value.try_into().map_err(|_| ConfigError::Malformed)?;
+                self.polling_interval_ms = u32::from_le_bytes(bytes).max(MIN_POLLING_INTERVAL_MS);
+            }
+            TAG_LOCATION => self.location = string(value)?,
+            TAG_ALERT_RULES => {
+                let rules = value.chunks_exact(ENCODED_RULE_LEN);
+                if !rules.remainder().is_empty() {
+                    return Err(ConfigError::Malformed);
+                }
+                // Rules this firmware can't evaluate, or more than it has room for, were
+                // written by newer firmware.
+                self.alert_rules = rules
+                    .filter_map(|bytes| AlertRule::from_bytes(bytes.try_into().ok()?))
+                    .take(MAX_ALERT_RULES)
+                    .collect();
+            }
+            TAG_SENSOR_NAME => {
+                let [index, name @ ..] = value else {
+                    return Err(ConfigError::Malformed);
+                };
+                let name = string(name)?;
+                // Sensors this firmware doesn't have, or names it can't use, were written by
+                // newer firmware.
+                if let (Some(slot), true) = (
+                    self.sensor_names.get_mut(*index as usize),
+                    is_valid_sensor_name(&name),
+                ) {
+                    *slot = name;
+                }
+            }
+            TAG_MQTT_HOST => self.mqtt_host = string(value)?,
+            TAG_MQTT_PORT => {
+                let bytes = value.try_into().map_err(|_| ConfigError::Malformed)?;
+                self.mqtt_port = u16::from_le_bytes(bytes);
+            }
+            TAG_MQTT_USERNAME => self.mqtt_username = string(value)?,
+            TAG_MQTT_PASSWORD => self.mqtt_password = string(value)?,
+            TAG_MQTT_CLIENT_ID => self.mqtt_client_id = string(value)?,
+            TAG_MQTT_TOPIC_PREFIX => self.mqtt_topic_prefix = string(value)?,
+            // Written by newer firmware.
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `name` can name a sensor. It ends up in MQTT topics, Home Assistant object ids
+/// and metric labels, so only ASCII letters, digits, `_` and `-` are allowed.
+pub fn is_valid_sensor_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Runs the steps of `migrations` that upgrade a record written at version `from`.
+fn migrate(config: &mut NodeConfig, from: u16, migrations: &[fn(&mut NodeConfig)]) {
+    for step in &migrations[from as usize - 1..] {
+        step(config);
+    }
+}
+
+fn string<const N: usize>(value: &[u8]) -> Result<String<N>, ConfigError> {
+    let value = core::str::from_utf8(value).map_err(|_| ConfigError::Malformed)?;
+    String::try_from(value).map_err(|_| ConfigError::Malformed)
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn field(&mut self, tag: u8, value: &[u8]) -> Result<(), ConfigError> {
+        let len = u8::try_from(value.len()).map_err(|_| ConfigError::BufferTooSmall)?;
+        let end = self.pos + 2 + value.len();
+        let out = self
+            .buf
+            .get_mut(self.pos..end)
+            .ok_or(ConfigError::BufferTooSmall)?;
+
+        out[0] = tag;
+        out[1] = len;
+        out[2..].copy_from_slice(value);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), bitwise since it only runs at boot and on save.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+/// The configuration record at the start of a flash region.
+pub struct ConfigStore<S> {
+    storage: S,
+}
+
+impl<S: ReadStorage + Storage> ConfigStore<S> {
+    pub fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    /// Loads the stored configuration. Missing or damaged records are replaced by the
+    /// defaults and migrated ones are written back in the current schema.
+    pub fn load(&mut self) -> Result<(NodeConfig, Source), ConfigError> {
+        let mut record = [0u8; RECORD_CAPACITY];
+        self.storage
+            .read(0, &mut record)
+            .map_err(|_| ConfigError::Storage)?;
+
+        match NodeConfig::decode(&record) {
+            Ok((config, source)) => {
+                if let Source::Migrated { .. } = source {
+                    self.save(&config)?;
+                }
+                Ok((config, source))
+            }
+            Err(e) => {
+                let config = NodeConfig::default();
+                self.save(&config)?;
+                Ok((config, Source::Defaults(e)))
+            }
+        }
+    }
+
+    pub fn save(&mut self, config: &NodeConfig) -> Result<(), ConfigError> {
+        let mut record = [0u8; RECORD_CAPACITY];
+        let len = config.encode(&mut record)?;
+
+        self.storage
+            .write(0, &record[..len])
+            .map_err(|_| ConfigError::Storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flash in RAM, erased to `0xff` like a fresh chip.
+    struct MemoryFlash {
+        data: [u8; 1024],
+        writes: usize,
+    }
+
+    impl MemoryFlash {
+        fn erased() -> Self {
+            Self {
+                data: [0xff; 1024],
+                writes: 0,
+            }
+        }
+
+        fn with_record(record: &[u8]) -> Self {
+            let mut flash = Self::erased();
+            flash.data[..record.len()].copy_from_slice(record);
+            flash
+        }
+    }
+
+    impl ReadStorage for MemoryFlash {
+        type Error = ();
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), ()> {
+            let start = offset as usize;
+            let data = self.data.get(start..start + bytes.len()).ok_or(())?;
+            bytes.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl Storage for MemoryFlash {
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), ()> {
+            let start = offset as usize;
+            let data = self.data.get_mut(start..start + bytes.len()).ok_or(())?;
+            data.copy_from_slice(bytes);
+            self.writes += 1;
+            Ok(())
+        }
+    }
+
+    fn configured() -> NodeConfig {
+        NodeConfig {
+            wifi_ssid: String::try_from("home").unwrap(),
+            wifi_password: String::try_from("hunter22").unwrap(),
+            polling_interval_ms: 5000,
+            location: String::try_from("attic").unwrap(),
+            ..NodeConfig::default()
+        }
+    }
+
+    /// `config` encoded, then given `version` and `extra` fields after its own, with the
+    /// header fixed up to match.
+    fn record(config: &NodeConfig, version: u16, extra: &[u8]) -> std::vec::Vec<u8> {
+        let mut buf = [0; RECORD_CAPACITY];
+        let len = config.encode(&mut buf).unwrap();
+
+        let mut record = buf[..len].to_vec();
+        record.extend_from_slice(extra);
+        let payload_len = (record.len() - HEADER_LEN) as u16;
+        let crc = crc32(&record[HEADER_LEN..]);
+        record[4..6].copy_from_slice(&version.to_le_bytes());
+        record[6..8].copy_from_slice(&payload_len.to_le_bytes());
+        record[8..12].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut store = ConfigStore::new(MemoryFlash::erased());
+        store.save(&configured()).unwrap();
+        assert_eq!(store.load().unwrap(), (configured(), Source::Flash));
+
+        let mut config = configured();
+        config.alert_rules.clear();
+        store.save(&config).unwrap();
+        assert_eq!(store.load().unwrap(), (config, Source::Flash));
+
+        // A loaded record is left alone.
+        assert_eq!(store.storage.writes, 2);
+    }
+
+    #[test]
+    fn full_record_fits() {
+        let mut config = configured();
+        config.wifi_ssid = String::try_from("s".repeat(SSID_CAPACITY).as_str()).unwrap();
+        config.wifi_password = String::try_from("p".repeat(PASSWORD_CAPACITY).as_str()).unwrap();
+        config.collector_url = String::try_from("u".repeat(URL_CAPACITY).as_str()).unwrap();
+        config.location = String::try_from("l".repeat(LOCATION_CAPACITY).as_str()).unwrap();
+        config.sensor_names = core::array::from_fn(|_| {
+            String::try_from("n".repeat(SENSOR_NAME_CAPACITY).as_str()).unwrap()
+        });
+        config.mqtt_host = String::try_from("h".repeat(MQTT_HOST_CAPACITY).as_str()).unwrap();
+        config.mqtt_username =
+            String::try_from("u".repeat(MQTT_USERNAME_CAPACITY).as_str()).unwrap();
+        config.mqtt_password =
+            String::try_from("p".repeat(MQTT_PASSWORD_CAPACITY).as_str()).unwrap();
+        config.mqtt_client_id =
+            String::try_from("c".repeat(MQTT_CLIENT_ID_CAPACITY).as_str()).unwrap();
+        config.mqtt_topic_prefix =
+            String::try_from("t".repeat(MQTT_TOPIC_PREFIX_CAPACITY).as_str()).unwrap();
+        while config.alert_rules.push(DEFAULT_ALERT_RULES[0]).is_ok() {}
+
+        let mut buf = [0; RECORD_CAPACITY];
+        let len = config.encode(&mut buf).unwrap();
+        assert_eq!(
+            NodeConfig::decode(&buf[..len]).unwrap(),
+            (config, Source::Flash)
+        );
+    }
+
+    #[test]
+    fn sensor_names() {
+        let mut config = configured();
+        assert_eq!(config.sensor_names, DEFAULT_SENSOR_NAMES);
+
+        config.sensor_names[1] = String::try_from("living-room_2").unwrap();
+        let mut store = ConfigStore::new(MemoryFlash::erased());
+        store.save(&config).unwrap();
+        assert_eq!(store.load().unwrap(), (config.clone(), Source::Flash));
+
+        // A sensor this firmware doesn't have, and a name it can't use, are skipped.
+        let extra = [
+            [TAG_SENSOR_NAME, 2, 2, b'x'].as_slice(),
+            &[TAG_SENSOR_NAME, 4, 0, b'a', b'/', b'b'],
+            &[TAG_SENSOR_NAME, 1, 0],
+        ]
+        .concat();
+        let record = record(&config, SCHEMA_VERSION, &extra);
+        assert_eq!(
+            NodeConfig::decode(&record).unwrap(),
+            (config, Source::Flash)
+        );
+
+        // No room for the sensor index.
+        let record = self::record(&configured(), SCHEMA_VERSION, &[TAG_SENSOR_NAME, 0]);
+        assert_eq!(NodeConfig::decode(&record), Err(ConfigError::Malformed));
+    }
+
+    #[test]
+    fn valid_sensor_names() {
+        assert!(is_valid_sensor_name("indoor"));
+        assert!(is_valid_sensor_name("Room_2-b"));
+        assert!(!is_valid_sensor_name(""));
+        assert!(!is_valid_sensor_name("living room"));
+        assert!(!is_valid_sensor_name("a/b"));
+        assert!(!is_valid_sensor_name("küche"));
+    }
+
+    #[test]
+    fn erased_flash_gets_defaults() {
+        let mut store = ConfigStore::new(MemoryFlash::erased());
+        assert_eq!(
+            store.load().unwrap(),
+            (NodeConfig::default(), Source::Defaults(ConfigError::Erased))
+        );
+        assert!(!NodeConfig::default().has_wifi());
+
+        // Written back, so the next boot finds them.
+        assert_eq!(
+            store.load().unwrap(),
+            (NodeConfig::default(), Source::Flash)
+        );
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut record = record(&configured(), SCHEMA_VERSION, &[]);
+        let last = record.len() - 1;
+        record[last] ^= 1;
+        assert_eq!(NodeConfig::decode(&record), Err(ConfigError::BadCrc));
+
+        let mut store = ConfigStore::new(MemoryFlash::with_record(&record));
+        assert_eq!(
+            store.load().unwrap(),
+            (NodeConfig::default(), Source::Defaults(ConfigError::BadCrc))
+        );
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut record = record(&configured(), SCHEMA_VERSION, &[]);
+        record[0] = b'X';
+        assert_eq!(NodeConfig::decode(&record), Err(ConfigError::BadMagic));
+
+        let mut store = ConfigStore::new(MemoryFlash::with_record(&record));
+        assert_eq!(
+            store.load().unwrap(),
+            (
+                NodeConfig::default(),
+                Source::Defaults(ConfigError::BadMagic)
+            )
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let record = record(&configured(), SCHEMA_VERSION, &[]);
+        assert_eq!(
+            NodeConfig::decode(&record[..HEADER_LEN - 1]),
+            Err(ConfigError::Malformed)
+        );
+        // Cut short of the payload length in the header.
+        assert_eq!(
+            NodeConfig::decode(&record[..record.len() - 1]),
+            Err(ConfigError::Malformed)
+        );
+        // A field running past the end of the payload.
+        assert_eq!(
+            NodeConfig::decode(&self::record(&configured(), SCHEMA_VERSION, &[99, 4, 0])),
+            Err(ConfigError::Malformed)
+        );
+        assert_eq!(
+            NodeConfig::decode(&self::record(&configured(), 0, &[])),
+            Err(ConfigError::Malformed)
+        );
+    }
+
+    #[test]
+    fn zero_polling_interval_is_raised() {
+        let config = NodeConfig {
+            polling_interval_ms: 0,
+            ..configured()
+        };
+        let (decoded, _) = NodeConfig::decode(&record(&config, SCHEMA_VERSION, &[])).unwrap();
+        assert_eq!(decoded.polling_interval_ms, MIN_POLLING_INTERVAL_MS);
+    }
+
+    #[test]
+    fn newer_version() {
+        // A field this firmware doesn't know is skipped.
+        let record = record(&configured(), SCHEMA_VERSION + 1, &[99, 1, 7]);
+        let mut store = ConfigStore::new(MemoryFlash::with_record(&record));
+        assert_eq!(
+            store.load().unwrap(),
+            (
+                configured(),
+                Source::Newer {
+                    version: SCHEMA_VERSION + 1
+                }
+            )
+        );
+
+        // Not rewritten, newer firmware may come back to it.
+        assert_eq!(store.storage.writes, 0);
+        assert_eq!(&store.storage.data[..record.len()], &record[..]);
+    }
+
+    #[test]
+    fn mqtt() {
+        let mut config = configured();
+        assert!(!config.has_mqtt());
+        assert_eq!(config.mqtt_port, 1883);
+        assert_eq!(config.mqtt_topic_prefix, "home-monitor");
+
+        config.mqtt_host = String::try_from("mqtt.example.org").unwrap();
+        config.mqtt_port = 8883;
+        config.mqtt_username = String::try_from("node").unwrap();
+        config.mqtt_password = String::try_from("secret").unwrap();
+        config.mqtt_client_id = String::try_from("attic-node").unwrap();
+        config.mqtt_topic_prefix = String::try_from("house/sensors").unwrap();
+        assert!(config.has_mqtt());
+
+        let mut store = ConfigStore::new(MemoryFlash::erased());
+        store.save(&config).unwrap();
+        assert_eq!(store.load().unwrap(), (config, Source::Flash));
+
+        let record = record(&configured(), SCHEMA_VERSION, &[TAG_MQTT_PORT, 1, 80]);
+        assert_eq!(NodeConfig::decode(&record), Err(ConfigError::Malformed));
+    }
+
+    #[test]
+    fn version_1_keeps_its_broker() {
+        let record = record(&configured(), 1, &[]);
+        let mut store = ConfigStore::new(MemoryFlash::with_record(&record));
+
+        let (config, source) = store.load().unwrap();
+        assert_eq!(source, Source::Migrated { from: 1 });
+        assert_eq!(config.mqtt_host, "broker.lan");
+        assert_eq!(config.wifi_ssid, "home");
+
+        // Written back in the current schema.
+        assert_eq!(store.storage.writes, 1);
+        assert_eq!(store.load().unwrap(), (config, Source::Flash));
+    }
+
+    #[test]
+    fn migration_steps() {
+        fn v1_to_v2(config: &mut NodeConfig) {
+            config.polling_interval_ms *= 2;
+        }
+        fn v2_to_v3(config: &mut NodeConfig) {
+            config.location = String::try_from("migrated").unwrap();
+        }
+        let migrations: [fn(&mut NodeConfig); 2] = [v1_to_v2, v2_to_v3];
+
+        let mut config = configured();
+        migrate(&mut config, 1, &migrations);
+        assert_eq!(config.polling_interval_ms, 10_000);
+        assert_eq!(config.location, "migrated");
+
+        // Only the steps after the stored version run.
+        let mut config = configured();
+        migrate(&mut config, 2, &migrations);
+        assert_eq!(config.polling_interval_ms, 5000);
+        assert_eq!(config.location, "migrated");
+
+        let mut config = configured();
+        migrate(&mut config, 3, &migrations);
+        assert_eq!(config, configured());
+    }
+}
@@ -0,0 +1,400 @@
+use defmt::Format;
+use serde::{Serialize, Serializer};
+
+use crate::alert::Quantity;
+use crate::drivers::environment::SensorId;
+use crate::drivers::sht3x::Sht3xReading;
+
+/// What switching the relay on does to the controlled quantity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Action {
+    /// E.g. a heater or a humidifier.
+    Raise,
+    /// E.g. a cooler or a dehumidifier.
+    Lower,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct ControlConfig {
+    /// Sensor whose readings drive the relay.
+    pub sensor: SensorId,
+    pub quantity: Quantity,
+    pub action: Action,
+    /// Value the relay drives the quantity to before switching off.
+    pub setpoint: f32,
+    /// How far past the setpoint the value has to drift before the relay switches on again.
+    pub hysteresis: f32,
+    /// Shortest time the relay stays on once switched on, whatever asks for it.
+    pub min_on_ms: u64,
+    /// Shortest time the relay stays off once switched off, also counted from boot.
+    pub min_off_ms: u64,
+    /// State of the relay while the sensor is failing.
+    pub failsafe_on: bool,
+}
+
+impl ControlConfig {
+    /// Whether the relay should be on at `value`, `None` within the hysteresis band where
+    /// it keeps its state.
+    fn demand(&self, value: f32) -> Option<bool> {
+        let (on, off) = match self.action {
+            Action::Lower => (
+                value >= self.setpoint + self.hysteresis,
+                value <= self.setpoint,
+            ),
+            Action::Raise => (
+                value <= self.setpoint - self.hysteresis,
+                value >= self.setpoint,
+            ),
+        };
+
+        match (on, off) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// What decides the state of the relay, highest priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Mode {
+    /// Set by hand through the API or MQTT.
+    Manual,
+    /// The sensor is failing, the relay is in its failsafe state.
+    Failsafe,
+    /// Driven by the readings.
+    Auto,
+    /// No reading yet, the relay is off.
+    Waiting,
+}
+
+impl Mode {
+    pub fn label(self) -> &'static str {
+        match self {
+            Mode::Manual => "manual",
+            Mode::Failsafe => "failsafe",
+            Mode::Auto => "auto",
+            Mode::Waiting => "waiting",
+        }
+    }
+}
+
+impl Serialize for Mode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.label())
+    }
+}
+
+/// A manual override, or the end of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Command {
+    /// Relay on, for that many seconds or until told otherwise.
+    On(Option<u32>),
+    /// Relay off, for that many seconds or until told otherwise.
+    Off(Option<u32>),
+    /// Back to the readings.
+    Auto,
+}
+
+impl Command {
+    /// Parses `on`, `off` or `auto`, in any case, the first two optionally followed by a
+    /// duration in seconds, e.g. `on 600`.
+    pub fn parse(text: &[u8]) -> Option<Self> {
+        let text = core::str::from_utf8(text).ok()?.trim();
+        let (word, rest) = text.split_once(' ').unwrap_or((text, ""));
+
+        let seconds = match rest.trim() {
+            "" => None,
+            seconds => Some(seconds.parse().ok()?),
+        };
+
+        if word.eq_ignore_ascii_case("on") {
+            Some(Command::On(seconds))
+        } else if word.eq_ignore_ascii_case("off") {
+            Some(Command::Off(seconds))
+        } else if word.eq_ignore_ascii_case("auto") && seconds.is_none() {
+            Some(Command::Auto)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format, Serialize)]
+pub struct RelayStatus {
+    pub on: bool,
+    pub mode: Mode,
+    /// What the mode asks for. Differs from `on` while a minimum on or off time holds the
+    /// relay.
+    pub wanted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Override {
+    on: bool,
+    /// When it ends, in ms, `None` for never.
+    until_ms: Option<u64>,
+}
+
+/// Bang-bang control of a relay with hysteresis and minimum on and off times.
+///
+/// Readings and commands only change what the relay should do, [`Controller::update`]
+/// decides when it switches and should run on every change and regularly in between, so
+/// held switches and overrides run out on time.
+pub struct Controller {
+    config: ControlConfig,
+    on: bool,
+    /// When the relay last switched, in ms.
+    switched_ms: u64,
+    /// What the readings ask for, `None` before the first one.
+    demand: Option<bool>,
+    faulted: bool,
+    manual: Option<Override>,
+}
+
+impl Controller {
+    pub const fn new(config: ControlConfig) -> Self {
+        Self {
+            config,
+            on: false,
+            switched_ms: 0,
+            demand: None,
+            faulted: false,
+            manual: None,
+        }
+    }
+
+    /// Takes a reading of `sensor`, ignored unless it is the controlled one.
+    pub fn reading(&mut self, sensor: SensorId, reading: &Sht3xReading) {
+        if sensor != self.config.sensor {
+            return;
+        }
+
+        self.faulted = false;
+        let value = self.config.quantity.of(reading);
+        // A first reading within the band leaves the relay off.
+        self.demand = self.config.demand(value).or(self.demand).or(Some(false));
+    }
+
+    /// Takes a fault of `sensor`. The relay goes to its failsafe state until the next
+    /// reading.
+    pub fn fault(&mut self, sensor: SensorId) {
+        if sensor == self.config.sensor {
+            self.faulted = true;
+        }
+    }
+
+    /// Applies a manual override given at `now_ms`.
+    pub fn command(&mut self, now_ms: u64, command: Command) {
+        let until = |seconds: Option<u32>| seconds.map(|s| now_ms + s as u64 * 1000);
+
+        self.manual = match command {
+            Command::On(seconds) => Some(Override {
+                on: true,
+                until_ms: until(seconds),
+            }),
+            Command::Off(seconds) => Some(Override {
+                on: false,
+                until_ms: until(seconds),
+            }),
+            Command::Auto => None,
+        };
+    }
+
+    /// Switches the relay if its mode asks for it and the minimum time in its current state
+    /// is up, and returns where it stands at `now_ms`.
+    pub fn update(&mut self, now_ms: u64) -> RelayStatus {
+        if self
+            .manual
+            .is_some_and(|manual| manual.until_ms.is_some_and(|until| now_ms >= until))
+        {
+            self.manual = None;
+        }
+
+        let (mode, wanted) = match (self.manual, self.faulted, self.demand) {
+            (Some(manual), _, _) => (Mode::Manual, manual.on),
+            (None, true, _) => (Mode::Failsafe, self.config.failsafe_on),
+            (None, false, Some(demand)) => (Mode::Auto, demand),
+            (None, false, None) => (Mode::Waiting, false),
+        };
+
+        let held_ms = if self.on {
+            self.config.min_on_ms
+        } else {
+            self.config.min_off_ms
+        };
+        if wanted != self.on && now_ms.saturating_sub(self.switched_ms) >= held_ms {
+            self.on = wanted;
+            self.switched_ms = now_ms;
+        }
+
+        RelayStatus {
+            on: self.on,
+            mode,
+            wanted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dehumidifier, like the one the relay task drives.
+    const DEHUMIDIFIER: ControlConfig = ControlConfig {
+        sensor: SensorId::at(0),
+        quantity: Quantity::Humidity,
+        action: Action::Lower,
+        setpoint: 60.0,
+        hysteresis: 5.0,
+        min_on_ms: 5 * 60 * 1000,
+        min_off_ms: 5 * 60 * 1000,
+        failsafe_on: false,
+    };
+
+    const NO_MIN_TIMES: ControlConfig = ControlConfig {
+        min_on_ms: 0,
+        min_off_ms: 0,
+        ..DEHUMIDIFIER
+    };
+
+    fn humidity(humidity: f64) -> Sht3xReading {
+        Sht3xReading::new(20.0, humidity)
+    }
+
+    fn status(on: bool, mode: Mode, wanted: bool) -> RelayStatus {
+        RelayStatus { on, mode, wanted }
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut controller = Controller::new(NO_MIN_TIMES);
+        assert_eq!(controller.update(0), status(false, Mode::Waiting, false));
+
+        let mut step = |now_ms, value| {
+            controller.reading(SensorId::at(0), &humidity(value));
+            controller.update(now_ms)
+        };
+        assert_eq!(step(1000, 62.0), status(false, Mode::Auto, false));
+        assert!(step(2000, 65.0).on);
+        // Within the band it stays on until the setpoint...
+        assert!(step(3000, 61.0).on);
+        assert!(!step(4000, 60.0).on);
+        // ...and off until setpoint plus hysteresis.
+        assert!(!step(5000, 64.9).on);
+
+        // Other sensors don't drive it.
+        controller.reading(SensorId::at(1), &humidity(90.0));
+        assert!(!controller.update(6000).on);
+    }
+
+    #[test]
+    fn raise() {
+        let heater = ControlConfig {
+            quantity: Quantity::Temperature,
+            action: Action::Raise,
+            setpoint: 20.0,
+            hysteresis: 1.0,
+            ..NO_MIN_TIMES
+        };
+        let mut controller = Controller::new(heater);
+
+        for (temperature, on) in [(18.9, true), (19.5, true), (20.0, false), (19.1, false)] {
+            controller.reading(SensorId::at(0), &Sht3xReading::new(temperature, 50.0));
+            assert_eq!(controller.update(0).on, on, "at {temperature} °C");
+        }
+    }
+
+    #[test]
+    fn min_on_and_off_times() {
+        let mut controller = Controller::new(DEHUMIDIFIER);
+        controller.reading(SensorId::at(0), &humidity(70.0));
+
+        // The minimum off time counts from boot.
+        assert_eq!(controller.update(10_000), status(false, Mode::Auto, true));
+        assert_eq!(controller.update(300_000), status(true, Mode::Auto, true));
+
+        controller.reading(SensorId::at(0), &humidity(50.0));
+        assert_eq!(controller.update(599_999), status(true, Mode::Auto, false));
+        assert_eq!(controller.update(600_000), status(false, Mode::Auto, false));
+
+        controller.reading(SensorId::at(0), &humidity(70.0));
+        assert!(!controller.update(899_999).on);
+        assert!(controller.update(900_000).on);
+    }
+
+    #[test]
+    fn manual_override_respects_min_times() {
+        let mut controller = Controller::new(DEHUMIDIFIER);
+        controller.command(0, Command::On(None));
+        assert_eq!(controller.update(0), status(false, Mode::Manual, true));
+        assert_eq!(controller.update(300_000), status(true, Mode::Manual, true));
+    }
+
+    #[test]
+    fn override_timeout() {
+        let mut controller = Controller::new(NO_MIN_TIMES);
+        controller.reading(SensorId::at(0), &humidity(50.0));
+
+        controller.command(1000, Command::On(Some(60)));
+        assert_eq!(controller.update(1000), status(true, Mode::Manual, true));
+        assert_eq!(controller.update(60_999), status(true, Mode::Manual, true));
+        // Back to the readings once it runs out.
+        assert_eq!(controller.update(61_000), status(false, Mode::Auto, false));
+
+        // Without a timeout it holds until told otherwise.
+        controller.command(62_000, Command::Off(None));
+        controller.reading(SensorId::at(0), &humidity(90.0));
+        assert_eq!(
+            controller.update(10_000_000),
+            status(false, Mode::Manual, false)
+        );
+        controller.command(10_000_000, Command::Auto);
+        assert_eq!(
+            controller.update(10_000_000),
+            status(true, Mode::Auto, true)
+        );
+    }
+
+    #[test]
+    fn failsafe() {
+        let mut controller = Controller::new(ControlConfig {
+            failsafe_on: true,
+            ..NO_MIN_TIMES
+        });
+        controller.reading(SensorId::at(0), &humidity(50.0));
+        assert!(!controller.update(0).on);
+
+        // Only a fault of its own sensor counts.
+        controller.fault(SensorId::at(1));
+        assert_eq!(controller.update(1).mode, Mode::Auto);
+        controller.fault(SensorId::at(0));
+        assert_eq!(controller.update(2), status(true, Mode::Failsafe, true));
+
+        // A manual override still wins, and the failsafe is back when it runs out.
+        controller.command(3, Command::Off(Some(1)));
+        assert_eq!(controller.update(3), status(false, Mode::Manual, false));
+        assert_eq!(controller.update(1003), status(true, Mode::Failsafe, true));
+
+        // Cleared by the next reading.
+        controller.reading(SensorId::at(0), &humidity(50.0));
+        assert_eq!(controller.update(1004), status(false, Mode::Auto, false));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::parse(b"on"), Some(Command::On(None)));
+        assert_eq!(Command::parse(b" OFF 600\n"), Some(Command::Off(Some(600))));
+        assert_eq!(Command::parse(b"Auto"), Some(Command::Auto));
+        for invalid in [&b"auto 5"[..], b"on x", b"toggle", b"", &[0xff]] {
+            assert_eq!(Command::parse(invalid), None);
+        }
+    }
+
+    #[test]
+    fn status_json() {
+        let status = status(true, Mode::Manual, true);
+        let json: heapless::String<64> = serde_json_core::to_string(&status).unwrap();
+        assert_eq!(json, r#"{"on":true,"mode":"manual","wanted":true}"#);
+    }
+}
@@ -0,0 +1,81 @@
+use core::fmt::Write;
+
+use heapless::String;
+
+pub const NODE_ID_LEN: usize = 25;
+pub const SETUP_KEY_LEN: usize = 16;
+
+const NODE_ID_PREFIX: &str = "home-monitor-";
+
+/// Mixed into the setup key, since the MAC it is derived from is part of the SSID. Set
+/// `SETUP_KEY_SECRET` when building firmware for labelled nodes, without it anyone who
+/// knows this code can work out the key.
+const SETUP_KEY_SECRET: &str = match option_env!("SETUP_KEY_SECRET") {
+    Some(secret) => secret,
+    None => "",
+};
+
+/// Identity of this node, shared by everything that needs to tell nodes apart.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    /// `home-monitor-` followed by the base MAC address in lowercase hex.
+    pub node_id: String<NODE_ID_LEN>,
+    /// WPA2 key of the setup access point, printed on the node's label.
+    pub setup_key: String<SETUP_KEY_LEN>,
+    pub firmware_version: &'static str,
+}
+
+impl DeviceInfo {
+    pub const MANUFACTURER: &'static str = "home-monitor";
+    #[cfg(feature = "sht3x")]
+    pub const MODEL: &'static str = "ESP32 + SHT3x";
+    #[cfg(feature = "sht4x")]
+    pub const MODEL: &'static str = "ESP32 + SHT4x";
+    #[cfg(feature = "bme280")]
+    pub const MODEL: &'static str = "ESP32 + BME280";
+
+    pub fn new(mac: [u8; 6], firmware_version: &'static str) -> Self {
+        let mut node_id = String::new();
+        // Prefix plus twelve hex digits always fits in NODE_ID_LEN.
+        let _ = node_id.push_str(NODE_ID_PREFIX);
+        for byte in mac {
+            let _ = write!(node_id, "{:02x}", byte);
+        }
+
+        Self {
+            node_id,
+            setup_key: setup_key(mac),
+            firmware_version,
+        }
+    }
+}
+
+/// Sixteen hex digits of an FNV-1a hash over [`SETUP_KEY_SECRET`] and the MAC.
+fn setup_key(mac: [u8; 6]) -> String<SETUP_KEY_LEN> {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in SETUP_KEY_SECRET.as_bytes().iter().chain(&mac) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    let mut key = String::new();
+    // Sixteen hex digits always fit in SETUP_KEY_LEN.
+    let _ = write!(key, "{:016x}", hash);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity() {
+        let device = DeviceInfo::new([0xa4, 0xcf, 0x12, 0x0b, 0x3c, 0x01], "0.1.0");
+        assert_eq!(device.node_id, "home-monitor-a4cf120b3c01");
+
+        // Long enough for WPA2, and different for every node.
+        assert_eq!(device.setup_key.len(), SETUP_KEY_LEN);
+        assert!(device.setup_key.bytes().all(|b| b.is_ascii_hexdigit()));
+        let other = DeviceInfo::new([0xa4, 0xcf, 0x12, 0x0b, 0x3c, 0x02], "0.1.0");
+        assert_ne!(device.setup_key, other.setup_key);
+    }
+}
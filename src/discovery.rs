@@ -0,0 +1,454 @@
+use core::fmt::{self, Display, Write};
+
+use defmt::Format;
+use heapless::String;
+use serde::Serialize;
+
+use crate::device::DeviceInfo;
+
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum DiscoveryError {
+    BufferTooSmall,
+}
+
+/// A sensor entity announced to Home Assistant through MQTT discovery.
+#[derive(Debug, Clone, Copy)]
+pub struct Entity {
+    /// Last topic level of both the state and the config topic.
+    pub object_id: &'static str,
+    pub name: &'static str,
+    pub device_class: Option<&'static str>,
+    pub unit: Option<&'static str>,
+    pub state_class: Option<&'static str>,
+    /// `Some("diagnostic")` for entities about the node itself rather than the room.
+    pub entity_category: Option<&'static str>,
+    /// Name of the sensor the entity reports for, on nodes with several.
+    pub sensor: Option<&'static str>,
+}
+
+impl Entity {
+    /// The same entity for one sensor out of several.
+    pub const fn for_sensor(self, sensor: &'static str) -> Self {
+        Self {
+            sensor: Some(sensor),
+            ..self
+        }
+    }
+
+    /// `<sensor>_<object-id>` for per-sensor entities, otherwise just the object id.
+    pub fn object_id(&self) -> ObjectId<'_> {
+        ObjectId(self)
+    }
+}
+
+pub struct ObjectId<'a>(&'a Entity);
+
+impl Display for ObjectId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.sensor {
+            Some(sensor) => write!(f, "{}_{}", sensor, self.0.object_id),
+            None => f.write_str(self.0.object_id),
+        }
+    }
+}
+
+pub const TEMPERATURE: Entity = Entity {
+    object_id: "temperature",
+    name: "Temperature",
+    device_class: Some("temperature"),
+    unit: Some("°C"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+pub const HUMIDITY: Entity = Entity {
+    object_id: "humidity",
+    name: "Humidity",
+    device_class: Some("humidity"),
+    unit: Some("%"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+pub const PRESSURE: Entity = Entity {
+    object_id: "pressure",
+    name: "Pressure",
+    device_class: Some("atmospheric_pressure"),
+    unit: Some("hPa"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+/// Published with readings that carry CO2. None of the supported chips measures it yet, so
+/// it isn't announced.
+pub const CO2: Entity = Entity {
+    object_id: "co2",
+    name: "CO2",
+    device_class: Some("carbon_dioxide"),
+    unit: Some("ppm"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+pub const DEW_POINT: Entity = Entity {
+    object_id: "dew_point",
+    name: "Dew point",
+    device_class: Some("temperature"),
+    unit: Some("°C"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+pub const ABSOLUTE_HUMIDITY: Entity = Entity {
+    object_id: "absolute_humidity",
+    name: "Absolute humidity",
+    device_class: Some("absolute_humidity"),
+    unit: Some("g/m³"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+pub const HEAT_INDEX: Entity = Entity {
+    object_id: "heat_index",
+    name: "Heat index",
+    device_class: Some("temperature"),
+    unit: Some("°C"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+/// Dimensionless, though read like a temperature in °C.
+pub const HUMIDEX: Entity = Entity {
+    object_id: "humidex",
+    name: "Humidex",
+    device_class: None,
+    unit: None,
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+pub const VPD: Entity = Entity {
+    object_id: "vpd",
+    name: "Vapour-pressure deficit",
+    device_class: Some("pressure"),
+    unit: Some("kPa"),
+    state_class: Some("measurement"),
+    entity_category: None,
+    sensor: None,
+};
+
+/// `ok`, or why the sensor is failing as named by `SensorError::label`.
+pub const SENSOR_STATUS: Entity = Entity {
+    object_id: "status",
+    name: "Sensor status",
+    device_class: None,
+    unit: None,
+    state_class: None,
+    entity_category: Some("diagnostic"),
+    sensor: None,
+};
+
+pub const RSSI: Entity = Entity {
+    object_id: "rssi",
+    name: "Wi-Fi signal",
+    device_class: Some("signal_strength"),
+    unit: Some("dBm"),
+    state_class: Some("measurement"),
+    entity_category: Some("diagnostic"),
+    sensor: None,
+};
+
+pub const UPTIME: Entity = Entity {
+    object_id: "uptime",
+    name: "Uptime",
+    device_class: Some("duration"),
+    unit: Some("s"),
+    state_class: Some("total_increasing"),
+    entity_category: Some("diagnostic"),
+    sensor: None,
+};
+
+/// `on` or `off`. Set through `<prefix>/<node-id>/relay/set`, see `Command::parse`.
+pub const RELAY: Entity = Entity {
+    object_id: "relay",
+    name: "Relay",
+    device_class: None,
+    unit: None,
+    state_class: None,
+    entity_category: None,
+    sensor: None,
+};
+
+/// What drives the relay, as named by `Mode::label`.
+pub const RELAY_MODE: Entity = Entity {
+    object_id: "relay_mode",
+    name: "Relay mode",
+    device_class: None,
+    unit: None,
+    state_class: None,
+    entity_category: Some("diagnostic"),
+    sensor: None,
+};
+
+/// Announced once per sensor, see [`Entity::for_sensor`].
+pub const SENSOR_ENTITIES: [Entity; 3] = [TEMPERATURE, HUMIDITY, SENSOR_STATUS];
+
+/// Announced once per sensor after [`SENSOR_ENTITIES`] with the `bme280` feature.
+pub const PRESSURE_ENTITIES: [Entity; 1] = [PRESSURE];
+
+/// Announced once per sensor after [`SENSOR_ENTITIES`] with the `psychro` feature, see
+/// `Psychrometrics`.
+pub const DERIVED_ENTITIES: [Entity; 5] = [DEW_POINT, ABSOLUTE_HUMIDITY, HEAT_INDEX, HUMIDEX, VPD];
+
+/// Announced once per node.
+pub const NODE_ENTITIES: [Entity; 2] = [RSSI, UPTIME];
+
+/// Announced once per node after [`NODE_ENTITIES`] with the `relay` feature.
+pub const RELAY_ENTITIES: [Entity; 2] = [RELAY, RELAY_MODE];
+
+#[derive(Serialize)]
+struct DevicePayload<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    manufacturer: &'a str,
+    model: &'a str,
+    sw_version: &'a str,
+}
+
+#[derive(Serialize)]
+struct ConfigPayload<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    state_topic: &'a str,
+    availability_topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'a str>,
+    device: DevicePayload<'a>,
+}
+
+/// `homeassistant/sensor/<node-id>/<object-id>/config`, see [`Entity::object_id`].
+pub fn config_topic<const N: usize>(
+    device: &DeviceInfo,
+    entity: &Entity,
+) -> Result<String<N>, DiscoveryError> {
+    let mut topic = String::new();
+    write!(
+        topic,
+        "{}/sensor/{}/{}/config",
+        DISCOVERY_PREFIX,
+        device.node_id,
+        entity.object_id()
+    )
+    .map_err(|_| DiscoveryError::BufferTooSmall)?;
+    Ok(topic)
+}
+
+/// `<node-id>_<object-id>`, stable across reboots and firmware updates.
+pub fn unique_id<const N: usize>(
+    device: &DeviceInfo,
+    entity: &Entity,
+) -> Result<String<N>, DiscoveryError> {
+    let mut id = String::new();
+    write!(id, "{}_{}", device.node_id, entity.object_id())
+        .map_err(|_| DiscoveryError::BufferTooSmall)?;
+    Ok(id)
+}
+
+/// Serializes the retained config message for `entity` into `buf` and returns its length.
+pub fn config_payload(
+    buf: &mut [u8],
+    device: &DeviceInfo,
+    entity: &Entity,
+    state_topic: &str,
+    availability_topic: &str,
+) -> Result<usize, DiscoveryError> {
+    let unique_id: String<64> = unique_id(device, entity)?;
+
+    let mut name: String<48> = String::new();
+    match entity.sensor {
+        Some(sensor) => write!(name, "{} {}", sensor, entity.name),
+        None => write!(name, "{}", entity.name),
+    }
+    .map_err(|_| DiscoveryError::BufferTooSmall)?;
+
+    let payload = ConfigPayload {
+        name: &name,
+        unique_id: unique_id.as_str(),
+        state_topic,
+        availability_topic,
+        device_class: entity.device_class,
+        unit_of_measurement: entity.unit,
+        state_class: entity.state_class,
+        entity_category: entity.entity_category,
+        device: DevicePayload {
+            identifiers: [device.node_id.as_str()],
+            name: device.node_id.as_str(),
+            manufacturer: DeviceInfo::MANUFACTURER,
+            model: DeviceInfo::MODEL,
+            sw_version: device.firmware_version,
+        },
+    };
+
+    serde_json_core::to_slice(&payload, buf).map_err(|_| DiscoveryError::BufferTooSmall)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE_ID: &str = "home-monitor-a4cf120b3c01";
+
+    /// Checks the config topic of `entity` ends in `object_id`, and its payload is named
+    /// `name` and carries `classes` between the topics and the device.
+    fn snapshot(entity: &Entity, object_id: &str, name: &str, classes: &str) {
+        let device = DeviceInfo::new([0xa4, 0xcf, 0x12, 0x0b, 0x3c, 0x01], "0.1.0");
+        assert_eq!(device.node_id.as_str(), NODE_ID);
+
+        let topic: String<96> = config_topic(&device, entity).unwrap();
+        assert_eq!(
+            topic.as_str(),
+            format!("homeassistant/sensor/{NODE_ID}/{object_id}/config")
+        );
+
+        let state_topic = format!("home-monitor/{NODE_ID}/{object_id}");
+        let availability_topic = format!("home-monitor/{NODE_ID}/availability");
+        let mut buf = [0; 512];
+        let len =
+            config_payload(&mut buf, &device, entity, &state_topic, &availability_topic).unwrap();
+
+        // The model names the sensor chip the firmware was built for.
+        let model = DeviceInfo::MODEL;
+        let expected = format!(
+            r#"{{"name":"{name}","unique_id":"{NODE_ID}_{object_id}","state_topic":"{state_topic}","availability_topic":"{availability_topic}",{classes}"device":{{"identifiers":["{NODE_ID}"],"name":"{NODE_ID}","manufacturer":"home-monitor","model":"{model}","sw_version":"0.1.0"}}}}"#
+        );
+        assert_eq!(core::str::from_utf8(&buf[..len]).unwrap(), expected);
+    }
+
+    #[test]
+    fn sensor_entities() {
+        snapshot(
+            &TEMPERATURE,
+            "temperature",
+            "Temperature",
+            r#""device_class":"temperature","unit_of_measurement":"°C","state_class":"measurement","#,
+        );
+        snapshot(
+            &HUMIDITY,
+            "humidity",
+            "Humidity",
+            r#""device_class":"humidity","unit_of_measurement":"%","state_class":"measurement","#,
+        );
+        snapshot(
+            &SENSOR_STATUS,
+            "status",
+            "Sensor status",
+            r#""entity_category":"diagnostic","#,
+        );
+    }
+
+    #[test]
+    fn derived_entities() {
+        snapshot(
+            &DEW_POINT,
+            "dew_point",
+            "Dew point",
+            r#""device_class":"temperature","unit_of_measurement":"°C","state_class":"measurement","#,
+        );
+        snapshot(
+            &ABSOLUTE_HUMIDITY,
+            "absolute_humidity",
+            "Absolute humidity",
+            r#""device_class":"absolute_humidity","unit_of_measurement":"g/m³","state_class":"measurement","#,
+        );
+        snapshot(
+            &HEAT_INDEX,
+            "heat_index",
+            "Heat index",
+            r#""device_class":"temperature","unit_of_measurement":"°C","state_class":"measurement","#,
+        );
+        snapshot(
+            &HUMIDEX,
+            "humidex",
+            "Humidex",
+            r#""state_class":"measurement","#,
+        );
+        snapshot(
+            &VPD,
+            "vpd",
+            "Vapour-pressure deficit",
+            r#""device_class":"pressure","unit_of_measurement":"kPa","state_class":"measurement","#,
+        );
+    }
+
+    #[test]
+    fn node_entities() {
+        snapshot(
+            &RSSI,
+            "rssi",
+            "Wi-Fi signal",
+            r#""device_class":"signal_strength","unit_of_measurement":"dBm","state_class":"measurement","entity_category":"diagnostic","#,
+        );
+        snapshot(
+            &UPTIME,
+            "uptime",
+            "Uptime",
+            r#""device_class":"duration","unit_of_measurement":"s","state_class":"total_increasing","entity_category":"diagnostic","#,
+        );
+    }
+
+    #[test]
+    fn relay_entities() {
+        snapshot(&RELAY, "relay", "Relay", "");
+        snapshot(
+            &RELAY_MODE,
+            "relay_mode",
+            "Relay mode",
+            r#""entity_category":"diagnostic","#,
+        );
+    }
+
+    #[test]
+    fn per_sensor_entities() {
+        snapshot(
+            &HUMIDITY.for_sensor("duct"),
+            "duct_humidity",
+            "duct Humidity",
+            r#""device_class":"humidity","unit_of_measurement":"%","state_class":"measurement","#,
+        );
+        snapshot(
+            &SENSOR_STATUS.for_sensor("indoor"),
+            "indoor_status",
+            "indoor Sensor status",
+            r#""entity_category":"diagnostic","#,
+        );
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let device = DeviceInfo::new([0; 6], "0.1.0");
+        assert_eq!(
+            config_topic::<16>(&device, &TEMPERATURE),
+            Err(DiscoveryError::BufferTooSmall)
+        );
+        assert_eq!(
+            config_payload(&mut [0; 64], &device, &TEMPERATURE, "s", "a"),
+            Err(DiscoveryError::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use defmt::Format;
+
+pub const PORT: u16 = 53;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+/// Short, so phones notice quickly once the node is back in station mode.
+const ANSWER_TTL_SECS: u32 = 10;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+const RCODE_NOT_IMPLEMENTED: u16 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum DnsError {
+    /// Not a query, or too mangled to answer.
+    Malformed,
+    BufferTooSmall,
+}
+
+/// Answers a DNS query as if every name resolved to `addr`, the way captive portals make
+/// clients open their login page.
+///
+/// `A` questions get `addr`, other types get an empty answer. Returns the length of the
+/// response written to `buf`.
+pub fn answer(query: &[u8], addr: [u8; 4], buf: &mut [u8]) -> Result<usize, DnsError> {
+    let header = query.get(..HEADER_LEN).ok_or(DnsError::Malformed)?;
+
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+
+    if flags & FLAG_RESPONSE != 0 {
+        return Err(DnsError::Malformed);
+    }
+
+    let mut response_flags =
+        FLAG_RESPONSE | FLAG_AUTHORITATIVE | (flags & (OPCODE_MASK | FLAG_RECURSION_DESIRED));
+
+    // Only plain queries with a single question, which is all resolvers send in practice.
+    if flags & OPCODE_MASK != 0 || questions != 1 {
+        response_flags |= RCODE_NOT_IMPLEMENTED;
+        let out = buf.get_mut(..HEADER_LEN).ok_or(DnsError::BufferTooSmall)?;
+        out.fill(0);
+        out[0..2].copy_from_slice(&header[0..2]);
+        out[2..4].copy_from_slice(&response_flags.to_be_bytes());
+        return Ok(HEADER_LEN);
+    }
+
+    let name_end = name_end(query, HEADER_LEN)?;
+    let question_end = name_end + 4;
+    let question = query
+        .get(HEADER_LEN..question_end)
+        .ok_or(DnsError::Malformed)?;
+
+    let qtype = u16::from_be_bytes([query[name_end], query[name_end + 1]]);
+    let qclass = u16::from_be_bytes([query[name_end + 2], query[name_end + 3]]);
+    let answers = u16::from(qtype == TYPE_A && qclass == CLASS_IN);
+
+    let answer_len = if answers == 1 { 16 } else { 0 };
+    let len = HEADER_LEN + question.len() + answer_len;
+    let out = buf.get_mut(..len).ok_or(DnsError::BufferTooSmall)?;
+
+    out[0..2].copy_from_slice(&header[0..2]);
+    out[2..4].copy_from_slice(&response_flags.to_be_bytes());
+    out[4..6].copy_from_slice(&1u16.to_be_bytes());
+    out[6..8].copy_from_slice(&answers.to_be_bytes());
+    out[8..12].fill(0);
+    out[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+
+    if answers == 1 {
+        let answer = &mut out[HEADER_LEN + question.len()..];
+        // Compressed name pointing back at the question.
+        answer[0..2].copy_from_slice(&(0xc000u16 | HEADER_LEN as u16).to_be_bytes());
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&ANSWER_TTL_SECS.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&addr);
+    }
+
+    Ok(len)
+}
+
+/// Offset just past the uncompressed name starting at `pos`.
+fn name_end(packet: &[u8], mut pos: usize) -> Result<usize, DnsError> {
+    loop {
+        let len = *packet.get(pos).ok_or(DnsError::Malformed)? as usize;
+
+        match len {
+            0 => return Ok(pos + 1),
+            // Questions are never compressed, anything else here is garbage.
+            1..=63 => pos += 1 + len,
+            _ => return Err(DnsError::Malformed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: [u8; 4] = [192, 168, 4, 1];
+
+    /// A query for `foo` with id 0x1234, recursion desired.
+    const QUERY: [u8; 21] = [
+        0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 3, b'f', b'o', b'o', 0, 0, 1, 0, 1,
+    ];
+
+    #[test]
+    fn a_query() {
+        let mut buf = [0u8; 512];
+        let len = answer(&QUERY, ADDR, &mut buf).unwrap();
+
+        assert_eq!(len, QUERY.len() + 16);
+        // Same id, a recursion-desired authoritative response with one question, one answer.
+        assert_eq!(
+            &buf[..12],
+            &[0x12, 0x34, 0x85, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(&buf[12..QUERY.len()], &QUERY[12..]);
+        assert_eq!(
+            &buf[QUERY.len()..len],
+            &[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 10, 0, 4, 192, 168, 4, 1]
+        );
+    }
+
+    #[test]
+    fn other_types_get_no_answer() {
+        let mut aaaa = QUERY;
+        aaaa[18] = 28;
+
+        let mut buf = [0u8; 512];
+        let len = answer(&aaaa, ADDR, &mut buf).unwrap();
+        assert_eq!(len, QUERY.len());
+        assert_eq!(&buf[6..8], &[0, 0]);
+    }
+
+    #[test]
+    fn unsupported_queries() {
+        let mut buf = [0u8; 512];
+
+        let mut two_questions = QUERY;
+        two_questions[5] = 2;
+        assert_eq!(answer(&two_questions, ADDR, &mut buf), Ok(HEADER_LEN));
+        assert_eq!(&buf[..4], &[0x12, 0x34, 0x85, 0x04]);
+
+        let mut response = QUERY;
+        response[2] |= 0x80;
+        assert_eq!(answer(&response, ADDR, &mut buf), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn malformed_names() {
+        let mut buf = [0u8; 512];
+
+        assert_eq!(
+            answer(&QUERY[..11], ADDR, &mut buf),
+            Err(DnsError::Malformed)
+        );
+
+        // A label running past the end of the packet.
+        let mut long_label = QUERY;
+        long_label[12] = 20;
+        assert_eq!(
+            answer(&long_label, ADDR, &mut buf),
+            Err(DnsError::Malformed)
+        );
+
+        // A name without its terminating zero.
+        assert_eq!(
+            answer(&QUERY[..16], ADDR, &mut buf),
+            Err(DnsError::Malformed)
+        );
+
+        // The name ends but the type and class are cut off.
+        assert_eq!(
+            answer(&QUERY[..19], ADDR, &mut buf),
+            Err(DnsError::Malformed)
+        );
+
+        // A compression pointer, which a question never needs.
+        let mut pointer = QUERY;
+        pointer[12] = 0xc0;
+        pointer[13] = 12;
+        assert_eq!(answer(&pointer, ADDR, &mut buf), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn small_buffer() {
+        let mut buf = [0u8; QUERY.len() + 15];
+        assert_eq!(
+            answer(&QUERY, ADDR, &mut buf),
+            Err(DnsError::BufferTooSmall)
+        );
+
+        let mut two_questions = QUERY;
+        two_questions[5] = 2;
+        assert_eq!(
+            answer(&two_questions, ADDR, &mut buf[..HEADER_LEN - 1]),
+            Err(DnsError::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,516 @@
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::drivers::environment::{EnvironmentSensor, Measurement, SensorError};
+
+/// SDO tied to ground. Tied to VDDIO the chip answers at 0x77.
+pub const ADDRESS: u8 = 0x76;
+
+const CHIP_ID: u8 = 0x60;
+
+const REG_CALIB_00: u8 = 0x88;
+const REG_CHIP_ID: u8 = 0xd0;
+const REG_RESET: u8 = 0xe0;
+const REG_CALIB_26: u8 = 0xe1;
+const REG_CTRL_HUM: u8 = 0xf2;
+const REG_STATUS: u8 = 0xf3;
+const REG_CTRL_MEAS: u8 = 0xf4;
+const REG_DATA: u8 = 0xf7;
+
+const RESET_WORD: u8 = 0xb6;
+/// Humidity oversampling x1. Only takes effect after the next write to `ctrl_meas`.
+const CTRL_HUM: u8 = 0b001;
+/// Temperature and pressure oversampling x1, forced mode.
+const CTRL_MEAS_FORCED: u8 = (0b001 << 5) | (0b001 << 2) | 0b01;
+const STATUS_MEASURING: u8 = 1 << 3;
+
+/// A forced conversion at x1 oversampling takes at most 9.3 ms.
+const MEASURE_DELAY_US: u32 = 9_300;
+const POLL_DELAY_US: u32 = 1_000;
+const MAX_POLLS: usize = 10;
+const STARTUP_DELAY_US: u32 = 2_000;
+
+/// Trimming values burned into each chip, see section 4.2.2 of the datasheet.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Calibration {
+    pub t1: u16,
+    pub t2: i16,
+    pub t3: i16,
+    pub p1: u16,
+    pub p2: i16,
+    pub p3: i16,
+    pub p4: i16,
+    pub p5: i16,
+    pub p6: i16,
+    pub p7: i16,
+    pub p8: i16,
+    pub p9: i16,
+    pub h1: u8,
+    pub h2: i16,
+    pub h3: u8,
+    pub h4: i16,
+    pub h5: i16,
+    pub h6: i8,
+}
+
+impl Calibration {
+    /// Parses the registers from 0x88 to 0xa1 and from 0xe1 to 0xe7.
+    pub fn parse(block0: &[u8; 26], block1: &[u8; 7]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([block0[i], block0[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([block0[i], block0[i + 1]]);
+
+        Self {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p2: i16_at(8),
+            p3: i16_at(10),
+            p4: i16_at(12),
+            p5: i16_at(14),
+            p6: i16_at(16),
+            p7: i16_at(18),
+            p8: i16_at(20),
+            p9: i16_at(22),
+            h1: block0[25],
+            h2: i16::from_le_bytes([block1[0], block1[1]]),
+            h3: block1[2],
+            // H4 and H5 are 12-bit values sharing the nibbles of 0xe5.
+            h4: ((block1[3] as i8 as i16) << 4) | (block1[4] & 0x0f) as i16,
+            h5: ((block1[5] as i8 as i16) << 4) | (block1[4] >> 4) as i16,
+            h6: block1[6] as i8,
+        }
+    }
+
+    /// Compensates raw ADC values, the floating point formulas of section 8.1. Returns °C,
+    /// Pa and %RH.
+    pub fn compensate(&self, adc_t: u32, adc_p: u32, adc_h: u16) -> (f64, f64, f64) {
+        let adc_t = adc_t as f64;
+        let adc_p = adc_p as f64;
+        let adc_h = adc_h as f64;
+
+        let var1 = (adc_t / 16384.0 - self.t1 as f64 / 1024.0) * self.t2 as f64;
+        let var2 = adc_t / 131072.0 - self.t1 as f64 / 8192.0;
+        let var2 = var2 * var2 * self.t3 as f64;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * self.p6 as f64 / 32768.0;
+        var2 += var1 * self.p5 as f64 * 2.0;
+        var2 = var2 / 4.0 + self.p4 as f64 * 65536.0;
+        var1 = (self.p3 as f64 * var1 * var1 / 524288.0 + self.p2 as f64 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * self.p1 as f64;
+        let pressure = if var1 == 0.0 {
+            // Avoids a division by zero with an unprogrammed calibration.
+            0.0
+        } else {
+            let p = (1048576.0 - adc_p - var2 / 4096.0) * 6250.0 / var1;
+            let var1 = self.p9 as f64 * p * p / 2147483648.0;
+            let var2 = p * self.p8 as f64 / 32768.0;
+            p + (var1 + var2 + self.p7 as f64) / 16.0
+        };
+
+        let h = t_fine - 76800.0;
+        let h = (adc_h - (self.h4 as f64 * 64.0 + self.h5 as f64 / 16384.0 * h))
+            * (self.h2 as f64 / 65536.0
+                * (1.0
+                    + self.h6 as f64 / 67108864.0 * h * (1.0 + self.h3 as f64 / 67108864.0 * h)));
+        let humidity = (h * (1.0 - self.h1 as f64 * h / 524288.0)).clamp(0.0, 100.0);
+
+        (temperature, pressure, humidity)
+    }
+}
+
+pub struct Bme280<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    i2c: I2C,
+    delay: Delay,
+    address: u8,
+    calibration: Calibration,
+}
+
+impl<I2C, Delay> Bme280<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    /// Resets the chip and reads its calibration. Fails if no BME280 answers, a BMP280 has
+    /// a different chip id.
+    pub fn new(i2c: I2C, delay: Delay) -> Result<Self, SensorError> {
+        Self::with_address(i2c, delay, ADDRESS)
+    }
+
+    pub fn with_address(i2c: I2C, delay: Delay, address: u8) -> Result<Self, SensorError> {
+        let mut sensor = Self {
+            i2c,
+            delay,
+            address,
+            calibration: Calibration::default(),
+        };
+
+        let mut id = [0u8];
+        sensor.read_registers(REG_CHIP_ID, &mut id)?;
+        if id[0] != CHIP_ID {
+            return Err(SensorError::InvalidData);
+        }
+
+        sensor.soft_reset()?;
+
+        Ok(sensor)
+    }
+
+    /// Resets the chip and reads its calibration again.
+    pub fn soft_reset(&mut self) -> Result<(), SensorError> {
+        self.write_register(REG_RESET, RESET_WORD)?;
+        self.delay.delay_us(STARTUP_DELAY_US);
+
+        let mut block0 = [0u8; 26];
+        let mut block1 = [0u8; 7];
+        self.read_registers(REG_CALIB_00, &mut block0)?;
+        self.read_registers(REG_CALIB_26, &mut block1)?;
+        self.calibration = Calibration::parse(&block0, &block1);
+
+        Ok(())
+    }
+
+    fn read_registers(&mut self, start: u8, buf: &mut [u8]) -> Result<(), SensorError> {
+        self.i2c
+            .write_read(self.address, &[start], buf)
+            .map_err(|_| SensorError::Bus)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), SensorError> {
+        self.i2c
+            .write(self.address, &[register, value])
+            .map_err(|_| SensorError::Bus)
+    }
+}
+
+impl<I2C, Delay> EnvironmentSensor for Bme280<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn name(&self) -> &'static str {
+        "BME280"
+    }
+
+    fn measure(&mut self) -> Result<Measurement, SensorError> {
+        self.write_register(REG_CTRL_HUM, CTRL_HUM)?;
+        self.write_register(REG_CTRL_MEAS, CTRL_MEAS_FORCED)?;
+        self.delay.delay_us(MEASURE_DELAY_US);
+
+        let mut status = [0u8];
+        for _ in 0..MAX_POLLS {
+            self.read_registers(REG_STATUS, &mut status)?;
+            if status[0] & STATUS_MEASURING == 0 {
+                break;
+            }
+            self.delay.delay_us(POLL_DELAY_US);
+        }
+
+        if status[0] & STATUS_MEASURING != 0 {
+            return Err(SensorError::Timeout);
+        }
+
+        let mut data = [0u8; 8];
+        self.read_registers(REG_DATA, &mut data)?;
+
+        let adc_p = (data[0] as u32) << 12 | (data[1] as u32) << 4 | (data[2] as u32) >> 4;
+        let adc_t = (data[3] as u32) << 12 | (data[4] as u32) << 4 | (data[5] as u32) >> 4;
+        let adc_h = u16::from_be_bytes([data[6], data[7]]);
+
+        // Skipped conversions read back as 0x80000, which only happens if the chip reset.
+        if adc_t == 0x80000 || adc_p == 0x80000 {
+            return Err(SensorError::InvalidData);
+        }
+
+        let (temperature, pressure, humidity) = self.calibration.compensate(adc_t, adc_p, adc_h);
+
+        Ok(Measurement {
+            temperature: Some(temperature),
+            humidity: Some(humidity),
+            pressure: Some(pressure / 100.0),
+            co2: None,
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        self.soft_reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use embedded_hal::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+
+    use super::*;
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _: u32) {}
+    }
+
+    /// The compensation example of the BMP280 datasheet, section 3.12, which the BME280
+    /// shares for temperature and pressure. Gives 25.08 °C and 100653.27 Pa.
+    const EXAMPLE: Calibration = Calibration {
+        t1: 27504,
+        t2: 26435,
+        t3: -1000,
+        p1: 36477,
+        p2: -10685,
+        p3: 3024,
+        p4: 2855,
+        p5: 140,
+        p6: -7,
+        p7: 15500,
+        p8: -14600,
+        p9: 6000,
+        // Humidity trimming read from a BME280 breakout.
+        h1: 75,
+        h2: 362,
+        h3: 0,
+        h4: 313,
+        h5: 50,
+        h6: 30,
+    };
+    const EXAMPLE_ADC_T: u32 = 519888;
+    const EXAMPLE_ADC_P: u32 = 415148;
+
+    /// Register dump of a BME280 with `calibration`, having just measured the raw values.
+    fn registers(calibration: &Calibration, adc_t: u32, adc_p: u32, adc_h: u16) -> [u8; 256] {
+        let c = calibration;
+        let mut registers = [0; 256];
+        registers[REG_CHIP_ID as usize] = CHIP_ID;
+
+        let words = [
+            c.t1,
+            c.t2 as u16,
+            c.t3 as u16,
+            c.p1,
+            c.p2 as u16,
+            c.p3 as u16,
+            c.p4 as u16,
+            c.p5 as u16,
+            c.p6 as u16,
+            c.p7 as u16,
+            c.p8 as u16,
+            c.p9 as u16,
+        ];
+        for (i, word) in words.iter().enumerate() {
+            let at = REG_CALIB_00 as usize + 2 * i;
+            registers[at..at + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        registers[0xa1] = c.h1;
+
+        let at = REG_CALIB_26 as usize;
+        registers[at..at + 2].copy_from_slice(&c.h2.to_le_bytes());
+        registers[at + 2] = c.h3;
+        registers[at + 3] = (c.h4 >> 4) as u8;
+        registers[at + 4] = (c.h4 & 0x0f) as u8 | ((c.h5 & 0x0f) << 4) as u8;
+        registers[at + 5] = (c.h5 >> 4) as u8;
+        registers[at + 6] = c.h6 as u8;
+
+        // 20-bit values, MSB first with the low nibble in the top of the third byte.
+        let at = REG_DATA as usize;
+        for (i, adc) in [adc_p, adc_t].into_iter().enumerate() {
+            registers[at + 3 * i] = (adc >> 12) as u8;
+            registers[at + 3 * i + 1] = (adc >> 4) as u8;
+            registers[at + 3 * i + 2] = (adc << 4) as u8;
+        }
+        registers[at + 6..at + 8].copy_from_slice(&adc_h.to_be_bytes());
+
+        registers
+    }
+
+    /// A BME280 serving a register dump, with the auto-incrementing register pointer of
+    /// the real chip.
+    struct MockBme280 {
+        registers: [u8; 256],
+        /// Every register write, in order.
+        writes: Vec<(u8, u8)>,
+        present: bool,
+    }
+
+    impl MockBme280 {
+        fn new(registers: [u8; 256]) -> Self {
+            Self {
+                registers,
+                writes: Vec::new(),
+                present: true,
+            }
+        }
+    }
+
+    impl ErrorType for MockBme280 {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockBme280 {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), ErrorKind> {
+            if !self.present || address != ADDRESS {
+                return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+            }
+
+            let mut pointer = 0;
+            for operation in operations {
+                match operation {
+                    Operation::Write([register]) => pointer = *register as usize,
+                    Operation::Write([register, value]) => self.writes.push((*register, *value)),
+                    Operation::Write(_) => return Err(ErrorKind::Other),
+                    Operation::Read(buf) => {
+                        buf.copy_from_slice(&self.registers[pointer..pointer + buf.len()]);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn bme280(registers: [u8; 256]) -> Bme280<MockBme280, NoDelay> {
+        Bme280::new(MockBme280::new(registers), NoDelay).unwrap()
+    }
+
+    /// Humidity in %RH by the 32-bit integer formula of the BME280 datasheet, section
+    /// 4.2.3, to check the floating point one against.
+    fn reference_humidity(c: &Calibration, adc_t: i32, adc_h: i32) -> f64 {
+        let var1 = (((adc_t >> 3) - ((c.t1 as i32) << 1)) * c.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - c.t1 as i32) * ((adc_t >> 4) - c.t1 as i32)) >> 12)
+            * c.t3 as i32)
+            >> 14;
+        let t_fine = var1 + var2;
+
+        let mut v = t_fine - 76800;
+        v = ((((adc_h << 14) - ((c.h4 as i32) << 20) - (c.h5 as i32 * v)) + 16384) >> 15)
+            * (((((((v * c.h6 as i32) >> 10) * (((v * c.h3 as i32) >> 11) + 32768)) >> 10)
+                + 2097152)
+                * c.h2 as i32
+                + 8192)
+                >> 14);
+        v -= ((((v >> 15) * (v >> 15)) >> 7) * c.h1 as i32) >> 4;
+        v = v.clamp(0, 419430400);
+        (v >> 12) as f64 / 1024.0
+    }
+
+    #[test]
+    fn calibration_from_registers() {
+        let registers = registers(&EXAMPLE, 0, 0, 0);
+        let block0 = registers[0x88..0xa2].try_into().unwrap();
+        let block1 = registers[0xe1..0xe8].try_into().unwrap();
+        assert_eq!(Calibration::parse(block0, block1), EXAMPLE);
+
+        // H4 and H5 share 0xe5, and are sign extended from 12 bits.
+        let calibration = Calibration::parse(&[0; 26], &[0, 0, 0, 0x14, 0x3a, 0xff, 0xe2]);
+        assert_eq!(calibration.h4, 0x14a);
+        assert_eq!(calibration.h5, -13);
+        assert_eq!(calibration.h6, -30);
+    }
+
+    #[test]
+    fn datasheet_example() {
+        let (temperature, pressure, _) = EXAMPLE.compensate(EXAMPLE_ADC_T, EXAMPLE_ADC_P, 0);
+        assert!((temperature - 25.08).abs() < 0.005, "{temperature}");
+        assert!((pressure - 100653.27).abs() < 0.01, "{pressure}");
+    }
+
+    #[test]
+    fn humidity_matches_the_integer_formula() {
+        for adc_h in [0x5000, 0x6a5b, 0x7800, 0x8800] {
+            let (_, _, humidity) = EXAMPLE.compensate(EXAMPLE_ADC_T, EXAMPLE_ADC_P, adc_h);
+            let reference = reference_humidity(&EXAMPLE, EXAMPLE_ADC_T as i32, adc_h as i32);
+            assert!(humidity > 0.0 && humidity < 100.0, "{humidity}");
+            assert!(
+                (humidity - reference).abs() < 0.05,
+                "{humidity} vs {reference}"
+            );
+        }
+
+        // Clamped to 0..100 %RH at the ends of the ADC range.
+        let (_, _, humidity) = EXAMPLE.compensate(EXAMPLE_ADC_T, EXAMPLE_ADC_P, 0);
+        assert_eq!(humidity, 0.0);
+        let (_, _, humidity) = EXAMPLE.compensate(EXAMPLE_ADC_T, EXAMPLE_ADC_P, 0xffff);
+        assert_eq!(humidity, 100.0);
+    }
+
+    #[test]
+    fn unprogrammed_calibration() {
+        let (_, pressure, _) = Calibration::default().compensate(EXAMPLE_ADC_T, EXAMPLE_ADC_P, 0);
+        assert_eq!(pressure, 0.0);
+    }
+
+    #[test]
+    fn measure() {
+        let mut sensor = bme280(registers(&EXAMPLE, EXAMPLE_ADC_T, EXAMPLE_ADC_P, 0x6a5b));
+        assert_eq!(sensor.calibration, EXAMPLE);
+
+        let measurement = sensor.measure().unwrap();
+        assert!((measurement.temperature.unwrap() - 25.08).abs() < 0.005);
+        // In hPa.
+        assert!((measurement.pressure.unwrap() - 1006.5327).abs() < 1e-4);
+        let reference = reference_humidity(&EXAMPLE, EXAMPLE_ADC_T as i32, 0x6a5b);
+        assert!((measurement.humidity.unwrap() - reference).abs() < 0.05);
+        assert_eq!(measurement.co2, None);
+
+        // Reset on creation, then humidity before ctrl_meas so it takes effect.
+        assert_eq!(
+            sensor.i2c.writes,
+            [
+                (REG_RESET, RESET_WORD),
+                (REG_CTRL_HUM, CTRL_HUM),
+                (REG_CTRL_MEAS, CTRL_MEAS_FORCED)
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_chip() {
+        // A BMP280, which has no humidity sensor.
+        let mut registers = registers(&EXAMPLE, EXAMPLE_ADC_T, EXAMPLE_ADC_P, 0);
+        registers[REG_CHIP_ID as usize] = 0x58;
+        assert!(matches!(
+            Bme280::new(MockBme280::new(registers), NoDelay),
+            Err(SensorError::InvalidData)
+        ));
+
+        let mut mock = MockBme280::new(registers);
+        mock.present = false;
+        assert!(matches!(Bme280::new(mock, NoDelay), Err(SensorError::Bus)));
+    }
+
+    #[test]
+    fn conversion_never_finishes() {
+        let mut sensor = bme280(registers(&EXAMPLE, EXAMPLE_ADC_T, EXAMPLE_ADC_P, 0));
+        sensor.i2c.registers[REG_STATUS as usize] = STATUS_MEASURING;
+        assert_eq!(sensor.measure(), Err(SensorError::Timeout));
+    }
+
+    #[test]
+    fn skipped_conversion() {
+        let mut sensor = bme280(registers(&EXAMPLE, 0x80000, EXAMPLE_ADC_P, 0));
+        assert_eq!(sensor.measure(), Err(SensorError::InvalidData));
+
+        let mut sensor = bme280(registers(&EXAMPLE, EXAMPLE_ADC_T, 0x80000, 0));
+        assert_eq!(sensor.measure(), Err(SensorError::InvalidData));
+    }
+
+    #[test]
+    fn reset_reads_the_calibration_again() {
+        let mut sensor = bme280(registers(&Calibration::default(), 0, 0, 0));
+        assert_eq!(sensor.calibration, Calibration::default());
+
+        sensor.i2c.registers = registers(&EXAMPLE, 0, 0, 0);
+        sensor.reset().unwrap();
+        assert_eq!(sensor.calibration, EXAMPLE);
+    }
+}
@@ -0,0 +1,278 @@
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use serde::Serialize;
+
+use crate::drivers::sht3x::Sht3xReading;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SensorError {
+    Bus,
+    Timeout,
+    /// Bad CRC, a value out of range or an unexpected chip id.
+    InvalidData,
+    /// A sensor measuring on its own has no new result yet. Not a fault, try again later.
+    NotReady,
+}
+
+impl SensorError {
+    /// Short name used as a metrics label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            SensorError::Bus => "bus",
+            SensorError::Timeout => "timeout",
+            SensorError::InvalidData => "invalid_data",
+            SensorError::NotReady => "not_ready",
+        }
+    }
+}
+
+/// Chips of one kind can be strapped to one of two addresses, so a bus holds at most two.
+pub const MAX_SENSORS: usize = 2;
+
+/// Names the sensors are reported under, by [`SensorId`], until the configuration sets
+/// others.
+pub const DEFAULT_SENSOR_NAMES: [&str; MAX_SENSORS] = ["indoor", "duct"];
+
+static SENSOR_NAMES: Mutex<CriticalSectionRawMutex, Cell<[&'static str; MAX_SENSORS]>> =
+    Mutex::new(Cell::new(DEFAULT_SENSOR_NAMES));
+
+/// Reports the sensors under `names` from now on, by [`SensorId`]. Set from
+/// `NodeConfig::sensor_names` at boot.
+pub fn set_sensor_names(names: [&'static str; MAX_SENSORS]) {
+    SENSOR_NAMES.lock(|current| current.set(names));
+}
+
+/// The names the sensors are currently reported under, by [`SensorId`].
+pub fn sensor_names() -> [&'static str; MAX_SENSORS] {
+    SENSOR_NAMES.lock(Cell::get)
+}
+
+/// Which sensor a measurement came from. Sensor 0 is the chip at its default address,
+/// sensor 1 the one strapped to the alternate address, so the id of a probe doesn't change
+/// when the other one goes missing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct SensorId(u8);
+
+impl SensorId {
+    /// `None` for indexes past [`MAX_SENSORS`].
+    pub const fn new(index: usize) -> Option<Self> {
+        if index < MAX_SENSORS {
+            Some(Self(index as u8))
+        } else {
+            None
+        }
+    }
+
+    /// Sensor `index`, which must be below [`MAX_SENSORS`].
+    #[cfg(test)]
+    pub const fn at(index: usize) -> Self {
+        Self::new(index).unwrap()
+    }
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// What the sensor is reported under, see [`set_sensor_names`].
+    pub fn name(self) -> &'static str {
+        sensor_names()[self.index()]
+    }
+
+    pub fn all() -> impl Iterator<Item = SensorId> {
+        (0..MAX_SENSORS as u8).map(SensorId)
+    }
+}
+
+/// One measurement. Quantities the chip doesn't measure are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Format, Serialize)]
+pub struct Measurement {
+    /// Degrees Celsius.
+    pub temperature: Option<f64>,
+    /// Relative humidity in percent.
+    pub humidity: Option<f64>,
+    /// Hectopascal.
+    pub pressure: Option<f64>,
+    /// Parts per million.
+    pub co2: Option<u16>,
+}
+
+impl Measurement {
+    /// Temperature and humidity, which the filter, the alerts and the statistics work on, if
+    /// the chip measured both.
+    pub fn reading(&self) -> Option<Sht3xReading> {
+        Some(Sht3xReading {
+            temperature: self.temperature?,
+            humidity: self.humidity?,
+        })
+    }
+}
+
+pub trait EnvironmentSensor {
+    /// Chip name, for logs.
+    fn name(&self) -> &'static str;
+
+    fn measure(&mut self) -> Result<Measurement, SensorError>;
+
+    /// Resets the chip and sets it up again as it was configured.
+    fn reset(&mut self) -> Result<(), SensorError>;
+}
+
+/// Ways of bringing back a sensor that keeps failing, least disruptive first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RecoveryStep {
+    /// Soft reset of the sensor alone.
+    ResetSensor,
+    /// General call reset, which resets every chip on the bus that takes it.
+    ResetBus,
+    /// Clocks a bus a chip holds low free and restarts the I2C controller.
+    RecoverBus,
+}
+
+impl RecoveryStep {
+    const LADDER: [RecoveryStep; 3] = [
+        RecoveryStep::ResetSensor,
+        RecoveryStep::ResetBus,
+        RecoveryStep::RecoverBus,
+    ];
+
+    /// Short name used as a metrics label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            RecoveryStep::ResetSensor => "sensor_reset",
+            RecoveryStep::ResetBus => "general_call_reset",
+            RecoveryStep::RecoverBus => "bus_recovery",
+        }
+    }
+}
+
+/// Counts consecutive failed reads of one sensor and escalates through the
+/// [`RecoveryStep`]s while they go on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultTracker {
+    errors_per_step: u32,
+    errors: u32,
+}
+
+impl FaultTracker {
+    pub const fn new(errors_per_step: u32) -> Self {
+        Self {
+            errors_per_step: if errors_per_step == 0 {
+                1
+            } else {
+                errors_per_step
+            },
+            errors: 0,
+        }
+    }
+
+    /// Records a failed read. Every `errors_per_step` failures in a row returns the next
+    /// step to take, starting over at the bottom after the last one.
+    pub fn failed(&mut self) -> Option<RecoveryStep> {
+        self.errors = self.errors.saturating_add(1);
+        if !self.errors.is_multiple_of(self.errors_per_step) {
+            return None;
+        }
+
+        let step = (self.errors / self.errors_per_step - 1) as usize;
+        Some(RecoveryStep::LADDER[step % RecoveryStep::LADDER.len()])
+    }
+
+    /// Records a good read. Returns whether the sensor was faulted until now.
+    pub fn succeeded(&mut self) -> bool {
+        let faulted = self.is_faulted();
+        self.errors = 0;
+        faulted
+    }
+
+    /// From the first recovery step until the next good read.
+    pub fn is_faulted(&self) -> bool {
+        self.errors >= self.errors_per_step
+    }
+}
+
+/// CRC-8 used by Sensirion chips: polynomial 0x31, initial value 0xff.
+pub(crate) fn sensirion_crc(data: &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalation() {
+        let mut fault = FaultTracker::new(2);
+        let steps: std::vec::Vec<_> = (0..8).map(|_| fault.failed()).collect();
+
+        assert_eq!(
+            steps,
+            [
+                None,
+                Some(RecoveryStep::ResetSensor),
+                None,
+                Some(RecoveryStep::ResetBus),
+                None,
+                Some(RecoveryStep::RecoverBus),
+                None,
+                Some(RecoveryStep::ResetSensor),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_errors_per_step() {
+        let mut fault = FaultTracker::new(0);
+
+        assert_eq!(fault.failed(), Some(RecoveryStep::ResetSensor));
+        assert_eq!(fault.failed(), Some(RecoveryStep::ResetBus));
+        assert!(fault.is_faulted());
+    }
+
+    #[test]
+    fn faulted_from_first_step() {
+        let mut fault = FaultTracker::new(3);
+        assert!(!fault.is_faulted());
+
+        fault.failed();
+        fault.failed();
+        assert!(!fault.is_faulted());
+
+        fault.failed();
+        assert!(fault.is_faulted());
+        fault.failed();
+        assert!(fault.is_faulted());
+    }
+
+    #[test]
+    fn success_resets() {
+        let mut fault = FaultTracker::new(2);
+
+        fault.failed();
+        assert!(!fault.succeeded());
+
+        fault.failed();
+        fault.failed();
+        assert!(fault.succeeded());
+        assert!(!fault.is_faulted());
+
+        // Counting starts over rather than carrying on up the ladder.
+        assert_eq!(fault.failed(), None);
+        assert_eq!(fault.failed(), Some(RecoveryStep::ResetSensor));
+    }
+}
@@ -0,0 +1,175 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{Format, info, warn};
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{self, Error as _, ErrorKind, ErrorType, Operation};
+use esp_hal::Blocking;
+use esp_hal::delay::Delay;
+use esp_hal::gpio::{AnyPin, DriveMode, Flex, OutputConfig, Pull};
+use esp_hal::i2c::master::{AnyI2c, Config, ConfigError, Error as I2cError, I2c};
+
+/// A slave holding SDA low lets go within the rest of its byte and the acknowledge bit.
+const RECOVERY_CLOCKS: usize = 9;
+/// Half a period of the recovery clock, 100 kHz is the rate every chip takes.
+const HALF_PERIOD_US: u32 = 5;
+
+/// Set by [`request_recovery`], handled by the next transaction on the bus.
+static RECOVERY_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BusError {
+    I2c(I2cError),
+    /// The controller failed to start again after a recovery.
+    Unavailable,
+}
+
+impl i2c::Error for BusError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            BusError::I2c(err) => err.kind(),
+            BusError::Unavailable => ErrorKind::Other,
+        }
+    }
+}
+
+/// Has the bus clocked free and the controller restarted before its next transaction. The
+/// bus is shared, so whoever finds it stuck can only ask.
+pub fn request_recovery() {
+    RECOVERY_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// The I2C controller, keeping hold of its pins so it can let go of them to recover a bus a
+/// slave holds low, and start again.
+pub struct RecoverableI2c {
+    /// `None` while recovering, or if the controller failed to start again.
+    i2c: Option<I2c<'static, Blocking>>,
+    peripheral: AnyI2c<'static>,
+    sda: AnyPin<'static>,
+    scl: AnyPin<'static>,
+    config: Config,
+}
+
+impl RecoverableI2c {
+    pub fn new(
+        peripheral: impl Into<AnyI2c<'static>>,
+        sda: impl Into<AnyPin<'static>>,
+        scl: impl Into<AnyPin<'static>>,
+        config: Config,
+    ) -> Result<Self, ConfigError> {
+        let mut bus = Self {
+            i2c: None,
+            peripheral: peripheral.into(),
+            sda: sda.into(),
+            scl: scl.into(),
+            config,
+        };
+        bus.i2c = Some(bus.connect()?);
+
+        Ok(bus)
+    }
+
+    /// Clocks SCL until the slave holding SDA low lets go, sends a STOP and restarts the
+    /// controller. Slaves lose the transfer they were in the middle of.
+    pub fn recover(&mut self) {
+        // Drops the driver, the pins are ours again.
+        self.i2c = None;
+
+        // SAFETY: nothing else uses the pins while the driver is gone, and `Flex` lets go
+        // of them at the end of the block.
+        let released = unsafe {
+            release_bus(
+                Flex::new(self.sda.clone_unchecked()),
+                Flex::new(self.scl.clone_unchecked()),
+            )
+        };
+
+        if !released {
+            warn!("i2c: SDA still held low after recovery");
+        }
+
+        match self.connect() {
+            Ok(i2c) => {
+                info!("i2c: bus recovered");
+                self.i2c = Some(i2c);
+            }
+            Err(e) => warn!("i2c: failed to restart controller: {:?}", e),
+        }
+    }
+
+    fn connect(&self) -> Result<I2c<'static, Blocking>, ConfigError> {
+        // SAFETY: the driver is the only user of the peripheral and the pins until it is
+        // dropped again in `recover`.
+        let (peripheral, sda, scl) = unsafe {
+            (
+                self.peripheral.clone_unchecked(),
+                self.sda.clone_unchecked(),
+                self.scl.clone_unchecked(),
+            )
+        };
+
+        Ok(I2c::new(peripheral, self.config)?
+            .with_sda(sda)
+            .with_scl(scl))
+    }
+
+    fn bus(&mut self) -> Result<&mut I2c<'static, Blocking>, BusError> {
+        if RECOVERY_REQUESTED.swap(false, Ordering::Relaxed) {
+            self.recover();
+        }
+
+        self.i2c.as_mut().ok_or(BusError::Unavailable)
+    }
+}
+
+/// Drives both lines open drain by hand. Returns whether SDA is free afterwards.
+fn release_bus(mut sda: Flex<'_>, mut scl: Flex<'_>) -> bool {
+    let open_drain = OutputConfig::default()
+        .with_drive_mode(DriveMode::OpenDrain)
+        .with_pull(Pull::Up);
+    let mut delay = Delay::new();
+
+    for pin in [&mut sda, &mut scl] {
+        pin.apply_output_config(&open_drain);
+        pin.set_high();
+        pin.set_input_enable(true);
+        pin.set_output_enable(true);
+    }
+    delay.delay_us(HALF_PERIOD_US);
+
+    for _ in 0..RECOVERY_CLOCKS {
+        if sda.is_high() {
+            break;
+        }
+        scl.set_low();
+        delay.delay_us(HALF_PERIOD_US);
+        scl.set_high();
+        delay.delay_us(HALF_PERIOD_US);
+    }
+
+    // STOP: SDA rises while SCL is high.
+    scl.set_low();
+    delay.delay_us(HALF_PERIOD_US);
+    sda.set_low();
+    delay.delay_us(HALF_PERIOD_US);
+    scl.set_high();
+    delay.delay_us(HALF_PERIOD_US);
+    sda.set_high();
+    delay.delay_us(HALF_PERIOD_US);
+
+    sda.is_high()
+}
+
+impl ErrorType for RecoverableI2c {
+    type Error = BusError;
+}
+
+impl i2c::I2c for RecoverableI2c {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        // The driver's inherent `transaction` takes its own operation type.
+        i2c::I2c::transaction(self.bus()?, address, operations).map_err(BusError::I2c)
+    }
+}
@@ -1,2 +1,7 @@
+pub mod bme280;
+pub mod environment;
+pub mod i2c_bus;
+pub mod scan;
 pub mod sht3x;
+pub mod sht4x;
 pub mod ssd1306;
@@ -0,0 +1,217 @@
+use embedded_hal::i2c::I2c;
+
+/// A chip the firmware has a driver for, and the addresses it can be strapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chip {
+    pub name: &'static str,
+    pub addresses: &'static [u8],
+}
+
+pub const SSD1306: Chip = Chip {
+    name: "SSD1306",
+    addresses: &[0x3c, 0x3d],
+};
+
+pub const SHT3X: Chip = Chip {
+    name: "SHT3x",
+    addresses: &[0x44, 0x45],
+};
+
+pub const SHT4X: Chip = Chip {
+    name: "SHT4x",
+    addresses: &[0x44, 0x45, 0x46],
+};
+
+pub const BME280: Chip = Chip {
+    name: "BME280",
+    addresses: &[0x76, 0x77],
+};
+
+pub const KNOWN_CHIPS: [Chip; 4] = [SSD1306, SHT3X, SHT4X, BME280];
+
+/// Address every chip listens to, the first byte says what for.
+const GENERAL_CALL: u8 = 0x00;
+const GENERAL_CALL_RESET: u8 = 0x06;
+
+/// Addresses that acknowledged during a scan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scan {
+    /// Bit `n` is set if a device answered at address `n`.
+    present: u128,
+}
+
+impl Scan {
+    /// Probes every address of `chips`, each one once even if several chips share it.
+    pub fn run<I: I2c>(i2c: &mut I, chips: &[Chip]) -> Self {
+        let mut scan = Self::default();
+        let mut probed = 0u128;
+
+        for &address in chips.iter().flat_map(|chip| chip.addresses) {
+            let bit = 1u128 << (address & 0x7f);
+            if probed & bit != 0 {
+                continue;
+            }
+            probed |= bit;
+
+            if probe(i2c, address) {
+                scan.present |= bit;
+            }
+        }
+
+        scan
+    }
+
+    pub fn contains(&self, address: u8) -> bool {
+        address < 0x80 && self.present & (1 << address) != 0
+    }
+
+    /// First address of `chip` that answered. Chips sharing an address can't be told apart
+    /// by a scan, so this only says something answered where `chip` could be.
+    pub fn find(&self, chip: &Chip) -> Option<u8> {
+        chip.addresses
+            .iter()
+            .copied()
+            .find(|&address| self.contains(address))
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..0x80).filter(|&address| self.contains(address))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.present == 0
+    }
+}
+
+/// Addresses the device without sending data. Everything on the bus acknowledges its own
+/// address even when it has no measurement ready, unlike a read.
+pub fn probe<I: I2c>(i2c: &mut I, address: u8) -> bool {
+    i2c.write(address, &[]).is_ok()
+}
+
+/// Asks every chip on the bus that supports it to reset, the same as a power cycle.
+pub fn general_call_reset<I: I2c>(i2c: &mut I) -> Result<(), I::Error> {
+    i2c.write(GENERAL_CALL, &[GENERAL_CALL_RESET])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use embedded_hal::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+
+    use super::*;
+
+    /// A bus with devices acknowledging at `devices`, recording every transaction.
+    #[derive(Default)]
+    struct MockBus {
+        devices: Vec<u8>,
+        transactions: Vec<(u8, Vec<u8>)>,
+    }
+
+    impl MockBus {
+        fn with_devices(devices: &[u8]) -> Self {
+            Self {
+                devices: devices.to_vec(),
+                ..Self::default()
+            }
+        }
+
+        fn addresses(&self) -> Vec<u8> {
+            self.transactions
+                .iter()
+                .map(|&(address, _)| address)
+                .collect()
+        }
+    }
+
+    impl ErrorType for MockBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockBus {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), ErrorKind> {
+            let mut written = Vec::new();
+            for operation in operations.iter() {
+                if let Operation::Write(bytes) = operation {
+                    written.extend_from_slice(bytes);
+                }
+            }
+            self.transactions.push((address, written));
+
+            if address == GENERAL_CALL || self.devices.contains(&address) {
+                Ok(())
+            } else {
+                Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))
+            }
+        }
+    }
+
+    #[test]
+    fn finds_devices() {
+        let mut bus = MockBus::with_devices(&[0x3d, 0x45, 0x50]);
+        let found = Scan::run(&mut bus, &KNOWN_CHIPS);
+
+        assert_eq!(found.find(&SSD1306), Some(0x3d));
+        assert_eq!(found.find(&SHT3X), Some(0x45));
+        // Shares its addresses with the SHT3x.
+        assert_eq!(found.find(&SHT4X), Some(0x45));
+        assert_eq!(found.find(&BME280), None);
+
+        // Only addresses of known chips are probed.
+        assert!(!found.contains(0x50));
+        assert_eq!(found.addresses().collect::<Vec<_>>(), [0x3d, 0x45]);
+        assert!(!found.is_empty());
+    }
+
+    #[test]
+    fn probes_each_address_once_without_data() {
+        let mut bus = MockBus::with_devices(&[0x44]);
+        Scan::run(&mut bus, &KNOWN_CHIPS);
+
+        assert_eq!(bus.addresses(), [0x3c, 0x3d, 0x44, 0x45, 0x46, 0x76, 0x77]);
+        assert!(
+            bus.transactions
+                .iter()
+                .all(|(_, written)| written.is_empty())
+        );
+    }
+
+    #[test]
+    fn first_address_wins() {
+        let mut bus = MockBus::with_devices(&[0x76, 0x77]);
+        let found = Scan::run(&mut bus, &[BME280]);
+        assert_eq!(found.find(&BME280), Some(0x76));
+        assert!(found.contains(0x77));
+    }
+
+    #[test]
+    fn empty_bus() {
+        let mut bus = MockBus::default();
+        let found = Scan::run(&mut bus, &KNOWN_CHIPS);
+
+        assert!(found.is_empty());
+        assert_eq!(found.find(&SSD1306), None);
+        assert_eq!(found.addresses().count(), 0);
+        // Out of the 7-bit range.
+        assert!(!found.contains(0xff));
+    }
+
+    #[test]
+    fn probe() {
+        let mut bus = MockBus::with_devices(&[0x44]);
+        assert!(super::probe(&mut bus, 0x44));
+        assert!(!super::probe(&mut bus, 0x45));
+    }
+
+    #[test]
+    fn general_call() {
+        let mut bus = MockBus::default();
+        general_call_reset(&mut bus).unwrap();
+        assert_eq!(bus.transactions, [(0x00, [0x06].to_vec())]);
+    }
+}
@@ -1,28 +1,279 @@
 use defmt::Format;
 use embedded_hal::delay::DelayNs;
-use embedded_hal::i2c::I2c;
-use sensirion_rht::{Addr, Device, Repeatability, kind};
+use embedded_hal::i2c::{Error as _, ErrorKind, I2c};
 use serde::Serialize;
 
-#[derive(Debug, Format)]
+use crate::drivers::environment::{EnvironmentSensor, Measurement, SensorError, sensirion_crc};
+
+/// ADDR pin tied low. Tied high the sensor answers at 0x45.
+pub const ADDRESS: u8 = 0x44;
+
+const FETCH_DATA: u16 = 0xe000;
+const ART: u16 = 0x2b32;
+const BREAK: u16 = 0x3093;
+/// The sensor takes up to 1 ms to go idle after a break.
+const BREAK_DELAY_US: u32 = 1_000;
+const HEATER_ENABLE: u16 = 0x306d;
+const HEATER_DISABLE: u16 = 0x3066;
+const READ_STATUS: u16 = 0xf32d;
+const CLEAR_STATUS: u16 = 0x3041;
+const READ_SERIAL_NUMBER: u16 = 0x3780;
+const SERIAL_NUMBER_DELAY_US: u32 = 1_000;
+const SOFT_RESET: u16 = 0x30a2;
+const SOFT_RESET_DELAY_US: u32 = 1_500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
 pub enum Sht3xError {
     Bus,
     Timeout,
     InvalidData,
+    /// No measurement finished since the last fetch.
+    NotReady,
+}
+
+impl From<Sht3xError> for SensorError {
+    fn from(err: Sht3xError) -> Self {
+        match err {
+            Sht3xError::Bus => SensorError::Bus,
+            Sht3xError::Timeout => SensorError::Timeout,
+            Sht3xError::InvalidData => SensorError::InvalidData,
+            Sht3xError::NotReady => SensorError::NotReady,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Format, Serialize)]
 pub struct Sht3xReading {
     pub temperature: f64,
     pub humidity: f64,
 }
 
+#[cfg(test)]
+impl Sht3xReading {
+    pub const fn new(temperature: f64, humidity: f64) -> Self {
+        Self {
+            temperature,
+            humidity,
+        }
+    }
+}
+
+/// The status register, see table 17 of the datasheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Status(pub u16);
+
+impl Status {
+    /// At least one of the alert conditions is active.
+    pub fn alert_pending(self) -> bool {
+        self.0 & (1 << 15) != 0
+    }
+
+    pub fn heater_on(self) -> bool {
+        self.0 & (1 << 13) != 0
+    }
+
+    pub fn humidity_alert(self) -> bool {
+        self.0 & (1 << 11) != 0
+    }
+
+    pub fn temperature_alert(self) -> bool {
+        self.0 & (1 << 10) != 0
+    }
+
+    /// Set by a power-on, soft or hard reset until the status is cleared.
+    pub fn reset_detected(self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    /// The last command was invalid or failed its checksum.
+    pub fn command_failed(self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// The checksum of the last write transfer was wrong.
+    pub fn write_crc_failed(self) -> bool {
+        self.0 & 1 != 0
+    }
+}
+
+/// When [`CondensationRecovery`] turns the heater on and for how long.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryConfig {
+    /// Humidity at or above which the sensor counts as saturated.
+    pub saturated_rh: f64,
+    /// How long humidity has to stay saturated before heating.
+    pub saturated_for_ms: u64,
+    pub pulse_ms: u64,
+    /// Readings stay off after the pulse until the sensor has cooled down.
+    pub cooldown_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum HeaterAction {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecoveryState {
+    Watching { saturated_since: Option<u64> },
+    Heating { until: u64 },
+    Cooling { until: u64 },
+}
+
+/// Pulses the heater when humidity stays saturated, to evaporate condensation that
+/// otherwise makes the sensor creep. Readings taken while heating or cooling down are
+/// off and should be discarded, see [`CondensationRecovery::is_recovering`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CondensationRecovery {
+    config: RecoveryConfig,
+    state: RecoveryState,
+}
+
+impl CondensationRecovery {
+    pub const fn new(config: RecoveryConfig) -> Self {
+        Self {
+            config,
+            state: RecoveryState::Watching {
+                saturated_since: None,
+            },
+        }
+    }
+
+    /// Feeds the humidity measured at `now_ms`, `None` if nothing was measured. Call it on
+    /// every poll, also while recovering, so the heater goes off on time. Returns what to
+    /// do with the heater, if anything.
+    pub fn update(&mut self, now_ms: u64, humidity: Option<f64>) -> Option<HeaterAction> {
+        let config = &self.config;
+
+        match self.state {
+            RecoveryState::Watching { saturated_since } => {
+                let since = match humidity {
+                    Some(rh) if rh >= config.saturated_rh => saturated_since.or(Some(now_ms)),
+                    Some(_) => None,
+                    None => saturated_since,
+                };
+
+                match since {
+                    Some(since) if now_ms - since >= config.saturated_for_ms => {
+                        self.state = RecoveryState::Heating {
+                            until: now_ms + config.pulse_ms,
+                        };
+                        Some(HeaterAction::On)
+                    }
+                    _ => {
+                        self.state = RecoveryState::Watching {
+                            saturated_since: since,
+                        };
+                        None
+                    }
+                }
+            }
+            RecoveryState::Heating { until } if now_ms >= until => {
+                self.state = RecoveryState::Cooling {
+                    until: now_ms + config.cooldown_ms,
+                };
+                Some(HeaterAction::Off)
+            }
+            RecoveryState::Cooling { until } if now_ms >= until => {
+                self.state = RecoveryState::Watching {
+                    saturated_since: None,
+                };
+                None
+            }
+            RecoveryState::Heating { .. } | RecoveryState::Cooling { .. } => None,
+        }
+    }
+
+    /// Heating, or cooling down after it. Readings are skewed meanwhile.
+    pub fn is_recovering(&self) -> bool {
+        !matches!(self.state, RecoveryState::Watching { .. })
+    }
+}
+
+/// Higher repeatability lowers the noise at the cost of a longer conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Repeatability {
+    High,
+    Medium,
+    Low,
+}
+
+impl Repeatability {
+    /// Longest conversion time, from table 4 of the datasheet.
+    fn max_duration_us(self) -> u32 {
+        match self {
+            Repeatability::High => 15_500,
+            Repeatability::Medium => 6_500,
+            Repeatability::Low => 4_500,
+        }
+    }
+}
+
+/// Measurements per second in periodic mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Rate {
+    Half,
+    One,
+    Two,
+    Four,
+    Ten,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Mode {
+    /// Every read starts a conversion and waits for it.
+    SingleShot(Repeatability),
+    /// The sensor measures on its own, reads fetch the latest result.
+    Periodic(Repeatability, Rate),
+    /// Accelerated response time: periodic at 4 measurements per second, with faster
+    /// settling after a step change.
+    Art,
+}
+
+impl Mode {
+    fn is_periodic(self) -> bool {
+        !matches!(self, Mode::SingleShot(_))
+    }
+
+    /// The command starting this mode, see table 9 and 10 of the datasheet.
+    fn command(self) -> u16 {
+        match self {
+            Mode::SingleShot(Repeatability::High) => 0x2400,
+            Mode::SingleShot(Repeatability::Medium) => 0x240b,
+            Mode::SingleShot(Repeatability::Low) => 0x2416,
+            Mode::Periodic(Repeatability::High, Rate::Half) => 0x2032,
+            Mode::Periodic(Repeatability::Medium, Rate::Half) => 0x2024,
+            Mode::Periodic(Repeatability::Low, Rate::Half) => 0x202f,
+            Mode::Periodic(Repeatability::High, Rate::One) => 0x2130,
+            Mode::Periodic(Repeatability::Medium, Rate::One) => 0x2126,
+            Mode::Periodic(Repeatability::Low, Rate::One) => 0x212d,
+            Mode::Periodic(Repeatability::High, Rate::Two) => 0x2236,
+            Mode::Periodic(Repeatability::Medium, Rate::Two) => 0x2220,
+            Mode::Periodic(Repeatability::Low, Rate::Two) => 0x222b,
+            Mode::Periodic(Repeatability::High, Rate::Four) => 0x2334,
+            Mode::Periodic(Repeatability::Medium, Rate::Four) => 0x2322,
+            Mode::Periodic(Repeatability::Low, Rate::Four) => 0x2329,
+            Mode::Periodic(Repeatability::High, Rate::Ten) => 0x2737,
+            Mode::Periodic(Repeatability::Medium, Rate::Ten) => 0x2721,
+            Mode::Periodic(Repeatability::Low, Rate::Ten) => 0x272a,
+            Mode::Art => ART,
+        }
+    }
+}
+
 pub struct Sht3x<I2C, Delay>
 where
     I2C: I2c,
     Delay: DelayNs,
 {
-    inner: Device<I2C, Delay, kind::SHT3x>,
+    i2c: I2C,
+    delay: Delay,
+    address: u8,
+    mode: Mode,
+    /// Periodic acquisition may be running. Starts out set because the sensor keeps
+    /// measuring across a reset of the MCU alone, and ignores most commands until stopped.
+    acquiring: bool,
 }
 
 impl<I2C, Delay> Sht3x<I2C, Delay>
@@ -31,21 +282,507 @@ where
     Delay: DelayNs,
 {
     pub fn new(i2c: I2C, delay: Delay) -> Self {
-        let device = Device::new_sht3x(Addr::A, i2c, delay);
-        Self { inner: device }
+        Self::with_address(i2c, delay, ADDRESS)
+    }
+
+    /// Starts out in single shot mode at high repeatability, see [`Sht3x::set_mode`].
+    pub fn with_address(i2c: I2C, delay: Delay, address: u8) -> Self {
+        Self {
+            i2c,
+            delay,
+            address,
+            mode: Mode::SingleShot(Repeatability::High),
+            acquiring: true,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switches the acquisition mode, stopping the running one first. Periodic modes start
+    /// measuring right away.
+    pub fn set_mode(&mut self, mode: Mode) -> Result<(), Sht3xError> {
+        if self.acquiring {
+            self.stop()?;
+        }
+
+        if mode.is_periodic() {
+            self.command(mode.command())?;
+            self.acquiring = true;
+        }
+
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Ends periodic acquisition, the sensor goes back to idle.
+    pub fn stop(&mut self) -> Result<(), Sht3xError> {
+        self.command(BREAK)?;
+        self.delay.delay_us(BREAK_DELAY_US);
+        self.acquiring = false;
+        Ok(())
     }
 
+    /// Resets the sensor to its power-up state, idle with the heater off, and starts the
+    /// current mode again. A wedged sensor may not take the break first, the reset goes out
+    /// regardless.
+    pub fn soft_reset(&mut self) -> Result<(), Sht3xError> {
+        if self.acquiring {
+            let _ = self.stop();
+        }
+
+        self.command(SOFT_RESET)?;
+        self.delay.delay_us(SOFT_RESET_DELAY_US);
+        self.acquiring = false;
+
+        self.set_mode(self.mode)
+    }
+
+    /// In single shot mode, measures and blocks until the conversion is done. In the
+    /// periodic modes, returns the latest result without waiting, or
+    /// [`Sht3xError::NotReady`] if none finished since the last read.
     pub fn read(&mut self) -> Result<Sht3xReading, Sht3xError> {
-        self.inner
-            .single_shot(Repeatability::High)
-            .map(|(t, h)| Sht3xReading {
-                temperature: t.as_celsius(),
-                humidity: h.as_percent(),
-            })
-            .map_err(|err| match err {
-                sensirion_rht::Error::I2C(_) => Sht3xError::Bus,
-                sensirion_rht::Error::Timeout => Sht3xError::Timeout,
-                sensirion_rht::Error::CRC => Sht3xError::InvalidData,
+        let mut frame = [0u8; 6];
+
+        match self.mode {
+            Mode::SingleShot(repeatability) => {
+                if self.acquiring {
+                    self.stop()?;
+                }
+
+                self.command(self.mode.command())?;
+                self.delay.delay_us(repeatability.max_duration_us());
+                self.read_frame(&mut frame, Sht3xError::Timeout)?;
+            }
+            Mode::Periodic(..) | Mode::Art => {
+                self.command(FETCH_DATA)?;
+                self.read_frame(&mut frame, Sht3xError::NotReady)?;
+            }
+        }
+
+        decode(&frame)
+    }
+
+    /// Switches the on-chip heater. It warms the sensor by a few degrees, enough to dry off
+    /// condensation, and skews readings while on.
+    pub fn set_heater(&mut self, on: bool) -> Result<(), Sht3xError> {
+        let command = if on { HEATER_ENABLE } else { HEATER_DISABLE };
+        self.while_idle(|sensor| sensor.command(command))
+    }
+
+    pub fn status(&mut self) -> Result<Status, Sht3xError> {
+        self.while_idle(|sensor| {
+            sensor.command(READ_STATUS)?;
+            let mut frame = [0u8; 3];
+            sensor.read_frame(&mut frame, Sht3xError::Bus)?;
+            let [status] = decode_words(&frame)?;
+            Ok(Status(status))
+        })
+    }
+
+    /// Clears the alert and reset flags of the status register.
+    pub fn clear_status(&mut self) -> Result<(), Sht3xError> {
+        self.while_idle(|sensor| sensor.command(CLEAR_STATUS))
+    }
+
+    /// The 32-bit serial number, unique per chip.
+    pub fn serial_number(&mut self) -> Result<u32, Sht3xError> {
+        self.while_idle(|sensor| {
+            sensor.command(READ_SERIAL_NUMBER)?;
+            sensor.delay.delay_us(SERIAL_NUMBER_DELAY_US);
+            let mut frame = [0u8; 6];
+            sensor.read_frame(&mut frame, Sht3xError::Bus)?;
+            let [high, low] = decode_words(&frame)?;
+            Ok((high as u32) << 16 | low as u32)
+        })
+    }
+
+    /// Runs `f` with periodic acquisition stopped, the sensor only takes fetch and break
+    /// commands while measuring. Acquisition resumes afterwards.
+    fn while_idle<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, Sht3xError>,
+    ) -> Result<T, Sht3xError> {
+        if self.acquiring {
+            self.stop()?;
+        }
+
+        let result = f(self);
+
+        if self.mode.is_periodic() {
+            self.command(self.mode.command())?;
+            self.acquiring = true;
+        }
+
+        result
+    }
+
+    fn command(&mut self, command: u16) -> Result<(), Sht3xError> {
+        self.i2c
+            .write(self.address, &command.to_be_bytes())
+            .map_err(|_| Sht3xError::Bus)
+    }
+
+    /// The sensor doesn't acknowledge a read while it has no result, that maps to `empty`.
+    fn read_frame(&mut self, frame: &mut [u8], empty: Sht3xError) -> Result<(), Sht3xError> {
+        self.i2c
+            .read(self.address, frame)
+            .map_err(|err| match err.kind() {
+                ErrorKind::NoAcknowledge(_) => empty,
+                _ => Sht3xError::Bus,
             })
     }
 }
+
+/// Splits a frame into its words, each followed by its CRC.
+fn decode_words<const N: usize>(frame: &[u8]) -> Result<[u16; N], Sht3xError> {
+    let mut words = [0u16; N];
+
+    for (word, chunk) in words.iter_mut().zip(frame.chunks_exact(3)) {
+        if sensirion_crc(&chunk[..2]) != chunk[2] {
+            return Err(Sht3xError::InvalidData);
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+
+    Ok(words)
+}
+
+/// Converts a measurement frame to °C and %RH.
+fn decode(frame: &[u8; 6]) -> Result<Sht3xReading, Sht3xError> {
+    let [raw_t, raw_rh] = decode_words(frame)?;
+
+    Ok(Sht3xReading {
+        temperature: -45.0 + 175.0 * raw_t as f64 / 65535.0,
+        humidity: 100.0 * raw_rh as f64 / 65535.0,
+    })
+}
+
+impl<I2C, Delay> EnvironmentSensor for Sht3x<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn name(&self) -> &'static str {
+        "SHT3x"
+    }
+
+    fn measure(&mut self) -> Result<Measurement, SensorError> {
+        let reading = self.read()?;
+
+        Ok(Measurement {
+            temperature: Some(reading.temperature),
+            humidity: Some(reading.humidity),
+            ..Measurement::default()
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        Ok(self.soft_reset()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    use embedded_hal::i2c::{ErrorType, NoAcknowledgeSource, Operation};
+
+    use super::*;
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _: u32) {}
+    }
+
+    /// An SHT3x that answers reads with the queued frames in turn, `None` for a read it
+    /// doesn't acknowledge, and records the commands it gets.
+    #[derive(Default)]
+    struct MockSht3x {
+        frames: VecDeque<Option<[u8; 6]>>,
+        commands: Vec<u16>,
+        /// Fails every transfer with an error other than a NACK.
+        broken: bool,
+    }
+
+    impl MockSht3x {
+        fn answering(frames: impl IntoIterator<Item = Option<[u8; 6]>>) -> Self {
+            Self {
+                frames: frames.into_iter().collect(),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl ErrorType for MockSht3x {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockSht3x {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), ErrorKind> {
+            assert_eq!(address, ADDRESS);
+            if self.broken {
+                return Err(ErrorKind::ArbitrationLoss);
+            }
+
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        self.commands.push(u16::from_be_bytes([bytes[0], bytes[1]]))
+                    }
+                    Operation::Read(buf) => match self.frames.pop_front().flatten() {
+                        Some(frame) => buf.copy_from_slice(&frame[..buf.len()]),
+                        None => {
+                            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data));
+                        }
+                    },
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// A sensor that has been stopped and set to `mode`, with its command log cleared.
+    fn sensor(
+        mode: Mode,
+        frames: impl IntoIterator<Item = Option<[u8; 6]>>,
+    ) -> Sht3x<MockSht3x, NoDelay> {
+        let mut sensor = Sht3x::new(MockSht3x::answering(frames), NoDelay);
+        sensor.set_mode(mode).unwrap();
+        sensor.i2c.commands.clear();
+        sensor
+    }
+
+    /// Two words as the sensor sends them, each followed by its CRC.
+    fn frame(raw_t: u16, raw_rh: u16) -> [u8; 6] {
+        let [t0, t1] = raw_t.to_be_bytes();
+        let [h0, h1] = raw_rh.to_be_bytes();
+        [
+            t0,
+            t1,
+            sensirion_crc(&[t0, t1]),
+            h0,
+            h1,
+            sensirion_crc(&[h0, h1]),
+        ]
+    }
+
+    const PERIODIC: Mode = Mode::Periodic(Repeatability::High, Rate::One);
+
+    #[test]
+    fn mode_commands() {
+        let repeatabilities = [
+            Repeatability::High,
+            Repeatability::Medium,
+            Repeatability::Low,
+        ];
+        let rates = [
+            (Rate::Half, 0x20),
+            (Rate::One, 0x21),
+            (Rate::Two, 0x22),
+            (Rate::Four, 0x23),
+            (Rate::Ten, 0x27),
+        ];
+
+        // Single shot without clock stretching, table 9.
+        let single: Vec<u16> = repeatabilities
+            .iter()
+            .map(|&repeatability| Mode::SingleShot(repeatability).command())
+            .collect();
+        assert_eq!(single, [0x2400, 0x240b, 0x2416]);
+
+        // The MSB of a periodic command picks the rate, table 10.
+        let mut commands = single;
+        for (rate, msb) in rates {
+            for repeatability in repeatabilities {
+                let command = Mode::Periodic(repeatability, rate).command();
+                assert_eq!(command >> 8, msb, "{rate:?} {repeatability:?}");
+                commands.push(command);
+            }
+        }
+        assert_eq!(
+            Mode::Periodic(Repeatability::High, Rate::Half).command(),
+            0x2032
+        );
+        assert_eq!(
+            Mode::Periodic(Repeatability::Medium, Rate::Two).command(),
+            0x2220
+        );
+        assert_eq!(
+            Mode::Periodic(Repeatability::Low, Rate::Ten).command(),
+            0x272a
+        );
+
+        commands.push(Mode::Art.command());
+        assert_eq!(Mode::Art.command(), 0x2b32);
+
+        let mut distinct = commands.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert_eq!(distinct.len(), commands.len());
+    }
+
+    #[test]
+    fn set_mode_breaks_only_while_acquiring() {
+        // Acquisition may still run from before an MCU reset, so the first switch breaks.
+        let mut sensor = Sht3x::new(MockSht3x::default(), NoDelay);
+        sensor
+            .set_mode(Mode::SingleShot(Repeatability::Low))
+            .unwrap();
+        assert_eq!(sensor.i2c.commands, [BREAK]);
+
+        // Idle in single shot, nothing to stop.
+        sensor.i2c.commands.clear();
+        sensor.set_mode(PERIODIC).unwrap();
+        assert_eq!(sensor.i2c.commands, [0x2130]);
+
+        sensor.i2c.commands.clear();
+        sensor.set_mode(Mode::Art).unwrap();
+        assert_eq!(sensor.i2c.commands, [BREAK, ART]);
+
+        sensor.i2c.commands.clear();
+        sensor
+            .set_mode(Mode::SingleShot(Repeatability::High))
+            .unwrap();
+        assert_eq!(sensor.i2c.commands, [BREAK]);
+        assert_eq!(sensor.mode(), Mode::SingleShot(Repeatability::High));
+
+        sensor.i2c.commands.clear();
+        sensor
+            .set_mode(Mode::SingleShot(Repeatability::Medium))
+            .unwrap();
+        assert_eq!(sensor.i2c.commands, []);
+    }
+
+    #[test]
+    fn single_shot_read() {
+        let mode = Mode::SingleShot(Repeatability::Medium);
+        let mut sensor = sensor(mode, [Some(frame(0x6666, 0x8000)), None]);
+
+        let reading = sensor.read().unwrap();
+        assert!((reading.temperature - 25.0).abs() < 1e-9);
+        assert_eq!(sensor.i2c.commands, [0x240b]);
+
+        // The conversion should be done by the time the read goes out.
+        assert_eq!(sensor.read(), Err(Sht3xError::Timeout));
+    }
+
+    #[test]
+    fn periodic_read() {
+        let mut sensor = sensor(PERIODIC, [None, Some(frame(0x6666, 0x8000))]);
+
+        // Nothing measured since the last fetch.
+        assert_eq!(sensor.read(), Err(Sht3xError::NotReady));
+        assert_eq!(
+            SensorError::from(Sht3xError::NotReady),
+            SensorError::NotReady
+        );
+
+        let reading = sensor.read().unwrap();
+        assert!((reading.humidity - 50.0).abs() < 1e-3);
+        assert_eq!(sensor.i2c.commands, [FETCH_DATA, FETCH_DATA]);
+
+        sensor.i2c.broken = true;
+        assert_eq!(sensor.read(), Err(Sht3xError::Bus));
+    }
+
+    #[test]
+    fn conversion() {
+        // T = -45 + 175 * raw / 65535, RH = 100 * raw / 65535, section 4.13.
+        assert_eq!(decode(&frame(0, 0)), Ok(Sht3xReading::new(-45.0, 0.0)));
+        assert_eq!(
+            decode(&frame(0xffff, 0xffff)),
+            Ok(Sht3xReading::new(130.0, 100.0))
+        );
+
+        let reading = decode(&frame(0x6666, 0x8000)).unwrap();
+        assert!((reading.temperature - 25.0).abs() < 1e-9);
+        assert!((reading.humidity - 50.0008).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bad_crc() {
+        for byte in [2, 5] {
+            let mut corrupt = frame(0x6666, 0x8000);
+            corrupt[byte] ^= 1;
+            assert_eq!(decode(&corrupt), Err(Sht3xError::InvalidData));
+        }
+
+        let mut corrupt = frame(0x6666, 0x8000);
+        corrupt[0] ^= 1;
+        let mut sensor = sensor(PERIODIC, [Some(corrupt)]);
+        assert_eq!(sensor.measure(), Err(SensorError::InvalidData));
+    }
+
+    const RECOVERY: RecoveryConfig = RecoveryConfig {
+        saturated_rh: 98.0,
+        saturated_for_ms: 1_000,
+        pulse_ms: 500,
+        cooldown_ms: 300,
+    };
+
+    #[test]
+    fn saturation_timer() {
+        let mut recovery = CondensationRecovery::new(RECOVERY);
+
+        // A dry reading restarts the timer.
+        assert_eq!(recovery.update(0, Some(99.0)), None);
+        assert_eq!(recovery.update(900, Some(97.9)), None);
+        assert_eq!(recovery.update(1_000, Some(99.0)), None);
+        assert_eq!(recovery.update(1_999, Some(98.0)), None);
+        assert!(!recovery.is_recovering());
+
+        // Missed readings don't.
+        assert_eq!(recovery.update(2_000, None), Some(HeaterAction::On));
+        assert!(recovery.is_recovering());
+
+        let mut recovery = CondensationRecovery::new(RECOVERY);
+        assert_eq!(recovery.update(0, Some(99.0)), None);
+        assert_eq!(recovery.update(500, None), None);
+        assert_eq!(recovery.update(1_000, Some(99.0)), Some(HeaterAction::On));
+    }
+
+    #[test]
+    fn pulse_and_cooldown() {
+        let mut recovery = CondensationRecovery::new(RECOVERY);
+        recovery.update(0, Some(99.0));
+        assert_eq!(recovery.update(1_000, Some(99.0)), Some(HeaterAction::On));
+
+        // Readings while heating are skewed and ignored, whatever they say.
+        assert_eq!(recovery.update(1_499, Some(20.0)), None);
+        assert!(recovery.is_recovering());
+        assert_eq!(recovery.update(1_500, Some(99.0)), Some(HeaterAction::Off));
+        assert!(recovery.is_recovering());
+
+        // The cooldown runs from when the heater went off.
+        assert_eq!(recovery.update(1_799, Some(99.0)), None);
+        assert!(recovery.is_recovering());
+        assert_eq!(recovery.update(1_800, Some(99.0)), None);
+        assert!(!recovery.is_recovering());
+
+        // Watching again from scratch, the saturated readings before don't count.
+        assert_eq!(recovery.update(2_700, Some(99.0)), None);
+        assert_eq!(recovery.update(3_700, Some(99.0)), Some(HeaterAction::On));
+    }
+
+    #[test]
+    fn late_polls() {
+        let mut recovery = CondensationRecovery::new(RECOVERY);
+        recovery.update(0, Some(99.0));
+        assert_eq!(recovery.update(1_000, Some(99.0)), Some(HeaterAction::On));
+
+        // A poll well past the pulse switches off at once, and cools down from then on.
+        assert_eq!(recovery.update(5_000, None), Some(HeaterAction::Off));
+        assert_eq!(recovery.update(5_299, None), None);
+        assert!(recovery.is_recovering());
+        assert_eq!(recovery.update(5_300, None), None);
+        assert!(!recovery.is_recovering());
+    }
+}
@@ -0,0 +1,230 @@
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::drivers::environment::{EnvironmentSensor, Measurement, SensorError, sensirion_crc};
+
+/// SHT40-AD1B and most other variants. The -BD1B answers at 0x45, the -CD1B at 0x46.
+pub const ADDRESS: u8 = 0x44;
+
+const MEASURE_HIGH_PRECISION: u8 = 0xfd;
+const SOFT_RESET: u8 = 0x94;
+/// Longest conversion at high precision is 8.3 ms.
+const MEASURE_DELAY_US: u32 = 8_300;
+const RESET_DELAY_US: u32 = 1_000;
+
+pub struct Sht4x<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    i2c: I2C,
+    delay: Delay,
+    address: u8,
+}
+
+impl<I2C, Delay> Sht4x<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    pub fn new(i2c: I2C, delay: Delay) -> Self {
+        Self::with_address(i2c, delay, ADDRESS)
+    }
+
+    pub fn with_address(i2c: I2C, delay: Delay, address: u8) -> Self {
+        Self {
+            i2c,
+            delay,
+            address,
+        }
+    }
+
+    pub fn soft_reset(&mut self) -> Result<(), SensorError> {
+        self.i2c
+            .write(self.address, &[SOFT_RESET])
+            .map_err(|_| SensorError::Bus)?;
+        self.delay.delay_us(RESET_DELAY_US);
+        Ok(())
+    }
+}
+
+impl<I2C, Delay> EnvironmentSensor for Sht4x<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn name(&self) -> &'static str {
+        "SHT4x"
+    }
+
+    fn measure(&mut self) -> Result<Measurement, SensorError> {
+        self.i2c
+            .write(self.address, &[MEASURE_HIGH_PRECISION])
+            .map_err(|_| SensorError::Bus)?;
+        self.delay.delay_us(MEASURE_DELAY_US);
+
+        let mut frame = [0u8; 6];
+        self.i2c
+            .read(self.address, &mut frame)
+            .map_err(|_| SensorError::Bus)?;
+
+        let (temperature, humidity) = decode(&frame)?;
+
+        Ok(Measurement {
+            temperature: Some(temperature),
+            humidity: Some(humidity),
+            ..Measurement::default()
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), SensorError> {
+        self.soft_reset()
+    }
+}
+
+/// Converts a measurement frame, two CRC-protected words, to °C and %RH.
+fn decode(frame: &[u8; 6]) -> Result<(f64, f64), SensorError> {
+    if sensirion_crc(&frame[0..2]) != frame[2] || sensirion_crc(&frame[3..5]) != frame[5] {
+        return Err(SensorError::InvalidData);
+    }
+
+    let raw_t = u16::from_be_bytes([frame[0], frame[1]]) as f64;
+    let raw_rh = u16::from_be_bytes([frame[3], frame[4]]) as f64;
+
+    let temperature = -45.0 + 175.0 * raw_t / 65535.0;
+    // The formula reaches past 0..100 at the extremes, the datasheet says to clamp.
+    let humidity = (-6.0 + 125.0 * raw_rh / 65535.0).clamp(0.0, 100.0);
+
+    Ok((temperature, humidity))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use embedded_hal::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+
+    use super::*;
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _: u32) {}
+    }
+
+    /// An SHT4x that answers every read with `frame` and records the commands it gets.
+    struct MockSht4x {
+        frame: [u8; 6],
+        commands: Vec<u8>,
+        present: bool,
+    }
+
+    impl MockSht4x {
+        fn answering(frame: [u8; 6]) -> Self {
+            Self {
+                frame,
+                commands: Vec::new(),
+                present: true,
+            }
+        }
+    }
+
+    impl ErrorType for MockSht4x {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockSht4x {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), ErrorKind> {
+            if !self.present || address != ADDRESS {
+                return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+            }
+
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => self.commands.extend_from_slice(bytes),
+                    Operation::Read(buf) => buf.copy_from_slice(&self.frame[..buf.len()]),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Two words as the sensor sends them, each followed by its CRC.
+    fn frame(raw_t: u16, raw_rh: u16) -> [u8; 6] {
+        let [t0, t1] = raw_t.to_be_bytes();
+        let [h0, h1] = raw_rh.to_be_bytes();
+        [
+            t0,
+            t1,
+            sensirion_crc(&[t0, t1]),
+            h0,
+            h1,
+            sensirion_crc(&[h0, h1]),
+        ]
+    }
+
+    #[test]
+    fn crc() {
+        // The example of the datasheet.
+        assert_eq!(sensirion_crc(&[0xbe, 0xef]), 0x92);
+    }
+
+    #[test]
+    fn measure() {
+        let mut sensor = Sht4x::new(MockSht4x::answering(frame(0x6666, 0x8000)), NoDelay);
+        let measurement = sensor.measure().unwrap();
+
+        // T = -45 + 175 * 26214 / 65535, RH = -6 + 125 * 32768 / 65535.
+        assert!((measurement.temperature.unwrap() - 25.0).abs() < 1e-9);
+        assert!((measurement.humidity.unwrap() - 56.501).abs() < 1e-3);
+        assert_eq!(measurement.pressure, None);
+        assert_eq!(measurement.co2, None);
+        assert_eq!(sensor.i2c.commands, [MEASURE_HIGH_PRECISION]);
+    }
+
+    #[test]
+    fn conversion_range() {
+        assert_eq!(decode(&frame(0, 0)), Ok((-45.0, 0.0)));
+        assert_eq!(decode(&frame(0xffff, 0xffff)), Ok((130.0, 100.0)));
+
+        // RH is clamped where the formula leaves 0..100 %.
+        let (_, humidity) = decode(&frame(0, 3145)).unwrap();
+        assert_eq!(humidity, 0.0);
+        let (_, humidity) = decode(&frame(0, 3146)).unwrap();
+        assert!(humidity > 0.0);
+        let (_, humidity) = decode(&frame(0, 59_769)).unwrap();
+        assert_eq!(humidity, 100.0);
+    }
+
+    #[test]
+    fn bad_crc() {
+        let mut corrupt = frame(0x6666, 0x8000);
+        corrupt[5] ^= 1;
+        let mut sensor = Sht4x::new(MockSht4x::answering(corrupt), NoDelay);
+        assert_eq!(sensor.measure(), Err(SensorError::InvalidData));
+
+        let mut corrupt = frame(0x6666, 0x8000);
+        corrupt[0] ^= 1;
+        assert_eq!(decode(&corrupt), Err(SensorError::InvalidData));
+    }
+
+    #[test]
+    fn not_answering() {
+        let mut mock = MockSht4x::answering(frame(0x6666, 0x8000));
+        mock.present = false;
+        let mut sensor = Sht4x::new(mock, NoDelay);
+        assert_eq!(sensor.measure(), Err(SensorError::Bus));
+        assert_eq!(sensor.reset(), Err(SensorError::Bus));
+    }
+
+    #[test]
+    fn reset() {
+        let mut sensor = Sht4x::new(MockSht4x::answering(frame(0, 0)), NoDelay);
+        sensor.reset().unwrap();
+        assert_eq!(sensor.i2c.commands, [SOFT_RESET]);
+    }
+}
@@ -12,6 +12,13 @@ use ssd1306::size::DisplaySize128x64;
 
 use display_interface_i2c::I2CInterface;
 
+use crate::drivers::environment::Measurement;
+use crate::psychro::Psychrometrics;
+use crate::stats::WindowSummary;
+
+/// Most modules. Those with the D/C pin pulled high answer at 0x3D.
+pub const ADDRESS: u8 = 0x3c;
+
 pub struct Ssd1306<I2C>
 where
     I2C: I2c,
@@ -28,7 +35,11 @@ where
     I2C: I2c<Error = E>,
 {
     pub fn new(i2c: I2C) -> Result<Self, DisplayError> {
-        let interface = I2CDisplayInterface::new(i2c);
+        Self::with_address(i2c, ADDRESS)
+    }
+
+    pub fn with_address(i2c: I2C, address: u8) -> Result<Self, DisplayError> {
+        let interface = I2CDisplayInterface::new_custom_address(i2c, address);
         let mut driver =
             ssd1306::Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
                 .into_buffered_graphics_mode();
@@ -44,10 +55,12 @@ where
         self.inner.flush()
     }
 
+    /// One block per sensor with its name, temperature and humidity or why it is failing,
+    /// then the Wi-Fi status on the bottom line. Pressure and CO2 go next to the name for
+    /// chips that measure them. Fits two sensors.
     pub fn show_sensor_data(
         &mut self,
-        temperature: f64,
-        humidity: f64,
+        sensors: &[(&str, Result<Measurement, &str>)],
         wifi_status: &str,
     ) -> Result<(), DisplayError> {
         self.inner.clear_buffer();
@@ -55,34 +68,163 @@ where
         let title_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
         let value_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
 
-        let mut buf1 = [0u8; 64];
-        let mut buf2 = [0u8; 64];
+        for (row, &(name, values)) in (0i32..).zip(sensors.iter().take(2)) {
+            let y = row * 26;
+            let mut title_buf = [0u8; 32];
+            let mut buf = [0u8; 64];
+
+            let title = match values {
+                Ok(Measurement {
+                    pressure: Some(pressure),
+                    co2: Some(co2),
+                    ..
+                }) => format_no_std::show(
+                    &mut title_buf,
+                    format_args!("{} {:.0}hPa {}ppm", name, pressure, co2),
+                ),
+                Ok(Measurement {
+                    pressure: Some(pressure),
+                    ..
+                }) => format_no_std::show(
+                    &mut title_buf,
+                    format_args!("{}  {:.1} hPa", name, pressure),
+                ),
+                Ok(Measurement { co2: Some(co2), .. }) => {
+                    format_no_std::show(&mut title_buf, format_args!("{}  {} ppm", name, co2))
+                }
+                _ => Ok(name),
+            };
 
-        Text::with_baseline("Temperature:", Point::new(0, 0), title_style, Baseline::Top)
+            Text::with_baseline(
+                title.unwrap_or(name),
+                Point::new(0, y),
+                title_style,
+                Baseline::Top,
+            )
             .draw(&mut self.inner)?;
 
-        Text::with_baseline(
-            format_no_std::show(&mut buf1, format_args!("{:.2} C", temperature)).unwrap(),
-            Point::new(0, 12),
-            value_style,
-            Baseline::Top,
-        )
-        .draw(&mut self.inner)?;
+            let line = match values.map(|measurement| measurement.reading()) {
+                Ok(Some(reading)) => format_no_std::show(
+                    &mut buf,
+                    format_args!("{:.2} C  {:.2} %", reading.temperature, reading.humidity),
+                ),
+                Ok(None) => Ok("-"),
+                Err(fault) => format_no_std::show(&mut buf, format_args!("fault: {}", fault)),
+            };
 
-        Text::with_baseline("Humidity:", Point::new(0, 32), title_style, Baseline::Top)
+            Text::with_baseline(
+                line.unwrap(),
+                Point::new(0, y + 12),
+                value_style,
+                Baseline::Top,
+            )
             .draw(&mut self.inner)?;
-
-        Text::with_baseline(
-            format_no_std::show(&mut buf2, format_args!("{:.2} %", humidity)).unwrap(),
-            Point::new(0, 44),
-            value_style,
-            Baseline::Top,
-        )
-        .draw(&mut self.inner)?;
+        }
 
         Text::with_baseline(wifi_status, Point::new(0, 56), value_style, Baseline::Top)
             .draw(&mut self.inner)?;
 
         self.inner.flush()
     }
+
+    /// Same layout as [`Ssd1306::show_sensor_data`], with the dew point next to the name and
+    /// absolute humidity, heat index and vapour-pressure deficit below. `None` for sensors
+    /// with nothing to derive from.
+    pub fn show_derived_data(
+        &mut self,
+        sensors: &[(&str, Option<Psychrometrics>)],
+        wifi_status: &str,
+    ) -> Result<(), DisplayError> {
+        self.inner.clear_buffer();
+
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+
+        for (row, &(name, derived)) in (0i32..).zip(sensors.iter().take(2)) {
+            let y = row * 26;
+            let mut title_buf = [0u8; 32];
+            let mut value_buf = [0u8; 64];
+
+            let (title, line) = match derived {
+                Some(derived) => (
+                    format_no_std::show(
+                        &mut title_buf,
+                        format_args!("{}  dp {:.1} C", name, derived.dew_point),
+                    ),
+                    format_no_std::show(
+                        &mut value_buf,
+                        format_args!(
+                            "{:.1}g {:.0}C {:.2}kPa",
+                            derived.absolute_humidity, derived.heat_index, derived.vpd
+                        ),
+                    ),
+                ),
+                None => (Ok(name), Ok("-")),
+            };
+
+            Text::with_baseline(title.unwrap(), Point::new(0, y), style, Baseline::Top)
+                .draw(&mut self.inner)?;
+            Text::with_baseline(line.unwrap(), Point::new(0, y + 12), style, Baseline::Top)
+                .draw(&mut self.inner)?;
+        }
+
+        Text::with_baseline(wifi_status, Point::new(0, 56), style, Baseline::Top)
+            .draw(&mut self.inner)?;
+
+        self.inner.flush()
+    }
+
+    /// Same layout as [`Ssd1306::show_sensor_data`], with the means of `window` next to the
+    /// name and the ranges below. `None` for sensors without readings in the window.
+    pub fn show_statistics(
+        &mut self,
+        window: &str,
+        sensors: &[(&str, Option<WindowSummary>)],
+        wifi_status: &str,
+    ) -> Result<(), DisplayError> {
+        self.inner.clear_buffer();
+
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+
+        for (row, &(name, summary)) in (0i32..).zip(sensors.iter().take(2)) {
+            let y = row * 26;
+            let mut title_buf = [0u8; 32];
+            let mut value_buf = [0u8; 64];
+
+            let (title, line) = match summary {
+                Some(summary) => (
+                    format_no_std::show(
+                        &mut title_buf,
+                        format_args!(
+                            "{} {} {:.1}C {:.0}%",
+                            name, window, summary.temperature.mean, summary.humidity.mean
+                        ),
+                    ),
+                    format_no_std::show(
+                        &mut value_buf,
+                        format_args!(
+                            "{:.1}..{:.1}C {:.0}..{:.0}%",
+                            summary.temperature.min,
+                            summary.temperature.max,
+                            summary.humidity.min,
+                            summary.humidity.max
+                        ),
+                    ),
+                ),
+                None => (
+                    format_no_std::show(&mut title_buf, format_args!("{} {}", name, window)),
+                    Ok("-"),
+                ),
+            };
+
+            Text::with_baseline(title.unwrap(), Point::new(0, y), style, Baseline::Top)
+                .draw(&mut self.inner)?;
+            Text::with_baseline(line.unwrap(), Point::new(0, y + 12), style, Baseline::Top)
+                .draw(&mut self.inner)?;
+        }
+
+        Text::with_baseline(wifi_status, Point::new(0, 56), style, Baseline::Top)
+            .draw(&mut self.inner)?;
+
+        self.inner.flush()
+    }
 }
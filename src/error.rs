@@ -1,6 +1,8 @@
 use defmt::Format;
 use thiserror::Error;
 
+use crate::drivers::environment::SensorError;
+
 #[derive(Error, Format, Debug)]
 pub enum AppError {
     #[error("failed to spawn embassy task")]
@@ -15,6 +17,9 @@ pub enum AppError {
     #[error("failed to initialize display")]
     Display,
 
+    #[error("failed to initialize sensor")]
+    Sensor(SensorError),
+
     #[error("esp radio failed to initialize")]
     EspRadioInitFailed(#[from] esp_radio::InitializationError),
 
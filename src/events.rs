@@ -1,7 +1,9 @@
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
 
-use crate::drivers::sht3x::Sht3xReading;
+use crate::alert::Alert;
+use crate::drivers::environment::{Measurement, SensorError, SensorId};
+use crate::filter::Quality;
 use crate::tasks::wifi::WifiState;
 
 const EVENT_CHANNEL_SIZE: usize = 10;
@@ -18,6 +20,14 @@ pub async fn receive_event() -> Event {
 
 #[derive(Debug, Clone, Copy)]
 pub enum Event {
-    SensorReading(Sht3xReading),
+    /// A reading that passed the filter, flagged with what the filter did since the last one.
+    /// Always has temperature and humidity, see `Measurement::reading`.
+    SensorReading(SensorId, Measurement, Quality),
+    /// A sensor kept failing, with the last error. Its next reading means it is back.
+    SensorFault(SensorId, SensorError),
+    /// A rule went off, see `AlertEngine`.
+    AlertRaised(Alert),
+    /// The alert of a rule went away again.
+    AlertCleared(Alert),
     WifiStatus(WifiState),
 }
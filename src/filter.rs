@@ -0,0 +1,470 @@
+use core::ops::BitOr;
+
+use defmt::Format;
+use heapless::Deque;
+use serde::{Serialize, Serializer};
+
+use crate::drivers::sht3x::Sht3xReading;
+
+/// Longest median window [`FilterConfig::median_of`] can ask for.
+pub const MEDIAN_CAPACITY: usize = 9;
+
+/// Names of the [`Quality`] flags, lowest bit first.
+const QUALITY_LABELS: [&str; 4] = [
+    "out_of_range",
+    "rate_exceeded",
+    "rate_overridden",
+    "heater_active",
+];
+
+/// Flags published with a reading about what the filter did since the previous one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Quality(u8);
+
+impl Quality {
+    pub const GOOD: Quality = Quality(0);
+    /// A reading since the previous one was outside the physical range and discarded.
+    pub const OUT_OF_RANGE: Quality = Quality(1 << 0);
+    /// A reading since the previous one changed faster than plausible and was discarded.
+    pub const RATE_EXCEEDED: Quality = Quality(1 << 1);
+    /// This reading changed faster than plausible, but kept doing so long enough to be
+    /// taken as a real step.
+    pub const RATE_OVERRIDDEN: Quality = Quality(1 << 2);
+    /// This reading was taken while the heater was on, or the sensor still cooling down
+    /// after it, so it reads warm and dry. It skipped the filter.
+    pub const HEATER_ACTIVE: Quality = Quality(1 << 3);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Quality) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_good(&self) -> bool {
+        *self == Quality::GOOD
+    }
+
+    /// Names of the flags that are set.
+    pub fn labels(self) -> impl Iterator<Item = &'static str> {
+        QUALITY_LABELS
+            .into_iter()
+            .enumerate()
+            .filter(move |(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, label)| label)
+    }
+}
+
+impl BitOr for Quality {
+    type Output = Quality;
+
+    fn bitor(self, rhs: Quality) -> Quality {
+        Quality(self.0 | rhs.0)
+    }
+}
+
+/// A list of flag names, e.g. `["rate_exceeded"]`.
+impl Serialize for Quality {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.labels())
+    }
+}
+
+/// Why a reading was discarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Rejection {
+    OutOfRange,
+    RateExceeded,
+}
+
+impl Rejection {
+    /// Short name used as a metrics label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Rejection::OutOfRange => "out_of_range",
+            Rejection::RateExceeded => "rate_exceeded",
+        }
+    }
+
+    /// The flag the next accepted reading carries.
+    pub fn quality(&self) -> Quality {
+        match self {
+            Rejection::OutOfRange => Quality::OUT_OF_RANGE,
+            Rejection::RateExceeded => Quality::RATE_EXCEEDED,
+        }
+    }
+}
+
+/// Inclusive range of plausible values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Limits {
+    fn contains(&self, value: f64) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterConfig {
+    /// Degrees Celsius.
+    pub temperature: Limits,
+    /// Percent relative humidity.
+    pub humidity: Limits,
+    /// Largest plausible change in °C per second, `None` to skip the check.
+    pub max_temperature_rate: Option<f64>,
+    /// Largest plausible change in %RH per second, `None` to skip the check.
+    pub max_humidity_rate: Option<f64>,
+    /// Readings rejected for their rate in a row before the new level is taken as real,
+    /// so a probe moved somewhere else isn't rejected forever.
+    pub max_rate_rejections: u32,
+    /// Publish the median of this many readings, 1 to pass them through. Capped at
+    /// [`MEDIAN_CAPACITY`].
+    pub median_of: usize,
+}
+
+/// Checks readings of one sensor against [`FilterConfig`], in order: physical range, rate
+/// of change against the last accepted reading, then the median filter.
+pub struct ReadingFilter {
+    config: FilterConfig,
+    /// Last accepted raw reading and when it was taken, in ms.
+    last: Option<(u64, Sht3xReading)>,
+    rate_rejections: u32,
+    /// Flags of readings rejected since the last accepted one.
+    pending: Quality,
+    window: Deque<Sht3xReading, MEDIAN_CAPACITY>,
+}
+
+impl ReadingFilter {
+    pub const fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            last: None,
+            rate_rejections: 0,
+            pending: Quality::GOOD,
+            window: Deque::new(),
+        }
+    }
+
+    /// Filters a reading taken at `now_ms`. Returns the reading to publish with its quality
+    /// flags, or why it was discarded.
+    pub fn apply(
+        &mut self,
+        now_ms: u64,
+        reading: Sht3xReading,
+    ) -> Result<(Sht3xReading, Quality), Rejection> {
+        let mut quality = self.pending;
+
+        if !self.config.temperature.contains(reading.temperature)
+            || !self.config.humidity.contains(reading.humidity)
+        {
+            return Err(self.reject(Rejection::OutOfRange));
+        }
+
+        if self.exceeds_rate(now_ms, &reading) {
+            if self.rate_rejections < self.config.max_rate_rejections {
+                self.rate_rejections += 1;
+                return Err(self.reject(Rejection::RateExceeded));
+            }
+            quality = quality | Quality::RATE_OVERRIDDEN;
+        }
+
+        self.last = Some((now_ms, reading));
+        self.rate_rejections = 0;
+        self.pending = Quality::GOOD;
+
+        Ok((self.smooth(reading), quality))
+    }
+
+    fn reject(&mut self, rejection: Rejection) -> Rejection {
+        self.pending = self.pending | rejection.quality();
+        rejection
+    }
+
+    fn exceeds_rate(&self, now_ms: u64, reading: &Sht3xReading) -> bool {
+        let Some((then_ms, last)) = self.last else {
+            return false;
+        };
+        // Readings in the same millisecond still get some leeway.
+        let seconds = now_ms.saturating_sub(then_ms).max(1) as f64 / 1000.0;
+
+        let too_fast = |rate: Option<f64>, from: f64, to: f64| match rate {
+            Some(rate) => (to - from).abs() > rate * seconds,
+            None => false,
+        };
+
+        too_fast(
+            self.config.max_temperature_rate,
+            last.temperature,
+            reading.temperature,
+        ) || too_fast(
+            self.config.max_humidity_rate,
+            last.humidity,
+            reading.humidity,
+        )
+    }
+
+    /// Median of the last `median_of` accepted readings, each quantity on its own.
+    fn smooth(&mut self, reading: Sht3xReading) -> Sht3xReading {
+        let len = self.config.median_of.clamp(1, MEDIAN_CAPACITY);
+        if len == 1 {
+            return reading;
+        }
+
+        while self.window.len() >= len {
+            self.window.pop_front();
+        }
+        // Room was just made above.
+        let _ = self.window.push_back(reading);
+
+        Sht3xReading {
+            temperature: median(self.window.iter().map(|r| r.temperature)),
+            humidity: median(self.window.iter().map(|r| r.humidity)),
+        }
+    }
+}
+
+/// Lower median for an even count. `values` must not be empty.
+fn median(values: impl Iterator<Item = f64>) -> f64 {
+    let mut sorted = [0.0; MEDIAN_CAPACITY];
+    let mut len = 0;
+    for (slot, value) in sorted.iter_mut().zip(values) {
+        *slot = value;
+        len += 1;
+    }
+
+    let sorted = &mut sorted[..len];
+    sorted.sort_unstable_by(f64::total_cmp);
+    sorted[(len - 1) / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: FilterConfig = FilterConfig {
+        temperature: Limits {
+            min: -40.0,
+            max: 125.0,
+        },
+        humidity: Limits {
+            min: 0.0,
+            max: 100.0,
+        },
+        max_temperature_rate: Some(1.0),
+        max_humidity_rate: Some(5.0),
+        max_rate_rejections: 2,
+        median_of: 1,
+    };
+
+    #[test]
+    fn range() {
+        let mut filter = ReadingFilter::new(CONFIG);
+        for out_of_range in [
+            Sht3xReading::new(-40.1, 50.0),
+            Sht3xReading::new(125.1, 50.0),
+            Sht3xReading::new(20.0, -0.1),
+            Sht3xReading::new(20.0, 100.1),
+            Sht3xReading::new(f64::NAN, 50.0),
+            Sht3xReading::new(20.0, f64::NAN),
+        ] {
+            assert_eq!(filter.apply(0, out_of_range), Err(Rejection::OutOfRange));
+        }
+
+        // The limits are inclusive, and the next accepted reading carries the flag.
+        assert_eq!(
+            filter.apply(0, Sht3xReading::new(125.0, 100.0)),
+            Ok((Sht3xReading::new(125.0, 100.0), Quality::OUT_OF_RANGE))
+        );
+        assert_eq!(
+            filter.apply(1000, Sht3xReading::new(124.5, 99.0)),
+            Ok((Sht3xReading::new(124.5, 99.0), Quality::GOOD))
+        );
+    }
+
+    #[test]
+    fn rate() {
+        let mut filter = ReadingFilter::new(CONFIG);
+        assert!(filter.apply(0, Sht3xReading::new(20.0, 50.0)).is_ok());
+
+        // 1 °C/s and 5 %RH/s, scaled by the time since the last accepted reading.
+        assert_eq!(
+            filter.apply(1000, Sht3xReading::new(21.0, 55.0)),
+            Ok((Sht3xReading::new(21.0, 55.0), Quality::GOOD))
+        );
+        assert_eq!(
+            filter.apply(2000, Sht3xReading::new(22.5, 55.0)),
+            Err(Rejection::RateExceeded)
+        );
+        assert_eq!(
+            filter.apply(2000, Sht3xReading::new(21.0, 61.0)),
+            Err(Rejection::RateExceeded)
+        );
+        assert_eq!(
+            filter.apply(4000, Sht3xReading::new(23.5, 55.0)),
+            Ok((Sht3xReading::new(23.5, 55.0), Quality::RATE_EXCEEDED))
+        );
+
+        // Readings in the same millisecond are compared as if a millisecond apart.
+        assert_eq!(
+            filter.apply(4000, Sht3xReading::new(23.5, 55.0)),
+            Ok((Sht3xReading::new(23.5, 55.0), Quality::GOOD))
+        );
+
+        let unlimited = FilterConfig {
+            max_temperature_rate: None,
+            max_humidity_rate: None,
+            ..CONFIG
+        };
+        let mut filter = ReadingFilter::new(unlimited);
+        assert!(filter.apply(0, Sht3xReading::new(-40.0, 0.0)).is_ok());
+        assert!(filter.apply(1, Sht3xReading::new(125.0, 100.0)).is_ok());
+    }
+
+    #[test]
+    fn rate_rejections_give_way_to_a_real_step() {
+        let mut filter = ReadingFilter::new(CONFIG);
+        assert!(filter.apply(0, Sht3xReading::new(20.0, 50.0)).is_ok());
+
+        // Moved somewhere warmer: rejected `max_rate_rejections` times, then taken.
+        for now_ms in [1000, 2000] {
+            assert_eq!(
+                filter.apply(now_ms, Sht3xReading::new(30.0, 50.0)),
+                Err(Rejection::RateExceeded)
+            );
+        }
+        let (accepted, quality) = filter.apply(3000, Sht3xReading::new(30.0, 50.0)).unwrap();
+        assert_eq!(accepted, Sht3xReading::new(30.0, 50.0));
+        assert_eq!(quality, Quality::RATE_EXCEEDED | Quality::RATE_OVERRIDDEN);
+        assert_eq!(
+            std::vec::Vec::from_iter(quality.labels()),
+            ["rate_exceeded", "rate_overridden"]
+        );
+
+        // The count starts over after an accepted reading.
+        assert_eq!(
+            filter.apply(4000, Sht3xReading::new(20.0, 50.0)),
+            Err(Rejection::RateExceeded)
+        );
+        assert_eq!(
+            filter.apply(5000, Sht3xReading::new(30.5, 50.0)),
+            Ok((Sht3xReading::new(30.5, 50.0), Quality::RATE_EXCEEDED))
+        );
+        for now_ms in [6000, 7000] {
+            assert_eq!(
+                filter.apply(now_ms, Sht3xReading::new(40.0, 50.0)),
+                Err(Rejection::RateExceeded)
+            );
+        }
+        assert!(
+            filter
+                .apply(8000, Sht3xReading::new(40.0, 50.0))
+                .unwrap()
+                .1
+                .contains(Quality::RATE_OVERRIDDEN)
+        );
+
+        // Out-of-range readings don't count towards it.
+        let mut filter = ReadingFilter::new(CONFIG);
+        assert!(filter.apply(0, Sht3xReading::new(20.0, 50.0)).is_ok());
+        assert!(filter.apply(1000, Sht3xReading::new(30.0, 50.0)).is_err());
+        assert!(filter.apply(2000, Sht3xReading::new(200.0, 50.0)).is_err());
+        assert!(filter.apply(3000, Sht3xReading::new(30.0, 50.0)).is_err());
+        assert_eq!(
+            filter.apply(4000, Sht3xReading::new(30.0, 50.0)),
+            Ok((
+                Sht3xReading::new(30.0, 50.0),
+                Quality::OUT_OF_RANGE | Quality::RATE_EXCEEDED | Quality::RATE_OVERRIDDEN
+            ))
+        );
+    }
+
+    #[test]
+    fn median() {
+        let config = FilterConfig {
+            max_temperature_rate: None,
+            max_humidity_rate: None,
+            median_of: 3,
+            ..CONFIG
+        };
+        let mut filter = ReadingFilter::new(config);
+
+        let smoothed = [
+            (20.0, 50.0),
+            (30.0, 40.0),
+            (21.0, 45.0),
+            (22.0, 41.0),
+            (50.0, 60.0),
+            (23.0, 42.0),
+        ]
+        .into_iter()
+        .zip(0..)
+        .map(|((temperature, humidity), i)| {
+            filter
+                .apply(i * 1000, Sht3xReading::new(temperature, humidity))
+                .unwrap()
+                .0
+        });
+
+        // Each quantity on its own, the lower median while the window fills up.
+        assert_eq!(
+            std::vec::Vec::from_iter(smoothed),
+            [
+                Sht3xReading::new(20.0, 50.0),
+                Sht3xReading::new(20.0, 40.0),
+                Sht3xReading::new(21.0, 45.0),
+                Sht3xReading::new(22.0, 41.0),
+                Sht3xReading::new(22.0, 45.0),
+                Sht3xReading::new(23.0, 42.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn median_leaves_out_rejected_readings() {
+        let config = FilterConfig {
+            median_of: 3,
+            ..CONFIG
+        };
+        let mut filter = ReadingFilter::new(config);
+        assert!(filter.apply(0, Sht3xReading::new(20.0, 50.0)).is_ok());
+        assert!(filter.apply(1000, Sht3xReading::new(20.5, 50.0)).is_ok());
+        assert!(filter.apply(2000, Sht3xReading::new(-50.0, 50.0)).is_err());
+        assert_eq!(
+            filter.apply(3000, Sht3xReading::new(21.0, 50.0)).unwrap().0,
+            Sht3xReading::new(20.5, 50.0)
+        );
+
+        // Windows past MEDIAN_CAPACITY are capped, none passes readings through.
+        for median_of in [0, MEDIAN_CAPACITY + 1] {
+            let mut filter = ReadingFilter::new(FilterConfig {
+                median_of,
+                max_temperature_rate: None,
+                ..CONFIG
+            });
+            for i in 0..20 {
+                filter.apply(i, Sht3xReading::new(i as f64, 50.0)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn quality() {
+        assert!(Quality::default().is_good());
+        assert_eq!(Quality::GOOD.labels().count(), 0);
+
+        let quality = Quality::OUT_OF_RANGE | Quality::HEATER_ACTIVE;
+        assert!(!quality.is_good());
+        assert!(quality.contains(Quality::OUT_OF_RANGE));
+        assert!(!quality.contains(Quality::RATE_EXCEEDED));
+        assert_eq!(quality.bits(), 0b1001);
+
+        let mut buf = [0; 64];
+        let len = serde_json_core::to_slice(&quality, &mut buf).unwrap();
+        assert_eq!(&buf[..len], br#"["out_of_range","heater_active"]"#);
+    }
+}
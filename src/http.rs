@@ -0,0 +1,779 @@
+use core::fmt;
+
+use defmt::Format;
+
+const LINE_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ProtocolError {
+    InvalidStatusLine,
+    InvalidRequestLine,
+    InvalidHeader,
+    LineTooLong,
+    InvalidContentLength,
+    InvalidChunkSize,
+    InvalidChunkTerminator,
+    UnexpectedEof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum HttpError {
+    BufferTooSmall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.0)
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self.0 {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Content Too Large",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+
+    fn has_body(self) -> bool {
+        !(self.is_informational() || self.0 == 204 || self.0 == 304)
+    }
+}
+
+pub struct Request<'a> {
+    path: &'a str,
+    host: &'a str,
+    content_type: &'a str,
+    authorization: Option<&'a str>,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn post(host: &'a str, path: &'a str) -> Self {
+        Self {
+            path,
+            host,
+            content_type: "application/octet-stream",
+            authorization: None,
+            body: &[],
+        }
+    }
+
+    pub fn json(mut self, body: &'a [u8]) -> Self {
+        self.content_type = "application/json";
+        self.body = body;
+        self
+    }
+
+    pub fn text(mut self, body: &'a [u8]) -> Self {
+        self.content_type = "text/plain; charset=utf-8";
+        self.body = body;
+        self
+    }
+
+    /// Sets the `Authorization` header, scheme included, e.g. `Token <token>`.
+    pub fn authorization(mut self, credentials: &'a str) -> Self {
+        self.authorization = Some(credentials);
+        self
+    }
+
+    pub fn body(&self) -> &'a [u8] {
+        self.body
+    }
+
+    /// Serializes the request line and headers into `buf`. The body is sent separately.
+    pub fn write_head<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], HttpError> {
+        format_no_std::show(
+            buf,
+            format_args!(
+                "POST {} HTTP/1.1\r\n\
+Host: {}\r\n\
+Content-Type: {}\r\n\
+Content-Length: {}\r\n\
+{}\
+Connection: keep-alive\r\n\
+\r\n",
+                self.path,
+                self.host,
+                self.content_type,
+                self.body.len(),
+                OptionalHeader("Authorization", self.authorization)
+            ),
+        )
+        .map(str::as_bytes)
+        .map_err(|_| HttpError::BufferTooSmall)
+    }
+}
+
+/// A header line, or nothing if there is no value.
+struct OptionalHeader<'a>(&'a str, Option<&'a str>);
+
+impl fmt::Display for OptionalHeader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.1 {
+            Some(value) => write!(f, "{}: {}\r\n", self.0, value),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Request line and the headers a small server cares about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RequestHead<'a> {
+    pub method: &'a str,
+    /// Path without the query string.
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub content_length: usize,
+}
+
+impl<'a> RequestHead<'a> {
+    /// Parses the head at the start of `buf`. Returns the head and its length including the
+    /// blank line, or `None` if the blank line hasn't arrived yet.
+    pub fn parse(buf: &'a [u8]) -> Result<Option<(Self, usize)>, ProtocolError> {
+        let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return Ok(None);
+        };
+
+        let head =
+            core::str::from_utf8(&buf[..end]).map_err(|_| ProtocolError::InvalidRequestLine)?;
+        let mut lines = head.split("\r\n");
+
+        let mut parts = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ProtocolError::InvalidRequestLine);
+        };
+
+        if method.is_empty() || !target.starts_with('/') || !version.starts_with("HTTP/1.") {
+            return Err(ProtocolError::InvalidRequestLine);
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        let mut content_length = 0;
+
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(ProtocolError::InvalidHeader)?;
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| ProtocolError::InvalidContentLength)?;
+            }
+        }
+
+        let head = Self {
+            method,
+            path,
+            query,
+            content_length,
+        };
+
+        Ok(Some((head, end + 4)))
+    }
+}
+
+/// Formats a response body into a fixed buffer.
+pub struct BodyWriter<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> BodyWriter<'b> {
+    pub fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+}
+
+impl fmt::Write for BodyWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.pos + s.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// Serializes a status line and headers for a response whose connection is closed after
+/// the body.
+pub fn write_response_head<'b>(
+    buf: &'b mut [u8],
+    status: StatusCode,
+    content_type: &str,
+    content_length: usize,
+) -> Result<&'b [u8], HttpError> {
+    format_no_std::show(
+        buf,
+        format_args!(
+            "HTTP/1.1 {} {}\r\n\
+Content-Type: {}\r\n\
+Content-Length: {}\r\n\
+Cache-Control: no-store\r\n\
+Connection: close\r\n\
+\r\n",
+            status.0,
+            status.reason(),
+            content_type,
+            content_length
+        ),
+    )
+    .map(str::as_bytes)
+    .map_err(|_| HttpError::BufferTooSmall)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Response {
+    pub status: StatusCode,
+    pub keep_alive: bool,
+    pub body_len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    StatusLine,
+    Headers,
+    Body(usize),
+    ChunkSize,
+    ChunkData(usize),
+    ChunkDataEnd,
+    Trailers,
+    UntilClose,
+    Done,
+}
+
+/// Incremental HTTP/1.x response parser.
+///
+/// Bytes are pushed in with [`ResponseParser::feed`] as they arrive from the socket, so the
+/// parser never needs the whole response in memory. The body is counted and discarded.
+pub struct ResponseParser {
+    state: State,
+    line: [u8; LINE_CAPACITY],
+    line_len: usize,
+    status: StatusCode,
+    http_1_0: bool,
+    content_length: Option<usize>,
+    chunked: bool,
+    connection_close: bool,
+    connection_keep_alive: bool,
+    body_len: usize,
+}
+
+impl Default for ResponseParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseParser {
+    pub fn new() -> Self {
+        Self {
+            state: State::StatusLine,
+            line: [0; LINE_CAPACITY],
+            line_len: 0,
+            status: StatusCode(0),
+            http_1_0: false,
+            content_length: None,
+            chunked: false,
+            connection_close: false,
+            connection_keep_alive: false,
+            body_len: 0,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Consumes as much of `data` as belongs to this response and returns the number of
+    /// bytes used. Anything after the end of the response is left untouched.
+    pub fn feed(&mut self, data: &[u8]) -> Result<usize, ProtocolError> {
+        let mut consumed = 0;
+
+        while consumed < data.len() && self.state != State::Done {
+            let rest = &data[consumed..];
+
+            match self.state {
+                State::Body(remaining) => {
+                    let n = remaining.min(rest.len());
+                    self.body_len += n;
+                    consumed += n;
+                    self.state = match remaining - n {
+                        0 => State::Done,
+                        remaining => State::Body(remaining),
+                    };
+                }
+                State::ChunkData(remaining) => {
+                    let n = remaining.min(rest.len());
+                    self.body_len += n;
+                    consumed += n;
+                    self.state = match remaining - n {
+                        0 => State::ChunkDataEnd,
+                        remaining => State::ChunkData(remaining),
+                    };
+                }
+                State::UntilClose => {
+                    self.body_len += rest.len();
+                    consumed += rest.len();
+                }
+                _ => {
+                    let byte = rest[0];
+                    consumed += 1;
+
+                    if byte == b'\n' {
+                        self.process_line()?;
+                        self.line_len = 0;
+                    } else if self.line_len < LINE_CAPACITY {
+                        self.line[self.line_len] = byte;
+                        self.line_len += 1;
+                    } else {
+                        return Err(ProtocolError::LineTooLong);
+                    }
+                }
+            }
+        }
+
+        Ok(consumed)
+    }
+
+    /// Signals that the peer closed the connection. Only responses delimited by connection
+    /// close are allowed to end here.
+    pub fn finish(&mut self) -> Result<Response, ProtocolError> {
+        if self.state == State::UntilClose {
+            self.state = State::Done;
+        }
+
+        self.response().ok_or(ProtocolError::UnexpectedEof)
+    }
+
+    pub fn response(&self) -> Option<Response> {
+        if self.state != State::Done {
+            return None;
+        }
+
+        Some(Response {
+            status: self.status,
+            keep_alive: self.keep_alive(),
+            body_len: self.body_len,
+        })
+    }
+
+    fn keep_alive(&self) -> bool {
+        let delimited = self.chunked || self.content_length.is_some() || !self.status.has_body();
+
+        if !delimited || self.connection_close {
+            return false;
+        }
+
+        !self.http_1_0 || self.connection_keep_alive
+    }
+
+    fn process_line(&mut self) -> Result<(), ProtocolError> {
+        let buf = self.line;
+        let line = buf[..self.line_len]
+            .strip_suffix(b"\r")
+            .unwrap_or(&buf[..self.line_len]);
+
+        match self.state {
+            State::StatusLine => {
+                self.parse_status_line(line)?;
+                self.state = State::Headers;
+            }
+            State::Headers if line.is_empty() => self.end_of_headers(),
+            State::Headers => self.parse_header(line)?,
+            State::ChunkSize => {
+                let size = parse_chunk_size(line)?;
+                self.state = if size == 0 {
+                    State::Trailers
+                } else {
+                    State::ChunkData(size)
+                };
+            }
+            State::ChunkDataEnd if line.is_empty() => self.state = State::ChunkSize,
+            State::ChunkDataEnd => return Err(ProtocolError::InvalidChunkTerminator),
+            State::Trailers if line.is_empty() => self.state = State::Done,
+            State::Trailers => {}
+            State::Body(_) | State::ChunkData(_) | State::UntilClose | State::Done => {}
+        }
+
+        Ok(())
+    }
+
+    fn parse_status_line(&mut self, line: &[u8]) -> Result<(), ProtocolError> {
+        let line = core::str::from_utf8(line).map_err(|_| ProtocolError::InvalidStatusLine)?;
+        let mut parts = line.splitn(3, ' ');
+
+        self.http_1_0 = match parts.next() {
+            Some("HTTP/1.1") => false,
+            Some("HTTP/1.0") => true,
+            _ => return Err(ProtocolError::InvalidStatusLine),
+        };
+
+        let code = parts
+            .next()
+            .filter(|code| code.len() == 3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .filter(|code| (100..600).contains(code))
+            .ok_or(ProtocolError::InvalidStatusLine)?;
+
+        self.status = StatusCode(code);
+        Ok(())
+    }
+
+    fn parse_header(&mut self, line: &[u8]) -> Result<(), ProtocolError> {
+        let line = core::str::from_utf8(line).map_err(|_| ProtocolError::InvalidHeader)?;
+        let (name, value) = line.split_once(':').ok_or(ProtocolError::InvalidHeader)?;
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("content-length") {
+            let length = value
+                .parse()
+                .map_err(|_| ProtocolError::InvalidContentLength)?;
+            if self
+                .content_length
+                .is_some_and(|existing| existing != length)
+            {
+                return Err(ProtocolError::InvalidContentLength);
+            }
+            self.content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            self.chunked = value
+                .rsplit(',')
+                .next()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        } else if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    self.connection_close = true;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    self.connection_keep_alive = true;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn end_of_headers(&mut self) {
+        self.state = if !self.status.has_body() {
+            State::Done
+        } else if self.chunked {
+            State::ChunkSize
+        } else {
+            match self.content_length {
+                Some(0) => State::Done,
+                Some(length) => State::Body(length),
+                None => State::UntilClose,
+            }
+        };
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize, ProtocolError> {
+    let line = core::str::from_utf8(line).map_err(|_| ProtocolError::InvalidChunkSize)?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+
+    if size.is_empty() {
+        return Err(ProtocolError::InvalidChunkSize);
+    }
+
+    usize::from_str_radix(size, 16).map_err(|_| ProtocolError::InvalidChunkSize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum UrlError {
+    UnsupportedScheme,
+    MissingHost,
+    InvalidPort,
+}
+
+/// An `http://host[:port]` collector address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Url<'a> {
+    authority: &'a str,
+    host: &'a str,
+    port: u16,
+}
+
+impl<'a> Url<'a> {
+    pub const DEFAULT_PORT: u16 = 80;
+
+    /// Parses `http://host:port`, `host:port` or `host`. Any path after the authority is
+    /// ignored, request paths are chosen per call.
+    pub fn parse(url: &'a str) -> Result<Self, UrlError> {
+        let rest = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some(_) => return Err(UrlError::UnsupportedScheme),
+            None => url,
+        };
+
+        let authority = rest.split('/').next().unwrap_or_default();
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| UrlError::InvalidPort)?),
+            None => (authority, Self::DEFAULT_PORT),
+        };
+
+        if host.is_empty() {
+            return Err(UrlError::MissingHost);
+        }
+
+        if port == 0 {
+            return Err(UrlError::InvalidPort);
+        }
+
+        Ok(Self {
+            authority,
+            host,
+            port,
+        })
+    }
+
+    /// `host[:port]` exactly as configured, suitable for the `Host:` header.
+    pub fn authority(&self) -> &'a str {
+        self.authority
+    }
+
+    pub fn host(&self) -> &'a str {
+        self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `chunks` one after the other, as separate socket reads, then the close.
+    fn parse(chunks: &[&[u8]]) -> Result<Response, ProtocolError> {
+        let mut parser = ResponseParser::new();
+        for chunk in chunks {
+            parser.feed(chunk)?;
+            if let Some(response) = parser.response() {
+                return Ok(response);
+            }
+        }
+        parser.finish()
+    }
+
+    #[test]
+    fn status_line() {
+        let response = parse(&[b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n"]).unwrap();
+        assert_eq!(response.status, StatusCode(201));
+
+        // The reason phrase is optional.
+        let response = parse(&[b"HTTP/1.1 204\r\n\r\n"]).unwrap();
+        assert_eq!(response.status, StatusCode(204));
+
+        for line in [
+            &b"garbage\r\n"[..],
+            b"HTTP/2 200 OK\r\n",
+            b"HTTP/1.1 20 OK\r\n",
+            b"HTTP/1.1 999 Nope\r\n",
+            b"HTTP/1.1 abc OK\r\n",
+        ] {
+            assert_eq!(parse(&[line]), Err(ProtocolError::InvalidStatusLine));
+        }
+    }
+
+    #[test]
+    fn content_length() {
+        let response = parse(&[b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"]).unwrap();
+        assert_eq!(
+            response,
+            Response {
+                status: StatusCode(200),
+                keep_alive: true,
+                body_len: 5,
+            }
+        );
+
+        // Split across reads, headers in any case.
+        let response = parse(&[
+            b"HTTP/1.1 200 OK\r\ncontent-LENGTH:",
+            b" 3\r\n\r",
+            b"\nab",
+            b"c",
+        ]);
+        assert_eq!(response.unwrap().body_len, 3);
+
+        assert_eq!(
+            parse(&[b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc"]),
+            Err(ProtocolError::UnexpectedEof)
+        );
+        assert_eq!(
+            parse(&[b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n"]),
+            Err(ProtocolError::InvalidContentLength)
+        );
+        assert_eq!(
+            parse(&[b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"]),
+            Err(ProtocolError::InvalidContentLength)
+        );
+        assert_eq!(
+            parse(&[b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n"]),
+            Err(ProtocolError::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn leaves_the_next_response_alone() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.1";
+        let mut parser = ResponseParser::new();
+        assert_eq!(parser.feed(raw), Ok(raw.len() - 8));
+        assert!(parser.is_complete());
+    }
+
+    #[test]
+    fn chunked() {
+        let raw = b"HTTP/1.1 500 Internal Server Error\r\n\
+Transfer-Encoding: chunked\r\n\r\n\
+4\r\nWiki\r\n5;name=value\r\npedia\r\n0\r\nTrailer: x\r\n\r\nEXTRA";
+
+        // One byte per read, the worst case.
+        let mut parser = ResponseParser::new();
+        let consumed: usize = raw
+            .iter()
+            .map(|byte| parser.feed(core::slice::from_ref(byte)).unwrap())
+            .sum();
+
+        assert_eq!(consumed, raw.len() - b"EXTRA".len());
+        assert_eq!(
+            parser.response(),
+            Some(Response {
+                status: StatusCode(500),
+                keep_alive: true,
+                body_len: 9,
+            })
+        );
+
+        assert_eq!(
+            parse(&[b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"]),
+            Err(ProtocolError::InvalidChunkSize)
+        );
+        assert_eq!(
+            parse(&[b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabX\r\n"]),
+            Err(ProtocolError::InvalidChunkTerminator)
+        );
+        assert_eq!(
+            parse(&[b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nab"]),
+            Err(ProtocolError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn keep_alive() {
+        // Delimited by the close, so the connection can't be reused.
+        let response = parse(&[b"HTTP/1.0 404 Not Found\r\n\r\nabc", b"de"]).unwrap();
+        assert_eq!(response.body_len, 5);
+        assert!(!response.keep_alive);
+
+        let response = parse(&[b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"]);
+        assert!(!response.unwrap().keep_alive);
+
+        let response =
+            parse(&[b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n"]);
+        assert!(response.unwrap().keep_alive);
+    }
+
+    #[test]
+    fn classification() {
+        let class = |code| {
+            let status = StatusCode(code);
+            [
+                status.is_informational(),
+                status.is_success(),
+                status.is_redirection(),
+                status.is_client_error(),
+                status.is_server_error(),
+            ]
+            .iter()
+            .position(|&is| is)
+        };
+
+        assert_eq!(class(100), Some(0));
+        assert_eq!(class(200), Some(1));
+        assert_eq!(class(299), Some(1));
+        assert_eq!(class(301), Some(2));
+        assert_eq!(class(400), Some(3));
+        assert_eq!(class(404), Some(3));
+        assert_eq!(class(499), Some(3));
+        assert_eq!(class(500), Some(4));
+        assert_eq!(class(503), Some(4));
+        assert_eq!(class(600), None);
+    }
+
+    #[test]
+    fn request_head() {
+        let mut buf = [0u8; 256];
+        let request = Request::post("collector.lan:8080", "/reading").json(b"{}");
+
+        assert_eq!(
+            request.write_head(&mut buf).unwrap(),
+            b"POST /reading HTTP/1.1\r\n\
+Host: collector.lan:8080\r\n\
+Content-Type: application/json\r\n\
+Content-Length: 2\r\n\
+Connection: keep-alive\r\n\r\n"
+        );
+        assert_eq!(
+            request.write_head(&mut [0u8; 16]),
+            Err(HttpError::BufferTooSmall)
+        );
+
+        let request = Request::post("h", "/write")
+            .text(b"m v=1\n")
+            .authorization("Token abc");
+        assert_eq!(
+            request.write_head(&mut buf).unwrap(),
+            b"POST /write HTTP/1.1\r\n\
+Host: h\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Length: 6\r\n\
+Authorization: Token abc\r\n\
+Connection: keep-alive\r\n\r\n"
+        );
+    }
+}
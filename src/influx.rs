@@ -0,0 +1,286 @@
+use core::fmt::{self, Write};
+
+use defmt::Format;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum InfluxError {
+    EmptyMeasurement,
+    EmptyKey,
+    /// Line protocol needs at least one field.
+    NoFields,
+    /// NaN and infinities can't be written.
+    NonFiniteFloat,
+    BufferTooSmall,
+}
+
+impl From<fmt::Error> for InfluxError {
+    fn from(_: fmt::Error) -> Self {
+        InfluxError::BufferTooSmall
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue<'a> {
+    Float(f64),
+    Integer(i64),
+    Boolean(bool),
+    String(&'a str),
+}
+
+/// One line of line protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point<'a> {
+    pub measurement: &'a str,
+    /// Tags with an empty value are left out, line protocol doesn't allow them.
+    pub tags: &'a [(&'a str, &'a str)],
+    pub fields: &'a [(&'a str, FieldValue<'a>)],
+    /// Unix time in nanoseconds. Without one the server stamps the point on arrival.
+    pub timestamp_ns: Option<i64>,
+}
+
+impl Point<'_> {
+    /// Writes the point followed by a newline.
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), InfluxError> {
+        if self.measurement.is_empty() {
+            return Err(InfluxError::EmptyMeasurement);
+        }
+
+        if self.fields.is_empty() {
+            return Err(InfluxError::NoFields);
+        }
+
+        escape(out, self.measurement, &[',', ' '])?;
+
+        for &(key, value) in self.tags {
+            if key.is_empty() {
+                return Err(InfluxError::EmptyKey);
+            }
+            if value.is_empty() {
+                continue;
+            }
+
+            out.write_char(',')?;
+            escape(out, key, &[',', '=', ' '])?;
+            out.write_char('=')?;
+            escape(out, value, &[',', '=', ' '])?;
+        }
+
+        for (i, &(key, value)) in self.fields.iter().enumerate() {
+            if key.is_empty() {
+                return Err(InfluxError::EmptyKey);
+            }
+
+            out.write_char(if i == 0 { ' ' } else { ',' })?;
+            escape(out, key, &[',', '=', ' '])?;
+            out.write_char('=')?;
+
+            match value {
+                FieldValue::Float(value) if !value.is_finite() => {
+                    return Err(InfluxError::NonFiniteFloat);
+                }
+                FieldValue::Float(value) => write!(out, "{}", value)?,
+                FieldValue::Integer(value) => write!(out, "{}i", value)?,
+                FieldValue::Boolean(value) => write!(out, "{}", value)?,
+                FieldValue::String(value) => {
+                    out.write_char('"')?;
+                    escape(out, value, &['"', '\\'])?;
+                    out.write_char('"')?;
+                }
+            }
+        }
+
+        if let Some(timestamp_ns) = self.timestamp_ns {
+            write!(out, " {}", timestamp_ns)?;
+        }
+
+        out.write_char('\n')?;
+        Ok(())
+    }
+}
+
+/// Writes `text` with a backslash before each character in `special`. Newlines can't be
+/// escaped in line protocol and would end the line, so they become spaces.
+fn escape<W: Write>(out: &mut W, text: &str, special: &[char]) -> fmt::Result {
+    for c in text.chars() {
+        match c {
+            '\n' | '\r' => {
+                if special.contains(&' ') {
+                    out.write_str("\\ ")?;
+                } else {
+                    out.write_char(' ')?;
+                }
+            }
+            c if special.contains(&c) => {
+                out.write_char('\\')?;
+                out.write_char(c)?;
+            }
+            c => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+    use crate::http::BodyWriter;
+
+    fn line(point: &Point<'_>) -> Result<String, InfluxError> {
+        let mut out = String::new();
+        point.write(&mut out)?;
+        Ok(out)
+    }
+
+    const POINT: Point<'static> = Point {
+        measurement: "environment",
+        tags: &[],
+        fields: &[("value", FieldValue::Integer(1))],
+        timestamp_ns: None,
+    };
+
+    #[test]
+    fn point() {
+        let point = Point {
+            tags: &[("node", "hm-a1b2c3"), ("location", "")],
+            fields: &[
+                ("temperature", FieldValue::Float(21.5)),
+                ("humidity", FieldValue::Float(40.0)),
+                ("samples", FieldValue::Integer(-3)),
+                ("heater", FieldValue::Boolean(true)),
+            ],
+            timestamp_ns: Some(1_700_000_000_123_456_000),
+            ..POINT
+        };
+
+        // The empty location is left out.
+        assert_eq!(
+            line(&point).unwrap(),
+            "environment,node=hm-a1b2c3 \
+             temperature=21.5,humidity=40,samples=-3i,heater=true 1700000000123456000\n"
+        );
+    }
+
+    #[test]
+    fn measurement_escaping() {
+        // An equals sign can't end a measurement, so it stays as it is.
+        let point = Point {
+            measurement: "my room,a=b",
+            ..POINT
+        };
+        assert_eq!(line(&point).unwrap(), "my\\ room\\,a=b value=1i\n");
+    }
+
+    #[test]
+    fn tag_escaping() {
+        let point = Point {
+            tags: &[("tag key", "living room,1=2"), ("a=b,c", "x")],
+            ..POINT
+        };
+        assert_eq!(
+            line(&point).unwrap(),
+            "environment,tag\\ key=living\\ room\\,1\\=2,a\\=b\\,c=x value=1i\n"
+        );
+    }
+
+    #[test]
+    fn field_key_escaping() {
+        let point = Point {
+            fields: &[
+                ("dew point", FieldValue::Float(9.5)),
+                ("a=b,c", FieldValue::Boolean(false)),
+            ],
+            ..POINT
+        };
+        assert_eq!(
+            line(&point).unwrap(),
+            "environment dew\\ point=9.5,a\\=b\\,c=false\n"
+        );
+    }
+
+    #[test]
+    fn string_fields() {
+        // Only quotes and backslashes need escaping inside the quotes.
+        let point = Point {
+            fields: &[("status", FieldValue::String("say \"hi\", a=b \\ now"))],
+            ..POINT
+        };
+        assert_eq!(
+            line(&point).unwrap(),
+            "environment status=\"say \\\"hi\\\", a=b \\\\ now\"\n"
+        );
+
+        let point = Point {
+            fields: &[("status", FieldValue::String(""))],
+            ..POINT
+        };
+        assert_eq!(line(&point).unwrap(), "environment status=\"\"\n");
+    }
+
+    #[test]
+    fn newlines_cannot_end_the_line() {
+        let point = Point {
+            measurement: "m\n",
+            tags: &[("location", "a\nb")],
+            fields: &[("status", FieldValue::String("x\r\ny"))],
+            ..POINT
+        };
+        assert_eq!(
+            line(&point).unwrap(),
+            "m\\ ,location=a\\ b status=\"x  y\"\n"
+        );
+    }
+
+    #[test]
+    fn invalid_points() {
+        let nan = Point {
+            fields: &[("value", FieldValue::Float(f64::NAN))],
+            ..POINT
+        };
+        assert_eq!(line(&nan), Err(InfluxError::NonFiniteFloat));
+        let infinite = Point {
+            fields: &[("value", FieldValue::Float(f64::INFINITY))],
+            ..POINT
+        };
+        assert_eq!(line(&infinite), Err(InfluxError::NonFiniteFloat));
+
+        assert_eq!(
+            line(&Point {
+                fields: &[],
+                ..POINT
+            }),
+            Err(InfluxError::NoFields)
+        );
+        assert_eq!(
+            line(&Point {
+                measurement: "",
+                ..POINT
+            }),
+            Err(InfluxError::EmptyMeasurement)
+        );
+        assert_eq!(
+            line(&Point {
+                tags: &[("", "x")],
+                ..POINT
+            }),
+            Err(InfluxError::EmptyKey)
+        );
+        assert_eq!(
+            line(&Point {
+                fields: &[("", FieldValue::Integer(1))],
+                ..POINT
+            }),
+            Err(InfluxError::EmptyKey)
+        );
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0; 8];
+        assert_eq!(
+            POINT.write(&mut BodyWriter::new(&mut buf)),
+            Err(InfluxError::BufferTooSmall)
+        );
+    }
+}
@@ -1,7 +1,25 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod alert;
+pub mod api;
 pub mod app;
+pub mod config;
+pub mod control;
+pub mod device;
+pub mod discovery;
+pub mod dns;
 pub mod drivers;
 pub mod error;
 pub mod events;
+pub mod filter;
+pub mod http;
+pub mod influx;
+pub mod metrics;
+pub mod mqtt;
+pub mod portal;
+pub mod psychro;
+pub mod queue;
+pub mod report;
+pub mod sntp;
+pub mod stats;
 pub mod tasks;
@@ -18,7 +18,7 @@ esp_bootloader_esp_idf::esp_app_desc!();
 async fn main(spawner: Spawner) {
     defmt::info!("reset reason: {}", esp_hal::system::reset_reason().unwrap() as usize);
 
-    if let Err(e) = app::run(spawner).await {
+    if let Err(e) = app::run(spawner, ESP_APP_DESC.version()).await {
         error!("Error during app::run - {}", e);
     }
 }
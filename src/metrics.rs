@@ -0,0 +1,558 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::drivers::environment::{MAX_SENSORS, SensorId};
+
+/// Prefix of every metric name.
+const NAMESPACE: &str = "home_monitor";
+
+/// Causes of failed uploads, as named by `UploadError::kind`.
+pub const UPLOAD_FAILURE_CAUSES: [&str; 8] = [
+    "encode",
+    "transport",
+    "connection_closed",
+    "timeout",
+    "protocol",
+    "client_error",
+    "server_error",
+    "unexpected_status",
+];
+
+/// Sensor errors, as named by `SensorError::label`.
+pub const SENSOR_ERROR_KINDS: [&str; 3] = ["bus", "timeout", "invalid_data"];
+
+/// Reasons readings are discarded, as named by `Rejection::label`.
+pub const REJECTIONS: [&str; 2] = ["out_of_range", "rate_exceeded"];
+
+/// Recovery steps, as named by `RecoveryStep::label`.
+pub const RECOVERY_STEPS: [&str; 3] = ["sensor_reset", "general_call_reset", "bus_recovery"];
+
+/// Uplinks, as named by their `UplinkQueue`.
+pub const UPLINKS: [&str; 3] = ["http", "mqtt", "influx"];
+
+/// The metrics every task reports into.
+pub static METRICS: Registry = Registry::new();
+
+/// A monotonically increasing count. Wraps after `u32::MAX`, which Prometheus treats as a
+/// counter reset.
+#[derive(Debug)]
+pub struct Counter(AtomicU32);
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u32) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down. Unset gauges are left out of the exposition, so a missing
+/// sensor doesn't look like a reading of zero.
+#[derive(Debug)]
+pub struct Gauge(AtomicU32);
+
+/// A NaN pattern no float operation produces, marking the gauge as unset.
+const UNSET: u32 = 0x7fc0_dead;
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(UNSET))
+    }
+
+    pub fn set(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        self.0.store(UNSET, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<f32> {
+        match self.0.load(Ordering::Relaxed) {
+            UNSET => None,
+            bits => Some(f32::from_bits(bits)),
+        }
+    }
+}
+
+/// One counter per label value.
+#[derive(Debug)]
+pub struct LabeledCounter<const N: usize> {
+    labels: [&'static str; N],
+    counters: [Counter; N],
+}
+
+impl<const N: usize> LabeledCounter<N> {
+    pub const fn new(labels: [&'static str; N]) -> Self {
+        Self {
+            labels,
+            counters: [const { Counter::new() }; N],
+        }
+    }
+
+    /// Counts one event for `label`. Labels not given to [`LabeledCounter::new`] are ignored.
+    pub fn inc(&self, label: &str) {
+        self.add(label, 1);
+    }
+
+    pub fn add(&self, label: &str, n: u32) {
+        if let Some(counter) = self.get_counter(label) {
+            counter.add(n);
+        }
+    }
+
+    pub fn get(&self, label: &str) -> Option<u32> {
+        self.get_counter(label).map(Counter::get)
+    }
+
+    fn get_counter(&self, label: &str) -> Option<&Counter> {
+        self.labels
+            .iter()
+            .position(|&l| l == label)
+            .map(|i| &self.counters[i])
+    }
+}
+
+/// One gauge per sensor, by `SensorId`. Unset gauges are left out like unlabeled ones.
+/// Labeled with the sensor names at scrape time, see [`Runtime::sensor_names`].
+#[derive(Debug)]
+pub struct SensorGauge {
+    gauges: [Gauge; MAX_SENSORS],
+}
+
+impl Default for SensorGauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SensorGauge {
+    pub const fn new() -> Self {
+        Self {
+            gauges: [const { Gauge::new() }; MAX_SENSORS],
+        }
+    }
+
+    pub fn set(&self, sensor: SensorId, value: f32) {
+        self.gauges[sensor.index()].set(value);
+    }
+
+    pub fn clear(&self, sensor: SensorId) {
+        self.gauges[sensor.index()].clear();
+    }
+
+    pub fn get(&self, sensor: SensorId) -> Option<f32> {
+        self.gauges[sensor.index()].get()
+    }
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    pub temperature: SensorGauge,
+    pub humidity: SensorGauge,
+    pub pressure: SensorGauge,
+    pub co2: SensorGauge,
+    pub rssi: Gauge,
+    pub sensor_errors: LabeledCounter<{ SENSOR_ERROR_KINDS.len() }>,
+    pub sensor_recoveries: LabeledCounter<{ RECOVERY_STEPS.len() }>,
+    pub rejected_readings: LabeledCounter<{ REJECTIONS.len() }>,
+    pub suppressed_readings: Counter,
+    pub uploads: Counter,
+    pub upload_failures: LabeledCounter<{ UPLOAD_FAILURE_CAUSES.len() }>,
+    pub wifi_reconnects: Counter,
+    pub dropped_readings: LabeledCounter<{ UPLINKS.len() }>,
+    pub uplink_failures: LabeledCounter<{ UPLINKS.len() }>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Self {
+            temperature: SensorGauge::new(),
+            humidity: SensorGauge::new(),
+            pressure: SensorGauge::new(),
+            co2: SensorGauge::new(),
+            rssi: Gauge::new(),
+            sensor_errors: LabeledCounter::new(SENSOR_ERROR_KINDS),
+            sensor_recoveries: LabeledCounter::new(RECOVERY_STEPS),
+            rejected_readings: LabeledCounter::new(REJECTIONS),
+            suppressed_readings: Counter::new(),
+            uploads: Counter::new(),
+            upload_failures: LabeledCounter::new(UPLOAD_FAILURE_CAUSES),
+            wifi_reconnects: Counter::new(),
+            dropped_readings: LabeledCounter::new(UPLINKS),
+            uplink_failures: LabeledCounter::new(UPLINKS),
+        }
+    }
+}
+
+/// Values read at scrape time rather than tracked in the registry.
+#[derive(Debug, Clone, Copy)]
+pub struct Runtime {
+    pub uptime_s: u64,
+    pub heap_used: usize,
+    pub heap_free: usize,
+    /// Labels of the sensor gauges, by `SensorId`, see `SensorId::name`.
+    pub sensor_names: [&'static str; MAX_SENSORS],
+}
+
+/// Writes metric families in the Prometheus text exposition format, version 0.0.4.
+pub struct Encoder<W> {
+    out: W,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) -> fmt::Result {
+        writeln!(self.out, "# HELP {}_{} {}", NAMESPACE, name, help)?;
+        writeln!(self.out, "# TYPE {}_{} {}", NAMESPACE, name, kind)
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: Option<f32>) -> fmt::Result {
+        self.header(name, "gauge", help)?;
+        match value {
+            Some(value) => writeln!(self.out, "{}_{} {}", NAMESPACE, name, value),
+            None => Ok(()),
+        }
+    }
+
+    /// Labels the gauge of each sensor with its entry in `sensors`.
+    pub fn sensor_gauge(
+        &mut self,
+        name: &str,
+        help: &str,
+        gauge: &SensorGauge,
+        sensors: &[&str; MAX_SENSORS],
+    ) -> fmt::Result {
+        self.header(name, "gauge", help)?;
+        for (value, gauge) in sensors.iter().zip(&gauge.gauges) {
+            if let Some(reading) = gauge.get() {
+                writeln!(
+                    self.out,
+                    "{}_{}{{sensor=\"{}\"}} {}",
+                    NAMESPACE, name, value, reading
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A gauge holding an integer, which would lose precision as an `f32`.
+    pub fn gauge_int(&mut self, name: &str, help: &str, value: u64) -> fmt::Result {
+        self.header(name, "gauge", help)?;
+        writeln!(self.out, "{}_{} {}", NAMESPACE, name, value)
+    }
+
+    /// `name` gets the `_total` suffix counters carry.
+    pub fn counter(&mut self, name: &str, help: &str, value: u32) -> fmt::Result {
+        self.header(name, "counter", help)?;
+        writeln!(self.out, "{}_{}_total {}", NAMESPACE, name, value)
+    }
+
+    pub fn labeled_counter<const N: usize>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        counter: &LabeledCounter<N>,
+    ) -> fmt::Result {
+        self.header(name, "counter", help)?;
+        for (value, count) in counter.labels.iter().zip(&counter.counters) {
+            writeln!(
+                self.out,
+                "{}_{}_total{{{}=\"{}\"}} {}",
+                NAMESPACE,
+                name,
+                label,
+                value,
+                count.get()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Registry {
+    /// Writes every metric, followed by the values in `runtime`.
+    pub fn encode<W: Write>(&self, runtime: &Runtime, out: W) -> Result<W, fmt::Error> {
+        let mut e = Encoder::new(out);
+
+        e.sensor_gauge(
+            "temperature_celsius",
+            "Last measured temperature.",
+            &self.temperature,
+            &runtime.sensor_names,
+        )?;
+        e.sensor_gauge(
+            "humidity_percent",
+            "Last measured relative humidity.",
+            &self.humidity,
+            &runtime.sensor_names,
+        )?;
+        e.sensor_gauge(
+            "pressure_hpa",
+            "Last measured air pressure.",
+            &self.pressure,
+            &runtime.sensor_names,
+        )?;
+        e.sensor_gauge(
+            "co2_ppm",
+            "Last measured CO2 concentration.",
+            &self.co2,
+            &runtime.sensor_names,
+        )?;
+        e.gauge(
+            "wifi_rssi_dbm",
+            "Signal strength of the Wi-Fi connection.",
+            self.rssi.get(),
+        )?;
+        e.labeled_counter(
+            "sensor_read_errors",
+            "Failed sensor reads by error.",
+            "kind",
+            &self.sensor_errors,
+        )?;
+        e.labeled_counter(
+            "sensor_recoveries",
+            "Recovery steps taken for sensors that kept failing.",
+            "step",
+            &self.sensor_recoveries,
+        )?;
+        e.labeled_counter(
+            "rejected_readings",
+            "Readings discarded as implausible by reason.",
+            "reason",
+            &self.rejected_readings,
+        )?;
+        e.counter(
+            "suppressed_readings",
+            "Readings held back from the uplinks as unchanged.",
+            self.suppressed_readings.get(),
+        )?;
+        e.counter(
+            "http_uploads",
+            "Uploads accepted by the collector.",
+            self.uploads.get(),
+        )?;
+        e.labeled_counter(
+            "http_upload_failures",
+            "Failed uploads by cause.",
+            "cause",
+            &self.upload_failures,
+        )?;
+        e.counter(
+            "wifi_reconnects",
+            "Times the Wi-Fi connection was lost.",
+            self.wifi_reconnects.get(),
+        )?;
+        e.labeled_counter(
+            "dropped_readings",
+            "Readings discarded because an uplink queue was full.",
+            "uplink",
+            &self.dropped_readings,
+        )?;
+        e.labeled_counter(
+            "uplink_failures",
+            "Failed uplink attempts that will be retried.",
+            "uplink",
+            &self.uplink_failures,
+        )?;
+        e.gauge_int("uptime_seconds", "Time since boot.", runtime.uptime_s)?;
+        e.gauge_int("heap_used_bytes", "Heap in use.", runtime.heap_used as u64)?;
+        e.gauge_int(
+            "heap_free_bytes",
+            "Heap available.",
+            runtime.heap_free as u64,
+        )?;
+
+        Ok(e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+    use crate::api::RESPONSE_CAPACITY;
+    use crate::config::SENSOR_NAME_CAPACITY;
+    use crate::http::BodyWriter;
+
+    const RUNTIME: Runtime = Runtime {
+        uptime_s: 10,
+        heap_used: 100,
+        heap_free: 200,
+        sensor_names: ["indoor", "duct"],
+    };
+
+    #[test]
+    fn gauges() {
+        let mut e = Encoder::new(String::new());
+        e.gauge("rssi_dbm", "Signal.", Some(-67.5)).unwrap();
+        e.gauge("unset", "Not measured.", None).unwrap();
+        e.gauge_int("uptime_seconds", "Uptime.", u64::MAX).unwrap();
+
+        assert_eq!(
+            e.into_inner(),
+            "# HELP home_monitor_rssi_dbm Signal.\n\
+             # TYPE home_monitor_rssi_dbm gauge\n\
+             home_monitor_rssi_dbm -67.5\n\
+             # HELP home_monitor_unset Not measured.\n\
+             # TYPE home_monitor_unset gauge\n\
+             # HELP home_monitor_uptime_seconds Uptime.\n\
+             # TYPE home_monitor_uptime_seconds gauge\n\
+             home_monitor_uptime_seconds 18446744073709551615\n"
+        );
+    }
+
+    #[test]
+    fn sensor_gauges_leave_out_unset_sensors() {
+        let gauge = SensorGauge::new();
+        gauge.set(SensorId::at(1), 21.5);
+        assert_eq!(gauge.get(SensorId::at(0)), None);
+        assert_eq!(gauge.get(SensorId::at(1)), Some(21.5));
+
+        // Labeled with the names given at scrape time.
+        let mut e = Encoder::new(String::new());
+        e.sensor_gauge(
+            "temperature_celsius",
+            "Temperature.",
+            &gauge,
+            &["indoor", "attic"],
+        )
+        .unwrap();
+        assert_eq!(
+            e.into_inner(),
+            "# HELP home_monitor_temperature_celsius Temperature.\n\
+             # TYPE home_monitor_temperature_celsius gauge\n\
+             home_monitor_temperature_celsius{sensor=\"attic\"} 21.5\n"
+        );
+
+        gauge.clear(SensorId::at(1));
+        assert_eq!(gauge.get(SensorId::at(1)), None);
+    }
+
+    #[test]
+    fn counters_get_the_total_suffix() {
+        let counter = LabeledCounter::new(["bus", "timeout"]);
+        counter.inc("timeout");
+        counter.add("timeout", 2);
+        counter.inc("unknown");
+        assert_eq!(counter.get("unknown"), None);
+
+        let mut e = Encoder::new(String::new());
+        e.counter("uploads", "Uploads.", 3).unwrap();
+        e.labeled_counter("errors", "Errors.", "kind", &counter)
+            .unwrap();
+
+        // Labels with no events yet still show, as zero.
+        assert_eq!(
+            e.into_inner(),
+            "# HELP home_monitor_uploads Uploads.\n\
+             # TYPE home_monitor_uploads counter\n\
+             home_monitor_uploads_total 3\n\
+             # HELP home_monitor_errors Errors.\n\
+             # TYPE home_monitor_errors counter\n\
+             home_monitor_errors_total{kind=\"bus\"} 0\n\
+             home_monitor_errors_total{kind=\"timeout\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn registry() {
+        let registry = Registry::new();
+        registry.humidity.set(SensorId::at(0), 45.0);
+        registry.sensor_errors.inc("timeout");
+        registry.uploads.add(3);
+        registry.dropped_readings.add("mqtt", 2);
+
+        let out = registry.encode(&RUNTIME, String::new()).unwrap();
+        assert!(out.contains("home_monitor_humidity_percent{sensor=\"indoor\"} 45\n"));
+        assert!(!out.contains("home_monitor_humidity_percent{sensor=\"duct\"}"));
+        assert!(!out.contains("\nhome_monitor_temperature_celsius"));
+        assert!(!out.contains("\nhome_monitor_wifi_rssi_dbm"));
+        assert!(out.contains("home_monitor_sensor_read_errors_total{kind=\"timeout\"} 1\n"));
+        assert!(out.contains("home_monitor_sensor_read_errors_total{kind=\"bus\"} 0\n"));
+        assert!(out.contains("home_monitor_http_uploads_total 3\n"));
+        assert!(out.contains("home_monitor_dropped_readings_total{uplink=\"mqtt\"} 2\n"));
+        assert!(out.contains("home_monitor_uptime_seconds 10\n"));
+        assert!(out.ends_with("home_monitor_heap_free_bytes 200\n"));
+    }
+
+    #[test]
+    fn full_registry_fits_the_response() {
+        // The longest a sample can print, every digit of a tiny negative value.
+        let longest = -f32::MIN_POSITIVE;
+
+        let registry = Registry::new();
+        for gauges in [
+            &registry.temperature,
+            &registry.humidity,
+            &registry.pressure,
+            &registry.co2,
+        ] {
+            gauges.gauges.iter().for_each(|gauge| gauge.set(longest));
+        }
+        registry.rssi.set(longest);
+
+        for counter in [&registry.suppressed_readings, &registry.uploads] {
+            counter.add(u32::MAX);
+        }
+        let labeled = registry
+            .sensor_errors
+            .counters
+            .iter()
+            .chain(&registry.sensor_recoveries.counters)
+            .chain(&registry.rejected_readings.counters)
+            .chain(&registry.upload_failures.counters)
+            .chain(&registry.dropped_readings.counters)
+            .chain(&registry.uplink_failures.counters);
+        labeled.for_each(|counter| counter.add(u32::MAX));
+        registry.wifi_reconnects.add(u32::MAX);
+
+        let longest_name = core::str::from_utf8(&[b'x'; SENSOR_NAME_CAPACITY]).unwrap();
+        let runtime = Runtime {
+            uptime_s: u64::MAX,
+            heap_used: usize::MAX,
+            heap_free: usize::MAX,
+            sensor_names: [longest_name; MAX_SENSORS],
+        };
+        let mut buf = [0; RESPONSE_CAPACITY];
+        let out = registry.encode(&runtime, BodyWriter::new(&mut buf));
+        assert!(out.is_ok());
+    }
+}
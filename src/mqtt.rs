@@ -0,0 +1,675 @@
+use defmt::Format;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// The remaining length field is a variable length integer of at most four bytes.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MqttError {
+    BufferTooSmall,
+    MalformedPacket,
+    UnexpectedPacket(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ProtocolVersion {
+    V311,
+    V5,
+}
+
+impl ProtocolVersion {
+    fn level(self) -> u8 {
+        match self {
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LastWill<'a> {
+    pub topic: &'a str,
+    pub message: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Connect<'a> {
+    pub version: ProtocolVersion,
+    pub client_id: &'a str,
+    pub keep_alive_secs: u16,
+    pub clean_session: bool,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+    pub will: Option<LastWill<'a>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Publish<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+    /// Required for [`QoS::AtLeastOnce`], ignored otherwise.
+    pub packet_id: u16,
+}
+
+/// Subscribes to a single topic filter.
+#[derive(Debug, Clone, Copy)]
+pub struct Subscribe<'a> {
+    pub topic: &'a str,
+    /// Highest QoS the broker may deliver with.
+    pub qos: QoS,
+    pub packet_id: u16,
+}
+
+/// Packets a client can receive from the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Packet<'a> {
+    ConnAck {
+        session_present: bool,
+        /// Return code (3.1.1) or reason code (5), zero means accepted.
+        code: u8,
+    },
+    PubAck {
+        packet_id: u16,
+    },
+    SubAck {
+        packet_id: u16,
+        /// Granted QoS, or a failure code from 0x80 up.
+        code: u8,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+        qos: u8,
+        packet_id: Option<u16>,
+    },
+    PingResp,
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), MqttError> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), MqttError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), MqttError> {
+        let end = self.pos + data.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(MqttError::BufferTooSmall)?
+            .copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn binary(&mut self, data: &[u8]) -> Result<(), MqttError> {
+        let len = u16::try_from(data.len()).map_err(|_| MqttError::BufferTooSmall)?;
+        self.u16(len)?;
+        self.bytes(data)
+    }
+
+    fn string(&mut self, value: &str) -> Result<(), MqttError> {
+        self.binary(value.as_bytes())
+    }
+
+    fn varint(&mut self, mut value: usize) -> Result<(), MqttError> {
+        if value > MAX_REMAINING_LENGTH {
+            return Err(MqttError::BufferTooSmall);
+        }
+
+        loop {
+            let mut byte = (value % 128) as u8;
+            value /= 128;
+            if value > 0 {
+                byte |= 0x80;
+            }
+            self.u8(byte)?;
+            if value == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn finish(self) -> usize {
+        self.pos
+    }
+}
+
+fn binary_len(data: &[u8]) -> usize {
+    2 + data.len()
+}
+
+/// Properties are always sent empty, so under MQTT 5 each property block is one zero byte.
+fn properties_len(version: ProtocolVersion) -> usize {
+    match version {
+        ProtocolVersion::V311 => 0,
+        ProtocolVersion::V5 => 1,
+    }
+}
+
+fn write_empty_properties(w: &mut Writer<'_>, version: ProtocolVersion) -> Result<(), MqttError> {
+    match version {
+        ProtocolVersion::V311 => Ok(()),
+        ProtocolVersion::V5 => w.varint(0),
+    }
+}
+
+impl Connect<'_> {
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, MqttError> {
+        let mut flags = 0u8;
+        let mut remaining = binary_len(b"MQTT") + 1 + 1 + 2 + properties_len(self.version);
+
+        remaining += binary_len(self.client_id.as_bytes());
+
+        if self.clean_session {
+            flags |= 0x02;
+        }
+
+        if let Some(will) = &self.will {
+            flags |= 0x04 | ((will.qos as u8) << 3);
+            if will.retain {
+                flags |= 0x20;
+            }
+            remaining += properties_len(self.version)
+                + binary_len(will.topic.as_bytes())
+                + binary_len(will.message);
+        }
+
+        if let Some(username) = self.username {
+            flags |= 0x80;
+            remaining += binary_len(username.as_bytes());
+        }
+
+        if let Some(password) = self.password {
+            flags |= 0x40;
+            remaining += binary_len(password);
+        }
+
+        let mut w = Writer::new(buf);
+        w.u8(CONNECT << 4)?;
+        w.varint(remaining)?;
+        w.string("MQTT")?;
+        w.u8(self.version.level())?;
+        w.u8(flags)?;
+        w.u16(self.keep_alive_secs)?;
+        write_empty_properties(&mut w, self.version)?;
+        w.string(self.client_id)?;
+
+        if let Some(will) = &self.will {
+            write_empty_properties(&mut w, self.version)?;
+            w.string(will.topic)?;
+            w.binary(will.message)?;
+        }
+
+        if let Some(username) = self.username {
+            w.string(username)?;
+        }
+
+        if let Some(password) = self.password {
+            w.binary(password)?;
+        }
+
+        Ok(w.finish())
+    }
+}
+
+impl Publish<'_> {
+    pub fn encode(&self, buf: &mut [u8], version: ProtocolVersion) -> Result<usize, MqttError> {
+        let mut header = (PUBLISH << 4) | ((self.qos as u8) << 1);
+        if self.dup {
+            header |= 0x08;
+        }
+        if self.retain {
+            header |= 0x01;
+        }
+
+        let packet_id_len = match self.qos {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 2,
+        };
+
+        let remaining = binary_len(self.topic.as_bytes())
+            + packet_id_len
+            + properties_len(version)
+            + self.payload.len();
+
+        let mut w = Writer::new(buf);
+        w.u8(header)?;
+        w.varint(remaining)?;
+        w.string(self.topic)?;
+        if self.qos == QoS::AtLeastOnce {
+            w.u16(self.packet_id)?;
+        }
+        write_empty_properties(&mut w, version)?;
+        w.bytes(self.payload)?;
+
+        Ok(w.finish())
+    }
+}
+
+impl Subscribe<'_> {
+    pub fn encode(&self, buf: &mut [u8], version: ProtocolVersion) -> Result<usize, MqttError> {
+        let remaining = 2 + properties_len(version) + binary_len(self.topic.as_bytes()) + 1;
+
+        let mut w = Writer::new(buf);
+        // The reserved flags of SUBSCRIBE are fixed at 0b0010.
+        w.u8((SUBSCRIBE << 4) | 0x02)?;
+        w.varint(remaining)?;
+        w.u16(self.packet_id)?;
+        write_empty_properties(&mut w, version)?;
+        w.string(self.topic)?;
+        w.u8(self.qos as u8)?;
+
+        Ok(w.finish())
+    }
+}
+
+/// Acknowledges a QoS 1 publish received from the broker.
+pub fn encode_puback(buf: &mut [u8], packet_id: u16) -> Result<usize, MqttError> {
+    let mut w = Writer::new(buf);
+    w.u8(PUBACK << 4)?;
+    w.varint(2)?;
+    w.u16(packet_id)?;
+    Ok(w.finish())
+}
+
+pub fn encode_disconnect(buf: &mut [u8]) -> Result<usize, MqttError> {
+    let mut w = Writer::new(buf);
+    w.u8(DISCONNECT << 4)?;
+    w.varint(0)?;
+    Ok(w.finish())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, MqttError> {
+        let value = *self.buf.get(self.pos).ok_or(MqttError::MalformedPacket)?;
+        self.pos += 1;
+        Ok(value)
+    }
+
+    fn u16(&mut self) -> Result<u16, MqttError> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MqttError> {
+        let data = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(MqttError::MalformedPacket)?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn string(&mut self) -> Result<&'a str, MqttError> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| MqttError::MalformedPacket)
+    }
+
+    fn skip_properties(&mut self, version: ProtocolVersion) -> Result<(), MqttError> {
+        if version == ProtocolVersion::V5 {
+            let len = self.varint()?;
+            self.take(len)?;
+        }
+        Ok(())
+    }
+
+    fn varint(&mut self) -> Result<usize, MqttError> {
+        let mut value = 0usize;
+
+        for shift in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << (7 * shift);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(MqttError::MalformedPacket)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+}
+
+/// Splits a fixed header off `buf`. Returns the header byte, the offset of the variable
+/// header and the total packet length, or `None` if more bytes are needed.
+fn fixed_header(buf: &[u8]) -> Result<Option<(u8, usize, usize)>, MqttError> {
+    let Some(&header) = buf.first() else {
+        return Ok(None);
+    };
+
+    let mut remaining = 0usize;
+
+    for i in 0..4 {
+        let Some(&byte) = buf.get(1 + i) else {
+            return Ok(None);
+        };
+
+        remaining |= ((byte & 0x7f) as usize) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            let start = 2 + i;
+            let total = start + remaining;
+            return Ok((buf.len() >= total).then_some((header, start, total)));
+        }
+    }
+
+    Err(MqttError::MalformedPacket)
+}
+
+impl<'a> Packet<'a> {
+    /// Decodes one packet from the front of `buf` and returns it together with its length.
+    /// Returns `Ok(None)` if `buf` doesn't hold a complete packet yet.
+    pub fn decode(
+        buf: &'a [u8],
+        version: ProtocolVersion,
+    ) -> Result<Option<(Self, usize)>, MqttError> {
+        let Some((header, start, total)) = fixed_header(buf)? else {
+            return Ok(None);
+        };
+
+        let mut r = Reader {
+            buf: &buf[start..total],
+            pos: 0,
+        };
+
+        let packet = match header >> 4 {
+            CONNACK => {
+                let flags = r.u8()?;
+                let code = r.u8()?;
+                Packet::ConnAck {
+                    session_present: flags & 0x01 != 0,
+                    code,
+                }
+            }
+            PUBACK => Packet::PubAck {
+                packet_id: r.u16()?,
+            },
+            SUBACK => {
+                let packet_id = r.u16()?;
+                r.skip_properties(version)?;
+                Packet::SubAck {
+                    packet_id,
+                    code: r.u8()?,
+                }
+            }
+            PINGRESP => Packet::PingResp,
+            PUBLISH => {
+                let qos = (header >> 1) & 0x03;
+                let topic = r.string()?;
+                let packet_id = if qos > 0 { Some(r.u16()?) } else { None };
+                r.skip_properties(version)?;
+                Packet::Publish {
+                    topic,
+                    payload: r.rest(),
+                    qos,
+                    packet_id,
+                }
+            }
+            other => return Err(MqttError::UnexpectedPacket(other)),
+        };
+
+        Ok(Some((packet, total)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(version: ProtocolVersion) -> Connect<'static> {
+        Connect {
+            version,
+            client_id: "node",
+            keep_alive_secs: 60,
+            clean_session: true,
+            username: None,
+            password: None,
+            will: None,
+        }
+    }
+
+    #[test]
+    fn varint_boundaries() {
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (MAX_REMAINING_LENGTH, &[0xff, 0xff, 0xff, 0x7f]),
+        ] {
+            let mut buf = [0u8; 4];
+            let mut w = Writer::new(&mut buf);
+            w.varint(value).unwrap();
+            let len = w.finish();
+            assert_eq!(&buf[..len], encoded, "{value}");
+
+            let mut r = Reader {
+                buf: encoded,
+                pos: 0,
+            };
+            assert_eq!(r.varint(), Ok(value));
+        }
+
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            Writer::new(&mut buf).varint(MAX_REMAINING_LENGTH + 1),
+            Err(MqttError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn overlong_varint() {
+        let mut r = Reader {
+            buf: &[0xff, 0xff, 0xff, 0xff, 0x7f],
+            pos: 0,
+        };
+        assert_eq!(r.varint(), Err(MqttError::MalformedPacket));
+
+        assert_eq!(
+            Packet::decode(b"\x30\xff\xff\xff\xff\x7f", ProtocolVersion::V311),
+            Err(MqttError::MalformedPacket)
+        );
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let packet = b"\x32\x0a\x00\x01t\x00\x09\x02\x01\x01on";
+
+        for len in 0..packet.len() {
+            assert_eq!(
+                Packet::decode(&packet[..len], ProtocolVersion::V5),
+                Ok(None),
+                "{len} bytes"
+            );
+        }
+
+        // A fixed header that promises 128 bytes but carries one.
+        assert_eq!(
+            Packet::decode(b"\x30\x80\x01\x00", ProtocolVersion::V311),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn decode_publish() {
+        assert_eq!(
+            Packet::decode(b"\x30\x05\x00\x01ton", ProtocolVersion::V311),
+            Ok(Some((
+                Packet::Publish {
+                    topic: "t",
+                    payload: b"on",
+                    qos: 0,
+                    packet_id: None,
+                },
+                7
+            )))
+        );
+
+        // The payload format indicator property is skipped.
+        assert_eq!(
+            Packet::decode(
+                b"\x32\x0a\x00\x01t\x00\x09\x02\x01\x01on",
+                ProtocolVersion::V5
+            ),
+            Ok(Some((
+                Packet::Publish {
+                    topic: "t",
+                    payload: b"on",
+                    qos: 1,
+                    packet_id: Some(9),
+                },
+                12
+            )))
+        );
+
+        // Anything after the packet is left for the next call.
+        assert_eq!(
+            Packet::decode(b"\x30\x05\x00\x01ton\xd0\x00", ProtocolVersion::V311)
+                .unwrap()
+                .map(|(_, len)| len),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn decode_acks() {
+        let v311 = ProtocolVersion::V311;
+        assert_eq!(
+            Packet::decode(b"\x20\x02\x01\x00", v311),
+            Ok(Some((
+                Packet::ConnAck {
+                    session_present: true,
+                    code: 0
+                },
+                4
+            )))
+        );
+        assert_eq!(
+            Packet::decode(b"\x40\x02\x00\x07", v311),
+            Ok(Some((Packet::PubAck { packet_id: 7 }, 4)))
+        );
+        assert_eq!(
+            Packet::decode(b"\x90\x04\x00\x03\x00\x80", ProtocolVersion::V5),
+            Ok(Some((
+                Packet::SubAck {
+                    packet_id: 3,
+                    code: 0x80
+                },
+                6
+            )))
+        );
+        assert_eq!(
+            Packet::decode(b"\xd0\x00", v311),
+            Ok(Some((Packet::PingResp, 2)))
+        );
+        assert_eq!(
+            Packet::decode(b"\xf0\x00", v311),
+            Err(MqttError::UnexpectedPacket(15))
+        );
+    }
+
+    #[test]
+    fn encode_connect() {
+        let mut buf = [0u8; 64];
+
+        let len = connect(ProtocolVersion::V311).encode(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"\x10\x10\x00\x04MQTT\x04\x02\x00\x3c\x00\x04node"
+        );
+
+        let connect = Connect {
+            username: Some("u"),
+            password: Some(b"p"),
+            will: Some(LastWill {
+                topic: "t/s",
+                message: b"offline",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..connect(ProtocolVersion::V5)
+        };
+        let len = connect.encode(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"\x10\x26\x00\x04MQTT\x05\xee\x00\x3c\x00\x00\x04node\
+\x00\x00\x03t/s\x00\x07offline\x00\x01u\x00\x01p"
+        );
+
+        assert_eq!(
+            connect.encode(&mut buf[..len - 1]),
+            Err(MqttError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn encode_publish() {
+        let mut buf = [0u8; 256];
+
+        let publish = Publish {
+            topic: "a/b",
+            payload: b"21.5",
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            dup: false,
+            packet_id: 7,
+        };
+        let len = publish.encode(&mut buf, ProtocolVersion::V311).unwrap();
+        assert_eq!(&buf[..len], b"\x32\x0b\x00\x03a/b\x00\x0721.5");
+        let len = publish.encode(&mut buf, ProtocolVersion::V5).unwrap();
+        assert_eq!(&buf[..len], b"\x32\x0c\x00\x03a/b\x00\x07\x0021.5");
+
+        // QoS 0 has no packet id, and 203 bytes need a two byte remaining length.
+        let payload = [0u8; 200];
+        let publish = Publish {
+            topic: "a",
+            payload: &payload,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            ..publish
+        };
+        let len = publish.encode(&mut buf, ProtocolVersion::V311).unwrap();
+        assert_eq!(&buf[..5], b"\x31\xcb\x01\x00\x01");
+        assert_eq!(len, 3 + 203);
+    }
+}
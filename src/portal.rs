@@ -0,0 +1,461 @@
+use core::fmt::{self, Write};
+
+use defmt::Format;
+use heapless::{String, Vec};
+
+use crate::config::{
+    DEFAULT_MQTT_PORT, LOCATION_CAPACITY, MQTT_HOST_CAPACITY, MQTT_PASSWORD_CAPACITY,
+    MQTT_USERNAME_CAPACITY, NodeConfig, PASSWORD_CAPACITY, SSID_CAPACITY, URL_CAPACITY,
+};
+use crate::http::{BodyWriter, Url};
+
+/// Networks listed in the form, strongest first.
+pub const SCAN_CAPACITY: usize = 16;
+
+pub type ScanList = Vec<String<SSID_CAPACITY>, SCAN_CAPACITY>;
+
+pub const SAVED_PAGE: &str = "<!DOCTYPE html><html><head>\
+<meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+<title>home-monitor setup</title></head><body>\
+<h1>Saved</h1><p>The node is rebooting and will join the network.</p>\
+</body></html>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum FormError {
+    MissingSsid,
+    InvalidEncoding,
+    TooLong,
+    InvalidCollectorUrl,
+    InvalidBrokerPort,
+    BufferTooSmall,
+}
+
+impl FormError {
+    /// Shown above the form when it is served again.
+    pub fn message(self) -> &'static str {
+        match self {
+            FormError::MissingSsid => "Choose a network or enter its name.",
+            FormError::InvalidEncoding => "The form could not be read, please try again.",
+            FormError::TooLong => "A value is too long.",
+            FormError::InvalidCollectorUrl => "The collector URL must look like http://host:port.",
+            FormError::InvalidBrokerPort => "The broker port must be a number from 1 to 65535.",
+            FormError::BufferTooSmall => "The page is too large.",
+        }
+    }
+}
+
+/// Settings submitted through the form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provisioning {
+    pub ssid: String<SSID_CAPACITY>,
+    pub password: String<PASSWORD_CAPACITY>,
+    /// `None` keeps the configured collector.
+    pub collector_url: Option<String<URL_CAPACITY>>,
+    /// Empty clears the location.
+    pub location: String<LOCATION_CAPACITY>,
+    /// Empty turns MQTT off.
+    pub mqtt_host: String<MQTT_HOST_CAPACITY>,
+    pub mqtt_port: u16,
+    pub mqtt_username: String<MQTT_USERNAME_CAPACITY>,
+    /// `None` keeps the configured password, like the collector URL.
+    pub mqtt_password: Option<String<MQTT_PASSWORD_CAPACITY>>,
+}
+
+impl Provisioning {
+    /// Decodes an `application/x-www-form-urlencoded` body. A name typed into `ssid_other`
+    /// wins over the one picked from the scan list, for hidden networks.
+    pub fn parse(body: &[u8]) -> Result<Self, FormError> {
+        let body = core::str::from_utf8(body).map_err(|_| FormError::InvalidEncoding)?;
+
+        let mut selected: String<SSID_CAPACITY> = String::new();
+        let mut typed: String<SSID_CAPACITY> = String::new();
+        let mut password = String::new();
+        let mut collector_url: String<URL_CAPACITY> = String::new();
+        let mut location: String<LOCATION_CAPACITY> = String::new();
+        let mut mqtt_host: String<MQTT_HOST_CAPACITY> = String::new();
+        let mut mqtt_port: String<5> = String::new();
+        let mut mqtt_username: String<MQTT_USERNAME_CAPACITY> = String::new();
+        let mut mqtt_password: String<MQTT_PASSWORD_CAPACITY> = String::new();
+
+        for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            match name {
+                "ssid" => selected = decode(value)?,
+                "ssid_other" => typed = decode(value)?,
+                "password" => password = decode(value)?,
+                "collector_url" => collector_url = decode(value)?,
+                "location" => location = decode(value)?,
+                "mqtt_host" => mqtt_host = decode(value)?,
+                "mqtt_port" => {
+                    mqtt_port = decode(value).map_err(|_| FormError::InvalidBrokerPort)?
+                }
+                "mqtt_username" => mqtt_username = decode(value)?,
+                "mqtt_password" => mqtt_password = decode(value)?,
+                _ => {}
+            }
+        }
+
+        let ssid = if typed.trim().is_empty() {
+            selected
+        } else {
+            typed
+        };
+
+        if ssid.is_empty() {
+            return Err(FormError::MissingSsid);
+        }
+
+        let collector_url = match collector_url.trim() {
+            "" => None,
+            url => {
+                Url::parse(url).map_err(|_| FormError::InvalidCollectorUrl)?;
+                Some(String::try_from(url).map_err(|_| FormError::TooLong)?)
+            }
+        };
+
+        let location = String::try_from(location.trim()).map_err(|_| FormError::TooLong)?;
+
+        let mqtt_host = String::try_from(mqtt_host.trim()).map_err(|_| FormError::TooLong)?;
+        let mqtt_port = match mqtt_port.trim() {
+            "" => DEFAULT_MQTT_PORT,
+            port => match port.parse() {
+                Ok(0) | Err(_) => return Err(FormError::InvalidBrokerPort),
+                Ok(port) => port,
+            },
+        };
+        let mqtt_username =
+            String::try_from(mqtt_username.trim()).map_err(|_| FormError::TooLong)?;
+        let mqtt_password = Some(mqtt_password).filter(|password| !password.is_empty());
+
+        Ok(Self {
+            ssid,
+            password,
+            collector_url,
+            location,
+            mqtt_host,
+            mqtt_port,
+            mqtt_username,
+            mqtt_password,
+        })
+    }
+
+    pub fn apply(self, config: &mut NodeConfig) {
+        config.wifi_ssid = self.ssid;
+        config.wifi_password = self.password;
+        if let Some(url) = self.collector_url {
+            config.collector_url = url;
+        }
+        config.location = self.location;
+        config.mqtt_host = self.mqtt_host;
+        config.mqtt_port = self.mqtt_port;
+        config.mqtt_username = self.mqtt_username;
+        if let Some(password) = self.mqtt_password {
+            config.mqtt_password = password;
+        }
+    }
+}
+
+/// Decodes one percent-encoded form value, `+` stands for a space.
+fn decode<const N: usize>(value: &str) -> Result<String<N>, FormError> {
+    let mut bytes: Vec<u8, N> = Vec::new();
+    let mut input = value.bytes();
+
+    while let Some(byte) = input.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hi = input.next().and_then(hex_digit);
+                let lo = input.next().and_then(hex_digit);
+                match (hi, lo) {
+                    (Some(hi), Some(lo)) => hi << 4 | lo,
+                    _ => return Err(FormError::InvalidEncoding),
+                }
+            }
+            byte => byte,
+        };
+
+        bytes.push(byte).map_err(|_| FormError::TooLong)?;
+    }
+
+    String::from_utf8(bytes).map_err(|_| FormError::InvalidEncoding)
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Writes `text` with the characters HTML gives meaning to escaped.
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders the setup form into `buf` and returns its length.
+pub fn render_form(
+    buf: &mut [u8],
+    node_id: &str,
+    networks: &[String<SSID_CAPACITY>],
+    config: &NodeConfig,
+    error: Option<FormError>,
+) -> Result<usize, FormError> {
+    let mut page = BodyWriter::new(buf);
+    write_form(&mut page, node_id, networks, config, error)
+        .map_err(|_| FormError::BufferTooSmall)?;
+    Ok(page.len())
+}
+
+fn write_form(
+    page: &mut BodyWriter<'_>,
+    node_id: &str,
+    networks: &[String<SSID_CAPACITY>],
+    config: &NodeConfig,
+    error: Option<FormError>,
+) -> fmt::Result {
+    write!(
+        page,
+        "<!DOCTYPE html><html><head>\
+<meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+<title>home-monitor setup</title></head><body>\
+<h1>home-monitor setup</h1><p>{}</p>",
+        Escaped(node_id)
+    )?;
+
+    if let Some(error) = error {
+        write!(page, "<p><b>{}</b></p>", error.message())?;
+    }
+
+    page.write_str(
+        "<form method=\"post\" action=\"/save\">\
+<p><label>Network<br><select name=\"ssid\">",
+    )?;
+
+    for ssid in networks {
+        let selected = if *ssid == config.wifi_ssid {
+            " selected"
+        } else {
+            ""
+        };
+        write!(page, "<option{}>{}</option>", selected, Escaped(ssid))?;
+    }
+
+    write!(
+        page,
+        "</select></label></p>\
+<p><label>Other network<br><input name=\"ssid_other\" maxlength=\"{}\"></label></p>\
+<p><label>Password<br><input name=\"password\" type=\"password\" maxlength=\"{}\"></label></p>\
+<p><label>Collector URL<br><input name=\"collector_url\" value=\"{}\" maxlength=\"{}\">\
+</label></p>\
+<p><label>Location<br><input name=\"location\" value=\"{}\" maxlength=\"{}\"></label></p>\
+<p><label>MQTT broker<br><input name=\"mqtt_host\" value=\"{}\" maxlength=\"{}\"></label></p>\
+<p><label>MQTT port<br><input name=\"mqtt_port\" type=\"number\" value=\"{}\" min=\"1\" \
+max=\"65535\"></label></p>\
+<p><label>MQTT user<br><input name=\"mqtt_username\" value=\"{}\" maxlength=\"{}\"></label></p>\
+<p><label>MQTT password<br><input name=\"mqtt_password\" type=\"password\" maxlength=\"{}\">\
+</label></p>\
+<p><button>Save and reboot</button></p></form></body></html>",
+        SSID_CAPACITY,
+        PASSWORD_CAPACITY,
+        Escaped(&config.collector_url),
+        URL_CAPACITY,
+        Escaped(&config.location),
+        LOCATION_CAPACITY,
+        Escaped(&config.mqtt_host),
+        MQTT_HOST_CAPACITY,
+        config.mqtt_port,
+        Escaped(&config.mqtt_username),
+        MQTT_USERNAME_CAPACITY,
+        MQTT_PASSWORD_CAPACITY
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long(c: char, len: usize) -> std::string::String {
+        core::iter::repeat_n(c, len).collect()
+    }
+
+    fn parse(body: &str) -> Result<Provisioning, FormError> {
+        Provisioning::parse(body.as_bytes())
+    }
+
+    #[test]
+    fn decoding() {
+        let form = parse("ssid=My+Net&password=p%40ss%26%2B%25&location=+Living+room+").unwrap();
+        assert_eq!(form.ssid, "My Net");
+        assert_eq!(form.password, "p@ss&+%");
+        assert_eq!(form.location, "Living room");
+
+        // Multi-byte UTF-8 survives, a stray byte doesn't.
+        assert_eq!(parse("ssid=Caf%C3%A9").unwrap().ssid, "Café");
+        assert_eq!(parse("ssid=%FF").unwrap_err(), FormError::InvalidEncoding);
+
+        for body in ["ssid=a%", "ssid=a%4", "ssid=a%zz"] {
+            assert_eq!(
+                parse(body).unwrap_err(),
+                FormError::InvalidEncoding,
+                "{body}"
+            );
+        }
+    }
+
+    #[test]
+    fn network_choice() {
+        // A typed name wins over the scan list, unless it's blank.
+        assert_eq!(parse("ssid=a&ssid_other=hidden").unwrap().ssid, "hidden");
+        assert_eq!(parse("ssid=a&ssid_other=++").unwrap().ssid, "a");
+        assert_eq!(parse("ssid_other=hidden").unwrap().ssid, "hidden");
+    }
+
+    #[test]
+    fn missing_fields() {
+        assert_eq!(parse("").unwrap_err(), FormError::MissingSsid);
+        assert_eq!(parse("password=x").unwrap_err(), FormError::MissingSsid);
+        assert_eq!(
+            parse("ssid=&ssid_other=").unwrap_err(),
+            FormError::MissingSsid
+        );
+
+        let form = parse("ssid=a&unknown=1&password").unwrap();
+        assert_eq!(form.password, "");
+        assert_eq!(form.collector_url, None);
+        assert_eq!(form.location, "");
+        assert_eq!(form.mqtt_host, "");
+        assert_eq!(form.mqtt_port, DEFAULT_MQTT_PORT);
+        assert_eq!(form.mqtt_password, None);
+    }
+
+    #[test]
+    fn over_capacity() {
+        let ssid = long('s', SSID_CAPACITY);
+        assert!(parse(&format!("ssid={ssid}")).is_ok());
+        assert_eq!(
+            parse(&format!("ssid={ssid}s")).unwrap_err(),
+            FormError::TooLong
+        );
+
+        let password = long('p', PASSWORD_CAPACITY);
+        assert!(parse(&format!("ssid=a&password={password}")).is_ok());
+        assert_eq!(
+            parse(&format!("ssid=a&password={password}p")).unwrap_err(),
+            FormError::TooLong
+        );
+
+        // Capacity counts decoded bytes, not the escapes.
+        let escaped = long('x', PASSWORD_CAPACITY / 2).replace('x', "%41");
+        assert!(parse(&format!("ssid=a&password={escaped}")).is_ok());
+
+        let url = format!("http://{}", long('h', URL_CAPACITY - "http://".len()));
+        assert!(parse(&format!("ssid=a&collector_url={url}")).is_ok());
+        assert_eq!(
+            parse(&format!("ssid=a&collector_url={url}h")).unwrap_err(),
+            FormError::TooLong
+        );
+
+        let host = long('m', MQTT_HOST_CAPACITY + 1);
+        assert_eq!(
+            parse(&format!("ssid=a&mqtt_host={host}")).unwrap_err(),
+            FormError::TooLong
+        );
+    }
+
+    #[test]
+    fn collector_url() {
+        let form = parse("ssid=a&collector_url=http%3A%2F%2Fcollector.lan%3A8080").unwrap();
+        assert_eq!(form.collector_url.unwrap(), "http://collector.lan:8080");
+
+        assert_eq!(
+            parse("ssid=a&collector_url=ftp%3A%2F%2Fx").unwrap_err(),
+            FormError::InvalidCollectorUrl
+        );
+    }
+
+    #[test]
+    fn broker() {
+        let form =
+            parse("ssid=a&mqtt_host=broker.lan&mqtt_port=8883&mqtt_username=u&mqtt_password=p")
+                .unwrap();
+        assert_eq!(form.mqtt_host, "broker.lan");
+        assert_eq!(form.mqtt_port, 8883);
+        assert_eq!(form.mqtt_username, "u");
+        assert_eq!(form.mqtt_password.as_deref(), Some("p"));
+
+        for port in ["0", "65536", "123456", "x", "-1"] {
+            assert_eq!(
+                parse(&format!("ssid=a&mqtt_port={port}")).unwrap_err(),
+                FormError::InvalidBrokerPort,
+                "{port}"
+            );
+        }
+    }
+
+    #[test]
+    fn apply() {
+        let mut config = NodeConfig {
+            collector_url: String::try_from("http://old:1").unwrap(),
+            mqtt_password: String::try_from("secret").unwrap(),
+            ..NodeConfig::default()
+        };
+
+        parse("ssid=a&password=b&location=Attic&mqtt_host=broker.lan")
+            .unwrap()
+            .apply(&mut config);
+
+        assert_eq!(config.wifi_ssid, "a");
+        assert_eq!(config.wifi_password, "b");
+        assert_eq!(config.location, "Attic");
+        assert_eq!(config.mqtt_host, "broker.lan");
+        // Left empty in the form, so kept.
+        assert_eq!(config.collector_url, "http://old:1");
+        assert_eq!(config.mqtt_password, "secret");
+    }
+
+    #[test]
+    fn render() {
+        let config = NodeConfig {
+            wifi_ssid: String::try_from("b&b").unwrap(),
+            location: String::try_from("Living room").unwrap(),
+            ..NodeConfig::default()
+        };
+        let networks: ScanList = ["a<b", "b&b"]
+            .into_iter()
+            .map(|ssid| String::try_from(ssid).unwrap())
+            .collect();
+
+        let mut buf = [0u8; 4096];
+        let len = render_form(
+            &mut buf,
+            "node",
+            &networks,
+            &config,
+            Some(FormError::TooLong),
+        )
+        .unwrap();
+        let page = core::str::from_utf8(&buf[..len]).unwrap();
+
+        assert!(page.contains("<option>a&lt;b</option>"));
+        assert!(page.contains("<option selected>b&amp;b</option>"));
+        assert!(page.contains("name=\"location\" value=\"Living room\""));
+        assert!(page.contains(FormError::TooLong.message()));
+
+        assert_eq!(
+            render_form(&mut buf[..256], "node", &networks, &config, None),
+            Err(FormError::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use defmt::Format;
+use libm::{exp, log, sqrt};
+use serde::Serialize;
+
+use crate::drivers::sht3x::Sht3xReading;
+
+/// Magnus coefficients over water, valid from -45 to 60 °C (Sonntag 1990, as used by
+/// Sensirion).
+const MAGNUS_B: f64 = 17.62;
+const MAGNUS_C: f64 = 243.12;
+/// Saturation vapour pressure at 0 °C in hPa.
+const MAGNUS_E0: f64 = 6.112;
+
+/// Specific gas constant of water vapour, J/(kg·K).
+const R_VAPOUR: f64 = 461.5;
+const KELVIN: f64 = 273.15;
+
+/// Saturation vapour pressure over water in hPa at `temperature` °C.
+pub fn saturation_vapour_pressure(temperature: f64) -> f64 {
+    MAGNUS_E0 * exp(MAGNUS_B * temperature / (MAGNUS_C + temperature))
+}
+
+/// Partial pressure of water vapour in hPa.
+pub fn vapour_pressure(temperature: f64, humidity: f64) -> f64 {
+    saturation_vapour_pressure(temperature) * humidity / 100.0
+}
+
+/// Dew point in °C by the Magnus formula. `None` for bone dry air, which has none.
+pub fn dew_point(temperature: f64, humidity: f64) -> Option<f64> {
+    if humidity <= 0.0 {
+        return None;
+    }
+
+    let gamma = log(humidity / 100.0) + MAGNUS_B * temperature / (MAGNUS_C + temperature);
+    Some(MAGNUS_C * gamma / (MAGNUS_B - gamma))
+}
+
+/// Water vapour per volume of air in g/m³.
+pub fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    // hPa to Pa, kg to g.
+    vapour_pressure(temperature, humidity) * 100.0 / (R_VAPOUR * (temperature + KELVIN)) * 1000.0
+}
+
+/// How much more water the air could hold, in kPa.
+pub fn vapour_pressure_deficit(temperature: f64, humidity: f64) -> f64 {
+    saturation_vapour_pressure(temperature) * (1.0 - humidity / 100.0) / 10.0
+}
+
+/// Canadian humidex, a felt temperature in °C from the temperature and the dew point.
+pub fn humidex(temperature: f64, dew_point: f64) -> f64 {
+    let vapour_pressure = 6.11 * exp(5417.7530 * (1.0 / 273.16 - 1.0 / (dew_point + KELVIN)));
+    temperature + 0.5555 * (vapour_pressure - 10.0)
+}
+
+/// US National Weather Service heat index in °C: Steadman's approximation in the mild
+/// range, the Rothfusz regression with its adjustments above 80 °F.
+pub fn heat_index(temperature: f64, humidity: f64) -> f64 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+            - 0.224_755_41 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * sqrt((17.0 - (t - 95.0).abs()) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+        hi
+    };
+
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+/// Values derived from one reading. Kept as `f32`, they go out with two decimals at most
+/// and the shorter encoding keeps upload bodies small.
+#[derive(Debug, Clone, Copy, PartialEq, Format, Serialize)]
+pub struct Psychrometrics {
+    /// °C.
+    pub dew_point: f32,
+    /// g/m³.
+    pub absolute_humidity: f32,
+    /// °C.
+    pub heat_index: f32,
+    pub humidex: f32,
+    /// Vapour-pressure deficit in kPa.
+    pub vpd: f32,
+}
+
+impl Psychrometrics {
+    /// `None` at 0 %RH, where there is no dew point.
+    pub fn new(reading: &Sht3xReading) -> Option<Self> {
+        let Sht3xReading {
+            temperature,
+            humidity,
+        } = *reading;
+        let dew_point = dew_point(temperature, humidity)?;
+
+        Some(Self {
+            dew_point: dew_point as f32,
+            absolute_humidity: absolute_humidity(temperature, humidity) as f32,
+            heat_index: heat_index(temperature, humidity) as f32,
+            humidex: humidex(temperature, dew_point) as f32,
+            vpd: vapour_pressure_deficit(temperature, humidity) as f32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    fn fahrenheit(celsius: f64) -> f64 {
+        celsius * 9.0 / 5.0 + 32.0
+    }
+
+    fn celsius(fahrenheit: f64) -> f64 {
+        (fahrenheit - 32.0) * 5.0 / 9.0
+    }
+
+    #[test]
+    fn saturation_vapour_pressure_matches_the_tables() {
+        assert_eq!(saturation_vapour_pressure(0.0), MAGNUS_E0);
+        // WMO tables, hPa over water. The Magnus fit stays within 0.5 %.
+        for (temperature, expected) in [
+            (-20.0, 1.2540),
+            (20.0, 23.389),
+            (25.0, 31.693),
+            (30.0, 42.467),
+        ] {
+            assert_near(
+                saturation_vapour_pressure(temperature),
+                expected,
+                expected * 0.005,
+            );
+        }
+    }
+
+    #[test]
+    fn dew_point_matches_the_tables() {
+        // °C, rounded to a tenth as tables give them.
+        for (temperature, humidity, expected) in [
+            (25.0, 50.0, 13.9),
+            (30.0, 80.0, 26.2),
+            (10.0, 60.0, 2.6),
+            (-10.0, 80.0, -12.8),
+        ] {
+            assert_near(dew_point(temperature, humidity).unwrap(), expected, 0.05);
+        }
+
+        // Saturated air is at its dew point.
+        assert_near(dew_point(20.0, 100.0).unwrap(), 20.0, 1e-9);
+        assert_eq!(dew_point(20.0, 0.0), None);
+    }
+
+    #[test]
+    fn absolute_humidity_matches_the_tables() {
+        // Water vapour in saturated air, g/m³.
+        for (temperature, expected) in [(0.0, 4.85), (20.0, 17.3), (30.0, 30.4)] {
+            assert_near(absolute_humidity(temperature, 100.0), expected, 0.2);
+        }
+        assert_near(absolute_humidity(25.0, 50.0), 11.5, 0.05);
+        assert_eq!(absolute_humidity(25.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn vapour_pressure_deficit_matches_the_tables() {
+        // kPa, as in greenhouse VPD charts.
+        for (temperature, humidity, expected) in
+            [(25.0, 50.0, 1.58), (30.0, 60.0, 1.70), (20.0, 80.0, 0.47)]
+        {
+            assert_near(
+                vapour_pressure_deficit(temperature, humidity),
+                expected,
+                0.01,
+            );
+        }
+        assert_eq!(vapour_pressure_deficit(25.0, 100.0), 0.0);
+    }
+
+    #[test]
+    fn heat_index_matches_the_nws_chart() {
+        // The NWS chart in °F, whose values are rounded to whole degrees.
+        for (temperature, humidity, expected) in [
+            (80.0, 40.0, 80.0),
+            (86.0, 90.0, 105.0),
+            (90.0, 70.0, 106.0),
+            (96.0, 65.0, 121.0),
+            (100.0, 40.0, 109.0),
+        ] {
+            assert_near(
+                fahrenheit(heat_index(celsius(temperature), humidity)),
+                expected,
+                1.0,
+            );
+        }
+
+        // Below 80 °F Steadman's approximation, which stays close to the temperature.
+        assert_near(fahrenheit(heat_index(celsius(70.0), 50.0)), 69.0, 0.5);
+    }
+
+    #[test]
+    fn heat_index_adjustments() {
+        let rothfusz = |t: f64, rh: f64| {
+            -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+                - 0.224_755_41 * t * rh
+                - 0.006_837_83 * t * t
+                - 0.054_817_17 * rh * rh
+                + 0.001_228_74 * t * t * rh
+                + 0.000_852_82 * t * rh * rh
+                - 0.000_001_99 * t * t * rh * rh
+        };
+
+        // The NWS adjustments: down for dry heat, up for humid air just past 80 °F.
+        let dry = rothfusz(95.0, 5.0) - 2.0;
+        assert_near(fahrenheit(heat_index(celsius(95.0), 5.0)), dry, 1e-9);
+        let humid = rothfusz(84.0, 95.0) + 1.0 * (3.0 / 5.0);
+        assert_near(fahrenheit(heat_index(celsius(84.0), 95.0)), humid, 1e-9);
+    }
+
+    #[test]
+    fn humidex_matches_the_environment_canada_table() {
+        // Temperature and dew point in °C.
+        assert_near(humidex(30.0, 15.0), 34.0, 0.5);
+        assert_near(humidex(35.0, 25.0), 47.0, 0.5);
+        // No vapour to speak of lowers it below the temperature.
+        assert!(humidex(20.0, -30.0) < 20.0);
+    }
+
+    #[test]
+    fn psychrometrics() {
+        let derived = Psychrometrics::new(&Sht3xReading::new(25.0, 50.0)).unwrap();
+        assert_near(derived.dew_point as f64, 13.85, 0.01);
+        assert_near(derived.absolute_humidity as f64, 11.48, 0.01);
+        assert_near(derived.heat_index as f64, 24.9, 0.1);
+        assert_near(derived.vpd as f64, 1.58, 0.001);
+        assert_eq!(
+            derived.humidex,
+            humidex(25.0, dew_point(25.0, 50.0).unwrap()) as f32
+        );
+
+        assert_eq!(Psychrometrics::new(&Sht3xReading::new(25.0, 0.0)), None);
+    }
+}
@@ -0,0 +1,231 @@
+use defmt::Format;
+use heapless::Deque;
+
+/// What to do with a new item when the queue is already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum DropPolicy {
+    /// Discard the oldest item to make room.
+    DropOldest,
+    /// Discard every other queued item of each stream, halving the time resolution of the
+    /// backlog so it covers a longer outage.
+    Downsample,
+}
+
+/// Bounded FIFO that never rejects a push, it sheds old data according to its
+/// [`DropPolicy`] and counts how many items were lost.
+pub struct ReadingQueue<T, const N: usize> {
+    items: Deque<T, N>,
+    policy: DropPolicy,
+    /// Which stream an item belongs to. Interleaved streams are downsampled separately, so
+    /// one of them isn't dropped entirely.
+    stream: fn(&T) -> usize,
+    dropped: u32,
+}
+
+fn single_stream<T>(_: &T) -> usize {
+    0
+}
+
+impl<T, const N: usize> ReadingQueue<T, N> {
+    pub const fn new(policy: DropPolicy) -> Self {
+        Self::with_streams(policy, single_stream::<T>)
+    }
+
+    /// Streams past the 32nd share their parity with earlier ones when downsampling.
+    pub const fn with_streams(policy: DropPolicy, stream: fn(&T) -> usize) -> Self {
+        Self {
+            items: Deque::new(),
+            policy,
+            stream,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.items.is_full() {
+            match self.policy {
+                DropPolicy::DropOldest => self.drop_oldest(),
+                DropPolicy::Downsample => self.downsample(),
+            }
+        }
+
+        // Room was just made above, so this cannot fail for any N > 0.
+        let _ = self.items.push_back(item);
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.items.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.items.pop_front()
+    }
+
+    /// Removes items from the front for as long as `pred` holds and returns how many were
+    /// removed.
+    pub fn pop_while(&mut self, mut pred: impl FnMut(&T) -> bool) -> usize {
+        let mut removed = 0;
+
+        while self.items.front().is_some_and(&mut pred) {
+            self.items.pop_front();
+            removed += 1;
+        }
+
+        removed
+    }
+
+    /// Oldest-first iterator over the queued items.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Total number of items discarded by the drop policy since the queue was created.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    fn drop_oldest(&mut self) {
+        if self.items.pop_front().is_some() {
+            self.dropped = self.dropped.saturating_add(1);
+        }
+    }
+
+    fn downsample(&mut self) {
+        let len = self.items.len();
+        // One bit per stream, set while the next item of that stream is to be dropped.
+        let mut odd = 0u32;
+
+        for _ in 0..len {
+            let Some(item) = self.items.pop_front() else {
+                break;
+            };
+
+            // Keep the oldest item of each stream and every second one after it.
+            let bit = 1 << ((self.stream)(&item) % 32);
+            odd ^= bit;
+            if odd & bit != 0 {
+                let _ = self.items.push_back(item);
+            } else {
+                self.dropped = self.dropped.saturating_add(1);
+            }
+        }
+
+        if self.items.is_full() {
+            self.drop_oldest();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items<T: Copy, const N: usize>(queue: &ReadingQueue<T, N>) -> std::vec::Vec<T> {
+        queue.iter().copied().collect()
+    }
+
+    #[test]
+    fn fifo() {
+        let mut queue: ReadingQueue<u32, 4> = ReadingQueue::new(DropPolicy::DropOldest);
+        assert!(queue.is_empty());
+
+        queue.push(1);
+        queue.push(2);
+        queue.push(3);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.front(), Some(&1));
+        assert_eq!(queue.pop_front(), Some(1));
+        assert_eq!(queue.pop_front(), Some(2));
+
+        queue.push(4);
+        assert_eq!(items(&queue), [3, 4]);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut queue: ReadingQueue<u32, 4> = ReadingQueue::new(DropPolicy::DropOldest);
+        for i in 0..6 {
+            queue.push(i);
+        }
+
+        assert_eq!(items(&queue), [2, 3, 4, 5]);
+        assert_eq!(queue.dropped(), 2);
+
+        queue.push(6);
+        assert_eq!(items(&queue), [3, 4, 5, 6]);
+        assert_eq!(queue.dropped(), 3);
+    }
+
+    #[test]
+    fn downsample() {
+        let mut queue: ReadingQueue<u32, 4> = ReadingQueue::new(DropPolicy::Downsample);
+        for i in 0..4 {
+            queue.push(i);
+        }
+        assert_eq!(queue.dropped(), 0);
+
+        // Full: every second item goes, the oldest stays.
+        queue.push(4);
+        assert_eq!(items(&queue), [0, 2, 4]);
+        assert_eq!(queue.dropped(), 2);
+
+        // Not full again until the next one.
+        queue.push(5);
+        assert_eq!(items(&queue), [0, 2, 4, 5]);
+        queue.push(6);
+        assert_eq!(items(&queue), [0, 4, 6]);
+        assert_eq!(queue.dropped(), 4);
+    }
+
+    #[test]
+    fn downsample_single_slot() {
+        // Nothing left to halve, the oldest goes instead.
+        let mut queue: ReadingQueue<u32, 1> = ReadingQueue::new(DropPolicy::Downsample);
+        queue.push(1);
+        queue.push(2);
+        assert_eq!(items(&queue), [2]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn downsample_streams_separately() {
+        let mut queue: ReadingQueue<(usize, u32), 4> =
+            ReadingQueue::with_streams(DropPolicy::Downsample, |item| item.0);
+        for i in 0..5 {
+            queue.push((i as usize % 2, i));
+        }
+
+        // Both streams keep their oldest reading rather than one losing all of them.
+        assert_eq!(items(&queue), [(0, 0), (1, 1), (0, 4)]);
+        assert_eq!(queue.dropped(), 2);
+    }
+
+    #[test]
+    fn acknowledge_up_to_sequence() {
+        let mut queue: ReadingQueue<u32, 8> = ReadingQueue::new(DropPolicy::DropOldest);
+        for seq in 10..15 {
+            queue.push(seq);
+        }
+
+        assert_eq!(queue.pop_while(|&seq| seq <= 12), 3);
+        assert_eq!(items(&queue), [13, 14]);
+
+        // Already gone, nothing more to remove.
+        assert_eq!(queue.pop_while(|&seq| seq <= 12), 0);
+        assert_eq!(queue.pop_while(|&seq| seq <= 20), 2);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::drivers::sht3x::Sht3xReading;
+use crate::filter::Quality;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReportConfig {
+    /// Changes in °C up to this from the last published reading are held back. Zero
+    /// publishes every change.
+    pub temperature_deadband: f64,
+    /// Changes in %RH up to this from the last published reading are held back.
+    pub humidity_deadband: f64,
+    /// Readings closer than this to the last published one are held back however much
+    /// they changed, so a burst of changes goes out as one.
+    pub min_interval_ms: u64,
+    /// A reading is published at least this often even if nothing changed, so the
+    /// collector can tell a quiet room from a dead node.
+    pub max_interval_ms: u64,
+}
+
+/// Decides which readings of one sensor go to the uplinks. Every reading is compared
+/// against the last one published, so a slow drift still goes out once it adds up to the
+/// deadband.
+pub struct Reporter {
+    config: ReportConfig,
+    /// Last published reading and when it was taken, in ms.
+    last: Option<(u64, Sht3xReading)>,
+    /// Flags of readings held back since the last published one.
+    pending: Quality,
+}
+
+impl Reporter {
+    pub const fn new(config: ReportConfig) -> Self {
+        Self {
+            config,
+            last: None,
+            pending: Quality::GOOD,
+        }
+    }
+
+    /// Offers a reading taken at `now_ms` with its filter flags. Returns the flags to
+    /// publish it with, which include those of the readings held back before it, or `None`
+    /// to hold it back.
+    pub fn offer(
+        &mut self,
+        now_ms: u64,
+        reading: &Sht3xReading,
+        quality: Quality,
+    ) -> Option<Quality> {
+        let publish = match self.last {
+            // Nothing published yet, or the sensor just came back from a fault.
+            None => true,
+            Some((then_ms, last)) => {
+                let elapsed_ms = now_ms.saturating_sub(then_ms);
+
+                elapsed_ms >= self.config.min_interval_ms
+                    && (self.exceeds_deadband(&last, reading)
+                        || elapsed_ms >= self.config.max_interval_ms)
+            }
+        };
+
+        if !publish {
+            self.pending = self.pending | quality;
+            return None;
+        }
+
+        let quality = self.pending | quality;
+        self.last = Some((now_ms, *reading));
+        self.pending = Quality::GOOD;
+
+        Some(quality)
+    }
+
+    /// Forgets the last published reading, so the next one goes out whatever its value.
+    /// For after a fault, whose report the uplinks show until then.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    fn exceeds_deadband(&self, last: &Sht3xReading, reading: &Sht3xReading) -> bool {
+        (reading.temperature - last.temperature).abs() > self.config.temperature_deadband
+            || (reading.humidity - last.humidity).abs() > self.config.humidity_deadband
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ReportConfig = ReportConfig {
+        temperature_deadband: 0.1,
+        humidity_deadband: 0.5,
+        min_interval_ms: 10 * 1000,
+        max_interval_ms: 5 * 60 * 1000,
+    };
+
+    /// Offers `(now_ms, temperature, humidity)` in turn and returns when a reading went
+    /// out.
+    fn published(
+        reporter: &mut Reporter,
+        readings: impl IntoIterator<Item = (u64, f64, f64)>,
+    ) -> std::vec::Vec<u64> {
+        readings
+            .into_iter()
+            .filter(|&(now_ms, temperature, humidity)| {
+                let reading = Sht3xReading::new(temperature, humidity);
+                reporter.offer(now_ms, &reading, Quality::GOOD).is_some()
+            })
+            .map(|(now_ms, ..)| now_ms)
+            .collect()
+    }
+
+    #[test]
+    fn first_reading_goes_out() {
+        let mut reporter = Reporter::new(CONFIG);
+        assert_eq!(
+            reporter.offer(0, &Sht3xReading::new(20.0, 50.0), Quality::GOOD),
+            Some(Quality::GOOD)
+        );
+    }
+
+    #[test]
+    fn deadband() {
+        let mut reporter = Reporter::new(CONFIG);
+        let readings = [
+            (0, 20.0, 50.0),
+            (20_000, 20.08, 50.0),
+            (40_000, 20.2, 50.0),
+            (60_000, 20.2, 50.4),
+            (80_000, 20.2, 50.6),
+            (100_000, 20.2, 50.6),
+            (120_000, 20.05, 50.6),
+        ];
+        assert_eq!(
+            published(&mut reporter, readings),
+            [0, 40_000, 80_000, 120_000]
+        );
+    }
+
+    #[test]
+    fn deadband_against_the_last_published_reading() {
+        // 0.03 °C every 20 s, each step within the deadband but not their sum.
+        let mut reporter = Reporter::new(CONFIG);
+        let readings = (0..10).map(|i| (i * 20_000, 20.0 + 0.03 * i as f64, 50.0));
+        assert_eq!(published(&mut reporter, readings), [0, 80_000, 160_000]);
+    }
+
+    #[test]
+    fn heartbeat() {
+        // Jitter within the deadbands, once a second for 20 minutes.
+        let mut reporter = Reporter::new(CONFIG);
+        let readings = (0..1200).map(|s| {
+            let temperature = if s % 2 == 0 { 20.04 } else { 19.96 };
+            let humidity = if s % 3 == 0 { 50.2 } else { 50.0 };
+            (s * 1000, temperature, humidity)
+        });
+        assert_eq!(
+            published(&mut reporter, readings),
+            [0, 300_000, 600_000, 900_000]
+        );
+    }
+
+    #[test]
+    fn min_interval() {
+        let mut reporter = Reporter::new(CONFIG);
+        let readings = [
+            (0, 20.0, 50.0),
+            (1_000, 25.0, 50.0),
+            (2_000, 15.0, 50.0),
+            (5_000, 30.0, 50.0),
+            (10_000, 30.0, 50.0),
+            (11_000, 31.0, 50.0),
+            (20_000, 31.0, 50.0),
+        ];
+        assert_eq!(published(&mut reporter, readings), [0, 10_000, 20_000]);
+
+        // A spike that is over by the end of the interval never goes out.
+        let mut reporter = Reporter::new(CONFIG);
+        let readings = [(0, 20.0, 50.0), (1_000, 25.0, 50.0), (10_000, 20.0, 50.0)];
+        assert_eq!(published(&mut reporter, readings), [0]);
+    }
+
+    #[test]
+    fn no_policy_publishes_everything() {
+        let mut reporter = Reporter::new(ReportConfig {
+            temperature_deadband: 0.0,
+            humidity_deadband: 0.0,
+            min_interval_ms: 0,
+            max_interval_ms: 0,
+        });
+        let readings = (0..5).map(|i| (i, 20.0, 50.0));
+        assert_eq!(published(&mut reporter, readings), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn pending_quality() {
+        let mut reporter = Reporter::new(CONFIG);
+        let steady = Sht3xReading::new(20.0, 50.0);
+        assert!(reporter.offer(0, &steady, Quality::GOOD).is_some());
+
+        // The flags of held back readings go out with the next published one, and only
+        // with it.
+        assert_eq!(reporter.offer(1_000, &steady, Quality::RATE_EXCEEDED), None);
+        assert_eq!(reporter.offer(2_000, &steady, Quality::OUT_OF_RANGE), None);
+        assert_eq!(
+            reporter.offer(
+                20_000,
+                &Sht3xReading::new(21.0, 50.0),
+                Quality::RATE_OVERRIDDEN
+            ),
+            Some(Quality::RATE_EXCEEDED | Quality::OUT_OF_RANGE | Quality::RATE_OVERRIDDEN)
+        );
+        assert_eq!(
+            reporter.offer(40_000, &Sht3xReading::new(22.0, 50.0), Quality::GOOD),
+            Some(Quality::GOOD)
+        );
+
+        // Also across a reset.
+        assert_eq!(
+            reporter.offer(
+                41_000,
+                &Sht3xReading::new(22.0, 50.0),
+                Quality::HEATER_ACTIVE
+            ),
+            None
+        );
+        reporter.reset();
+        assert_eq!(
+            reporter.offer(42_000, &Sht3xReading::new(22.0, 50.0), Quality::GOOD),
+            Some(Quality::HEATER_ACTIVE)
+        );
+    }
+
+    #[test]
+    fn reset() {
+        let mut reporter = Reporter::new(CONFIG);
+        let steady = Sht3xReading::new(20.0, 50.0);
+        assert!(reporter.offer(0, &steady, Quality::GOOD).is_some());
+        assert!(reporter.offer(10_000, &steady, Quality::GOOD).is_none());
+
+        // Within the min interval too.
+        reporter.reset();
+        assert!(reporter.offer(11_000, &steady, Quality::GOOD).is_some());
+        assert!(reporter.offer(21_000, &steady, Quality::GOOD).is_none());
+    }
+}
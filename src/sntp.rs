@@ -0,0 +1,486 @@
+use defmt::Format;
+use serde::Serialize;
+
+pub const PACKET_LEN: usize = 48;
+pub const PORT: u16 = 123;
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+
+/// LI = 0, VN = 4, Mode = 3 (client).
+const CLIENT_HEADER: u8 = 0b00_100_011;
+const MODE_SERVER: u8 = 4;
+const LEAP_ALARM: u8 = 3;
+
+/// Offsets further than this from the current estimate are treated as a clock step, not
+/// drift.
+const MAX_SLEW_US: i64 = 1_000_000;
+/// Drift is only estimated over intervals at least this long, shorter ones are dominated by
+/// network jitter.
+const MIN_DRIFT_INTERVAL_US: u64 = 60_000_000;
+/// Crystal oscillators are well within this, anything larger is a bad sample.
+const MAX_DRIFT_PPB: i64 = 500_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SntpError {
+    Truncated,
+    NotServerMode,
+    KissOfDeath,
+    Unsynchronized,
+    OriginMismatch,
+    InvalidTimestamp,
+}
+
+/// Builds a client request. `cookie` is echoed back by the server as the origin timestamp
+/// and is used to match the response to this request.
+pub fn request_packet(cookie: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    packet[0] = CLIENT_HEADER;
+    packet[40..48].copy_from_slice(&cookie.to_be_bytes());
+    packet
+}
+
+/// The server side timestamps of an exchange, in Unix microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ServerResponse {
+    pub stratum: u8,
+    pub receive_us: i64,
+    pub transmit_us: i64,
+}
+
+impl ServerResponse {
+    pub fn parse(packet: &[u8], cookie: u64) -> Result<Self, SntpError> {
+        if packet.len() < PACKET_LEN {
+            return Err(SntpError::Truncated);
+        }
+
+        let leap = packet[0] >> 6;
+        let mode = packet[0] & 0b111;
+        let stratum = packet[1];
+
+        if mode != MODE_SERVER {
+            return Err(SntpError::NotServerMode);
+        }
+
+        if stratum == 0 {
+            return Err(SntpError::KissOfDeath);
+        }
+
+        if leap == LEAP_ALARM {
+            return Err(SntpError::Unsynchronized);
+        }
+
+        if read_u64(packet, 24) != cookie {
+            return Err(SntpError::OriginMismatch);
+        }
+
+        Ok(Self {
+            stratum,
+            receive_us: ntp_to_unix_us(read_u64(packet, 32))?,
+            transmit_us: ntp_to_unix_us(read_u64(packet, 40))?,
+        })
+    }
+
+    /// Difference between Unix time and the local uptime clock, given the uptime when the
+    /// request was sent and when the response arrived.
+    pub fn offset_us(&self, sent_us: u64, received_us: u64) -> i64 {
+        let outbound = self.receive_us - sent_us as i64;
+        let inbound = self.transmit_us - received_us as i64;
+        outbound / 2 + inbound / 2
+    }
+
+    pub fn round_trip_us(&self, sent_us: u64, received_us: u64) -> i64 {
+        (received_us as i64 - sent_us as i64) - (self.transmit_us - self.receive_us)
+    }
+}
+
+fn read_u64(packet: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&packet[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn ntp_to_unix_us(timestamp: u64) -> Result<i64, SntpError> {
+    if timestamp == 0 {
+        return Err(SntpError::InvalidTimestamp);
+    }
+
+    let mut secs = timestamp >> 32;
+    let frac = timestamp & 0xffff_ffff;
+
+    // Era 1 starts in 2036, where the seconds counter wraps. RFC 4330 section 3.
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+
+    // Era 0 seconds from 2^31 on fall before 1970.
+    let unix_secs = secs
+        .checked_sub(NTP_UNIX_OFFSET_SECS)
+        .ok_or(SntpError::InvalidTimestamp)?;
+    let micros = (frac * 1_000_000) >> 32;
+    Ok((unix_secs * 1_000_000 + micros) as i64)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SyncPoint {
+    uptime_us: u64,
+    offset_us: i64,
+}
+
+/// Maps the local uptime clock onto UTC using the offsets measured by SNTP, correcting for
+/// the drift of the local oscillator between syncs.
+#[derive(Debug, Clone, Copy)]
+pub struct WallClock {
+    last_sync: Option<SyncPoint>,
+    drift_ppb: i64,
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WallClock {
+    pub const fn new() -> Self {
+        Self {
+            last_sync: None,
+            drift_ppb: 0,
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.last_sync.is_some()
+    }
+
+    /// Estimated oscillator drift in parts per billion. Positive means the local clock runs
+    /// slow.
+    pub fn drift_ppb(&self) -> i64 {
+        self.drift_ppb
+    }
+
+    /// Records an offset measured at `uptime_us`.
+    pub fn update(&mut self, uptime_us: u64, offset_us: i64) {
+        if let Some(last) = self.last_sync {
+            let predicted = self.offset_at(uptime_us).unwrap_or(last.offset_us);
+
+            if (offset_us - predicted).abs() > MAX_SLEW_US {
+                self.drift_ppb = 0;
+            } else {
+                let elapsed_us = uptime_us.saturating_sub(last.uptime_us);
+
+                if elapsed_us < MIN_DRIFT_INTERVAL_US {
+                    // Keep the older sync point so the next interval is long enough to
+                    // measure drift, but still take the fresher offset.
+                    self.last_sync = Some(SyncPoint {
+                        uptime_us: last.uptime_us,
+                        offset_us: last.offset_us + (offset_us - predicted),
+                    });
+                    return;
+                }
+
+                let measured = ((offset_us - last.offset_us) as i128 * 1_000_000_000
+                    / elapsed_us as i128) as i64;
+                let measured = measured.clamp(-MAX_DRIFT_PPB, MAX_DRIFT_PPB);
+
+                self.drift_ppb = if self.drift_ppb == 0 {
+                    measured
+                } else {
+                    self.drift_ppb + (measured - self.drift_ppb) / 4
+                };
+            }
+        }
+
+        self.last_sync = Some(SyncPoint {
+            uptime_us,
+            offset_us,
+        });
+    }
+
+    /// Unix time in microseconds at the given uptime, or `None` before the first sync.
+    pub fn utc_us(&self, uptime_us: u64) -> Option<i64> {
+        self.offset_at(uptime_us)
+            .map(|offset_us| uptime_us as i64 + offset_us)
+    }
+
+    fn offset_at(&self, uptime_us: u64) -> Option<i64> {
+        self.last_sync.map(|last| {
+            let elapsed_us = uptime_us as i128 - last.uptime_us as i128;
+            last.offset_us + (elapsed_us * self.drift_ppb as i128 / 1_000_000_000) as i64
+        })
+    }
+}
+
+/// When a reading was taken. Before the first sync `unix_ms` holds milliseconds since boot
+/// instead and `synced` is false.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Format)]
+pub struct Timestamp {
+    pub unix_ms: u64,
+    pub synced: bool,
+}
+
+impl Timestamp {
+    pub fn new(clock: &WallClock, uptime_us: u64) -> Self {
+        match clock.utc_us(uptime_us) {
+            Some(utc_us) => Self {
+                unix_ms: (utc_us.max(0) / 1000) as u64,
+                synced: true,
+            },
+            None => Self {
+                unix_ms: uptime_us / 1000,
+                synced: false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIE: u64 = 0x0123_4567_89ab_cdef;
+    /// 2023-11-14T22:13:20Z.
+    const NOW_US: i64 = 1_700_000_000_000_000;
+
+    /// NTP timestamp of `seconds` since 1900 in the current era and `frac` 2^-32 s.
+    fn ntp(seconds: u64, frac: u32) -> u64 {
+        (seconds & 0xffff_ffff) << 32 | frac as u64
+    }
+
+    /// NTP timestamp of a Unix time in whole microseconds. The fraction is rounded up so
+    /// it converts back to the same microsecond.
+    fn ntp_of_unix(unix_us: i64) -> u64 {
+        let seconds = unix_us as u64 / 1_000_000 + NTP_UNIX_OFFSET_SECS;
+        let frac = (((unix_us as u64 % 1_000_000) << 32).div_ceil(1_000_000)) as u32;
+        ntp(seconds, frac)
+    }
+
+    fn response(header: u8, stratum: u8, receive: u64, transmit: u64) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        packet[0] = header;
+        packet[1] = stratum;
+        packet[24..32].copy_from_slice(&COOKIE.to_be_bytes());
+        packet[32..40].copy_from_slice(&receive.to_be_bytes());
+        packet[40..48].copy_from_slice(&transmit.to_be_bytes());
+        packet
+    }
+
+    /// LI = 0, VN = 4, Mode = 4 (server).
+    fn server(receive: u64, transmit: u64) -> [u8; PACKET_LEN] {
+        response(0b00_100_100, 2, receive, transmit)
+    }
+
+    #[test]
+    fn request() {
+        let packet = request_packet(COOKIE);
+        assert_eq!(packet[0], 0x23);
+        assert!(packet[1..40].iter().all(|&b| b == 0));
+        assert_eq!(&packet[40..], &COOKIE.to_be_bytes());
+    }
+
+    #[test]
+    fn response_timestamps() {
+        let packet = server(ntp_of_unix(NOW_US), ntp_of_unix(NOW_US + 250));
+        assert_eq!(
+            ServerResponse::parse(&packet, COOKIE),
+            Ok(ServerResponse {
+                stratum: 2,
+                receive_us: NOW_US,
+                transmit_us: NOW_US + 250,
+            })
+        );
+
+        // Half a second in the fraction.
+        let packet = server(ntp(NTP_UNIX_OFFSET_SECS, 1 << 31), ntp_of_unix(NOW_US));
+        assert_eq!(
+            ServerResponse::parse(&packet, COOKIE).unwrap().receive_us,
+            500_000
+        );
+    }
+
+    #[test]
+    fn eras() {
+        let transmit_us = |timestamp| {
+            let packet = server(ntp_of_unix(NOW_US), timestamp);
+            ServerResponse::parse(&packet, COOKIE).map(|response| response.transmit_us)
+        };
+
+        // Era 0 while the top bit is set, up to 2036-02-07T06:28:15Z.
+        assert_eq!(transmit_us(ntp(NTP_UNIX_OFFSET_SECS, 0)), Ok(0));
+        assert_eq!(
+            transmit_us(ntp(0xffff_ffff, 0)),
+            Ok((0xffff_ffff - NTP_UNIX_OFFSET_SECS as i64) * 1_000_000)
+        );
+        // Era 1 after that.
+        assert_eq!(
+            transmit_us(ntp(0, 1)),
+            Ok(((1 << 32) - NTP_UNIX_OFFSET_SECS as i64) * 1_000_000)
+        );
+        assert_eq!(
+            transmit_us(ntp(0x7fff_ffff, 0)),
+            Ok(((1 << 32) + 0x7fff_ffff - NTP_UNIX_OFFSET_SECS as i64) * 1_000_000)
+        );
+
+        // Era 0 before 1970, e.g. 1968 to 1970.
+        assert_eq!(
+            transmit_us(ntp(1 << 31, 0)),
+            Err(SntpError::InvalidTimestamp)
+        );
+        assert_eq!(
+            transmit_us(ntp(NTP_UNIX_OFFSET_SECS - 1, 0)),
+            Err(SntpError::InvalidTimestamp)
+        );
+        // Unset.
+        let packet = server(0, ntp_of_unix(NOW_US));
+        assert_eq!(
+            ServerResponse::parse(&packet, COOKIE),
+            Err(SntpError::InvalidTimestamp)
+        );
+    }
+
+    #[test]
+    fn rejected_responses() {
+        let receive = ntp_of_unix(NOW_US);
+        let transmit = ntp_of_unix(NOW_US + 100);
+        let parse = |packet: &[u8]| ServerResponse::parse(packet, COOKIE);
+
+        assert_eq!(
+            parse(&server(receive, transmit)[..PACKET_LEN - 1]),
+            Err(SntpError::Truncated)
+        );
+        // Our own request, mode 3.
+        assert_eq!(
+            parse(&response(0b00_100_011, 2, receive, transmit)),
+            Err(SntpError::NotServerMode)
+        );
+        assert_eq!(
+            parse(&response(0b00_100_100, 0, receive, transmit)),
+            Err(SntpError::KissOfDeath)
+        );
+        assert_eq!(
+            parse(&response(0b11_100_100, 2, receive, transmit)),
+            Err(SntpError::Unsynchronized)
+        );
+        // A pending leap second is fine.
+        assert!(parse(&response(0b01_100_100, 2, receive, transmit)).is_ok());
+
+        let packet = server(receive, transmit);
+        assert_eq!(
+            ServerResponse::parse(&packet, COOKIE + 1),
+            Err(SntpError::OriginMismatch)
+        );
+    }
+
+    #[test]
+    fn offset() {
+        let response = ServerResponse {
+            stratum: 1,
+            receive_us: NOW_US,
+            transmit_us: NOW_US + 100,
+        };
+
+        // Sent at 1 s of uptime, back 300 µs later of which the server took 100.
+        assert_eq!(response.offset_us(1_000_000, 1_000_300), NOW_US - 1_000_100);
+        assert_eq!(response.round_trip_us(1_000_000, 1_000_300), 200);
+
+        // The paths are taken to be symmetric, so a slow way back shifts the offset by
+        // half of it.
+        assert_eq!(response.offset_us(1_000_000, 1_010_300), NOW_US - 1_005_100);
+        assert_eq!(response.round_trip_us(1_000_000, 1_010_300), 10_200);
+    }
+
+    #[test]
+    fn unsynced() {
+        let clock = WallClock::new();
+        assert!(!clock.is_synced());
+        assert_eq!(clock.utc_us(5_000_000), None);
+        assert_eq!(
+            Timestamp::new(&clock, 5_000_000),
+            Timestamp {
+                unix_ms: 5000,
+                synced: false,
+            }
+        );
+    }
+
+    #[test]
+    fn drift_needs_a_long_enough_interval() {
+        let offset = NOW_US;
+        let mut clock = WallClock::new();
+        clock.update(10_000_000, offset);
+        assert!(clock.is_synced());
+        assert_eq!(clock.utc_us(10_000_000), Some(10_000_000 + offset));
+
+        // 30 s later, too soon to tell drift from jitter. The offset is taken, but the sync
+        // point keeps its uptime.
+        clock.update(40_000_000, offset + 50);
+        assert_eq!(clock.drift_ppb(), 0);
+        assert_eq!(clock.utc_us(40_000_000), Some(40_000_000 + offset + 50));
+
+        // So 30 s after that there are 60 s to measure over: 600 µs more is 10 ppm.
+        clock.update(70_000_000, offset + 650);
+        assert_eq!(clock.drift_ppb(), 10_000);
+        assert_eq!(
+            clock.utc_us(170_000_000),
+            Some(170_000_000 + offset + 650 + 1000)
+        );
+    }
+
+    #[test]
+    fn drift_converges() {
+        let offset = NOW_US;
+        let mut clock = WallClock::new();
+        clock.update(0, offset);
+
+        // The first estimate is taken as is, 36 ms an hour is 10 ppm slow.
+        clock.update(3_600_000_000, offset + 36_000);
+        assert_eq!(clock.drift_ppb(), 10_000);
+
+        // Later ones move it a quarter of the way.
+        clock.update(7_200_000_000, offset + 36_000 + 72_000);
+        assert_eq!(clock.drift_ppb(), 12_500);
+        let mut last_offset = offset + 108_000;
+        for hour in 3..40 {
+            last_offset += 72_000;
+            clock.update(hour * 3_600_000_000, last_offset);
+        }
+        assert!(
+            (clock.drift_ppb() - 20_000).abs() <= 10,
+            "{}",
+            clock.drift_ppb()
+        );
+
+        // Implausible drift is capped.
+        let mut clock = WallClock::new();
+        clock.update(0, offset);
+        clock.update(MIN_DRIFT_INTERVAL_US, offset + 900_000);
+        assert_eq!(clock.drift_ppb(), MAX_DRIFT_PPB);
+    }
+
+    #[test]
+    fn step_resets_drift() {
+        let offset = NOW_US;
+        let mut clock = WallClock::new();
+        clock.update(0, offset);
+        clock.update(3_600_000_000, offset + 36_000);
+        assert_eq!(clock.drift_ppb(), 10_000);
+
+        // Further than MAX_SLEW_US off the prediction is a step, not drift.
+        let stepped = offset + 36_000 + MAX_SLEW_US + 36_001;
+        clock.update(7_200_000_000, stepped);
+        assert_eq!(clock.drift_ppb(), 0);
+        assert_eq!(clock.utc_us(7_200_000_000), Some(7_200_000_000 + stepped));
+        assert_eq!(
+            Timestamp::new(&clock, 7_200_000_000),
+            Timestamp {
+                unix_ms: ((7_200_000_000 + stepped) / 1000) as u64,
+                synced: true,
+            }
+        );
+
+        // Times before the epoch come out as 0.
+        let mut clock = WallClock::new();
+        clock.update(10_000_000, -20_000_000);
+        assert_eq!(Timestamp::new(&clock, 10_000_000).unix_ms, 0);
+    }
+}
@@ -0,0 +1,310 @@
+use defmt::Format;
+use libm::sqrt;
+use serde::Serialize;
+
+use crate::drivers::sht3x::Sht3xReading;
+
+/// Buckets per window, whatever its length. A window slides a bucket at a time, so a 1 h
+/// window covers the last 57.5 to 60 minutes.
+pub const BUCKETS: usize = 24;
+
+/// Min, max, mean and spread of one quantity. Buckets are combined with Chan's parallel
+/// update, so no reading has to be kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Moments {
+    min: f32,
+    max: f32,
+    mean: f64,
+    /// Sum of squared differences from the mean.
+    m2: f64,
+}
+
+impl Moments {
+    fn of(value: f64) -> Self {
+        Self {
+            min: value as f32,
+            max: value as f32,
+            mean: value,
+            m2: 0.0,
+        }
+    }
+
+    /// Adds `other`, summarizing `other_count` values, to these moments of `count` values.
+    fn merge(&mut self, count: u32, other: &Moments, other_count: u32) {
+        let total = count as f64 + other_count as f64;
+        let delta = other.mean - self.mean;
+
+        self.mean += delta * other_count as f64 / total;
+        self.m2 += other.m2 + delta * delta * count as f64 * other_count as f64 / total;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn summary(&self, count: u32) -> Summary {
+        Summary {
+            min: self.min,
+            max: self.max,
+            mean: self.mean as f32,
+            std_dev: sqrt(self.m2 / count as f64) as f32,
+        }
+    }
+}
+
+/// Readings that fell into one slice of a window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    /// Which slice since boot, the time divided by the bucket length.
+    number: u64,
+    count: u32,
+    temperature: Moments,
+    humidity: Moments,
+}
+
+impl Bucket {
+    fn new(number: u64, reading: &Sht3xReading) -> Self {
+        Self {
+            number,
+            count: 1,
+            temperature: Moments::of(reading.temperature),
+            humidity: Moments::of(reading.humidity),
+        }
+    }
+
+    fn merge(&mut self, other: &Bucket) {
+        self.temperature
+            .merge(self.count, &other.temperature, other.count);
+        self.humidity
+            .merge(self.count, &other.humidity, other.count);
+        self.count += other.count;
+    }
+}
+
+/// Statistics of one quantity over a window. Kept as `f32` like `Psychrometrics`, for the
+/// shorter encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Format, Serialize)]
+pub struct Summary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Population standard deviation.
+    pub std_dev: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format, Serialize)]
+pub struct WindowSummary {
+    /// Label of the window, e.g. `24h`.
+    pub window: &'static str,
+    pub samples: u32,
+    /// °C.
+    pub temperature: Summary,
+    /// %RH.
+    pub humidity: Summary,
+}
+
+/// Readings of the last `span_ms`, downsampled into [`BUCKETS`] buckets so memory stays the
+/// same however long the window is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    label: &'static str,
+    bucket_ms: u64,
+    buckets: [Option<Bucket>; BUCKETS],
+}
+
+impl Window {
+    /// `span_ms` is rounded down to a whole number of buckets.
+    pub const fn new(label: &'static str, span_ms: u64) -> Self {
+        let bucket_ms = span_ms / BUCKETS as u64;
+
+        Self {
+            label,
+            bucket_ms: if bucket_ms == 0 { 1 } else { bucket_ms },
+            buckets: [None; BUCKETS],
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
+    /// Adds a reading taken at `now_ms`. Times must not go backwards.
+    pub fn add(&mut self, now_ms: u64, reading: &Sht3xReading) {
+        let number = now_ms / self.bucket_ms;
+        let slot = &mut self.buckets[(number % BUCKETS as u64) as usize];
+
+        match slot {
+            Some(bucket) if bucket.number == number => {
+                bucket.merge(&Bucket::new(number, reading));
+            }
+            _ => *slot = Some(Bucket::new(number, reading)),
+        }
+    }
+
+    /// Statistics of the readings still in the window at `now_ms`, `None` if there are
+    /// none.
+    pub fn summary(&self, now_ms: u64) -> Option<WindowSummary> {
+        let current = now_ms / self.bucket_ms;
+
+        let total = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|bucket| current.wrapping_sub(bucket.number) < BUCKETS as u64)
+            .fold(None, |total: Option<Bucket>, bucket| match total {
+                Some(mut total) => {
+                    total.merge(bucket);
+                    Some(total)
+                }
+                None => Some(*bucket),
+            })?;
+
+        Some(WindowSummary {
+            window: self.label,
+            samples: total.count,
+            temperature: total.temperature.summary(total.count),
+            humidity: total.humidity.summary(total.count),
+        })
+    }
+}
+
+/// Every window kept for one sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics<const W: usize> {
+    windows: [Window; W],
+}
+
+impl<const W: usize> Statistics<W> {
+    pub const fn new(windows: [Window; W]) -> Self {
+        Self { windows }
+    }
+
+    pub fn add(&mut self, now_ms: u64, reading: &Sht3xReading) {
+        for window in &mut self.windows {
+            window.add(now_ms, reading);
+        }
+    }
+
+    /// Summary of each window in order, `None` for those without readings.
+    pub fn summaries(&self, now_ms: u64) -> [Option<WindowSummary>; W] {
+        core::array::from_fn(|i| self.windows[i].summary(now_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+    const DAY_MS: u64 = 24 * HOUR_MS;
+
+    #[track_caller]
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() <= 1e-4,
+            "{actual} is not close to {expected}"
+        );
+    }
+
+    #[test]
+    fn empty() {
+        let window = Window::new("1h", HOUR_MS);
+        assert_eq!(window.label(), "1h");
+        assert_eq!(window.summary(0), None);
+    }
+
+    #[test]
+    fn hour() {
+        let mut window = Window::new("1h", HOUR_MS);
+        // 2.5 minute buckets, so these land in different ones, some shared.
+        let temperatures = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        for (i, temperature) in temperatures.into_iter().enumerate() {
+            window.add(
+                i as u64 * 100_000,
+                &Sht3xReading::new(temperature, 50.0 + temperature),
+            );
+        }
+
+        let summary = window.summary(HOUR_MS / 2).unwrap();
+        assert_eq!(summary.window, "1h");
+        assert_eq!(summary.samples, 8);
+        assert_eq!(
+            summary.temperature,
+            Summary {
+                min: 2.0,
+                max: 9.0,
+                mean: 5.0,
+                std_dev: 2.0,
+            }
+        );
+        assert_eq!(summary.humidity.min, 52.0);
+        assert_eq!(summary.humidity.max, 59.0);
+        assert_near(summary.humidity.mean, 55.0);
+        assert_near(summary.humidity.std_dev, 2.0);
+    }
+
+    #[test]
+    fn day() {
+        let mut window = Window::new("24h", DAY_MS);
+        // Every 10 s for two days, a sawtooth from 0 to 99.
+        let mut now_ms = 0;
+        for i in 0..2 * 24 * 360 {
+            now_ms = i * 10_000;
+            window.add(now_ms, &Sht3xReading::new((i % 100) as f64, 50.0));
+        }
+
+        // Only the last 23 to 24 hours are left.
+        let summary = window.summary(now_ms).unwrap();
+        assert!(
+            (23 * 360..=24 * 360).contains(&summary.samples),
+            "{}",
+            summary.samples
+        );
+        assert_eq!(summary.temperature.min, 0.0);
+        assert_eq!(summary.temperature.max, 99.0);
+        assert!((summary.temperature.mean - 49.5).abs() < 0.1);
+        assert_eq!(summary.humidity.std_dev, 0.0);
+    }
+
+    #[test]
+    fn buckets_roll_over() {
+        let mut window = Window::new("1h", HOUR_MS);
+        window.add(0, &Sht3xReading::new(-10.0, 10.0));
+        window.add(HOUR_MS / 2, &Sht3xReading::new(20.0, 40.0));
+        assert_eq!(window.summary(HOUR_MS / 2).unwrap().samples, 2);
+
+        // The first bucket leaves the window once 24 newer ones have started.
+        let bucket_ms = HOUR_MS / BUCKETS as u64;
+        assert_eq!(window.summary(HOUR_MS - 1).unwrap().samples, 2);
+        let summary = window.summary(HOUR_MS).unwrap();
+        assert_eq!(summary.samples, 1);
+        assert_eq!(summary.temperature.min, 20.0);
+        assert_eq!(window.summary(HOUR_MS / 2 + HOUR_MS), None);
+
+        // A reading a whole window later takes over the slot, and doesn't merge into it.
+        window.add(HOUR_MS / 2 + HOUR_MS, &Sht3xReading::new(5.0, 5.0));
+        let summary = window
+            .summary(HOUR_MS / 2 + HOUR_MS + bucket_ms - 1)
+            .unwrap();
+        assert_eq!(summary.samples, 1);
+        assert_eq!(summary.temperature.max, 5.0);
+        assert_eq!(summary.humidity.mean, 5.0);
+    }
+
+    #[test]
+    fn statistics_feed_every_window() {
+        let mut statistics =
+            Statistics::new([Window::new("1h", HOUR_MS), Window::new("24h", DAY_MS)]);
+        statistics.add(0, &Sht3xReading::new(10.0, 50.0));
+        statistics.add(2 * HOUR_MS, &Sht3xReading::new(20.0, 60.0));
+
+        let [hour, day] = statistics.summaries(2 * HOUR_MS);
+        let hour = hour.unwrap();
+        assert_eq!((hour.window, hour.samples), ("1h", 1));
+        assert_eq!(hour.temperature.mean, 20.0);
+        let day = day.unwrap();
+        assert_eq!((day.window, day.samples), ("24h", 2));
+        assert_eq!(day.temperature.mean, 15.0);
+        assert_eq!(day.humidity.std_dev, 5.0);
+
+        assert_eq!(statistics.summaries(DAY_MS + 3 * HOUR_MS), [None, None]);
+    }
+}
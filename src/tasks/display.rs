@@ -1,23 +1,72 @@
+use core::future::pending;
+
 use defmt::{Format, error};
+use display_interface::DisplayError;
+use embassy_futures::select::{Either3, select3};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
 
-use crate::tasks::DisplayHandle;
+use crate::alert::{Alert, Quantity};
+use crate::drivers::environment::{MAX_SENSORS, Measurement, SensorError, SensorId};
+use crate::psychro::Psychrometrics;
 use crate::tasks::wifi::WifiState;
+use crate::tasks::{DisplayHandle, statistics};
+
+/// What a sensor last reported.
+#[derive(Debug, Clone, Copy, Format)]
+pub enum SensorState {
+    Reading(Measurement),
+    Fault(SensorError),
+}
 
 #[derive(Debug, Clone, Copy, Format)]
 pub struct DisplayData {
-    pub temperature: f64,
-    pub humidity: f64,
+    /// State of each sensor, by `SensorId`. `None` until it first reports.
+    pub sensors: [Option<SensorState>; MAX_SENSORS],
     pub wifi_state: WifiState,
+    /// Newest active alert, flashed on the bottom line in turn with the Wi-Fi status.
+    pub alert: Option<Alert>,
 }
 
 impl DisplayData {
-    pub fn new(temperature: f64, humidity: f64, wifi_state: WifiState) -> Self {
+    pub fn new(
+        sensors: [Option<SensorState>; MAX_SENSORS],
+        wifi_state: WifiState,
+        alert: Option<Alert>,
+    ) -> Self {
         DisplayData {
-            temperature,
-            humidity,
+            sensors,
             wifi_state,
+            alert,
+        }
+    }
+}
+
+/// How long each page stays up.
+const PAGE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the alert banner stays on, and off, while it flashes.
+const FLASH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Page {
+    Readings,
+    /// Dew point and the rest, with the `psychro` feature.
+    Derived,
+    /// Min, max and mean over one of the statistics windows, by index.
+    Statistics(usize),
+}
+
+impl Page {
+    fn next(self) -> Self {
+        match self {
+            Page::Readings if cfg!(feature = "psychro") => Page::Derived,
+            Page::Readings | Page::Derived => Page::Statistics(0),
+            Page::Statistics(window) if window + 1 < statistics::WINDOW_COUNT => {
+                Page::Statistics(window + 1)
+            }
+            Page::Statistics(_) => Page::Readings,
         }
     }
 }
@@ -34,13 +83,120 @@ async fn wait() -> DisplayData {
 
 #[embassy_executor::task]
 pub async fn display_task(mut display: DisplayHandle) {
-    loop {
-        let data = wait().await;
+    let mut data = wait().await;
+    let mut page = Page::Readings;
+    // Kept on its own schedule so readings coming in faster than the pages turn don't
+    // hold the first page up.
+    let mut turn_at = Instant::now() + PAGE_INTERVAL;
+    let mut banner = false;
+    let mut flash_at = Instant::now();
 
-        if let Err(e) =
-            display.show_sensor_data(data.temperature, data.humidity, data.wifi_state.into())
-        {
+    loop {
+        if let Err(e) = show(&mut display, &data, page, banner) {
             error!("Display task error: {}", e);
         }
+
+        let flashing = data.alert.is_some();
+        let flash = async move {
+            if flashing {
+                Timer::at(flash_at).await
+            } else {
+                pending().await
+            }
+        };
+
+        match select3(wait(), Timer::at(turn_at), flash).await {
+            Either3::First(update) => {
+                // Comes up at once rather than whenever the last flash would have been.
+                if update.alert.is_some() && !flashing {
+                    banner = true;
+                    flash_at = Instant::now() + FLASH_INTERVAL;
+                }
+                data = update;
+            }
+            Either3::Second(()) => {
+                page = page.next();
+                turn_at += PAGE_INTERVAL;
+            }
+            Either3::Third(()) => {
+                banner = !banner;
+                flash_at += FLASH_INTERVAL;
+            }
+        }
+    }
+}
+
+/// The bottom line: the alert banner if `banner` is set and there is an alert, the Wi-Fi
+/// status otherwise.
+fn status<'b>(data: &DisplayData, banner: bool, buf: &'b mut [u8]) -> &'b str {
+    let Some(alert) = data.alert.filter(|_| banner) else {
+        return data.wifi_state.into();
+    };
+
+    let quantity = match alert.quantity {
+        Quantity::Temperature => "T",
+        Quantity::Humidity => "RH",
+    };
+
+    format_no_std::show(
+        buf,
+        format_args!(
+            "ALERT {} {} {} {}{}",
+            alert.sensor.name(),
+            quantity,
+            alert.comparison.symbol(),
+            alert.threshold,
+            alert.quantity.unit()
+        ),
+    )
+    .unwrap_or("ALERT")
+}
+
+fn show(
+    display: &mut DisplayHandle,
+    data: &DisplayData,
+    page: Page,
+    banner: bool,
+) -> Result<(), DisplayError> {
+    let mut status_buf = [0u8; 32];
+    let status = status(data, banner, &mut status_buf);
+
+    let states = SensorId::all()
+        .zip(data.sensors)
+        .filter_map(|(id, state)| Some((id, state?)));
+
+    match page {
+        Page::Readings => {
+            let rows: Vec<_, MAX_SENSORS> = states
+                .map(|(id, state)| {
+                    let values = match state {
+                        SensorState::Reading(measurement) => Ok(measurement),
+                        SensorState::Fault(e) => Err(e.label()),
+                    };
+                    (id.name(), values)
+                })
+                .collect();
+            display.show_sensor_data(&rows, status)
+        }
+        Page::Derived => {
+            let rows: Vec<_, MAX_SENSORS> = states
+                .map(|(id, state)| {
+                    let derived = match state {
+                        SensorState::Reading(measurement) => {
+                            measurement.reading().as_ref().and_then(Psychrometrics::new)
+                        }
+                        SensorState::Fault(_) => None,
+                    };
+                    (id.name(), derived)
+                })
+                .collect();
+            display.show_derived_data(&rows, status)
+        }
+        Page::Statistics(window) => {
+            let rows: Vec<_, MAX_SENSORS> = states
+                .map(|(id, _)| (id.name(), statistics::summaries(id)[window]))
+                .collect();
+            display.show_statistics(statistics::label(window), &rows, status)
+        }
     }
 }
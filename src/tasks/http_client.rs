@@ -1,141 +1,646 @@
-use core::net::Ipv4Addr;
+use core::cell::Cell;
 
-use defmt::{error, info, warn};
-use embassy_net::tcp::{TcpSocket, State}; // Import State enum
-use embassy_net::{IpEndpoint, Stack};
+use defmt::{Format, error, info, warn};
+use embassy_net::tcp::{self, State, TcpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Timer};
-use reqwless::client::HttpClient;
+use embassy_time::{Duration, Instant, with_timeout};
+use heapless::Vec;
+use serde::Serialize;
 
-use crate::drivers::sht3x::Sht3xReading;
+use crate::api::SensorStatistics;
+use crate::config::NodeConfig;
+use crate::control::RelayStatus;
+use crate::drivers::environment::MAX_SENSORS;
+use crate::filter::Quality;
+use crate::http::{ProtocolError, Request, ResponseParser, StatusCode, Url};
+use crate::metrics::METRICS;
+use crate::psychro::Psychrometrics;
+use crate::queue::DropPolicy;
+use crate::tasks::net::resolve;
+use crate::tasks::sntp;
+use crate::tasks::statistics::{self, WINDOW_COUNT};
+use crate::tasks::uplink::{
+    self, Failure, Health, QueuedAlert, QueuedReading, Uplink, UplinkQueue,
+};
 
-static HTTP_DATA_SIGNAL: Signal<CriticalSectionRawMutex, Sht3xReading> = Signal::new();
+const QUEUE_CAPACITY: usize = 512;
+const QUEUE_DROP_POLICY: DropPolicy = DropPolicy::Downsample;
 
-pub fn send_sensor_data(data: Sht3xReading) {
-    HTTP_DATA_SIGNAL.signal(data);
+/// Upload at most this many readings per batch request.
+const BATCH_MAX_READINGS: usize = 30;
+/// Worst case for one serialized [`BatchEntry`] is a little under 284 bytes, another 140
+/// with derived values and 56 with the relay state.
+const BATCH_ENTRY_CAPACITY: usize = 288
+    + if cfg!(feature = "psychro") { 136 } else { 0 }
+    + if cfg!(feature = "relay") { 56 } else { 0 };
+const BATCH_BODY_CAPACITY: usize = BATCH_MAX_READINGS * BATCH_ENTRY_CAPACITY;
+/// One serialized window is at most 222 bytes, the sensor name and brackets around them
+/// take another 40.
+const STATISTICS_BODY_CAPACITY: usize = 64 + MAX_SENSORS * (40 + WINDOW_COUNT * 224);
+/// A serialized `AlertMessage` is at most 192 bytes.
+const ALERT_BODY_CAPACITY: usize = 256;
+
+/// Readings waiting for the collector. They are kept while it is unreachable and sent
+/// oldest-first once it is back.
+pub static QUEUE: UplinkQueue<QUEUE_CAPACITY> = UplinkQueue::new("http", QUEUE_DROP_POLICY);
+
+/// Body of a single reading upload.
+#[derive(Serialize)]
+struct ReadingPayload {
+    sensor: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    humidity: Option<f64>,
+    /// Only from chips that measure it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pressure: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    co2: Option<u16>,
+    /// Set instead of the values when the sensor is failing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fault: Option<&'static str>,
+    /// What the plausibility filter discarded before this reading.
+    #[serde(skip_serializing_if = "Quality::is_good")]
+    quality: Quality,
+    /// With the `psychro` feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    derived: Option<Psychrometrics>,
+    /// With the `relay` feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relay: Option<RelayStatus>,
+    timestamp: u64,
+    synced: bool,
 }
 
-async fn wait_for_reading() -> Sht3xReading {
-    HTTP_DATA_SIGNAL.wait().await
+/// One element of the JSON array sent to the batch endpoint. `age_ms` is how long ago the
+/// reading was taken, relative to when the request was built.
+#[derive(Serialize)]
+struct BatchEntry {
+    seq: u32,
+    age_ms: u64,
+    timestamp: u64,
+    synced: bool,
+    sensor: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    humidity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pressure: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    co2: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fault: Option<&'static str>,
+    #[serde(skip_serializing_if = "Quality::is_good")]
+    quality: Quality,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    derived: Option<Psychrometrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relay: Option<RelayStatus>,
 }
 
-const SERVER_IP: [u8; 4] = [192, 168, 100, 14];
-const SERVER_PORT: u16 = 8080;
+/// Body of a statistics upload, sent every `statistics::UPLOAD_INTERVAL`.
+#[derive(Serialize)]
+struct StatisticsPayload<'a> {
+    timestamp: u64,
+    synced: bool,
+    statistics: &'a [SensorStatistics<'a>],
+}
+
+type UploadResult = Result<StatusCode, UploadError>;
+
+static LAST_UPLOAD: Mutex<CriticalSectionRawMutex, Cell<Option<(Instant, UploadResult)>>> =
+    Mutex::new(Cell::new(None));
 
-fn server_endpoint() -> IpEndpoint {
-    let ip = Ipv4Addr::new(SERVER_IP[0], SERVER_IP[1], SERVER_IP[2], SERVER_IP[3]);
-    IpEndpoint::new(ip.into(), SERVER_PORT)
+/// When the last upload attempt finished and how it went.
+pub fn last_upload() -> Option<(Instant, UploadResult)> {
+    LAST_UPLOAD.lock(Cell::get)
 }
 
-#[embassy_executor::task]
-pub async fn http_client_task(stack: &'static Stack<'static>) {
-    info!("http_client: task start");
+const READING_PATH: &str = "/reading";
+const BATCH_PATH: &str = "/readings";
+const STATISTICS_PATH: &str = "/statistics";
+const ALERT_PATH: &str = "/alert";
 
-    stack.wait_config_up().await;
-    if let Some(cfg) = stack.config_v4() {
-        info!("http_client: network up, my IP = {}", cfg.address);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+/// How long to send single readings after the collector answered 404 for a batch, before
+/// trying batches again. It may have been upgraded, or the 404 came from a proxy.
+const BATCH_PROBE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The collector address, resolved through DNS.
+///
+/// The last good address is kept so a flaky DNS server doesn't stop uploads to a collector
+/// that hasn't actually moved.
+struct Collector<'a> {
+    url: Url<'a>,
+    cached: Option<IpAddress>,
+    stale: bool,
+}
+
+impl<'a> Collector<'a> {
+    fn new(url: Url<'a>) -> Self {
+        Self {
+            url,
+            cached: None,
+            stale: true,
+        }
     }
 
-    let mut rx_buf = [0u8; 1024];
-    let mut tx_buf = [0u8; 1024];
-    
-    let mut socket = TcpSocket::new(*stack, &mut rx_buf, &mut tx_buf);
-    socket.set_timeout(Some(Duration::from_secs(20)));
-    
-    // NEW: Enable TCP Keep-Alive (Heartbeat every 15 seconds)
-    // This helps the socket detect if the server died silently.
-    socket.set_keep_alive(Some(Duration::from_secs(15)));
+    async fn endpoint(&mut self, stack: &Stack<'_>) -> Option<IpEndpoint> {
+        if self.stale {
+            let host = self.url.host();
+
+            if let Some(addr) = resolve(stack, host).await {
+                info!("http_client: {} resolved to {}", host, addr);
+                self.cached = Some(addr);
+                self.stale = false;
+            }
+
+            if let (true, Some(addr)) = (self.stale, self.cached) {
+                warn!("http_client: falling back to cached address {}", addr);
+            }
+        }
+
+        self.cached
+            .map(|addr| IpEndpoint::new(addr, self.url.port()))
+    }
+
+    /// Forces a fresh lookup before the next connect, e.g. after the collector stopped
+    /// answering at the cached address.
+    fn invalidate(&mut self) {
+        self.stale = true;
+    }
+}
 
-    let remote = server_endpoint();
+/// Older collectors only know the single reading endpoint and answer 404 for batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum UploadMode {
+    Batch,
+    Single,
+}
+
+impl UploadMode {
+    fn path(self) -> &'static str {
+        match self {
+            UploadMode::Batch => BATCH_PATH,
+            UploadMode::Single => READING_PATH,
+        }
+    }
+
+    fn max_readings(self) -> usize {
+        match self {
+            UploadMode::Batch => BATCH_MAX_READINGS,
+            UploadMode::Single => 1,
+        }
+    }
 
-    loop {
-        info!("http_client: waiting for reading");
-        let reading = wait_for_reading().await;
+    /// Serializes `readings` into `buf` in the format expected by this mode's endpoint.
+    fn encode(self, readings: &[QueuedReading], buf: &mut [u8]) -> Result<usize, UploadError> {
+        let now = Instant::now();
 
-        // 1. CHECK STATE: The "Source of Truth"
-        if socket.state() != State::Established {
-            info!("http_client: socket not open (state: {:?}), connecting...", socket.state());
-            
-            // If it's in a weird limbo state (like TimeWait or CloseWait), forcefully reset it.
-            if socket.state() != State::Closed {
-                warn!("http_client: forcing cleanup of old state");
-                socket.abort();
+        let result = match self {
+            UploadMode::Single => {
+                let entry = readings.first().ok_or(UploadError::Encode)?;
+                let timestamp = sntp::timestamp(entry.queued_at);
+                let payload = ReadingPayload {
+                    sensor: entry.sensor.name(),
+                    temperature: entry.temperature(),
+                    humidity: entry.humidity(),
+                    pressure: entry.pressure(),
+                    co2: entry.co2(),
+                    fault: entry.fault(),
+                    quality: entry.quality,
+                    derived: entry.derived(),
+                    relay: entry.relay,
+                    timestamp: timestamp.unix_ms,
+                    synced: timestamp.synced,
+                };
+                serde_json_core::to_slice(&payload, buf)
             }
+            UploadMode::Batch => {
+                let entries: Vec<BatchEntry, BATCH_MAX_READINGS> = readings
+                    .iter()
+                    .map(|entry| {
+                        let timestamp = sntp::timestamp(entry.queued_at);
+                        BatchEntry {
+                            seq: entry.seq,
+                            age_ms: now.saturating_duration_since(entry.queued_at).as_millis(),
+                            timestamp: timestamp.unix_ms,
+                            synced: timestamp.synced,
+                            sensor: entry.sensor.name(),
+                            temperature: entry.temperature(),
+                            humidity: entry.humidity(),
+                            pressure: entry.pressure(),
+                            co2: entry.co2(),
+                            fault: entry.fault(),
+                            quality: entry.quality,
+                            derived: entry.derived(),
+                            relay: entry.relay,
+                        }
+                    })
+                    .collect();
+                serde_json_core::to_slice(&entries.as_slice(), buf)
+            }
+        };
+
+        result.map_err(|_| UploadError::Encode)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+pub enum UploadError {
+    Encode,
+    Transport(tcp::Error),
+    ConnectionClosed,
+    Timeout,
+    Protocol(ProtocolError),
+    ClientError(StatusCode),
+    ServerError(StatusCode),
+    UnexpectedStatus(StatusCode),
+}
+
+impl UploadError {
+    /// Short machine readable name, for status reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UploadError::Encode => "encode",
+            UploadError::Transport(_) => "transport",
+            UploadError::ConnectionClosed => "connection_closed",
+            UploadError::Timeout => "timeout",
+            UploadError::Protocol(_) => "protocol",
+            UploadError::ClientError(_) => "client_error",
+            UploadError::ServerError(_) => "server_error",
+            UploadError::UnexpectedStatus(_) => "unexpected_status",
+        }
+    }
 
-            match socket.connect(remote).await {
-                Ok(()) => info!("http_client: connected"),
-                Err(e) => {
-                    warn!("http_client: connect error: {:?}", e);
-                    Timer::after(Duration::from_secs(3)).await;
-                    continue; // Retry connection next loop
-                }
+    /// The status the collector answered with, if it got that far.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            UploadError::ClientError(status)
+            | UploadError::ServerError(status)
+            | UploadError::UnexpectedStatus(status) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl From<tcp::Error> for UploadError {
+    fn from(err: tcp::Error) -> Self {
+        UploadError::Transport(err)
+    }
+}
+
+impl From<ProtocolError> for UploadError {
+    fn from(err: ProtocolError) -> Self {
+        UploadError::Protocol(err)
+    }
+}
+
+async fn write_all(socket: &mut TcpSocket<'_>, mut buf: &[u8]) -> Result<(), UploadError> {
+    while !buf.is_empty() {
+        match socket.write(buf).await? {
+            0 => return Err(UploadError::ConnectionClosed),
+            n => buf = &buf[n..],
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `request` over an established socket and waits for the complete response.
+///
+/// On success the socket is left open if the server agreed to keep the connection alive,
+/// otherwise it is closed so the next upload reconnects.
+async fn post(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+) -> Result<StatusCode, UploadError> {
+    let mut head_buf = [0u8; 256];
+    let head = request
+        .write_head(&mut head_buf)
+        .map_err(|_| UploadError::Encode)?;
+
+    write_all(socket, head).await?;
+    write_all(socket, request.body()).await?;
+    socket.flush().await?;
+
+    let mut parser = ResponseParser::new();
+    let mut resp_buf = [0u8; 256];
+
+    let response = loop {
+        let n = with_timeout(RESPONSE_TIMEOUT, socket.read(&mut resp_buf))
+            .await
+            .map_err(|_| UploadError::Timeout)??;
+
+        if n == 0 {
+            break parser.finish()?;
+        }
+
+        parser.feed(&resp_buf[..n])?;
+        if let Some(response) = parser.response() {
+            break response;
+        }
+    };
+
+    if !response.keep_alive {
+        socket.close();
+    }
+
+    match response.status {
+        status if status.is_success() => Ok(status),
+        status if status.is_client_error() => Err(UploadError::ClientError(status)),
+        status if status.is_server_error() => Err(UploadError::ServerError(status)),
+        status => Err(UploadError::UnexpectedStatus(status)),
+    }
+}
+
+/// Uploads to the collector over one kept-alive connection.
+struct HttpUplink<'a> {
+    stack: &'a Stack<'a>,
+    socket: TcpSocket<'a>,
+    collector: Collector<'a>,
+    mode: UploadMode,
+    /// When to try batches again in [`UploadMode::Single`].
+    batch_probe_at: Instant,
+    /// Cleared once the collector answers 404 for statistics, older ones don't take them.
+    upload_statistics: bool,
+    next_statistics: Instant,
+    health: Health,
+}
+
+impl HttpUplink<'_> {
+    /// Opens the connection if it isn't open already.
+    async fn connect(&mut self) -> Result<(), &'static str> {
+        if self.socket.state() == State::Established {
+            return Ok(());
+        }
+
+        info!(
+            "http_client: socket not open (state: {:?}), connecting...",
+            self.socket.state()
+        );
+
+        // If it's in a limbo state (like TimeWait or CloseWait), forcefully reset it.
+        if self.socket.state() != State::Closed {
+            warn!("http_client: forcing cleanup of old state");
+            self.socket.abort();
+        }
+
+        let Some(remote) = self.collector.endpoint(self.stack).await else {
+            warn!("http_client: collector address unknown, retrying");
+            return Err("unresolved");
+        };
+
+        match self.socket.connect(remote).await {
+            Ok(()) => {
+                info!("http_client: connected to {}", remote);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("http_client: connect error: {:?}", e);
+                self.collector.invalidate();
+                Err("connect")
             }
         }
+    }
 
-        let mut json_buf = [0u8; 100];
-        let json_len = serde_json_core::to_slice(&reading, &mut json_buf).unwrap();
+    /// Uploads the statistics of every sensor if they are due. Failures are only logged,
+    /// the next attempt is an interval later either way.
+    async fn upload_statistics_if_due(&mut self) {
+        let now = Instant::now();
+        if !self.upload_statistics || now < self.next_statistics {
+            return;
+        }
+        self.next_statistics = now + statistics::UPLOAD_INTERVAL;
 
-        let mut req_buf = [0u8; 256];
-        let request = format_no_std::show(
-            &mut req_buf,
-            format_args!(
-                "POST /reading HTTP/1.1\r\n\
-Host: local\r\n\
-Content-Type: application/json\r\n\
-Content-Length: {}\r\n\
-Connection: keep-alive\r\n\
-\r\n",
-                json_len
-            ),
-        ).unwrap();
+        let summaries = statistics::all();
+        if summaries.is_empty() {
+            return;
+        }
 
-        // ---- Send Request ----
-        if let Err(e) = socket.write(request.as_bytes()).await {
-            warn!("http_client: write header error: {:?}, aborting", e);
-            socket.abort();
-            continue;
+        let sensors: Vec<SensorStatistics<'_>, MAX_SENSORS> = summaries
+            .iter()
+            .map(|(sensor, windows)| SensorStatistics {
+                sensor: sensor.name(),
+                windows,
+            })
+            .collect();
+        let timestamp = sntp::timestamp(now);
+        let payload = StatisticsPayload {
+            timestamp: timestamp.unix_ms,
+            synced: timestamp.synced,
+            statistics: &sensors,
+        };
+
+        let mut body_buf = [0u8; STATISTICS_BODY_CAPACITY];
+        let Ok(body_len) = serde_json_core::to_slice(&payload, &mut body_buf) else {
+            warn!("http_client: failed to encode statistics");
+            return;
+        };
+
+        if self.connect().await.is_err() {
+            return;
         }
 
-        if let Err(e) = socket.write(&json_buf).await {
-            warn!("http_client: write body error: {:?}, aborting", e);
-            socket.abort();
-            continue;
+        let request = Request::post(self.collector.url.authority(), STATISTICS_PATH)
+            .json(&body_buf[..body_len]);
+
+        match post(&mut self.socket, &request).await {
+            Ok(status) => info!("http_client: statistics upload OK ({})", status.0),
+            Err(UploadError::ClientError(StatusCode(404))) => {
+                warn!("http_client: collector has no statistics endpoint, not sending them");
+                self.upload_statistics = false;
+            }
+            Err(e) if e.status().is_some() => {
+                warn!("http_client: statistics upload rejected: {:?}", e);
+            }
+            Err(e) => {
+                warn!("http_client: statistics upload failed: {:?}, aborting", e);
+                self.socket.abort();
+            }
+        }
+    }
+}
+
+impl Uplink for HttpUplink<'_> {
+    fn max_batch(&self) -> usize {
+        self.mode.max_readings()
+    }
+
+    async fn publish(&mut self, readings: &[QueuedReading]) -> Result<(), Failure> {
+        if let Err(reason) = self.connect().await {
+            self.health = Health::Down(reason);
+            return Err(Failure::Retry(RETRY_DELAY));
+        }
+
+        let Some(last) = readings.last() else {
+            return Ok(());
+        };
+
+        if self.mode == UploadMode::Single && Instant::now() >= self.batch_probe_at {
+            info!("http_client: trying batch uploads again");
+            self.mode = UploadMode::Batch;
+        }
+
+        let mut body_buf = [0u8; BATCH_BODY_CAPACITY];
+        let body_len = match self.mode.encode(readings, &mut body_buf) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!(
+                    "http_client: failed to encode readings up to {}: {:?}",
+                    last.seq, e
+                );
+                return Err(Failure::Reject);
+            }
+        };
+
+        let request = Request::post(self.collector.url.authority(), self.mode.path())
+            .json(&body_buf[..body_len]);
+
+        let result = post(&mut self.socket, &request).await;
+        LAST_UPLOAD.lock(|last| last.set(Some((Instant::now(), result))));
+
+        match &result {
+            Ok(_) => METRICS.uploads.inc(),
+            Err(e) => METRICS.upload_failures.inc(e.kind()),
         }
 
-        info!("http_client: before flush");
+        self.health = match &result {
+            Ok(_) => Health::Up,
+            Err(e) => Health::Down(e.kind()),
+        };
 
-        if let Err(e) = socket.flush().await {
-            warn!("http_client: flush error: {:?}, aborting", e);
-            socket.abort();
-            continue;
+        match result {
+            Ok(status) => {
+                info!(
+                    "http_client: upload OK ({}), {} readings up to {}",
+                    status.0,
+                    readings.len(),
+                    last.seq
+                );
+                self.upload_statistics_if_due().await;
+                Ok(())
+            }
+            Err(UploadError::ClientError(StatusCode(404))) if self.mode == UploadMode::Batch => {
+                warn!("http_client: collector has no batch endpoint, sending single readings");
+                self.mode = UploadMode::Single;
+                self.batch_probe_at = Instant::now() + BATCH_PROBE_INTERVAL;
+                Err(Failure::Retry(Duration::from_secs(0)))
+            }
+            Err(e @ (UploadError::ClientError(_) | UploadError::UnexpectedStatus(_))) => {
+                // Retrying won't change the answer, so don't let these readings block the queue.
+                warn!("http_client: upload rejected: {:?}", e);
+                Err(Failure::Reject)
+            }
+            Err(e @ UploadError::ServerError(_)) => {
+                warn!("http_client: upload rejected: {:?}, will retry", e);
+                Err(Failure::Retry(RETRY_DELAY))
+            }
+            Err(e) => {
+                warn!("http_client: upload failed: {:?}, aborting", e);
+                self.socket.abort();
+                Err(Failure::Retry(RETRY_DELAY))
+            }
         }
+    }
+
+    async fn publish_alert(&mut self, alert: &QueuedAlert) -> Result<(), Failure> {
+        if let Err(reason) = self.connect().await {
+            self.health = Health::Down(reason);
+            return Err(Failure::Retry(RETRY_DELAY));
+        }
+
+        let mut body_buf = [0u8; ALERT_BODY_CAPACITY];
+        let Ok(body_len) = serde_json_core::to_slice(&alert.message(), &mut body_buf) else {
+            warn!("http_client: failed to encode alert");
+            return Err(Failure::Reject);
+        };
 
-        info!("http_client: after flush");
+        let request =
+            Request::post(self.collector.url.authority(), ALERT_PATH).json(&body_buf[..body_len]);
 
-        // ---- Read Response ----
-        let mut resp_buf = [0u8; 1024];
-        
-        match embassy_time::with_timeout(Duration::from_millis(500), socket.read(&mut resp_buf)).await {
-            Ok(Ok(0)) => {
-                warn!("http_client: server closed connection (EOF)");
-                socket.abort(); 
+        let result = post(&mut self.socket, &request).await;
+        self.health = match &result {
+            Ok(_) => Health::Up,
+            Err(e) => Health::Down(e.kind()),
+        };
+
+        match result {
+            Ok(status) => {
+                info!("http_client: alert upload OK ({})", status.0);
+                Ok(())
             }
-            Ok(Ok(n)) => {
-                info!("http_client: OK, received {} bytes", n);
-                // Socket remains Established for next loop!
+            // Older collectors answer 404, they would never take it.
+            Err(e @ (UploadError::ClientError(_) | UploadError::UnexpectedStatus(_))) => {
+                warn!("http_client: alert rejected: {:?}", e);
+                Err(Failure::Reject)
             }
-            Ok(Err(e)) => {
-                warn!("http_client: read error: {:?}, aborting", e);
-                socket.abort();
+            Err(e @ UploadError::ServerError(_)) => {
+                warn!("http_client: alert rejected: {:?}, will retry", e);
+                Err(Failure::Retry(RETRY_DELAY))
             }
-            Err(_) => {
-                // Timeout implies server is keeping connection open but sent no extra data.
-                // This is the expected "Happy Path" for Keep-Alive.
-                info!("http_client: transaction complete (connection kept alive)");
+            Err(e) => {
+                warn!("http_client: alert upload failed: {:?}, aborting", e);
+                self.socket.abort();
+                Err(Failure::Retry(RETRY_DELAY))
             }
         }
+    }
 
-        info!("http_client: end of loop");
+    async fn idle(&mut self) {
+        self.upload_statistics_if_due().await;
     }
-}
\ No newline at end of file
+
+    fn health(&self) -> Health {
+        self.health
+    }
+}
+
+#[embassy_executor::task]
+pub async fn http_client_task(stack: &'static Stack<'static>, config: &'static NodeConfig) {
+    info!("http_client: task start");
+
+    stack.wait_config_up().await;
+    if let Some(cfg) = stack.config_v4() {
+        info!("http_client: network up, my IP = {}", cfg.address);
+    }
+
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 1024];
+
+    let mut socket = TcpSocket::new(*stack, &mut rx_buf, &mut tx_buf);
+    socket.set_timeout(Some(Duration::from_secs(20)));
+
+    // Heartbeat every 15 seconds so the socket notices if the server died silently.
+    socket.set_keep_alive(Some(Duration::from_secs(15)));
+
+    let url = match Url::parse(&config.collector_url) {
+        Ok(url) => url,
+        Err(e) => {
+            error!(
+                "http_client: invalid collector url {}: {:?}",
+                config.collector_url.as_str(),
+                e
+            );
+            return;
+        }
+    };
+
+    let mut uplink = HttpUplink {
+        stack,
+        socket,
+        collector: Collector::new(url),
+        mode: UploadMode::Batch,
+        batch_probe_at: Instant::now(),
+        upload_statistics: true,
+        next_statistics: Instant::now(),
+        health: Health::Starting,
+    };
+
+    uplink::run(&mut uplink, &QUEUE).await
+}
@@ -0,0 +1,352 @@
+use core::cell::Cell;
+use core::fmt::Write;
+
+use defmt::{info, warn};
+use embassy_net::Stack;
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec};
+
+use crate::alert::{Alert, MAX_ACTIVE_ALERTS};
+use crate::api::{
+    self, AlertBody, HeapStats, ReadingBody, SensorStatistics, Snapshot, StatusBody, UplinkStatus,
+};
+use crate::control::RelayStatus;
+use crate::device::DeviceInfo;
+use crate::drivers::environment::{MAX_SENSORS, Measurement, SensorError, SensorId};
+use crate::http::{ProtocolError, RequestHead, StatusCode, write_response_head};
+use crate::metrics::{METRICS, UPLINKS};
+use crate::tasks::uplink::{self, Health, UplinkStats};
+use crate::tasks::wifi::WifiState;
+use crate::tasks::{sntp, statistics};
+
+/// Connections served at once, one task and socket each.
+pub const SERVER_SOCKETS: usize = 3;
+
+const HTTP_PORT: u16 = 80;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request accepted, head and body together. Anything larger gets a 413.
+const REQUEST_CAPACITY: usize = 1024;
+
+/// Latest reading of each sensor, by `SensorId`.
+static LATEST_READINGS: Mutex<
+    CriticalSectionRawMutex,
+    Cell<[Option<(Instant, Measurement)>; MAX_SENSORS]>,
+> = Mutex::new(Cell::new([None; MAX_SENSORS]));
+/// Sensors that are failing, by `SensorId`, until their next reading.
+static SENSOR_FAULTS: Mutex<CriticalSectionRawMutex, Cell<[Option<SensorError>; MAX_SENSORS]>> =
+    Mutex::new(Cell::new([None; MAX_SENSORS]));
+/// Active alerts and when they were raised, oldest first.
+static ACTIVE_ALERTS: Mutex<
+    CriticalSectionRawMutex,
+    Cell<[Option<(Instant, Alert)>; MAX_ACTIVE_ALERTS]>,
+> = Mutex::new(Cell::new([None; MAX_ACTIVE_ALERTS]));
+static WIFI_STATE: Mutex<CriticalSectionRawMutex, Cell<WifiState>> =
+    Mutex::new(Cell::new(WifiState::Connecting));
+
+pub fn update_reading(sensor: SensorId, reading: Measurement) {
+    LATEST_READINGS.lock(|latest| {
+        let mut readings = latest.get();
+        readings[sensor.index()] = Some((Instant::now(), reading));
+        latest.set(readings);
+    });
+    set_fault(sensor, None);
+}
+
+pub fn update_fault(sensor: SensorId, error: SensorError) {
+    set_fault(sensor, Some(error));
+}
+
+fn set_fault(sensor: SensorId, error: Option<SensorError>) {
+    SENSOR_FAULTS.lock(|faults| {
+        let mut errors = faults.get();
+        errors[sensor.index()] = error;
+        faults.set(errors);
+    });
+}
+
+pub fn update_alerts(alerts: &[(Instant, Alert)]) {
+    let mut active = [None; MAX_ACTIVE_ALERTS];
+    for (slot, alert) in active.iter_mut().zip(alerts) {
+        *slot = Some(*alert);
+    }
+    ACTIVE_ALERTS.lock(|alerts| alerts.set(active));
+}
+
+pub fn update_wifi_state(state: WifiState) {
+    WIFI_STATE.lock(|wifi| wifi.set(state));
+}
+
+/// How reading a request from a socket ended.
+pub enum ReadRequest {
+    /// The head ends at `head_len` and the body at `end`.
+    Complete {
+        head_len: usize,
+        end: usize,
+    },
+    TooLarge,
+    Malformed(ProtocolError),
+    Closed,
+}
+
+/// Reads one request, head and `Content-Length` body, into `buf`.
+pub async fn read_request(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+) -> Result<ReadRequest, tcp::Error> {
+    let mut len = 0;
+
+    let (head_len, content_length) = loop {
+        match RequestHead::parse(&buf[..len]) {
+            Ok(Some((head, head_len))) => break (head_len, head.content_length),
+            Ok(None) if len == buf.len() => return Ok(ReadRequest::TooLarge),
+            Ok(None) => {}
+            Err(e) => return Ok(ReadRequest::Malformed(e)),
+        }
+
+        match socket.read(&mut buf[len..]).await? {
+            0 => return Ok(ReadRequest::Closed),
+            n => len += n,
+        }
+    };
+
+    let end = head_len + content_length;
+    if end > buf.len() {
+        return Ok(ReadRequest::TooLarge);
+    }
+
+    while len < end {
+        match socket.read(&mut buf[len..end]).await? {
+            0 => return Ok(ReadRequest::Closed),
+            n => len += n,
+        }
+    }
+
+    Ok(ReadRequest::Complete { head_len, end })
+}
+
+pub async fn write_all(socket: &mut TcpSocket<'_>, mut buf: &[u8]) -> Result<(), tcp::Error> {
+    while !buf.is_empty() {
+        match socket.write(buf).await? {
+            0 => return Err(tcp::Error::ConnectionReset),
+            n => buf = &buf[n..],
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a complete response. The connection is closed afterwards.
+pub async fn send_response(
+    socket: &mut TcpSocket<'_>,
+    status: StatusCode,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), tcp::Error> {
+    let mut head = [0u8; 192];
+    let Ok(head) = write_response_head(&mut head, status, content_type, body.len()) else {
+        return Ok(());
+    };
+
+    write_all(socket, head).await?;
+    write_all(socket, body).await?;
+    socket.flush().await
+}
+
+fn wifi_name(state: WifiState) -> &'static str {
+    match state {
+        WifiState::Connecting => "connecting",
+        WifiState::Connected => "connected",
+        WifiState::Disconnected => "disconnected",
+        WifiState::Provisioning => "provisioning",
+    }
+}
+
+fn uplink_status(stats: &UplinkStats) -> UplinkStatus<'static> {
+    let (state, error) = match stats.health {
+        Health::Starting => ("starting", None),
+        Health::Up => ("up", None),
+        Health::Down(reason) => ("down", Some(reason)),
+    };
+
+    UplinkStatus {
+        name: stats.name,
+        state,
+        error,
+        queued: stats.queued,
+        capacity: stats.capacity,
+        dropped: stats.dropped,
+        delivered: stats.delivered,
+        rejected: stats.rejected,
+        failures: stats.failures,
+    }
+}
+
+#[cfg(feature = "http")]
+fn last_upload() -> Option<api::UploadStatus<'static>> {
+    use crate::tasks::http_client;
+
+    let (at, result) = http_client::last_upload()?;
+
+    Some(api::UploadStatus {
+        ok: result.is_ok(),
+        error: result.err().map(|e| e.kind()),
+        status: match result {
+            Ok(status) => Some(status.0),
+            Err(e) => e.status().map(|status| status.0),
+        },
+        age_s: at.elapsed().as_secs(),
+    })
+}
+
+#[cfg(not(feature = "http"))]
+fn last_upload() -> Option<api::UploadStatus<'static>> {
+    None
+}
+
+/// Applies a relay command in the request, and returns where the relay stands after it.
+#[cfg(feature = "relay")]
+fn relay(head: &RequestHead<'_>, body: &[u8]) -> Option<RelayStatus> {
+    use crate::api::Route;
+    use crate::control::Command;
+    use crate::tasks::relay;
+
+    // `api::handle` answers a bad command, it only gets applied here.
+    Some(
+        match (Route::resolve(head.method, head.path), Command::parse(body)) {
+            (Ok(Route::SetRelay), Some(command)) => relay::command(command),
+            _ => relay::status(),
+        },
+    )
+}
+
+#[cfg(not(feature = "relay"))]
+fn relay(_head: &RequestHead<'_>, _body: &[u8]) -> Option<RelayStatus> {
+    None
+}
+
+async fn serve(
+    socket: &mut TcpSocket<'_>,
+    stack: &Stack<'_>,
+    device: &DeviceInfo,
+) -> Result<(), tcp::Error> {
+    let mut request = [0u8; REQUEST_CAPACITY];
+
+    let (head_len, end) = match read_request(socket, &mut request).await? {
+        ReadRequest::Complete { head_len, end } => (head_len, end),
+        ReadRequest::TooLarge => {
+            return send_response(socket, StatusCode(413), api::JSON, &[]).await;
+        }
+        ReadRequest::Malformed(e) => {
+            warn!("http_server: bad request: {:?}", e);
+            return send_response(socket, StatusCode(400), api::JSON, &[]).await;
+        }
+        ReadRequest::Closed => return Ok(()),
+    };
+
+    let Ok(Some((head, _))) = RequestHead::parse(&request[..head_len]) else {
+        return Ok(());
+    };
+    let body = &request[head_len..end];
+
+    let relay = relay(&head, body);
+
+    let mut ip: String<15> = String::new();
+    if let Some(config) = stack.config_v4() {
+        let _ = write!(ip, "{}", config.address.address());
+    }
+
+    let faults = SENSOR_FAULTS.lock(Cell::get);
+    let readings: Vec<ReadingBody, MAX_SENSORS> = SensorId::all()
+        .zip(LATEST_READINGS.lock(Cell::get))
+        .filter_map(|(sensor, latest)| {
+            latest.map(|(at, reading)| {
+                ReadingBody::new(
+                    sensor,
+                    &reading,
+                    at.elapsed().as_millis(),
+                    sntp::timestamp(at),
+                )
+                .with_fault(faults[sensor.index()])
+            })
+        })
+        .collect();
+
+    let alerts: Vec<AlertBody, MAX_ACTIVE_ALERTS> = ACTIVE_ALERTS
+        .lock(Cell::get)
+        .iter()
+        .flatten()
+        .map(|(at, alert)| AlertBody::new(alert, at.elapsed().as_secs(), sntp::timestamp(*at)))
+        .collect();
+
+    let summaries = statistics::all();
+    let sensor_statistics: Vec<SensorStatistics<'_>, MAX_SENSORS> = summaries
+        .iter()
+        .map(|(sensor, windows)| SensorStatistics {
+            sensor: sensor.name(),
+            windows,
+        })
+        .collect();
+
+    let uplinks: Vec<UplinkStatus<'_>, { UPLINKS.len() }> =
+        uplink::stats().await.iter().map(uplink_status).collect();
+
+    let snapshot = Snapshot {
+        readings: &readings,
+        statistics: &sensor_statistics,
+        alerts: &alerts,
+        relay,
+        status: StatusBody {
+            node_id: &device.node_id,
+            firmware_version: device.firmware_version,
+            wifi: wifi_name(WIFI_STATE.lock(Cell::get)),
+            ip: (!ip.is_empty()).then_some(ip.as_str()),
+            uptime_s: Instant::now().as_secs(),
+            heap: HeapStats {
+                used: esp_alloc::HEAP.used(),
+                free: esp_alloc::HEAP.free(),
+            },
+            last_upload: last_upload(),
+            uplinks: &uplinks,
+        },
+        metrics: &METRICS,
+    };
+
+    let mut response = [0u8; api::RESPONSE_CAPACITY];
+    let reply = api::handle(head.method, head.path, body, &snapshot, &mut response);
+
+    send_response(socket, reply.status, reply.content_type, reply.body).await
+}
+
+#[embassy_executor::task(pool_size = SERVER_SOCKETS)]
+pub async fn http_server_task(stack: &'static Stack<'static>, device: &'static DeviceInfo) {
+    stack.wait_config_up().await;
+
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 1024];
+
+    loop {
+        let mut socket = TcpSocket::new(*stack, &mut rx_buf, &mut tx_buf);
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            warn!("http_server: accept error: {:?}", e);
+            continue;
+        }
+
+        if let Some(remote) = socket.remote_endpoint() {
+            info!("http_server: request from {}", remote);
+        }
+
+        if let Err(e) = serve(&mut socket, stack, device).await {
+            warn!("http_server: connection error: {:?}", e);
+        }
+
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+    }
+}
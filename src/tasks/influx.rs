@@ -0,0 +1,542 @@
+use core::fmt::Write;
+
+use defmt::{Format, info, warn};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_net::udp::{self, PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_time::{Duration, Instant, with_timeout};
+use heapless::{String, Vec};
+
+use crate::config::NodeConfig;
+use crate::device::DeviceInfo;
+use crate::drivers::environment::SensorId;
+use crate::http::{BodyWriter, ProtocolError, Request, Response, ResponseParser, StatusCode};
+use crate::influx::{FieldValue, InfluxError, Point};
+use crate::queue::DropPolicy;
+use crate::stats::WindowSummary;
+use crate::tasks::http_server::write_all;
+use crate::tasks::net::resolve;
+use crate::tasks::statistics::{self, WINDOW_COUNT};
+use crate::tasks::uplink::{
+    self, Failure, Health, QueuedAlert, QueuedReading, Uplink, UplinkQueue,
+};
+use crate::tasks::{sensor, sntp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum Transport {
+    /// One datagram per reading to the UDP listener. Cheap, but unacknowledged.
+    Udp,
+    /// `POST /api/v2/write` of InfluxDB 2.x, authenticated with [`TOKEN`].
+    Http,
+}
+
+const TRANSPORT: Transport = Transport::Http;
+const INFLUX_HOST: &str = "influxdb.lan";
+const UDP_PORT: u16 = 8089;
+const HTTP_PORT: u16 = 8086;
+/// Sent as query parameters, so they must not need URL encoding.
+const ORG: &str = "home";
+const BUCKET: &str = "home-monitor";
+const TOKEN: &str = "";
+
+const MEASUREMENT: &str = "environment";
+/// One point per sensor and window, tagged with the window, every
+/// `statistics::UPLOAD_INTERVAL`.
+const STATISTICS_MEASUREMENT: &str = "statistics";
+/// One point when an alert is raised and another when it clears, tagged with the rule.
+const ALERT_MEASUREMENT: &str = "alerts";
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+
+const LINE_CAPACITY: usize = 320;
+/// Lines per datagram, kept well below the MTU.
+const UDP_BATCH_MAX_LINES: usize = 4;
+const HTTP_BATCH_MAX_LINES: usize = 10;
+const BODY_CAPACITY: usize = HTTP_BATCH_MAX_LINES * LINE_CAPACITY;
+/// A statistics line has nine fields.
+const STATISTICS_LINE_CAPACITY: usize = 2 * LINE_CAPACITY;
+
+const QUEUE_CAPACITY: usize = 64;
+
+pub static QUEUE: UplinkQueue<QUEUE_CAPACITY> = UplinkQueue::new("influx", DropPolicy::DropOldest);
+
+#[derive(Debug, Clone, Copy, Format)]
+enum WriteError {
+    Encode(InfluxError),
+    Unresolved,
+    Send(udp::SendError),
+    Connect(tcp::ConnectError),
+    Transport(tcp::Error),
+    Timeout,
+    Protocol(ProtocolError),
+    Rejected(StatusCode),
+}
+
+impl WriteError {
+    /// Short machine readable name, for status reports.
+    fn kind(&self) -> &'static str {
+        match self {
+            WriteError::Encode(_) => "encode",
+            WriteError::Unresolved => "unresolved",
+            WriteError::Send(_) => "send",
+            WriteError::Connect(_) => "connect",
+            WriteError::Transport(_) => "transport",
+            WriteError::Timeout => "timeout",
+            WriteError::Protocol(_) => "protocol",
+            WriteError::Rejected(_) => "rejected",
+        }
+    }
+}
+
+impl From<InfluxError> for WriteError {
+    fn from(err: InfluxError) -> Self {
+        WriteError::Encode(err)
+    }
+}
+
+impl From<tcp::Error> for WriteError {
+    fn from(err: tcp::Error) -> Self {
+        WriteError::Transport(err)
+    }
+}
+
+impl From<ProtocolError> for WriteError {
+    fn from(err: ProtocolError) -> Self {
+        WriteError::Protocol(err)
+    }
+}
+
+/// Formats a reading as one line, with pressure and CO2 from chips that measure them, or a
+/// fault as a `fault` field in place of the values, followed by the relay state if there
+/// is one. The timestamp is left for the server to
+/// fill in until the clock has synced.
+fn encode(
+    entry: &QueuedReading,
+    device: &DeviceInfo,
+    config: &NodeConfig,
+    buf: &mut [u8],
+) -> Result<usize, InfluxError> {
+    let QueuedReading {
+        queued_at: at,
+        sensor,
+        reading,
+        quality,
+        relay,
+        ..
+    } = *entry;
+    let mut fields: Vec<(&str, FieldValue<'_>), 7> = Vec::new();
+
+    // Can't fail, there is room for every field.
+    match reading {
+        Ok(reading) => {
+            let values = [
+                ("temperature", reading.temperature.map(FieldValue::Float)),
+                ("humidity", reading.humidity.map(FieldValue::Float)),
+                ("pressure", reading.pressure.map(FieldValue::Float)),
+                (
+                    "co2",
+                    reading.co2.map(|co2| FieldValue::Integer(co2.into())),
+                ),
+            ];
+            for (name, value) in values {
+                if let Some(value) = value {
+                    let _ = fields.push((name, value));
+                }
+            }
+        }
+        Err(e) => {
+            let _ = fields.push(("fault", FieldValue::String(e.label())));
+        }
+    }
+    // Flags only go out when the filter discarded something, see `Quality`.
+    if reading.is_ok() && !quality.is_good() {
+        let _ = fields.push(("quality", FieldValue::Integer(quality.bits() as i64)));
+    }
+    if let Some(relay) = relay {
+        let _ = fields.extend_from_slice(&[
+            ("relay", FieldValue::Boolean(relay.on)),
+            ("relay_mode", FieldValue::String(relay.mode.label())),
+        ]);
+    }
+
+    let point = Point {
+        measurement: MEASUREMENT,
+        tags: &[
+            ("node", device.node_id.as_str()),
+            ("location", config.location.as_str()),
+            ("sensor", sensor.name()),
+        ],
+        fields: &fields,
+        timestamp_ns: sntp::utc_at(at).map(|utc_us| utc_us * 1000),
+    };
+
+    let mut line = BodyWriter::new(buf);
+    point.write(&mut line)?;
+    Ok(line.len())
+}
+
+/// Formats the summary of one window of `sensor` as one line.
+fn encode_statistics(
+    at: Instant,
+    sensor: SensorId,
+    summary: &WindowSummary,
+    device: &DeviceInfo,
+    config: &NodeConfig,
+    buf: &mut [u8],
+) -> Result<usize, InfluxError> {
+    // Rounded, so the f32 values don't go out with the noise of their f64 widening.
+    let value = |value: f32| FieldValue::Float(libm::round(value as f64 * 1000.0) / 1000.0);
+    let (t, h) = (summary.temperature, summary.humidity);
+
+    let point = Point {
+        measurement: STATISTICS_MEASUREMENT,
+        tags: &[
+            ("node", device.node_id.as_str()),
+            ("location", config.location.as_str()),
+            ("sensor", sensor.name()),
+            ("window", summary.window),
+        ],
+        fields: &[
+            ("samples", FieldValue::Integer(summary.samples as i64)),
+            ("temperature_min", value(t.min)),
+            ("temperature_max", value(t.max)),
+            ("temperature_mean", value(t.mean)),
+            ("temperature_std_dev", value(t.std_dev)),
+            ("humidity_min", value(h.min)),
+            ("humidity_max", value(h.max)),
+            ("humidity_mean", value(h.mean)),
+            ("humidity_std_dev", value(h.std_dev)),
+        ],
+        timestamp_ns: sntp::utc_at(at).map(|utc_us| utc_us * 1000),
+    };
+
+    let mut line = BodyWriter::new(buf);
+    point.write(&mut line)?;
+    Ok(line.len())
+}
+
+/// Formats an alert raised or cleared as one line.
+fn encode_alert(
+    alert: &QueuedAlert,
+    device: &DeviceInfo,
+    config: &NodeConfig,
+    buf: &mut [u8],
+) -> Result<usize, InfluxError> {
+    let mut rule: String<3> = String::new();
+    write!(rule, "{}", alert.alert.rule)?;
+
+    let point = Point {
+        measurement: ALERT_MEASUREMENT,
+        tags: &[
+            ("node", device.node_id.as_str()),
+            ("location", config.location.as_str()),
+            ("sensor", alert.alert.sensor.name()),
+            ("rule", rule.as_str()),
+            ("quantity", alert.alert.quantity.label()),
+            ("comparison", alert.alert.comparison.label()),
+        ],
+        fields: &[
+            ("active", FieldValue::Boolean(alert.raised)),
+            ("threshold", FieldValue::Float(alert.alert.threshold as f64)),
+            ("value", FieldValue::Float(alert.alert.value as f64)),
+        ],
+        timestamp_ns: sntp::utc_at(alert.queued_at).map(|utc_us| utc_us * 1000),
+    };
+
+    let mut line = BodyWriter::new(buf);
+    point.write(&mut line)?;
+    Ok(line.len())
+}
+
+async fn send_udp(
+    socket: &mut UdpSocket<'_>,
+    server: IpAddress,
+    lines: &[u8],
+) -> Result<(), WriteError> {
+    socket
+        .send_to(lines, IpEndpoint::new(server, UDP_PORT))
+        .await
+        .map_err(WriteError::Send)
+}
+
+/// Sends a request and reads the complete response.
+async fn exchange(
+    socket: &mut TcpSocket<'_>,
+    head: &[u8],
+    body: &[u8],
+) -> Result<Response, WriteError> {
+    write_all(socket, head).await?;
+    write_all(socket, body).await?;
+    socket.flush().await?;
+
+    let mut parser = ResponseParser::new();
+    let mut buf = [0u8; 256];
+
+    loop {
+        let n = with_timeout(RESPONSE_TIMEOUT, socket.read(&mut buf))
+            .await
+            .map_err(|_| WriteError::Timeout)??;
+
+        if n == 0 {
+            return Ok(parser.finish()?);
+        }
+
+        parser.feed(&buf[..n])?;
+        if let Some(response) = parser.response() {
+            return Ok(response);
+        }
+    }
+}
+
+/// Writes `lines` over a fresh connection, which is closed again afterwards.
+async fn send_http(stack: &Stack<'_>, server: IpAddress, lines: &[u8]) -> Result<(), WriteError> {
+    let mut path: String<128> = String::new();
+    write!(
+        path,
+        "/api/v2/write?org={}&bucket={}&precision=ns",
+        ORG, BUCKET
+    )
+    .map_err(InfluxError::from)?;
+    let mut credentials: String<128> = String::new();
+    write!(credentials, "Token {}", TOKEN).map_err(InfluxError::from)?;
+
+    let request = Request::post(INFLUX_HOST, &path)
+        .text(lines)
+        .authorization(&credentials);
+
+    let mut head_buf = [0u8; 384];
+    let head = request
+        .write_head(&mut head_buf)
+        .map_err(|_| InfluxError::BufferTooSmall)?;
+
+    let mut rx_buf = [0u8; 512];
+    let mut tx_buf = [0u8; 512];
+    let mut socket = TcpSocket::new(*stack, &mut rx_buf, &mut tx_buf);
+    socket.set_timeout(Some(RESPONSE_TIMEOUT));
+
+    socket
+        .connect(IpEndpoint::new(server, HTTP_PORT))
+        .await
+        .map_err(WriteError::Connect)?;
+
+    let result = exchange(&mut socket, head, request.body()).await;
+
+    socket.close();
+    let _ = socket.flush().await;
+    socket.abort();
+
+    match result?.status {
+        status if status.is_success() => Ok(()),
+        status => Err(WriteError::Rejected(status)),
+    }
+}
+
+/// Writes to the server as readings come in. The address is looked up again after any
+/// failure, in case the server moved.
+struct InfluxUplink<'a> {
+    stack: &'a Stack<'a>,
+    device: &'a DeviceInfo,
+    config: &'a NodeConfig,
+    /// Only bound for [`Transport::Udp`].
+    udp: Option<UdpSocket<'a>>,
+    server: Option<IpAddress>,
+    next_statistics: Instant,
+    health: Health,
+}
+
+impl InfluxUplink<'_> {
+    /// Sends `lines` over the configured transport, looking the server up first if needed.
+    async fn write(&mut self, lines: &[u8]) -> Result<(), WriteError> {
+        if self.server.is_none() {
+            self.server = resolve(self.stack, INFLUX_HOST).await;
+        }
+
+        match (self.server, &mut self.udp) {
+            (None, _) => Err(WriteError::Unresolved),
+            (Some(addr), Some(socket)) => send_udp(socket, addr, lines).await,
+            (Some(addr), None) => send_http(self.stack, addr, lines).await,
+        }
+    }
+
+    /// Writes the statistics of every attached sensor if they are due, one sensor per
+    /// datagram or request. Failures are only logged, the next attempt is an interval
+    /// later either way.
+    async fn write_statistics_if_due(&mut self) {
+        let now = Instant::now();
+        if now < self.next_statistics {
+            return;
+        }
+        self.next_statistics = now + statistics::UPLOAD_INTERVAL;
+
+        for sensor in sensor::attached() {
+            let mut body = [0u8; WINDOW_COUNT * STATISTICS_LINE_CAPACITY];
+            let mut len = 0;
+
+            for summary in statistics::summaries(sensor).into_iter().flatten() {
+                match encode_statistics(
+                    now,
+                    sensor,
+                    &summary,
+                    self.device,
+                    self.config,
+                    &mut body[len..],
+                ) {
+                    Ok(n) => len += n,
+                    Err(e) => warn!("influx: failed to encode statistics: {:?}", e),
+                }
+            }
+
+            if len == 0 {
+                continue;
+            }
+
+            match self.write(&body[..len]).await {
+                Ok(()) => {}
+                Err(e @ WriteError::Rejected(_)) => {
+                    warn!("influx: statistics write rejected: {:?}", e);
+                }
+                Err(e) => {
+                    warn!("influx: statistics write failed: {:?}", e);
+                    self.server = None;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Uplink for InfluxUplink<'_> {
+    fn max_batch(&self) -> usize {
+        match TRANSPORT {
+            Transport::Udp => UDP_BATCH_MAX_LINES,
+            Transport::Http => HTTP_BATCH_MAX_LINES,
+        }
+    }
+
+    async fn publish(&mut self, batch: &[QueuedReading]) -> Result<(), Failure> {
+        let mut body = [0u8; BODY_CAPACITY];
+        let mut len = 0;
+
+        for entry in batch {
+            match encode(entry, self.device, self.config, &mut body[len..]) {
+                Ok(n) => len += n,
+                Err(e) => warn!("influx: failed to encode reading {}: {:?}", entry.seq, e),
+            }
+        }
+
+        if len == 0 {
+            return Err(Failure::Reject);
+        }
+
+        let result = self.write(&body[..len]).await;
+
+        self.health = match &result {
+            Ok(()) => Health::Up,
+            Err(e) => Health::Down(e.kind()),
+        };
+
+        match result {
+            Ok(()) => {
+                self.write_statistics_if_due().await;
+                Ok(())
+            }
+            Err(e @ WriteError::Rejected(status)) if status.is_client_error() => {
+                warn!("influx: write rejected: {:?}", e);
+                Err(Failure::Reject)
+            }
+            Err(e @ WriteError::Rejected(_)) => {
+                warn!("influx: write rejected: {:?}, will retry", e);
+                Err(Failure::Retry(RETRY_DELAY))
+            }
+            Err(e) => {
+                warn!("influx: write failed: {:?}", e);
+                self.server = None;
+                Err(Failure::Retry(RETRY_DELAY))
+            }
+        }
+    }
+
+    async fn publish_alert(&mut self, alert: &QueuedAlert) -> Result<(), Failure> {
+        let mut line = [0u8; LINE_CAPACITY];
+        let len = match encode_alert(alert, self.device, self.config, &mut line) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("influx: failed to encode alert: {:?}", e);
+                return Err(Failure::Reject);
+            }
+        };
+
+        let result = self.write(&line[..len]).await;
+
+        self.health = match &result {
+            Ok(()) => Health::Up,
+            Err(e) => Health::Down(e.kind()),
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(e @ WriteError::Rejected(status)) if status.is_client_error() => {
+                warn!("influx: alert write rejected: {:?}", e);
+                Err(Failure::Reject)
+            }
+            Err(e @ WriteError::Rejected(_)) => {
+                warn!("influx: alert write rejected: {:?}, will retry", e);
+                Err(Failure::Retry(RETRY_DELAY))
+            }
+            Err(e) => {
+                warn!("influx: alert write failed: {:?}", e);
+                self.server = None;
+                Err(Failure::Retry(RETRY_DELAY))
+            }
+        }
+    }
+
+    async fn idle(&mut self) {
+        self.write_statistics_if_due().await;
+    }
+
+    fn health(&self) -> Health {
+        self.health
+    }
+}
+
+#[embassy_executor::task]
+pub async fn influx_task(
+    stack: &'static Stack<'static>,
+    device: &'static DeviceInfo,
+    config: &'static NodeConfig,
+) {
+    stack.wait_config_up().await;
+    info!("influx: writing to {} over {:?}", INFLUX_HOST, TRANSPORT);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buf = [0u8; 0];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buf = [0u8; 2 * UDP_BATCH_MAX_LINES * LINE_CAPACITY];
+
+    let udp = match TRANSPORT {
+        Transport::Udp => {
+            let mut socket =
+                UdpSocket::new(*stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+            if let Err(e) = socket.bind(0) {
+                warn!("influx: bind error: {:?}", e);
+                return;
+            }
+            Some(socket)
+        }
+        Transport::Http => None,
+    };
+
+    let mut uplink = InfluxUplink {
+        stack,
+        device,
+        config,
+        udp,
+        server: None,
+        next_statistics: Instant::now(),
+        health: Health::Starting,
+    };
+
+    uplink::run(&mut uplink, &QUEUE).await
+}
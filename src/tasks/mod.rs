@@ -1,19 +1,56 @@
 use embedded_hal_bus::i2c::AtomicDevice;
-use esp_hal::Blocking;
+use esp_bootloader_esp_idf::partitions::FlashRegion;
 use esp_hal::delay::Delay;
-use esp_hal::i2c::master::I2c;
+use esp_storage::FlashStorage;
 
+#[cfg(feature = "bme280")]
+use crate::drivers::bme280::Bme280;
+use crate::drivers::i2c_bus::RecoverableI2c;
+#[cfg(feature = "sht3x")]
 use crate::drivers::sht3x::Sht3x;
+#[cfg(feature = "sht4x")]
+use crate::drivers::sht4x::Sht4x;
 use crate::drivers::ssd1306::Ssd1306;
 
 pub mod display;
+#[cfg(feature = "http")]
 pub mod http_client;
+pub mod http_server;
+#[cfg(feature = "influx")]
+pub mod influx;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
 pub mod net;
 pub mod orchestrate;
+pub mod portal;
+#[cfg(feature = "relay")]
+pub mod relay;
 pub mod sensor;
+pub mod sntp;
+pub mod statistics;
+pub mod uplink;
 pub mod wifi;
 
-pub type I2cBus = I2c<'static, Blocking>;
+pub type I2cBus = RecoverableI2c;
 
+/// The bus itself, for commands addressed to every chip on it.
+pub type BusHandle = AtomicDevice<'static, I2cBus>;
+
+#[cfg(feature = "sht3x")]
 pub type SensorHandle = Sht3x<AtomicDevice<'static, I2cBus>, Delay>;
+#[cfg(feature = "sht4x")]
+pub type SensorHandle = Sht4x<AtomicDevice<'static, I2cBus>, Delay>;
+#[cfg(feature = "bme280")]
+pub type SensorHandle = Bme280<AtomicDevice<'static, I2cBus>, Delay>;
+
+#[cfg(not(any(feature = "sht3x", feature = "sht4x", feature = "bme280")))]
+compile_error!("enable one sensor feature: sht3x, sht4x or bme280");
+
+#[cfg(any(
+    all(feature = "sht3x", feature = "sht4x"),
+    all(feature = "sht3x", feature = "bme280"),
+    all(feature = "sht4x", feature = "bme280"),
+))]
+compile_error!("enable only one sensor feature");
 pub type DisplayHandle = Ssd1306<AtomicDevice<'static, I2cBus>>;
+pub type ConfigFlash = FlashRegion<'static, FlashStorage<'static>>;
@@ -0,0 +1,844 @@
+use core::fmt::{Display, Write};
+
+use defmt::{Format, info, warn};
+use embassy_net::tcp::{self, State, TcpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use heapless::String;
+
+use crate::config::NodeConfig;
+use crate::control::RelayStatus;
+use crate::device::DeviceInfo;
+use crate::discovery::{self, Entity};
+use crate::drivers::environment::{Measurement, SensorError, SensorId};
+use crate::mqtt::{Connect, LastWill, MqttError, Packet, ProtocolVersion, Publish, QoS, Subscribe};
+use crate::psychro::Psychrometrics;
+use crate::queue::DropPolicy;
+use crate::tasks::net::resolve;
+use crate::tasks::uplink::{
+    self, Failure, Health, QueuedAlert, QueuedReading, Uplink, UplinkQueue,
+};
+use crate::tasks::{sensor, statistics, wifi};
+
+const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::V311;
+
+/// The node as the broker sees it: who it connects as and where its topics live. State
+/// topics are `<prefix>/<node-id>/<object-id>`, each sensor gets its own, see
+/// `Entity::object_id`.
+#[derive(Clone, Copy)]
+struct Node<'a> {
+    device: &'a DeviceInfo,
+    config: &'a NodeConfig,
+}
+
+impl Node<'_> {
+    /// The configured client id, or the node id when none is set.
+    fn client_id(&self) -> &str {
+        match self.config.mqtt_client_id.as_str() {
+            "" => &self.device.node_id,
+            client_id => client_id,
+        }
+    }
+
+    fn username(&self) -> Option<&str> {
+        Some(self.config.mqtt_username.as_str()).filter(|username| !username.is_empty())
+    }
+
+    fn password(&self) -> Option<&[u8]> {
+        Some(self.config.mqtt_password.as_bytes()).filter(|password| !password.is_empty())
+    }
+}
+
+const READING_QOS: QoS = QoS::AtLeastOnce;
+const RETAIN_READINGS: bool = false;
+
+/// Retained `online`/`offline` topic. The broker publishes `offline` as our Last Will when
+/// the connection drops without a DISCONNECT.
+const AVAILABILITY_OBJECT_ID: &str = "availability";
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+
+/// Retained JSON summaries are published to `<sensor>_statistics_<window>`, every
+/// `statistics::UPLOAD_INTERVAL`.
+const STATISTICS_OBJECT_ID: &str = "statistics";
+
+/// Alerts go to this topic as JSON when raised and again when cleared. Not retained, each
+/// message is an event rather than state.
+const ALERT_OBJECT_ID: &str = "alert";
+const ALERT_QOS: QoS = QoS::AtLeastOnce;
+
+/// With the `relay` feature, commands published here override the relay, see
+/// `Command::parse`. Its state goes out with every reading, see [`discovery::RELAY`].
+const RELAY_COMMAND_OBJECT_ID: &str = "relay/set";
+/// How often to check for relay commands while there is nothing to publish.
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Announce entities to Home Assistant after every connect.
+const HOME_ASSISTANT_DISCOVERY: bool = true;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// Diagnostics go out at least this often, which also keeps the connection alive when no
+/// readings arrive.
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(30);
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+const RX_CAPACITY: usize = 256;
+/// Large enough for a Home Assistant discovery message.
+const TX_CAPACITY: usize = 768;
+const TOPIC_CAPACITY: usize = 96;
+
+/// Only the newest readings matter, the topics carry current state rather than history.
+const QUEUE_CAPACITY: usize = 8;
+
+pub static QUEUE: UplinkQueue<QUEUE_CAPACITY> = UplinkQueue::new("mqtt", DropPolicy::DropOldest);
+
+#[derive(Debug, Clone, Copy, Format)]
+enum LinkError {
+    Unresolved,
+    Connect(tcp::ConnectError),
+    Transport(tcp::Error),
+    Closed,
+    Timeout,
+    Protocol(MqttError),
+    Refused(u8),
+}
+
+impl LinkError {
+    /// Short machine readable name, for status reports.
+    fn kind(&self) -> &'static str {
+        match self {
+            LinkError::Unresolved => "unresolved",
+            LinkError::Connect(_) => "connect",
+            LinkError::Transport(_) => "transport",
+            LinkError::Closed => "closed",
+            LinkError::Timeout => "timeout",
+            LinkError::Protocol(_) => "protocol",
+            LinkError::Refused(_) => "refused",
+        }
+    }
+}
+
+impl From<tcp::Error> for LinkError {
+    fn from(err: tcp::Error) -> Self {
+        LinkError::Transport(err)
+    }
+}
+
+impl From<tcp::ConnectError> for LinkError {
+    fn from(err: tcp::ConnectError) -> Self {
+        LinkError::Connect(err)
+    }
+}
+
+impl From<MqttError> for LinkError {
+    fn from(err: MqttError) -> Self {
+        LinkError::Protocol(err)
+    }
+}
+
+/// A broker connection: the socket plus a receive buffer for reassembling packets.
+struct Link<'a> {
+    socket: TcpSocket<'a>,
+    rx: [u8; RX_CAPACITY],
+    rx_len: usize,
+    next_packet_id: u16,
+}
+
+impl<'a> Link<'a> {
+    fn new(socket: TcpSocket<'a>) -> Self {
+        Self {
+            socket,
+            rx: [0; RX_CAPACITY],
+            rx_len: 0,
+            next_packet_id: 1,
+        }
+    }
+
+    async fn connect(&mut self, remote: IpEndpoint, node: Node<'_>) -> Result<(), LinkError> {
+        if self.socket.state() != State::Closed {
+            self.socket.abort();
+        }
+
+        self.rx_len = 0;
+        self.socket.connect(remote).await?;
+
+        let availability_topic = topic(node, AVAILABILITY_OBJECT_ID)?;
+
+        let connect = Connect {
+            version: PROTOCOL_VERSION,
+            client_id: node.client_id(),
+            keep_alive_secs: KEEP_ALIVE.as_secs() as u16,
+            clean_session: true,
+            username: node.username(),
+            password: node.password(),
+            will: Some(LastWill {
+                topic: &availability_topic,
+                message: OFFLINE,
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+        };
+
+        let mut buf = [0u8; TX_CAPACITY];
+        let len = connect.encode(&mut buf)?;
+        self.send(&buf[..len]).await?;
+
+        let code = self
+            .read_packet(|packet| match packet {
+                Packet::ConnAck { code, .. } => Ok(code),
+                _ => Err(MqttError::MalformedPacket),
+            })
+            .await??;
+
+        if code != 0 {
+            return Err(LinkError::Refused(code));
+        }
+
+        self.publish(&availability_topic, ONLINE, QoS::AtLeastOnce, true)
+            .await
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        packet_id
+    }
+
+    async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), LinkError> {
+        let packet_id = self.packet_id();
+
+        let publish = Publish {
+            topic,
+            payload,
+            qos,
+            retain,
+            dup: false,
+            packet_id,
+        };
+
+        let mut buf = [0u8; TX_CAPACITY];
+        let len = publish.encode(&mut buf, PROTOCOL_VERSION)?;
+        self.send(&buf[..len]).await?;
+
+        if qos == QoS::AtLeastOnce {
+            loop {
+                let acked = self
+                    .read_packet(|packet| match packet {
+                        Packet::PubAck { packet_id: id } => id == packet_id,
+                        _ => false,
+                    })
+                    .await?;
+                if acked {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes to `topic` at QoS 0 and returns the code the broker answered with.
+    async fn subscribe(&mut self, topic: &str) -> Result<u8, LinkError> {
+        let packet_id = self.packet_id();
+
+        let subscribe = Subscribe {
+            topic,
+            qos: QoS::AtMostOnce,
+            packet_id,
+        };
+
+        let mut buf = [0u8; TX_CAPACITY];
+        let len = subscribe.encode(&mut buf, PROTOCOL_VERSION)?;
+        self.send(&buf[..len]).await?;
+
+        loop {
+            let code = self
+                .read_packet(|packet| match packet {
+                    Packet::SubAck {
+                        packet_id: id,
+                        code,
+                    } if id == packet_id => Some(code),
+                    _ => None,
+                })
+                .await?;
+            if let Some(code) = code {
+                return Ok(code);
+            }
+        }
+    }
+
+    /// Handles whatever the broker sent on its own, without waiting for more.
+    async fn poll(&mut self) -> Result<(), LinkError> {
+        while self.socket.can_recv()
+            || Packet::decode(&self.rx[..self.rx_len], PROTOCOL_VERSION)?.is_some()
+        {
+            self.read_packet(|_| ()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&mut self, mut buf: &[u8]) -> Result<(), LinkError> {
+        while !buf.is_empty() {
+            match self.socket.write(buf).await? {
+                0 => return Err(LinkError::Closed),
+                n => buf = &buf[n..],
+            }
+        }
+
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    /// Waits for the next complete packet, passes it to `f` and then drops it from the
+    /// receive buffer. Publishes from the broker are handled on the way.
+    async fn read_packet<T>(&mut self, f: impl FnOnce(Packet<'_>) -> T) -> Result<T, LinkError> {
+        loop {
+            if let Some((packet, len)) = Packet::decode(&self.rx[..self.rx_len], PROTOCOL_VERSION)?
+            {
+                let out = match packet {
+                    // The only subscription is the relay command topic.
+                    Packet::Publish { payload, .. } => {
+                        relay_command(payload);
+                        None
+                    }
+                    packet => Some(f(packet)),
+                };
+                self.rx.copy_within(len..self.rx_len, 0);
+                self.rx_len -= len;
+                match out {
+                    Some(out) => return Ok(out),
+                    None => continue,
+                }
+            }
+
+            if self.rx_len == RX_CAPACITY {
+                return Err(LinkError::Protocol(MqttError::BufferTooSmall));
+            }
+
+            let n = with_timeout(ACK_TIMEOUT, self.socket.read(&mut self.rx[self.rx_len..]))
+                .await
+                .map_err(|_| LinkError::Timeout)??;
+
+            if n == 0 {
+                return Err(LinkError::Closed);
+            }
+
+            self.rx_len += n;
+        }
+    }
+}
+
+fn topic(node: Node<'_>, object_id: impl Display) -> Result<String<TOPIC_CAPACITY>, MqttError> {
+    let mut topic = String::new();
+    write!(
+        topic,
+        "{}/{}/{}",
+        node.config.mqtt_topic_prefix, node.device.node_id, object_id
+    )
+    .map_err(|_| MqttError::BufferTooSmall)?;
+    Ok(topic)
+}
+
+/// Applies a command published to [`RELAY_COMMAND_OBJECT_ID`].
+#[cfg(feature = "relay")]
+fn relay_command(payload: &[u8]) {
+    use crate::control::Command;
+    use crate::tasks::relay;
+
+    match Command::parse(payload) {
+        Some(command) => {
+            relay::command(command);
+        }
+        None => warn!("mqtt: ignoring relay command {=[u8]:a}", payload),
+    }
+}
+
+/// Nothing is subscribed to without the `relay` feature.
+#[cfg(not(feature = "relay"))]
+fn relay_command(_payload: &[u8]) {}
+
+async fn subscribe_commands(link: &mut Link<'_>, node: Node<'_>) -> Result<(), LinkError> {
+    let topic = topic(node, RELAY_COMMAND_OBJECT_ID)?;
+
+    match link.subscribe(&topic).await? {
+        code if code >= 0x80 => warn!("mqtt: broker refused relay commands ({=u8:#04x})", code),
+        _ => info!("mqtt: taking relay commands on {}", topic.as_str()),
+    }
+
+    Ok(())
+}
+
+async fn publish_state(
+    link: &mut Link<'_>,
+    node: Node<'_>,
+    entity: &Entity,
+    value: core::fmt::Arguments<'_>,
+    qos: QoS,
+    retain: bool,
+) -> Result<(), LinkError> {
+    let topic = topic(node, entity.object_id())?;
+    let mut buf = [0u8; 16];
+    let payload = format_no_std::show(&mut buf, value).map_err(|_| MqttError::BufferTooSmall)?;
+
+    link.publish(&topic, payload.as_bytes(), qos, retain).await
+}
+
+async fn publish_discovery(link: &mut Link<'_>, node: Node<'_>) -> Result<(), LinkError> {
+    let availability_topic = topic(node, AVAILABILITY_OBJECT_ID)?;
+
+    let pressure: &[Entity] = if cfg!(feature = "bme280") {
+        &discovery::PRESSURE_ENTITIES
+    } else {
+        &[]
+    };
+    let derived: &[Entity] = if cfg!(feature = "psychro") {
+        &discovery::DERIVED_ENTITIES
+    } else {
+        &[]
+    };
+    let relay: &[Entity] = if cfg!(feature = "relay") {
+        &discovery::RELAY_ENTITIES
+    } else {
+        &[]
+    };
+    let sensor_entities = sensor::attached().flat_map(move |sensor| {
+        discovery::SENSOR_ENTITIES
+            .iter()
+            .chain(pressure)
+            .chain(derived)
+            .map(move |entity| entity.for_sensor(sensor.name()))
+    });
+
+    let node_entities = discovery::NODE_ENTITIES.iter().chain(relay).copied();
+
+    for entity in node_entities.chain(sensor_entities) {
+        let config_topic: String<TOPIC_CAPACITY> =
+            discovery::config_topic(node.device, &entity).map_err(|_| MqttError::BufferTooSmall)?;
+        let state_topic = topic(node, entity.object_id())?;
+
+        let mut buf = [0u8; 640];
+        let len = discovery::config_payload(
+            &mut buf,
+            node.device,
+            &entity,
+            &state_topic,
+            &availability_topic,
+        )
+        .map_err(|_| MqttError::BufferTooSmall)?;
+
+        link.publish(&config_topic, &buf[..len], QoS::AtLeastOnce, true)
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn publish_reading_topics(
+    link: &mut Link<'_>,
+    node: Node<'_>,
+    sensor: SensorId,
+    reading: &Result<Measurement, SensorError>,
+    derived: Option<Psychrometrics>,
+) -> Result<(), LinkError> {
+    let status = discovery::SENSOR_STATUS.for_sensor(sensor.name());
+
+    // The values stay at the last good reading while the sensor is failing.
+    let reading = match reading {
+        Ok(reading) => reading,
+        Err(e) => {
+            return publish_state(
+                link,
+                node,
+                &status,
+                format_args!("{}", e.label()),
+                READING_QOS,
+                RETAIN_READINGS,
+            )
+            .await;
+        }
+    };
+
+    let values = [
+        (discovery::TEMPERATURE, reading.temperature),
+        (discovery::HUMIDITY, reading.humidity),
+        (discovery::PRESSURE, reading.pressure),
+    ];
+
+    for (entity, value) in values {
+        if let Some(value) = value {
+            publish_state(
+                link,
+                node,
+                &entity.for_sensor(sensor.name()),
+                format_args!("{:.2}", value),
+                READING_QOS,
+                RETAIN_READINGS,
+            )
+            .await?;
+        }
+    }
+
+    if let Some(co2) = reading.co2 {
+        publish_state(
+            link,
+            node,
+            &discovery::CO2.for_sensor(sensor.name()),
+            format_args!("{}", co2),
+            READING_QOS,
+            RETAIN_READINGS,
+        )
+        .await?;
+    }
+
+    if let Some(derived) = derived {
+        let values = [
+            (discovery::DEW_POINT, derived.dew_point),
+            (discovery::ABSOLUTE_HUMIDITY, derived.absolute_humidity),
+            (discovery::HEAT_INDEX, derived.heat_index),
+            (discovery::HUMIDEX, derived.humidex),
+            (discovery::VPD, derived.vpd),
+        ];
+
+        for (entity, value) in values {
+            publish_state(
+                link,
+                node,
+                &entity.for_sensor(sensor.name()),
+                format_args!("{:.2}", value),
+                READING_QOS,
+                RETAIN_READINGS,
+            )
+            .await?;
+        }
+    }
+
+    publish_state(
+        link,
+        node,
+        &status,
+        format_args!("ok"),
+        READING_QOS,
+        RETAIN_READINGS,
+    )
+    .await
+}
+
+async fn publish_relay(
+    link: &mut Link<'_>,
+    node: Node<'_>,
+    relay: &RelayStatus,
+) -> Result<(), LinkError> {
+    publish_state(
+        link,
+        node,
+        &discovery::RELAY,
+        format_args!("{}", if relay.on { "on" } else { "off" }),
+        READING_QOS,
+        RETAIN_READINGS,
+    )
+    .await?;
+
+    publish_state(
+        link,
+        node,
+        &discovery::RELAY_MODE,
+        format_args!("{}", relay.mode.label()),
+        READING_QOS,
+        RETAIN_READINGS,
+    )
+    .await
+}
+
+async fn publish_diagnostics(link: &mut Link<'_>, node: Node<'_>) -> Result<(), LinkError> {
+    if let Some(rssi) = wifi::rssi() {
+        publish_state(
+            link,
+            node,
+            &discovery::RSSI,
+            format_args!("{}", rssi),
+            QoS::AtMostOnce,
+            false,
+        )
+        .await?;
+    }
+
+    publish_state(
+        link,
+        node,
+        &discovery::UPTIME,
+        format_args!("{}", Instant::now().as_secs()),
+        QoS::AtMostOnce,
+        false,
+    )
+    .await
+}
+
+async fn publish_statistics(link: &mut Link<'_>, node: Node<'_>) -> Result<(), LinkError> {
+    for sensor in sensor::attached() {
+        for summary in statistics::summaries(sensor).into_iter().flatten() {
+            let topic = topic(
+                node,
+                format_args!(
+                    "{}_{}_{}",
+                    sensor.name(),
+                    STATISTICS_OBJECT_ID,
+                    summary.window
+                ),
+            )?;
+
+            let mut buf = [0u8; 256];
+            let len = serde_json_core::to_slice(&summary, &mut buf)
+                .map_err(|_| MqttError::BufferTooSmall)?;
+
+            link.publish(&topic, &buf[..len], QoS::AtMostOnce, true)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn publish_alert(
+    link: &mut Link<'_>,
+    node: Node<'_>,
+    alert: &QueuedAlert,
+) -> Result<(), LinkError> {
+    let topic = topic(node, ALERT_OBJECT_ID)?;
+
+    let mut buf = [0u8; 256];
+    let len = serde_json_core::to_slice(&alert.message(), &mut buf)
+        .map_err(|_| MqttError::BufferTooSmall)?;
+
+    link.publish(&topic, &buf[..len], ALERT_QOS, false).await
+}
+
+/// Publishes readings and diagnostics over one broker connection, reconnecting with
+/// backoff when it drops.
+struct MqttUplink<'a> {
+    stack: &'a Stack<'a>,
+    node: Node<'a>,
+    link: Link<'a>,
+    connected: bool,
+    reconnect_delay: Duration,
+    next_diagnostics: Instant,
+    next_statistics: Instant,
+    health: Health,
+}
+
+impl MqttUplink<'_> {
+    /// Connects and announces the node, unless already connected.
+    async fn connect(&mut self) -> Result<(), Failure> {
+        if self.connected {
+            return Ok(());
+        }
+
+        let config = self.node.config;
+        let connected = match resolve(self.stack, &config.mqtt_host).await {
+            Some(addr) => {
+                self.link
+                    .connect(IpEndpoint::new(addr, config.mqtt_port), self.node)
+                    .await
+            }
+            None => Err(LinkError::Unresolved),
+        };
+
+        let announced = match connected {
+            Ok(()) if HOME_ASSISTANT_DISCOVERY => {
+                publish_discovery(&mut self.link, self.node).await
+            }
+            connected => connected,
+        };
+
+        let subscribed = match announced {
+            Ok(()) if cfg!(feature = "relay") => {
+                subscribe_commands(&mut self.link, self.node).await
+            }
+            announced => announced,
+        };
+
+        match subscribed {
+            Ok(()) => {
+                info!(
+                    "mqtt: connected to {}:{}",
+                    config.mqtt_host.as_str(),
+                    config.mqtt_port
+                );
+                self.connected = true;
+                self.reconnect_delay = RECONNECT_DELAY_MIN;
+                self.next_diagnostics = Instant::now();
+                self.next_statistics = Instant::now();
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    "mqtt: connect to {} failed: {:?}",
+                    config.mqtt_host.as_str(),
+                    e
+                );
+                Err(self.disconnect(e))
+            }
+        }
+    }
+
+    /// Drops the connection after `e` and returns how long to wait before reconnecting.
+    fn disconnect(&mut self, e: LinkError) -> Failure {
+        self.link.socket.abort();
+        self.connected = false;
+        self.health = Health::Down(e.kind());
+
+        let delay = self.reconnect_delay;
+        self.reconnect_delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+        info!("mqtt: reconnecting in {} s", delay.as_secs());
+        Failure::Retry(delay)
+    }
+
+    /// Diagnostics and statistics, whichever are due.
+    async fn publish_periodic(&mut self) -> Result<(), LinkError> {
+        let now = Instant::now();
+
+        if now >= self.next_diagnostics {
+            publish_diagnostics(&mut self.link, self.node).await?;
+            self.next_diagnostics = now + DIAGNOSTICS_INTERVAL;
+        }
+
+        if now >= self.next_statistics {
+            publish_statistics(&mut self.link, self.node).await?;
+            self.next_statistics = now + statistics::UPLOAD_INTERVAL;
+        }
+
+        Ok(())
+    }
+}
+
+impl Uplink for MqttUplink<'_> {
+    async fn publish(&mut self, batch: &[QueuedReading]) -> Result<(), Failure> {
+        self.connect().await?;
+
+        let mut result = Ok(());
+        for entry in batch {
+            result = publish_reading_topics(
+                &mut self.link,
+                self.node,
+                entry.sensor,
+                &entry.reading,
+                entry.derived(),
+            )
+            .await;
+            if let (Ok(()), Some(relay)) = (&result, &entry.relay) {
+                result = publish_relay(&mut self.link, self.node, relay).await;
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+
+        if result.is_ok() {
+            result = self.publish_periodic().await;
+        }
+        if result.is_ok() {
+            result = self.link.poll().await;
+        }
+
+        match result {
+            Ok(()) => {
+                self.health = Health::Up;
+                Ok(())
+            }
+            Err(e) => {
+                warn!("mqtt: connection lost: {:?}", e);
+                Err(self.disconnect(e))
+            }
+        }
+    }
+
+    async fn publish_alert(&mut self, alert: &QueuedAlert) -> Result<(), Failure> {
+        self.connect().await?;
+
+        match publish_alert(&mut self.link, self.node, alert).await {
+            Ok(()) => {
+                self.health = Health::Up;
+                Ok(())
+            }
+            Err(e) => {
+                warn!("mqtt: connection lost: {:?}", e);
+                Err(self.disconnect(e))
+            }
+        }
+    }
+
+    /// Diagnostics also keep the connection alive while no readings arrive.
+    async fn idle(&mut self) {
+        if let Err(failure) = self.connect().await {
+            // Polling for relay commands must not speed up reconnects.
+            if let Failure::Retry(delay) = failure {
+                Timer::after(delay).await;
+            }
+            return;
+        }
+
+        let mut result = self.publish_periodic().await;
+        if result.is_ok() {
+            result = self.link.poll().await;
+        }
+
+        if let Err(e) = result {
+            warn!("mqtt: connection lost: {:?}", e);
+            self.disconnect(e);
+        }
+    }
+
+    fn idle_interval(&self) -> Duration {
+        if cfg!(feature = "relay") {
+            COMMAND_POLL_INTERVAL
+        } else {
+            uplink::IDLE_INTERVAL
+        }
+    }
+
+    fn health(&self) -> Health {
+        self.health
+    }
+}
+
+#[embassy_executor::task]
+pub async fn mqtt_task(
+    stack: &'static Stack<'static>,
+    device: &'static DeviceInfo,
+    config: &'static NodeConfig,
+) {
+    if !config.has_mqtt() {
+        info!("mqtt: no broker configured");
+        return;
+    }
+    info!("mqtt: task start");
+
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 1024];
+
+    let mut socket = TcpSocket::new(*stack, &mut rx_buf, &mut tx_buf);
+    socket.set_timeout(Some(KEEP_ALIVE * 2));
+
+    stack.wait_config_up().await;
+
+    let mut uplink = MqttUplink {
+        stack,
+        node: Node { device, config },
+        link: Link::new(socket),
+        connected: false,
+        reconnect_delay: RECONNECT_DELAY_MIN,
+        next_diagnostics: Instant::now(),
+        next_statistics: Instant::now(),
+        health: Health::Starting,
+    };
+
+    uplink::run(&mut uplink, &QUEUE).await
+}
@@ -1,11 +1,31 @@
-use defmt::info;
+use defmt::{info, warn};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::{IpAddress, Stack};
 use esp_radio::wifi::WifiDevice;
 
-#[embassy_executor::task]
+/// One runner per interface, the station and the setup access point.
+#[embassy_executor::task(pool_size = 2)]
 pub async fn net_task(mut runner: embassy_net::Runner<'static, WifiDevice<'static>>) {
     runner.run().await;
 }
 
+/// Looks up the first IPv4 address of `host`, logging why if there is none.
+pub async fn resolve(stack: &Stack<'_>, host: &str) -> Option<IpAddress> {
+    match stack.dns_query(host, DnsQueryType::A).await {
+        Ok(addrs) => {
+            let addr = addrs.first().copied();
+            if addr.is_none() {
+                warn!("dns: no address records for {}", host);
+            }
+            addr
+        }
+        Err(e) => {
+            warn!("dns: query for {} failed: {:?}", host, e);
+            None
+        }
+    }
+}
+
 #[embassy_executor::task]
 pub async fn alive_task() {
     let mut cnt = 0;
@@ -1,27 +1,139 @@
-use defmt::info;
+use defmt::{info, warn};
+use embassy_time::Instant;
+use heapless::Vec;
 
+use crate::alert::{Alert, MAX_ACTIVE_ALERTS};
+use crate::control::RelayStatus;
+use crate::drivers::environment::{MAX_SENSORS, SensorId};
+use crate::drivers::sht3x::Sht3xReading;
 use crate::events::{Event, receive_event};
-use crate::tasks::display::{DisplayData, update_display_text};
-use crate::tasks::http_client::send_sensor_data;
+use crate::filter::Quality;
+use crate::metrics::METRICS;
+use crate::report::{ReportConfig, Reporter};
+use crate::tasks::display::{DisplayData, SensorState, update_display_text};
+use crate::tasks::{http_server, statistics, uplink};
 use crate::tasks::wifi::WifiState;
 
+/// Readings only go to the uplinks when they moved past the deadbands, at most every 10 s
+/// and at least every 5 minutes. The display, the API and the statistics get all of them,
+/// except that readings skewed by the heater stay out of the statistics and the relay.
+const REPORT_POLICY: ReportConfig = ReportConfig {
+    temperature_deadband: 0.1,
+    humidity_deadband: 0.5,
+    min_interval_ms: 10 * 1000,
+    max_interval_ms: 5 * 60 * 1000,
+};
+
 #[embassy_executor::task]
 pub async fn orchestrate_task() {
     let mut wifi_state = WifiState::Connecting;
+    let mut sensors = [None; MAX_SENSORS];
+    let mut reporters = [const { Reporter::new(REPORT_POLICY) }; MAX_SENSORS];
+    // Oldest first, with when they were raised. The display shows the newest.
+    let mut alerts: Vec<(Instant, Alert), MAX_ACTIVE_ALERTS> = Vec::new();
+    // Relay state last published with a reading of each sensor.
+    let mut relayed = [None; MAX_SENSORS];
 
     loop {
         let event = receive_event().await;
 
         match event {
-            Event::SensorReading(data) => {
-                update_display_text(DisplayData::new(data.temperature, data.humidity, wifi_state));
-                send_sensor_data(data);
+            Event::SensorReading(sensor, measurement, quality) => {
+                let Some(data) = measurement.reading() else {
+                    continue;
+                };
+
+                sensors[sensor.index()] = Some(SensorState::Reading(measurement));
+                update_display_text(DisplayData::new(sensors, wifi_state, newest(&alerts)));
+                http_server::update_reading(sensor, measurement);
+
+                let heated = quality.contains(Quality::HEATER_ACTIVE);
+                if !heated {
+                    statistics::record(sensor, &data);
+                }
+
+                let relay = relay_reading(sensor, &data, heated);
+                // A switch goes out with the next reading whatever the deadbands.
+                if relay.map(|status| status.on) != relayed[sensor.index()] {
+                    reporters[sensor.index()].reset();
+                }
+
+                let now_ms = Instant::now().as_millis();
+                match reporters[sensor.index()].offer(now_ms, &data, quality) {
+                    Some(quality) => {
+                        relayed[sensor.index()] = relay.map(|status| status.on);
+                        uplink::publish(sensor, Ok(measurement), quality, relay).await;
+                    }
+                    None => METRICS.suppressed_readings.inc(),
+                }
+            }
+
+            Event::SensorFault(sensor, error) => {
+                sensors[sensor.index()] = Some(SensorState::Fault(error));
+                update_display_text(DisplayData::new(sensors, wifi_state, newest(&alerts)));
+                http_server::update_fault(sensor, error);
+                reporters[sensor.index()].reset();
+                let relay = relay_fault(sensor);
+                uplink::publish(sensor, Err(error), Quality::GOOD, relay).await;
+            }
+
+            Event::AlertRaised(alert) => {
+                warn!("alert: raised {}", alert);
+                alerts.retain(|(_, active)| !active.is_same(&alert));
+                // Can't fail, there is room for every rule on every sensor.
+                let _ = alerts.push((Instant::now(), alert));
+                update_display_text(DisplayData::new(sensors, wifi_state, newest(&alerts)));
+                http_server::update_alerts(&alerts);
+                uplink::publish_alert(alert, true);
+            }
+
+            Event::AlertCleared(alert) => {
+                info!("alert: cleared {}", alert);
+                alerts.retain(|(_, active)| !active.is_same(&alert));
+                update_display_text(DisplayData::new(sensors, wifi_state, newest(&alerts)));
+                http_server::update_alerts(&alerts);
+                uplink::publish_alert(alert, false);
             }
 
             Event::WifiStatus(state) => {
                  info!("WiFi state changed: {}", state);
                  wifi_state = state;
+                 http_server::update_wifi_state(state);
             }
         }
     }
 }
+
+fn newest(alerts: &[(Instant, Alert)]) -> Option<Alert> {
+    alerts.last().map(|(_, alert)| *alert)
+}
+
+/// Feeds a reading to the relay controller, unless it was skewed by the heater, and returns
+/// where the relay stands.
+#[cfg(feature = "relay")]
+fn relay_reading(sensor: SensorId, data: &Sht3xReading, heated: bool) -> Option<RelayStatus> {
+    use crate::tasks::relay;
+
+    Some(if heated {
+        relay::status()
+    } else {
+        relay::reading(sensor, data)
+    })
+}
+
+#[cfg(not(feature = "relay"))]
+fn relay_reading(_sensor: SensorId, _data: &Sht3xReading, _heated: bool) -> Option<RelayStatus> {
+    None
+}
+
+#[cfg(feature = "relay")]
+fn relay_fault(sensor: SensorId) -> Option<RelayStatus> {
+    use crate::tasks::relay;
+
+    Some(relay::fault(sensor))
+}
+
+#[cfg(not(feature = "relay"))]
+fn relay_fault(_sensor: SensorId) -> Option<RelayStatus> {
+    None
+}
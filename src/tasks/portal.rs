@@ -0,0 +1,274 @@
+use core::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use defmt::{Debug2Format, info, warn};
+use edge_dhcp::io::{self as dhcp_io, DEFAULT_SERVER_PORT};
+use edge_dhcp::server::{Server, ServerOptions};
+use edge_nal::UdpBind;
+use edge_nal_embassy::{Udp, UdpBuffers};
+use embassy_futures::join::join3;
+use embassy_net::Stack;
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+use crate::api::HTML;
+use crate::config::{ConfigError, ConfigStore, NodeConfig};
+use crate::device::DeviceInfo;
+use crate::dns;
+use crate::http::{RequestHead, StatusCode};
+use crate::portal::{FormError, Provisioning, SAVED_PAGE, ScanList, render_form};
+use crate::tasks::ConfigFlash;
+use crate::tasks::http_server::{ReadRequest, read_request, send_response};
+
+/// Address of the node on its own access point, also handed out as gateway and DNS server.
+pub const AP_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+pub const AP_PREFIX_LEN: u8 = 24;
+
+const HTTP_PORT: u16 = 80;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Lets the browser receive the confirmation page before the access point goes away.
+const REBOOT_DELAY: Duration = Duration::from_secs(2);
+
+const REQUEST_CAPACITY: usize = 1536;
+const PAGE_CAPACITY: usize = 3072;
+
+static PORTAL_SIGNAL: Signal<CriticalSectionRawMutex, ScanList> = Signal::new();
+
+/// Starts serving the setup portal, listing `networks` in the form.
+pub fn start(networks: ScanList) {
+    PORTAL_SIGNAL.signal(networks);
+}
+
+/// Hands out addresses on the access point network.
+async fn dhcp_server(stack: Stack<'static>) {
+    let buffers = UdpBuffers::<1, 1024, 1024, 4>::new();
+    let udp = Udp::new(stack, &buffers);
+
+    let addr = SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::UNSPECIFIED,
+        DEFAULT_SERVER_PORT,
+    ));
+    let mut socket = match udp.bind(addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("portal: dhcp bind failed: {:?}", Debug2Format(&e));
+            return;
+        }
+    };
+
+    let mut server = Server::<_, 8>::new_with_et(AP_ADDRESS);
+    let mut gateways = [AP_ADDRESS];
+    let dns_servers = [AP_ADDRESS];
+    let mut options = ServerOptions::new(AP_ADDRESS, Some(&mut gateways));
+    options.dns = &dns_servers;
+
+    let mut buf = [0u8; 1500];
+
+    loop {
+        if let Err(e) = dhcp_io::server::run(&mut server, &options, &mut socket, &mut buf).await {
+            warn!("portal: dhcp server error: {:?}", Debug2Format(&e));
+        }
+        Timer::after_millis(500).await;
+    }
+}
+
+/// Resolves every name to the node so phones and laptops open the portal on their own.
+async fn captive_dns(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0u8; 1024];
+
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+
+    if let Err(e) = socket.bind(dns::PORT) {
+        warn!("portal: dns bind error: {:?}", e);
+        return;
+    }
+
+    let mut query = [0u8; 512];
+    let mut response = [0u8; 512];
+
+    loop {
+        let (n, meta) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("portal: dns receive error: {:?}", e);
+                continue;
+            }
+        };
+
+        let len = match dns::answer(&query[..n], AP_ADDRESS.octets(), &mut response) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("portal: ignoring dns packet: {:?}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = socket.send_to(&response[..len], meta).await {
+            warn!("portal: dns send error: {:?}", e);
+        }
+    }
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: StatusCode,
+    body: &[u8],
+) -> Result<(), tcp::Error> {
+    send_response(socket, status, HTML, body).await
+}
+
+enum Outcome {
+    Served,
+    Saved,
+}
+
+async fn respond_with_form(
+    socket: &mut TcpSocket<'_>,
+    status: StatusCode,
+    device: &DeviceInfo,
+    networks: &ScanList,
+    config: &NodeConfig,
+    error: Option<FormError>,
+) -> Result<(), tcp::Error> {
+    let mut page = [0u8; PAGE_CAPACITY];
+
+    match render_form(&mut page, &device.node_id, networks, config, error) {
+        Ok(len) => respond(socket, status, &page[..len]).await,
+        Err(e) => {
+            warn!("portal: failed to render form: {:?}", e);
+            respond(socket, StatusCode(500), &[]).await
+        }
+    }
+}
+
+/// Reads one request and answers it. Any `GET` gets the form, which is what captive portal
+/// checks on phones need to show it.
+async fn serve(
+    socket: &mut TcpSocket<'_>,
+    device: &DeviceInfo,
+    networks: &ScanList,
+    config: &NodeConfig,
+    store: &mut Option<ConfigStore<ConfigFlash>>,
+) -> Result<Outcome, tcp::Error> {
+    let mut request = [0u8; REQUEST_CAPACITY];
+
+    let (head_len, body_end) = match read_request(socket, &mut request).await? {
+        ReadRequest::Complete { head_len, end } => (head_len, end),
+        ReadRequest::TooLarge => {
+            respond(socket, StatusCode(413), &[]).await?;
+            return Ok(Outcome::Served);
+        }
+        ReadRequest::Malformed(e) => {
+            warn!("portal: bad request: {:?}", e);
+            respond(socket, StatusCode(400), &[]).await?;
+            return Ok(Outcome::Served);
+        }
+        ReadRequest::Closed => return Ok(Outcome::Served),
+    };
+
+    let Ok(Some((head, _))) = RequestHead::parse(&request[..head_len]) else {
+        return Ok(Outcome::Served);
+    };
+    let body = &request[head_len..body_end];
+
+    match (head.method, head.path) {
+        ("POST", "/save") => {
+            let provisioning = match Provisioning::parse(body) {
+                Ok(provisioning) => provisioning,
+                Err(e) => {
+                    let status = StatusCode(400);
+                    respond_with_form(socket, status, device, networks, config, Some(e)).await?;
+                    return Ok(Outcome::Served);
+                }
+            };
+
+            let mut updated = config.clone();
+            provisioning.apply(&mut updated);
+
+            let saved = match store {
+                Some(store) => store.save(&updated),
+                None => Err(ConfigError::Storage),
+            };
+
+            if let Err(e) = saved {
+                warn!("portal: failed to save config: {:?}", e);
+                respond(socket, StatusCode(500), &[]).await?;
+                return Ok(Outcome::Served);
+            }
+
+            info!("portal: saved network {}", updated.wifi_ssid.as_str());
+            respond(socket, StatusCode(200), SAVED_PAGE.as_bytes()).await?;
+            Ok(Outcome::Saved)
+        }
+        ("GET", _) => {
+            respond_with_form(socket, StatusCode(200), device, networks, config, None).await?;
+            Ok(Outcome::Served)
+        }
+        _ => {
+            respond(socket, StatusCode(405), &[]).await?;
+            Ok(Outcome::Served)
+        }
+    }
+}
+
+async fn http_server(
+    stack: Stack<'static>,
+    device: &DeviceInfo,
+    networks: &ScanList,
+    config: &NodeConfig,
+    mut store: Option<ConfigStore<ConfigFlash>>,
+) {
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 1024];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            warn!("portal: accept error: {:?}", e);
+            continue;
+        }
+
+        let outcome = serve(&mut socket, device, networks, config, &mut store).await;
+
+        socket.close();
+        let _ = socket.flush().await;
+
+        match outcome {
+            Ok(Outcome::Served) => {}
+            Ok(Outcome::Saved) => {
+                info!("portal: rebooting into station mode");
+                Timer::after(REBOOT_DELAY).await;
+                esp_hal::system::software_reset();
+            }
+            Err(e) => warn!("portal: connection error: {:?}", e),
+        }
+
+        socket.abort();
+    }
+}
+
+#[embassy_executor::task]
+pub async fn portal_task(
+    stack: &'static Stack<'static>,
+    device: &'static DeviceInfo,
+    config: &'static NodeConfig,
+    store: Option<ConfigStore<ConfigFlash>>,
+) {
+    let networks = PORTAL_SIGNAL.wait().await;
+
+    info!("portal: serving setup page on http://{}", AP_ADDRESS);
+
+    join3(
+        dhcp_server(*stack),
+        captive_dns(*stack),
+        http_server(*stack, device, &networks, config, store),
+    )
+    .await;
+}
@@ -0,0 +1,101 @@
+use core::cell::RefCell;
+
+use defmt::info;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, with_timeout};
+use esp_hal::gpio::{Level, Output};
+
+use crate::alert::Quantity;
+use crate::control::{Action, Command, ControlConfig, Controller, RelayStatus};
+use crate::drivers::environment::SensorId;
+use crate::drivers::sht3x::Sht3xReading;
+
+/// A dehumidifier on sensor 0: on above 65 %, off again at 60 %, at least 5 minutes
+/// either way so a compressor can settle, and off while the sensor fails.
+const RELAY_CONTROL: ControlConfig = ControlConfig {
+    sensor: SensorId::new(0).unwrap(),
+    quantity: Quantity::Humidity,
+    action: Action::Lower,
+    setpoint: 60.0,
+    hysteresis: 5.0,
+    min_on_ms: 5 * 60 * 1000,
+    min_off_ms: 5 * 60 * 1000,
+    failsafe_on: false,
+};
+
+/// Whether the relay board switches on a high output. Most cheap modules switch on low.
+const ACTIVE_HIGH: bool = false;
+
+/// How often held switches and timed overrides are checked.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+static CONTROLLER: Mutex<CriticalSectionRawMutex, RefCell<Controller>> =
+    Mutex::new(RefCell::new(Controller::new(RELAY_CONTROL)));
+
+/// Wakes the task to drive the output right away.
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Output level that puts the relay `on`.
+pub fn level(on: bool) -> Level {
+    if on == ACTIVE_HIGH {
+        Level::High
+    } else {
+        Level::Low
+    }
+}
+
+/// Runs `f` on the controller and returns where the relay stands after it.
+fn apply(f: impl FnOnce(&mut Controller)) -> RelayStatus {
+    let now_ms = Instant::now().as_millis();
+    let status = CONTROLLER.lock(|controller| {
+        let mut controller = controller.borrow_mut();
+        f(&mut controller);
+        controller.update(now_ms)
+    });
+    CHANGED.signal(());
+
+    status
+}
+
+/// Feeds a reading that passed the filter to the controller.
+pub fn reading(sensor: SensorId, reading: &Sht3xReading) -> RelayStatus {
+    apply(|controller| controller.reading(sensor, reading))
+}
+
+/// Tells the controller `sensor` is failing.
+pub fn fault(sensor: SensorId) -> RelayStatus {
+    apply(|controller| controller.fault(sensor))
+}
+
+/// Applies a manual override from the API or MQTT.
+pub fn command(command: Command) -> RelayStatus {
+    info!("relay: {}", command);
+    let now_ms = Instant::now().as_millis();
+    apply(|controller| controller.command(now_ms, command))
+}
+
+pub fn status() -> RelayStatus {
+    apply(|_| {})
+}
+
+/// Drives `output` from the controller, see [`RELAY_CONTROL`].
+#[embassy_executor::task]
+pub async fn relay_task(mut output: Output<'static>) {
+    info!("relay: {}", RELAY_CONTROL);
+    let mut on = false;
+
+    loop {
+        let now_ms = Instant::now().as_millis();
+        let status = CONTROLLER.lock(|controller| controller.borrow_mut().update(now_ms));
+
+        if status.on != on {
+            info!("relay: switched {} in {} mode", status.on, status.mode);
+            on = status.on;
+        }
+        output.set_level(level(on));
+
+        let _ = with_timeout(TICK_INTERVAL, CHANGED.wait()).await;
+    }
+}
@@ -1,25 +1,262 @@
-use defmt::warn;
-use embassy_time::Timer;
+use core::cell::Cell;
 
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::alert::{AlertEngine, AlertRule, Transition};
+use crate::drivers::environment::{
+    EnvironmentSensor, FaultTracker, MAX_SENSORS, Measurement, RecoveryStep, SensorError, SensorId,
+};
+#[cfg(feature = "sht3x")]
+use crate::drivers::sht3x::{CondensationRecovery, HeaterAction, RecoveryConfig};
+use crate::drivers::{i2c_bus, scan};
 use crate::events::{Event, send_event};
-use crate::tasks::SensorHandle;
+#[cfg(feature = "sht3x")]
+use crate::filter::Quality;
+use crate::filter::{FilterConfig, Limits, ReadingFilter};
+use crate::metrics::METRICS;
+use crate::tasks::{BusHandle, SensorHandle};
+
+/// Sensors found at boot, one bit per `SensorId`.
+static ATTACHED: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// Sensors polled by [`sensor_task`], in `SensorId` order.
+pub type Sensors = Vec<(SensorId, SensorHandle), MAX_SENSORS>;
+
+/// Sensors found at boot. Uplinks announce only these.
+pub fn attached() -> impl Iterator<Item = SensorId> {
+    let attached = ATTACHED.lock(Cell::get);
+    SensorId::all().filter(move |id| attached & (1 << id.index()) != 0)
+}
+
+/// Failed reads in a row before each step up the recovery ladder, see [`RecoveryStep`].
+const ERRORS_PER_RECOVERY_STEP: u32 = 3;
+
+/// Plausibility checks every reading goes through before it is published.
+const READING_FILTER: FilterConfig = FilterConfig {
+    temperature: Limits {
+        min: -40.0,
+        max: 125.0,
+    },
+    humidity: Limits {
+        min: 0.0,
+        max: 100.0,
+    },
+    max_temperature_rate: Some(1.0),
+    max_humidity_rate: Some(5.0),
+    max_rate_rejections: 5,
+    median_of: 1,
+};
+
+/// Pulse the heater of a sensor whose humidity stays saturated, `None` to leave it off.
+#[cfg(feature = "sht3x")]
+const CONDENSATION_RECOVERY: Option<RecoveryConfig> = Some(RecoveryConfig {
+    saturated_rh: 98.0,
+    saturated_for_ms: 10 * 60 * 1000,
+    pulse_ms: 30 * 1000,
+    cooldown_ms: 2 * 60 * 1000,
+});
+
+/// Drives the heater of `sensor` through condensation recovery. Returns whether the
+/// reading in `measured` was taken while recovering, so is skewed by the heater.
+#[cfg(feature = "sht3x")]
+fn recover(
+    id: SensorId,
+    sensor: &mut SensorHandle,
+    recovery: &mut Option<CondensationRecovery>,
+    measured: &Result<Measurement, SensorError>,
+) -> bool {
+    let Some(recovery) = recovery else {
+        return false;
+    };
+
+    let recovering = recovery.is_recovering();
+    let humidity = match measured {
+        Ok(measurement) if !recovering => measurement.humidity,
+        _ => None,
+    };
+
+    if let Some(action) = recovery.update(Instant::now().as_millis(), humidity) {
+        info!("sensor: heater {} on {}", action, id.name());
+
+        if let Err(e) = sensor.set_heater(action == HeaterAction::On) {
+            warn!("sensor: failed to switch heater on {}: {}", id.name(), e);
+        }
+    }
+
+    recovering
+}
+
+/// Tries to bring back a sensor that keeps failing.
+fn escalate(id: SensorId, sensor: &mut SensorHandle, bus: &mut BusHandle, step: RecoveryStep) {
+    warn!("sensor: {} keeps failing, trying {}", id.name(), step);
+    METRICS.sensor_recoveries.inc(step.label());
+
+    match step {
+        RecoveryStep::ResetSensor => {}
+        RecoveryStep::ResetBus => {
+            if scan::general_call_reset(bus).is_err() {
+                warn!("sensor: general call reset failed");
+            }
+        }
+        // Done by the next transaction, the reset below.
+        RecoveryStep::RecoverBus => i2c_bus::request_recovery(),
+    }
+
+    // Every step leaves the chip at its power-up defaults.
+    if let Err(e) = sensor.reset() {
+        warn!("sensor: failed to reset {}: {}", id.name(), e);
+    }
+}
+
+/// Counts `measured` towards the faults of `sensor`, also while it recovers from
+/// condensation, escalating when it keeps failing and reporting when it starts to.
+async fn track(
+    id: SensorId,
+    sensor: &mut SensorHandle,
+    bus: &mut BusHandle,
+    fault: &mut FaultTracker,
+    measured: &Result<Measurement, SensorError>,
+) {
+    match *measured {
+        Ok(_) => {
+            if fault.succeeded() {
+                info!("sensor: {} recovered", id.name());
+            }
+        }
+        // Polled faster than it measures, the next round picks the result up.
+        Err(SensorError::NotReady) => {}
+        Err(e) => {
+            METRICS.sensor_errors.inc(e.label());
+            warn!("Sensor read error on {}: {}", id.name(), e);
 
-const SENSOR_POLLING_RATE_MS: u64 = 1000;
+            let faulted = fault.is_faulted();
+            if let Some(step) = fault.failed() {
+                escalate(id, sensor, bus, step);
+            }
+            if !faulted && fault.is_faulted() {
+                send_event(Event::SensorFault(id, e)).await;
+            }
+        }
+    }
+}
+
+fn record(id: SensorId, measurement: &Measurement) {
+    let gauges = [
+        (&METRICS.temperature, measurement.temperature),
+        (&METRICS.humidity, measurement.humidity),
+        (&METRICS.pressure, measurement.pressure),
+        (&METRICS.co2, measurement.co2.map(f64::from)),
+    ];
+
+    for (gauge, value) in gauges {
+        match value {
+            Some(value) => gauge.set(id, value as f32),
+            None => gauge.clear(id),
+        }
+    }
+}
 
 #[embassy_executor::task]
-pub async fn sensor_task(mut sensor: SensorHandle) {
+pub async fn sensor_task(
+    mut sensors: Sensors,
+    mut bus: BusHandle,
+    polling_interval: Duration,
+    alert_rules: &'static [AlertRule],
+) {
+    ATTACHED.lock(|attached| {
+        for (id, _) in &sensors {
+            attached.set(attached.get() | (1 << id.index()));
+        }
+    });
+
+    for (id, sensor) in &sensors {
+        info!(
+            "sensor: reading {} as {} every {} ms",
+            sensor.name(),
+            id.name(),
+            polling_interval.as_millis()
+        );
+    }
+
+    let mut faults = [FaultTracker::new(ERRORS_PER_RECOVERY_STEP); MAX_SENSORS];
+    let mut filters = [const { ReadingFilter::new(READING_FILTER) }; MAX_SENSORS];
+    let mut alerts = AlertEngine::new();
+
+    #[cfg(feature = "sht3x")]
+    let mut recovery = [CONDENSATION_RECOVERY.map(CondensationRecovery::new); MAX_SENSORS];
+
     loop {
-        match sensor.read() {
-            Ok(reading) => {
-                send_event(Event::SensorReading(reading)).await;
+        for (id, sensor) in &mut sensors {
+            let id = *id;
+
+            let measured = sensor.measure();
+            track(id, sensor, &mut bus, &mut faults[id.index()], &measured).await;
+
+            #[cfg(feature = "sht3x")]
+            if recover(id, sensor, &mut recovery[id.index()], &measured) {
+                // Published flagged, but kept away from the filter, whose rate limits and
+                // median it would upset, and from the alerts.
+                if let Some(measurement) = measured
+                    .ok()
+                    .filter(|measured| measured.reading().is_some())
+                {
+                    send_event(Event::SensorReading(
+                        id,
+                        measurement,
+                        Quality::HEATER_ACTIVE,
+                    ))
+                    .await;
+                }
+                continue;
             }
-            Err(e) => {
-                warn!("Sensor read error: {}", e);
+
+            let Ok(measurement) = measured else {
+                continue;
+            };
+
+            // Everything downstream works on temperature and humidity.
+            let Some(raw) = measurement.reading() else {
+                record(id, &measurement);
+                warn!("sensor: {} gave no temperature or humidity", sensor.name());
+                continue;
+            };
+
+            let now_ms = Instant::now().as_millis();
+            match filters[id.index()].apply(now_ms, raw) {
+                Ok((reading, quality)) => {
+                    let filtered = Measurement {
+                        temperature: Some(reading.temperature),
+                        humidity: Some(reading.humidity),
+                        ..measurement
+                    };
+                    record(id, &filtered);
+                    send_event(Event::SensorReading(id, filtered, quality)).await;
+
+                    for transition in alerts.evaluate(alert_rules, now_ms, id, &reading) {
+                        send_event(match transition {
+                            Transition::Raised(alert) => Event::AlertRaised(alert),
+                            Transition::Cleared(alert) => Event::AlertCleared(alert),
+                        })
+                        .await;
+                    }
+                }
+                Err(rejection) => {
+                    METRICS.rejected_readings.inc(rejection.label());
+                    warn!(
+                        "sensor: discarded {} C, {} % from {}: {}",
+                        raw.temperature,
+                        raw.humidity,
+                        id.name(),
+                        rejection
+                    );
+                }
             }
         }
 
-        //send_event(Event::SensorReading(crate::drivers::sht3x::Sht3xReading {temperature: 69f64, humidity: 68f64})).await;
-
-        Timer::after_millis(SENSOR_POLLING_RATE_MS).await;
+        Timer::after(polling_interval).await;
     }
 }
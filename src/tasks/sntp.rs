@@ -0,0 +1,128 @@
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+
+use crate::sntp::{self, ServerResponse, Timestamp, WallClock};
+use crate::tasks::net::resolve;
+
+const NTP_SERVER: &str = "pool.ntp.org";
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+static WALL_CLOCK: Mutex<CriticalSectionRawMutex, RefCell<WallClock>> =
+    Mutex::new(RefCell::new(WallClock::new()));
+
+/// Current Unix time in microseconds, or `None` until the first successful sync.
+pub fn now_utc() -> Option<i64> {
+    utc_at(Instant::now())
+}
+
+/// Unix time in microseconds of something that happened at `at`, see [`timestamp`].
+pub fn utc_at(at: Instant) -> Option<i64> {
+    WALL_CLOCK.lock(|clock| clock.borrow().utc_us(at.as_micros()))
+}
+
+pub fn is_synced() -> bool {
+    WALL_CLOCK.lock(|clock| clock.borrow().is_synced())
+}
+
+/// Timestamp for something that happened at `at`. Conversion happens at call time, so
+/// readings taken before the first sync still get wall-clock time if the clock has synced
+/// since.
+pub fn timestamp(at: Instant) -> Timestamp {
+    WALL_CLOCK.lock(|clock| Timestamp::new(&clock.borrow(), at.as_micros()))
+}
+
+async fn sync_once(stack: &Stack<'_>, socket: &mut UdpSocket<'_>) -> Result<(), ()> {
+    let Some(addr) = resolve(stack, NTP_SERVER).await else {
+        return Err(());
+    };
+    let server = IpEndpoint::new(addr, sntp::PORT);
+
+    let sent_us = Instant::now().as_micros();
+    let request = sntp::request_packet(sent_us);
+
+    if let Err(e) = socket.send_to(&request, server).await {
+        warn!("sntp: send error: {:?}", e);
+        return Err(());
+    }
+
+    let mut buf = [0u8; sntp::PACKET_LEN];
+
+    loop {
+        let (n, _) = match with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                warn!("sntp: receive error: {:?}", e);
+                return Err(());
+            }
+            Err(_) => {
+                warn!("sntp: no response from {}", server);
+                return Err(());
+            }
+        };
+
+        let received_us = Instant::now().as_micros();
+
+        let response = match ServerResponse::parse(&buf[..n], sent_us) {
+            Ok(response) => response,
+            // A late answer to an earlier request, keep waiting for ours.
+            Err(sntp::SntpError::OriginMismatch) => continue,
+            Err(e) => {
+                warn!("sntp: bad response from {}: {:?}", server, e);
+                return Err(());
+            }
+        };
+
+        let offset_us = response.offset_us(sent_us, received_us);
+
+        let drift_ppb = WALL_CLOCK.lock(|clock| {
+            let mut clock = clock.borrow_mut();
+            clock.update(received_us, offset_us);
+            clock.drift_ppb()
+        });
+
+        info!(
+            "sntp: synced to {} (stratum {}), rtt {} us, drift {} ppb",
+            server,
+            response.stratum,
+            response.round_trip_us(sent_us, received_us),
+            drift_ppb
+        );
+
+        return Ok(());
+    }
+}
+
+#[embassy_executor::task]
+pub async fn sntp_task(stack: &'static Stack<'static>) {
+    stack.wait_config_up().await;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buf = [0u8; 2 * sntp::PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buf = [0u8; sntp::PACKET_LEN];
+
+    let mut socket = UdpSocket::new(*stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+
+    if let Err(e) = socket.bind(0) {
+        warn!("sntp: bind error: {:?}", e);
+        return;
+    }
+
+    loop {
+        let delay = match sync_once(stack, &mut socket).await {
+            Ok(()) => SYNC_INTERVAL,
+            Err(()) => RETRY_INTERVAL,
+        };
+
+        Timer::after(delay).await;
+    }
+}
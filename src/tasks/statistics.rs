@@ -0,0 +1,55 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::drivers::environment::{MAX_SENSORS, SensorId};
+use crate::drivers::sht3x::Sht3xReading;
+use crate::stats::{Statistics, Window, WindowSummary};
+
+/// Windows kept for every sensor, each in `stats::BUCKETS` buckets.
+const WINDOWS: [Window; 2] = [
+    Window::new("1h", 60 * 60 * 1000),
+    Window::new("24h", 24 * 60 * 60 * 1000),
+];
+pub const WINDOW_COUNT: usize = WINDOWS.len();
+
+/// How often the uplinks send the statistics.
+pub const UPLOAD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+static STATISTICS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[Statistics<WINDOW_COUNT>; MAX_SENSORS]>,
+> = Mutex::new(RefCell::new(
+    [const { Statistics::new(WINDOWS) }; MAX_SENSORS],
+));
+
+/// Label of the window at `index` in [`summaries`], e.g. `24h`.
+pub fn label(index: usize) -> &'static str {
+    WINDOWS[index].label()
+}
+
+/// Adds a reading that passed the filter.
+pub fn record(sensor: SensorId, reading: &Sht3xReading) {
+    let now_ms = Instant::now().as_millis();
+    STATISTICS.lock(|statistics| statistics.borrow_mut()[sensor.index()].add(now_ms, reading));
+}
+
+/// Current summary of each window of `sensor`, `None` for those without readings.
+pub fn summaries(sensor: SensorId) -> [Option<WindowSummary>; WINDOW_COUNT] {
+    let now_ms = Instant::now().as_millis();
+    STATISTICS.lock(|statistics| statistics.borrow()[sensor.index()].summaries(now_ms))
+}
+
+/// Summaries of every sensor with readings in at least one window, leaving out the empty
+/// windows.
+pub fn all() -> Vec<(SensorId, Vec<WindowSummary, WINDOW_COUNT>), MAX_SENSORS> {
+    SensorId::all()
+        .filter_map(|sensor| {
+            let windows: Vec<_, WINDOW_COUNT> = summaries(sensor).into_iter().flatten().collect();
+            (!windows.is_empty()).then_some((sensor, windows))
+        })
+        .collect()
+}
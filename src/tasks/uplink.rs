@@ -0,0 +1,486 @@
+use core::cell::{Cell, RefCell};
+
+use defmt::{Format, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use heapless::{Deque, Vec};
+use serde::Serialize;
+
+use crate::alert::{Alert, Comparison, Quantity};
+use crate::control::RelayStatus;
+use crate::drivers::environment::{Measurement, SensorError, SensorId};
+use crate::filter::Quality;
+use crate::metrics::{Counter, METRICS, UPLINKS};
+use crate::psychro::Psychrometrics;
+use crate::queue::{DropPolicy, ReadingQueue};
+use crate::tasks::sntp;
+
+/// Most readings handed to [`Uplink::publish`] at once.
+pub const BATCH_CAPACITY: usize = 30;
+/// A partial batch is published once its oldest reading has waited this long.
+const BATCH_MAX_AGE: Duration = Duration::from_secs(30);
+/// [`Uplink::idle`] runs after the queue has been empty this long, unless the uplink asks
+/// otherwise.
+pub const IDLE_INTERVAL: Duration = Duration::from_secs(30);
+/// Alerts waiting for one uplink. The oldest goes when it is full.
+const ALERT_CAPACITY: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueuedReading {
+    pub seq: u32,
+    pub queued_at: Instant,
+    pub sensor: SensorId,
+    /// `Err` reports the sensor as failing instead.
+    pub reading: Result<Measurement, SensorError>,
+    pub quality: Quality,
+    /// Where the relay stood, `None` without the `relay` feature.
+    pub relay: Option<RelayStatus>,
+}
+
+impl QueuedReading {
+    pub fn temperature(&self) -> Option<f64> {
+        self.reading.ok().and_then(|reading| reading.temperature)
+    }
+
+    pub fn humidity(&self) -> Option<f64> {
+        self.reading.ok().and_then(|reading| reading.humidity)
+    }
+
+    pub fn pressure(&self) -> Option<f64> {
+        self.reading.ok().and_then(|reading| reading.pressure)
+    }
+
+    pub fn co2(&self) -> Option<u16> {
+        self.reading.ok().and_then(|reading| reading.co2)
+    }
+
+    /// Why the sensor is failing, see `SensorError::label`.
+    pub fn fault(&self) -> Option<&'static str> {
+        self.reading.err().map(|e| e.label())
+    }
+
+    /// Dew point and the like, only with the `psychro` feature.
+    pub fn derived(&self) -> Option<Psychrometrics> {
+        if !cfg!(feature = "psychro") {
+            return None;
+        }
+        self.reading
+            .ok()
+            .and_then(|reading| reading.reading())
+            .as_ref()
+            .and_then(Psychrometrics::new)
+    }
+}
+
+/// An alert raised or cleared, delivered on its own ahead of the readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueuedAlert {
+    pub queued_at: Instant,
+    pub alert: Alert,
+    /// `false` once it cleared.
+    pub raised: bool,
+}
+
+impl QueuedAlert {
+    /// `raised` or `cleared`.
+    pub fn state(&self) -> &'static str {
+        if self.raised { "raised" } else { "cleared" }
+    }
+
+    pub fn message(&self) -> AlertMessage {
+        let timestamp = sntp::timestamp(self.queued_at);
+
+        AlertMessage {
+            sensor: self.alert.sensor.name(),
+            state: self.state(),
+            rule: self.alert.rule,
+            quantity: self.alert.quantity,
+            comparison: self.alert.comparison,
+            threshold: self.alert.threshold,
+            value: self.alert.value,
+            timestamp: timestamp.unix_ms,
+            synced: timestamp.synced,
+        }
+    }
+}
+
+/// JSON form of a [`QueuedAlert`], for the uplinks that send JSON.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct AlertMessage {
+    pub sensor: &'static str,
+    /// `raised` or `cleared`.
+    pub state: &'static str,
+    pub rule: u8,
+    pub quantity: Quantity,
+    pub comparison: Comparison,
+    pub threshold: f32,
+    pub value: f32,
+    pub timestamp: u64,
+    pub synced: bool,
+}
+
+fn sensor_of(entry: &QueuedReading) -> usize {
+    entry.sensor.index()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Health {
+    /// Nothing attempted yet.
+    Starting,
+    Up,
+    /// The last attempt failed, for the given reason.
+    Down(&'static str),
+}
+
+/// Why a batch wasn't delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Failure {
+    /// Keep the batch and try again after the delay.
+    Retry(Duration),
+    /// The destination will never accept the batch, discard it.
+    Reject,
+}
+
+/// A destination for readings.
+///
+/// Every uplink runs in its own task, fed from its own [`UplinkQueue`], so a slow or
+/// unreachable destination only backs up its own queue.
+#[allow(async_fn_in_trait)]
+pub trait Uplink {
+    /// Most readings [`Uplink::publish`] takes at once, capped at [`BATCH_CAPACITY`].
+    fn max_batch(&self) -> usize {
+        1
+    }
+
+    /// Delivers `batch`, oldest reading first.
+    async fn publish(&mut self, batch: &[QueuedReading]) -> Result<(), Failure>;
+
+    /// Delivers an alert as a message of its own.
+    async fn publish_alert(&mut self, alert: &QueuedAlert) -> Result<(), Failure>;
+
+    /// Runs when there was nothing to publish for a while, e.g. to keep a connection open.
+    async fn idle(&mut self) {}
+
+    /// How long the queue has to stay empty before [`Uplink::idle`] runs.
+    fn idle_interval(&self) -> Duration {
+        IDLE_INTERVAL
+    }
+
+    fn health(&self) -> Health;
+}
+
+struct Backlog<const N: usize> {
+    readings: ReadingQueue<QueuedReading, N>,
+    next_seq: u32,
+}
+
+/// Readings waiting for one uplink, plus its delivery counters.
+pub struct UplinkQueue<const N: usize> {
+    name: &'static str,
+    /// Not a blocking mutex, downsampling a full queue walks all of it and must not do so
+    /// with interrupts off.
+    backlog: AsyncMutex<CriticalSectionRawMutex, Backlog<N>>,
+    alerts: Mutex<CriticalSectionRawMutex, RefCell<Deque<QueuedAlert, ALERT_CAPACITY>>>,
+    signal: Signal<CriticalSectionRawMutex, ()>,
+    health: Mutex<CriticalSectionRawMutex, Cell<Health>>,
+    delivered: Counter,
+    rejected: Counter,
+    failures: Counter,
+}
+
+/// Snapshot of an [`UplinkQueue`], for status reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct UplinkStats {
+    pub name: &'static str,
+    pub health: Health,
+    pub queued: usize,
+    pub capacity: usize,
+    /// Readings shed by the drop policy.
+    pub dropped: u32,
+    pub delivered: u32,
+    /// Readings the destination refused for good.
+    pub rejected: u32,
+    /// Failed attempts that were retried.
+    pub failures: u32,
+}
+
+impl<const N: usize> UplinkQueue<N> {
+    /// `name` must be one of [`UPLINKS`] for drops to show up in the metrics.
+    pub const fn new(name: &'static str, policy: DropPolicy) -> Self {
+        Self {
+            name,
+            backlog: AsyncMutex::new(Backlog {
+                readings: ReadingQueue::with_streams(policy, sensor_of),
+                next_seq: 0,
+            }),
+            alerts: Mutex::new(RefCell::new(Deque::new())),
+            signal: Signal::new(),
+            health: Mutex::new(Cell::new(Health::Starting)),
+            delivered: Counter::new(),
+            rejected: Counter::new(),
+            failures: Counter::new(),
+        }
+    }
+
+    /// Queues a reading of `sensor` taken at `at`, or the fault that stopped it. Only waits
+    /// for the queue lock, a full queue sheds old readings.
+    pub async fn push(
+        &self,
+        at: Instant,
+        sensor: SensorId,
+        reading: Result<Measurement, SensorError>,
+        quality: Quality,
+        relay: Option<RelayStatus>,
+    ) {
+        let dropped = {
+            let mut backlog = self.backlog.lock().await;
+            let seq = backlog.next_seq;
+            backlog.next_seq = seq.wrapping_add(1);
+
+            let dropped = backlog.readings.dropped();
+            backlog.readings.push(QueuedReading {
+                seq,
+                queued_at: at,
+                sensor,
+                reading,
+                quality,
+                relay,
+            });
+            backlog.readings.dropped().wrapping_sub(dropped)
+        };
+
+        if dropped > 0 {
+            METRICS.dropped_readings.add(self.name, dropped);
+        }
+
+        self.signal.signal(());
+    }
+
+    /// Queues an alert raised or cleared. Never blocks, a full queue sheds the oldest.
+    pub fn push_alert(&self, at: Instant, alert: Alert, raised: bool) {
+        let entry = QueuedAlert {
+            queued_at: at,
+            alert,
+            raised,
+        };
+
+        let dropped = self.alerts.lock(|alerts| {
+            let mut alerts = alerts.borrow_mut();
+            let dropped = alerts.is_full() && alerts.pop_front().is_some();
+            let _ = alerts.push_back(entry);
+            dropped
+        });
+
+        if dropped {
+            warn!("{}: alert queue full, dropped the oldest", self.name);
+        }
+
+        self.signal.signal(());
+    }
+
+    pub async fn stats(&self) -> UplinkStats {
+        let (queued, dropped) = {
+            let backlog = self.backlog.lock().await;
+            (backlog.readings.len(), backlog.readings.dropped())
+        };
+
+        UplinkStats {
+            name: self.name,
+            health: self.health.lock(Cell::get),
+            queued,
+            capacity: N,
+            dropped,
+            delivered: self.delivered.get(),
+            rejected: self.rejected.get(),
+            failures: self.failures.get(),
+        }
+    }
+
+    /// Waits until `max` readings are queued or the oldest has waited [`BATCH_MAX_AGE`], or
+    /// an alert is queued. Returns `false` if the queue stayed empty for `idle_interval`
+    /// instead.
+    async fn wait_batch(&self, max: usize, idle_interval: Duration) -> bool {
+        let idle_deadline = Instant::now() + idle_interval;
+
+        loop {
+            if self.oldest_alert().is_some() {
+                return true;
+            }
+
+            let (len, oldest) = {
+                let backlog = self.backlog.lock().await;
+                (
+                    backlog.readings.len(),
+                    backlog.readings.front().map(|entry| entry.queued_at),
+                )
+            };
+
+            let now = Instant::now();
+            let deadline = match oldest {
+                Some(oldest) if len >= max || now >= oldest + BATCH_MAX_AGE => return true,
+                Some(oldest) => oldest + BATCH_MAX_AGE,
+                None if now >= idle_deadline => return false,
+                None => idle_deadline,
+            };
+
+            let _ = with_timeout(deadline - now, self.signal.wait()).await;
+        }
+    }
+
+    /// Copies up to `max` of the oldest readings, leaving them queued until acknowledged.
+    async fn oldest(&self, max: usize) -> Vec<QueuedReading, BATCH_CAPACITY> {
+        let backlog = self.backlog.lock().await;
+        backlog.readings.iter().take(max).copied().collect()
+    }
+
+    fn oldest_alert(&self) -> Option<QueuedAlert> {
+        self.alerts.lock(|alerts| alerts.borrow().front().copied())
+    }
+
+    /// Removes `alert`, unless it was already shed while in flight.
+    fn acknowledge_alert(&self, alert: &QueuedAlert) {
+        self.alerts.lock(|alerts| {
+            let mut alerts = alerts.borrow_mut();
+            if alerts.front() == Some(alert) {
+                alerts.pop_front();
+            }
+        });
+    }
+
+    /// Removes `seq` and everything queued before it. Entries may already be gone if the
+    /// drop policy discarded them while the batch was in flight.
+    async fn acknowledge(&self, seq: u32) {
+        self.backlog
+            .lock()
+            .await
+            .readings
+            .pop_while(|entry| entry.seq.wrapping_sub(seq) as i32 <= 0);
+    }
+}
+
+/// Feeds `uplink` from `queue` forever.
+pub async fn run<U: Uplink, const N: usize>(uplink: &mut U, queue: &UplinkQueue<N>) -> ! {
+    let mut reported_dropped = 0;
+
+    loop {
+        if let Some(alert) = queue.oldest_alert() {
+            let result = uplink.publish_alert(&alert).await;
+            queue.health.lock(|health| health.set(uplink.health()));
+
+            match result {
+                Ok(()) => queue.acknowledge_alert(&alert),
+                Err(Failure::Reject) => {
+                    warn!("{}: discarding alert {}", queue.name, alert.alert);
+                    queue.acknowledge_alert(&alert);
+                }
+                Err(Failure::Retry(delay)) => {
+                    queue.failures.inc();
+                    METRICS.uplink_failures.inc(queue.name);
+                    Timer::after(delay).await;
+                }
+            }
+            continue;
+        }
+
+        let max = uplink.max_batch().clamp(1, BATCH_CAPACITY);
+
+        if !queue.wait_batch(max, uplink.idle_interval()).await {
+            uplink.idle().await;
+            queue.health.lock(|health| health.set(uplink.health()));
+            continue;
+        }
+
+        let batch = queue.oldest(max).await;
+        let Some(last) = batch.last() else {
+            continue;
+        };
+
+        let result = uplink.publish(&batch).await;
+        queue.health.lock(|health| health.set(uplink.health()));
+
+        match result {
+            Ok(()) => {
+                queue.acknowledge(last.seq).await;
+                queue.delivered.add(batch.len() as u32);
+            }
+            Err(Failure::Reject) => {
+                warn!(
+                    "{}: discarding {} readings up to {}",
+                    queue.name,
+                    batch.len(),
+                    last.seq
+                );
+                queue.acknowledge(last.seq).await;
+                queue.rejected.add(batch.len() as u32);
+            }
+            Err(Failure::Retry(delay)) => {
+                queue.failures.inc();
+                METRICS.uplink_failures.inc(queue.name);
+                Timer::after(delay).await;
+            }
+        }
+
+        let dropped = queue.stats().await.dropped;
+        if dropped != reported_dropped {
+            warn!(
+                "{}: {} readings dropped while the queue was full",
+                queue.name,
+                dropped.wrapping_sub(reported_dropped)
+            );
+            reported_dropped = dropped;
+        }
+    }
+}
+
+/// Hands a reading, or a fault of `sensor`, to every enabled uplink, with where the relay
+/// stood.
+pub async fn publish(
+    sensor: SensorId,
+    reading: Result<Measurement, SensorError>,
+    quality: Quality,
+    relay: Option<RelayStatus>,
+) {
+    let at = Instant::now();
+
+    #[cfg(feature = "http")]
+    crate::tasks::http_client::QUEUE
+        .push(at, sensor, reading, quality, relay)
+        .await;
+    #[cfg(feature = "mqtt")]
+    crate::tasks::mqtt::QUEUE
+        .push(at, sensor, reading, quality, relay)
+        .await;
+    #[cfg(feature = "influx")]
+    crate::tasks::influx::QUEUE
+        .push(at, sensor, reading, quality, relay)
+        .await;
+}
+
+/// Hands an alert raised, or cleared if not `raised`, to every enabled uplink.
+pub fn publish_alert(alert: Alert, raised: bool) {
+    let at = Instant::now();
+
+    #[cfg(feature = "http")]
+    crate::tasks::http_client::QUEUE.push_alert(at, alert, raised);
+    #[cfg(feature = "mqtt")]
+    crate::tasks::mqtt::QUEUE.push_alert(at, alert, raised);
+    #[cfg(feature = "influx")]
+    crate::tasks::influx::QUEUE.push_alert(at, alert, raised);
+}
+
+/// Stats of every enabled uplink.
+pub async fn stats() -> Vec<UplinkStats, { UPLINKS.len() }> {
+    let mut stats = Vec::new();
+
+    #[cfg(feature = "http")]
+    let _ = stats.push(crate::tasks::http_client::QUEUE.stats().await);
+    #[cfg(feature = "mqtt")]
+    let _ = stats.push(crate::tasks::mqtt::QUEUE.stats().await);
+    #[cfg(feature = "influx")]
+    let _ = stats.push(crate::tasks::influx::QUEUE.stats().await);
+
+    stats
+}
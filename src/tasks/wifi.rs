@@ -1,14 +1,46 @@
-use defmt::{error, info, warn, Format};
-use embassy_time::{Duration, Timer};
-use esp_radio::wifi::{WifiController, WifiEvent};
+use core::sync::atomic::{AtomicI32, Ordering};
 
+use defmt::{Format, error, info, warn};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_radio::wifi::{
+    AccessPointConfig, AuthMethod, ClientConfig, ModeConfig, ScanConfig, WifiController, WifiEvent,
+};
+use heapless::String;
+
+use crate::config::NodeConfig;
+use crate::device::DeviceInfo;
 use crate::events::{Event, send_event};
+use crate::metrics::METRICS;
+use crate::portal::ScanList;
+use crate::tasks::portal;
+
+/// How long a configured network has to stay out of reach before the node falls back to the
+/// setup portal, so a router reboot or a short outage doesn't bring up the access point.
+const OUTAGE_BEFORE_PORTAL: Duration = Duration::from_secs(30 * 60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long the setup portal waits for new settings before the node reboots to try the
+/// configured network again, e.g. after the router was down for a while.
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const RSSI_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Last measured signal strength in dBm, `i32::MIN` while disconnected.
+static RSSI: AtomicI32 = AtomicI32::new(i32::MIN);
+
+/// Signal strength of the current connection in dBm.
+pub fn rssi() -> Option<i32> {
+    match RSSI.load(Ordering::Relaxed) {
+        i32::MIN => None,
+        rssi => Some(rssi),
+    }
+}
 
 #[derive(Debug, Clone, Copy, Format)]
 pub enum WifiState {
     Connecting,
     Connected,
     Disconnected,
+    /// Serving the setup portal as an access point.
+    Provisioning,
 }
 
 impl From<WifiState> for &str {
@@ -17,14 +49,139 @@ impl From<WifiState> for &str {
             WifiState::Connecting => "Wifi: connecting",
             WifiState::Connected => "Wifi: connected",
             WifiState::Disconnected => "Wifi: disconnected",
+            WifiState::Provisioning => "Wifi: setup mode",
         }
     }
 }
 
+fn client_config(config: &NodeConfig) -> ModeConfig {
+    let auth_method = if config.wifi_password.is_empty() {
+        AuthMethod::None
+    } else {
+        AuthMethod::Wpa2Personal
+    };
+
+    ModeConfig::Client(
+        ClientConfig::default()
+            .with_ssid(config.wifi_ssid.as_str().into())
+            .with_password(config.wifi_password.as_str().into())
+            .with_auth_method(auth_method),
+    )
+}
+
+/// Names of the networks in range, strongest first and without duplicates.
+async fn scan(controller: &mut WifiController<'static>) -> ScanList {
+    let mut networks = ScanList::new();
+
+    let mut found = match controller
+        .scan_with_config_async(ScanConfig::default())
+        .await
+    {
+        Ok(found) => found,
+        Err(e) => {
+            warn!("wifi: scan failed: {:?}", e);
+            return networks;
+        }
+    };
+
+    found.sort_unstable_by_key(|ap| core::cmp::Reverse(ap.signal_strength));
+
+    for ap in &found {
+        let Ok(ssid) = String::try_from(ap.ssid.as_str()) else {
+            continue;
+        };
+
+        if ssid.is_empty() || networks.contains(&ssid) {
+            continue;
+        }
+
+        if networks.push(ssid).is_err() {
+            break;
+        }
+    }
+
+    networks
+}
+
+/// Switches to access point mode and hands over to the setup portal, which reboots the node
+/// once new settings are saved. The access point is protected by the node's setup key. A node that already has a network reboots after
+/// [`PORTAL_TIMEOUT`] without them, and any node reboots if the access point won't start.
+async fn provision(
+    controller: &mut WifiController<'static>,
+    config: &NodeConfig,
+    device: &DeviceInfo,
+) -> ! {
+    send_event(Event::WifiStatus(WifiState::Provisioning)).await;
+
+    // Scanning needs station mode, so it happens before the switch.
+    let networks = scan(controller).await;
+    info!("wifi: found {} networks", networks.len());
+
+    let ap_config = ModeConfig::AccessPoint(
+        AccessPointConfig::default()
+            .with_ssid(device.node_id.as_str().into())
+            .with_password(device.setup_key.as_str().into())
+            .with_auth_method(AuthMethod::Wpa2Personal),
+    );
+
+    if let Err(e) = controller.stop_async().await {
+        warn!("wifi: stop_async failed: {:?}", e);
+    }
+
+    let started = match controller.set_config(&ap_config) {
+        Err(e) => {
+            error!("wifi: failed to configure access point: {:?}", e);
+            false
+        }
+        Ok(()) => match controller.start_async().await {
+            Err(e) => {
+                error!("wifi: failed to start access point: {:?}", e);
+                false
+            }
+            Ok(()) => true,
+        },
+    };
+
+    if !started {
+        warn!("wifi: rebooting in {}s", RECONNECT_DELAY.as_secs());
+        Timer::after(RECONNECT_DELAY).await;
+        esp_hal::system::software_reset();
+    }
+
+    info!(
+        "wifi: setup portal on {}, key {}",
+        device.node_id.as_str(),
+        device.setup_key.as_str()
+    );
+    portal::start(networks);
+
+    // The controller has to stay alive for the access point to keep running.
+    if config.has_wifi() {
+        Timer::after(PORTAL_TIMEOUT).await;
+        info!(
+            "wifi: no new settings, rebooting to retry {}",
+            config.wifi_ssid.as_str()
+        );
+        esp_hal::system::software_reset();
+    }
+
+    loop {
+        Timer::after(Duration::from_secs(3600)).await;
+    }
+}
+
 #[embassy_executor::task]
-pub async fn wifi_task(mut controller: WifiController<'static>) {
+pub async fn wifi_task(
+    mut controller: WifiController<'static>,
+    config: &'static NodeConfig,
+    device: &'static DeviceInfo,
+) {
     info!("wifi_task: starting driver");
 
+    if let Err(e) = controller.set_config(&client_config(config)) {
+        error!("wifi_task: set_config failed: {:?}", e);
+    }
+
     if let Err(e) = controller.start_async().await {
         error!("wifi_task: start_async failed: {:?}", e);
         send_event(Event::WifiStatus(WifiState::Disconnected)).await;
@@ -33,26 +190,63 @@ pub async fn wifi_task(mut controller: WifiController<'static>) {
 
     info!("wifi_task: driver started, connecting as STA");
 
+    if !config.has_wifi() {
+        info!("wifi_task: no network configured");
+        provision(&mut controller, config, device).await;
+    }
+
     send_event(Event::WifiStatus(WifiState::Connecting)).await;
 
+    let mut failing_since: Option<Instant> = None;
+
     loop {
         info!("wifi_task: connecting to AP…");
 
         match controller.connect_async().await {
             Ok(()) => {
+                failing_since = None;
                 info!("wifi: connected, waiting for disconnect");
                 send_event(Event::WifiStatus(WifiState::Connected)).await;
 
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
+                loop {
+                    if let Ok(rssi) = controller.rssi() {
+                        RSSI.store(rssi, Ordering::Relaxed);
+                        METRICS.rssi.set(rssi as f32);
+                    }
+
+                    let disconnected = with_timeout(
+                        RSSI_POLL_INTERVAL,
+                        controller.wait_for_event(WifiEvent::StaDisconnected),
+                    )
+                    .await;
+
+                    if disconnected.is_ok() || !matches!(controller.is_connected(), Ok(true)) {
+                        break;
+                    }
+                }
+
+                RSSI.store(i32::MIN, Ordering::Relaxed);
+                METRICS.rssi.clear();
+                METRICS.wifi_reconnects.inc();
                 warn!("wifi: STA disconnected, retrying in 5s");
                 send_event(Event::WifiStatus(WifiState::Disconnected)).await;
 
-                Timer::after(Duration::from_secs(5)).await;
+                Timer::after(RECONNECT_DELAY).await;
             }
             Err(e) => {
-                warn!("wifi: connect_async failed: {:?}", e);
+                let outage = failing_since.get_or_insert_with(Instant::now).elapsed();
+                warn!(
+                    "wifi: connect_async failed ({}s without a network): {:?}",
+                    outage.as_secs(),
+                    e
+                );
                 send_event(Event::WifiStatus(WifiState::Disconnected)).await;
-                Timer::after(Duration::from_secs(5)).await;
+
+                if outage >= OUTAGE_BEFORE_PORTAL {
+                    provision(&mut controller, config, device).await;
+                }
+
+                Timer::after(RECONNECT_DELAY).await;
             }
         }
     }